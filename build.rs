@@ -8,13 +8,29 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // an optional file the bootloader loads into memory next to the kernel, e.g.
+    // `RAMDISK=initrd.tar cargo run`
+    println!("cargo:rerun-if-env-changed=RAMDISK");
+    let ramdisk = std::env::var_os("RAMDISK").map(PathBuf::from);
+    if let Some(ramdisk) = &ramdisk {
+        println!("cargo:rerun-if-changed={}", ramdisk.display());
+    }
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
+    let mut uefi = bootloader::UefiBoot::new(&kernel);
+    if let Some(ramdisk) = &ramdisk {
+        uefi.set_ramdisk(ramdisk);
+    }
+    uefi.create_disk_image(&uefi_path).unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path).unwrap();
+    let mut bios = bootloader::BiosBoot::new(&kernel);
+    if let Some(ramdisk) = &ramdisk {
+        bios.set_ramdisk(ramdisk);
+    }
+    bios.create_disk_image(&bios_path).unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+const PCI_IDS_PATH: &str = "src/assets/pci.ids";
+
+struct Vendor {
+    id: u16,
+    name: String,
+    devices: Vec<(u16, String)>,
+}
+
+struct Class {
+    id: u8,
+    name: String,
+    subclasses: Vec<Subclass>,
+}
+
+struct Subclass {
+    id: u8,
+    name: String,
+    programming_interfaces: Vec<(u8, String)>,
+}
+
+fn split_entry(line: &str) -> (&str, String) {
+    let line = line.trim();
+    let (id, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+    (id, name.trim().to_string())
+}
+
+fn parse_pci_ids(source: &str) -> (Vec<Vendor>, Vec<Class>) {
+    let mut vendors: Vec<Vendor> = vec![];
+    let mut classes: Vec<Class> = vec![];
+    let mut in_class_section = false;
+
+    for line in source.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        let depth = line.chars().take_while(|c| *c == '\t').count();
+
+        if let Some(class) = line.strip_prefix("C ") {
+            in_class_section = true;
+            let (id, name) = split_entry(class);
+
+            classes.push(Class {
+                id: u8::from_str_radix(id, 16).expect("invalid class id"),
+                name,
+                subclasses: vec![],
+            });
+            continue;
+        }
+
+        let (id, name) = split_entry(line);
+
+        match (in_class_section, depth) {
+            (false, 0) => vendors.push(Vendor {
+                id: u16::from_str_radix(id, 16).expect("invalid vendor id"),
+                name,
+                devices: vec![],
+            }),
+            (false, 1) => vendors
+                .last_mut()
+                .expect("device without vendor")
+                .devices
+                .push((
+                    u16::from_str_radix(id, 16).expect("invalid device id"),
+                    name,
+                )),
+            (true, 1) => classes
+                .last_mut()
+                .expect("subclass without class")
+                .subclasses
+                .push(Subclass {
+                    id: u8::from_str_radix(id, 16).expect("invalid subclass id"),
+                    name,
+                    programming_interfaces: vec![],
+                }),
+            (true, 2) => classes
+                .last_mut()
+                .and_then(|class| class.subclasses.last_mut())
+                .expect("programming interface without subclass")
+                .programming_interfaces
+                .push((u8::from_str_radix(id, 16).expect("invalid prog-if"), name)),
+            // subsystem entries aren't included
+            _ => {}
+        }
+    }
+
+    // the lookups binary search, so everything has to be sorted
+    vendors.sort_by_key(|vendor| vendor.id);
+    for vendor in &mut vendors {
+        vendor.devices.sort_by_key(|(id, _)| *id);
+    }
+
+    classes.sort_by_key(|class| class.id);
+    for class in &mut classes {
+        class.subclasses.sort_by_key(|subclass| subclass.id);
+        for subclass in &mut class.subclasses {
+            subclass.programming_interfaces.sort_by_key(|(id, _)| *id);
+        }
+    }
+
+    (vendors, classes)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", PCI_IDS_PATH);
+
+    let source = std::fs::read_to_string(PCI_IDS_PATH).expect("couldn't read pci.ids");
+    let (vendors, classes) = parse_pci_ids(&source);
+
+    let mut output = String::new();
+
+    writeln!(output, "pub static VENDORS: &[PciVendorEntry] = &[").unwrap();
+    for vendor in &vendors {
+        writeln!(
+            output,
+            "    PciVendorEntry {{ id: {:#06x}, name: {:?}, devices: &[",
+            vendor.id, vendor.name
+        )
+        .unwrap();
+        for (id, name) in &vendor.devices {
+            writeln!(output, "        ({:#06x}, {:?}),", id, name).unwrap();
+        }
+        writeln!(output, "    ] }},").unwrap();
+    }
+    writeln!(output, "];").unwrap();
+
+    writeln!(output, "pub static CLASSES: &[PciClassEntry] = &[").unwrap();
+    for class in &classes {
+        writeln!(
+            output,
+            "    PciClassEntry {{ id: {:#04x}, name: {:?}, subclasses: &[",
+            class.id, class.name
+        )
+        .unwrap();
+        for subclass in &class.subclasses {
+            writeln!(
+                output,
+                "        PciSubclassEntry {{ id: {:#04x}, name: {:?}, programming_interfaces: &[",
+                subclass.id, subclass.name
+            )
+            .unwrap();
+            for (id, name) in &subclass.programming_interfaces {
+                writeln!(output, "            ({:#04x}, {:?}),", id, name).unwrap();
+            }
+            writeln!(output, "        ] }},").unwrap();
+        }
+        writeln!(output, "    ] }},").unwrap();
+    }
+    writeln!(output, "];").unwrap();
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("pci_ids.rs"), output).expect("couldn't write pci_ids.rs");
+}
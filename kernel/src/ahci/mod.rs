@@ -0,0 +1,295 @@
+use crate::ahci::port::AhciPort;
+use crate::apic;
+use crate::block::register_device;
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::driver::{PciDeviceId, PciDriver, PciProbeError};
+use crate::pci_express::interrupts::PciInterrupts;
+use crate::pci_express::register_driver;
+use crate::pit::busy_wait;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use core::time::Duration;
+use log::{debug, info, warn};
+use x86_64::VirtAddr;
+
+pub mod port;
+
+const MASS_STORAGE_CLASS: u8 = 0x01;
+const SATA_SUBCLASS: u8 = 0x06;
+const AHCI_PROGRAMMING_INTERFACE: u8 = 0x01;
+
+/// The AHCI base memory register, ABAR.
+const ABAR_INDEX: u8 = 5;
+
+const REGISTER_CAPABILITIES: u64 = 0x00;
+const REGISTER_GLOBAL_HOST_CONTROL: u64 = 0x04;
+const REGISTER_INTERRUPT_STATUS: u64 = 0x08;
+const REGISTER_PORTS_IMPLEMENTED: u64 = 0x0C;
+const REGISTER_VERSION: u64 = 0x10;
+const REGISTER_CAPABILITIES_2: u64 = 0x24;
+const REGISTER_BIOS_HANDOFF: u64 = 0x28;
+
+const CAPABILITY_COMMAND_SLOTS: core::ops::Range<usize> = 8..13;
+const CAPABILITY_STAGGERED_SPIN_UP: usize = 27;
+const CAPABILITY_64_BIT: usize = 31;
+const CAPABILITY_2_BIOS_HANDOFF: usize = 0;
+
+const GLOBAL_HOST_CONTROL_RESET: usize = 0;
+const GLOBAL_HOST_CONTROL_INTERRUPT_ENABLE: usize = 1;
+const GLOBAL_HOST_CONTROL_AHCI_ENABLE: usize = 31;
+
+const BIOS_HANDOFF_BIOS_OWNED: usize = 0;
+const BIOS_HANDOFF_OS_OWNED: usize = 1;
+const BIOS_HANDOFF_BIOS_BUSY: usize = 4;
+
+const PORT_REGISTERS_OFFSET: u64 = 0x100;
+const PORT_REGISTERS_SIZE: u64 = 0x80;
+const MAX_PORTS: usize = 32;
+
+// Timeouts from the AHCI specification, 10.2 and 10.6.3.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+const BIOS_HANDOFF_TIMEOUT: Duration = Duration::from_millis(25);
+const BIOS_BUSY_TIMEOUT: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+static AHCI_DRIVER: AhciDriver = AhciDriver;
+
+static DEVICE_IDS: [PciDeviceId; 1] = [PciDeviceId::class_with_interface(
+    MASS_STORAGE_CLASS,
+    SATA_SUBCLASS,
+    AHCI_PROGRAMMING_INTERFACE,
+)];
+
+pub fn init() {
+    register_driver(&AHCI_DRIVER);
+}
+
+/// Polls `condition` until it holds or `timeout` passes, returns whether it held.
+fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let mut waited = Duration::ZERO;
+
+    while !condition() {
+        if waited >= timeout {
+            return false;
+        }
+
+        busy_wait(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    }
+
+    true
+}
+
+/// Reads and writes 32 bit registers at offsets from a base address, shared by the controller
+/// and its ports.
+#[derive(Copy, Clone)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    fn update(&self, offset: u64, f: impl FnOnce(u32) -> u32) {
+        self.write(offset, f(self.read(offset)));
+    }
+
+    fn offset(&self, offset: u64) -> Registers {
+        Registers(self.0 + offset)
+    }
+}
+
+struct AhciController {
+    registers: Registers,
+    ports: Vec<Arc<AhciPort>>,
+    _interrupts: Option<PciInterrupts>,
+}
+
+impl AhciController {
+    fn handle_interrupt(&self) {
+        let pending = self.registers.read(REGISTER_INTERRUPT_STATUS);
+
+        for port in &self.ports {
+            if pending.get_bit(port.number() as usize) {
+                port.collect_status();
+            }
+        }
+
+        // Only after the ports' statuses, otherwise the bits are set again right away.
+        self.registers.write(REGISTER_INTERRUPT_STATUS, pending);
+    }
+}
+
+/// Takes the controller over from the firmware, if it supports the handoff.
+fn bios_handoff(registers: Registers) {
+    if !registers
+        .read(REGISTER_CAPABILITIES_2)
+        .get_bit(CAPABILITY_2_BIOS_HANDOFF)
+    {
+        return;
+    }
+
+    registers.update(REGISTER_BIOS_HANDOFF, |mut handoff| {
+        *handoff.set_bit(BIOS_HANDOFF_OS_OWNED, true)
+    });
+
+    let released = wait_for(BIOS_HANDOFF_TIMEOUT, || {
+        !registers
+            .read(REGISTER_BIOS_HANDOFF)
+            .get_bit(BIOS_HANDOFF_BIOS_OWNED)
+    });
+
+    // The firmware may still finish outstanding commands once it let go.
+    let idle = wait_for(BIOS_BUSY_TIMEOUT, || {
+        !registers
+            .read(REGISTER_BIOS_HANDOFF)
+            .get_bit(BIOS_HANDOFF_BIOS_BUSY)
+    });
+
+    if !released || !idle {
+        warn!("AHCI firmware didn't release the controller, taking it anyway");
+    }
+}
+
+fn reset(registers: Registers) -> Result<(), PciProbeError> {
+    registers.update(REGISTER_GLOBAL_HOST_CONTROL, |mut control| {
+        *control.set_bit(GLOBAL_HOST_CONTROL_AHCI_ENABLE, true)
+    });
+    registers.update(REGISTER_GLOBAL_HOST_CONTROL, |mut control| {
+        *control.set_bit(GLOBAL_HOST_CONTROL_RESET, true)
+    });
+
+    if !wait_for(RESET_TIMEOUT, || {
+        !registers
+            .read(REGISTER_GLOBAL_HOST_CONTROL)
+            .get_bit(GLOBAL_HOST_CONTROL_RESET)
+    }) {
+        return Err(PciProbeError::InitializationFailed("HBA reset timed out"));
+    }
+
+    // The reset cleared AHCI mode as well.
+    registers.update(REGISTER_GLOBAL_HOST_CONTROL, |mut control| {
+        *control.set_bit(GLOBAL_HOST_CONTROL_AHCI_ENABLE, true)
+    });
+
+    Ok(())
+}
+
+fn probe(device: &PciDevice) -> Result<Vec<Arc<AhciPort>>, PciProbeError> {
+    let address = device
+        .bar(ABAR_INDEX)
+        .and_then(|bar| bar.map())
+        .ok_or(PciProbeError::InitializationFailed("ABAR not mapped"))?;
+    let registers = Registers(address);
+
+    device.set_memory_space(true);
+    device.enable_bus_master();
+
+    bios_handoff(registers);
+    reset(registers)?;
+
+    let capabilities = registers.read(REGISTER_CAPABILITIES);
+    let ports_implemented = registers.read(REGISTER_PORTS_IMPLEMENTED);
+    let version = registers.read(REGISTER_VERSION);
+
+    debug!(
+        "AHCI {}.{} controller {}: capabilities {:#x}, ports {:#x}, {} command slots",
+        version >> 16,
+        (version >> 8) & 0xFF,
+        device.address(),
+        capabilities,
+        ports_implemented,
+        capabilities.get_bits(CAPABILITY_COMMAND_SLOTS) + 1
+    );
+
+    // The interrupt handler needs the ports, which are only set up once interrupts are.
+    let slot: Arc<OnceCell<AhciController>> = Arc::new(OnceCell::uninit());
+    let handler_slot = slot.clone();
+
+    let interrupts = match device.enable_interrupts(
+        1,
+        apic::local_apic_id(),
+        Arc::new(move |_| {
+            if let Some(controller) = handler_slot.get() {
+                controller.handle_interrupt();
+            }
+        }),
+    ) {
+        Ok(interrupts) => Some(interrupts),
+        Err(e) => {
+            warn!(
+                "No interrupts for AHCI controller {}, polling: {:?}",
+                device.address(),
+                e
+            );
+            None
+        }
+    };
+
+    let has_interrupts = interrupts.is_some();
+
+    let ports = (0..MAX_PORTS)
+        .filter(|port| ports_implemented.get_bit(*port))
+        .filter_map(|port| {
+            AhciPort::new(
+                registers.offset(PORT_REGISTERS_OFFSET + port as u64 * PORT_REGISTERS_SIZE),
+                port as u8,
+                capabilities.get_bit(CAPABILITY_64_BIT),
+                capabilities.get_bit(CAPABILITY_STAGGERED_SPIN_UP),
+            )
+        })
+        .map(Arc::new)
+        .collect::<Vec<_>>();
+
+    slot.get_or_init(|| AhciController {
+        registers,
+        ports: ports.clone(),
+        _interrupts: interrupts,
+    });
+
+    // IDENTIFY ran polled, from here on commands wait for their completion interrupt.
+    if has_interrupts {
+        registers.write(REGISTER_INTERRUPT_STATUS, u32::MAX);
+        registers.update(REGISTER_GLOBAL_HOST_CONTROL, |mut control| {
+            *control.set_bit(GLOBAL_HOST_CONTROL_INTERRUPT_ENABLE, true)
+        });
+
+        for port in &ports {
+            port.enable_interrupts();
+        }
+    }
+
+    Ok(ports)
+}
+
+struct AhciDriver;
+
+impl PciDriver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &DEVICE_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), PciProbeError> {
+        let ports = probe(device)?;
+
+        if ports.is_empty() {
+            info!("AHCI controller {} has no disks", device.address());
+        }
+
+        for port in ports {
+            register_device(port);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,669 @@
+use crate::ahci::{wait_for, Registers};
+use crate::block::{check_request, disk_name, BlockDevice, BlockError};
+use crate::clocksource::Instant;
+use crate::memory::dma::DmaBuffer;
+use crate::pit::busy_wait;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+const PORT_COMMAND_LIST_BASE: u64 = 0x00;
+const PORT_COMMAND_LIST_BASE_UPPER: u64 = 0x04;
+const PORT_FIS_BASE: u64 = 0x08;
+const PORT_FIS_BASE_UPPER: u64 = 0x0C;
+const PORT_INTERRUPT_STATUS: u64 = 0x10;
+const PORT_INTERRUPT_ENABLE: u64 = 0x14;
+const PORT_COMMAND: u64 = 0x18;
+const PORT_TASK_FILE_DATA: u64 = 0x20;
+const PORT_SIGNATURE: u64 = 0x24;
+const PORT_SATA_STATUS: u64 = 0x28;
+const PORT_SATA_CONTROL: u64 = 0x2C;
+const PORT_SATA_ERROR: u64 = 0x30;
+const PORT_COMMAND_ISSUE: u64 = 0x38;
+
+const COMMAND_START: usize = 0;
+const COMMAND_SPIN_UP: usize = 1;
+const COMMAND_FIS_RECEIVE_ENABLE: usize = 4;
+const COMMAND_FIS_RECEIVE_RUNNING: usize = 14;
+const COMMAND_LIST_RUNNING: usize = 15;
+
+const TASK_FILE_ERROR: usize = 0;
+const TASK_FILE_DATA_REQUEST: usize = 3;
+const TASK_FILE_BUSY: usize = 7;
+
+const INTERRUPT_DEVICE_TO_HOST: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP: u32 = 1 << 1;
+const INTERRUPT_DMA_SETUP: u32 = 1 << 2;
+const INTERRUPT_SET_DEVICE_BITS: u32 = 1 << 3;
+// Overflow, interface fatal, host bus data and fatal, task file errors.
+const INTERRUPT_ERRORS: u32 = 1 << 24 | 1 << 27 | 1 << 28 | 1 << 29 | 1 << 30;
+
+const SATA_STATUS_DETECTION: core::ops::Range<usize> = 0..4;
+const DETECTION_PRESENT: u32 = 3;
+const SATA_CONTROL_DETECTION: core::ops::Range<usize> = 0..4;
+const DETECTION_RESET: u32 = 1;
+
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+const SIGNATURE_ATAPI: u32 = 0xEB14_0101;
+
+// Port memory: the command list (only slot 0 is used), received FISes and slot 0's command
+// table, whose PRDT fills the rest of the page.
+const PORT_MEMORY_SIZE: usize = 4096;
+const COMMAND_LIST_OFFSET: usize = 0;
+const RECEIVED_FIS_OFFSET: usize = 1024;
+const COMMAND_TABLE_OFFSET: usize = 2048;
+const PRDT_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+const PRDT_ENTRY_SIZE: usize = 16;
+const MAX_PRDT_ENTRIES: usize = (PORT_MEMORY_SIZE - PRDT_OFFSET) / PRDT_ENTRY_SIZE;
+const PRDT_MAX_BYTES: usize = 4 * 1024 * 1024;
+const PRDT_INTERRUPT_ON_COMPLETION: u32 = 1 << 31;
+
+const COMMAND_HEADER_WRITE: u32 = 1 << 6;
+const FIS_TYPE_REGISTER_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;
+const FIS_LENGTH_DWORDS: u32 = 5;
+const DEVICE_LBA_MODE: u8 = 1 << 6;
+
+const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+const ATA_READ_DMA: u8 = 0xC8;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA: u8 = 0xCA;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xE7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+const SECTOR_SIZE: usize = 512;
+const MAX_TRANSFER_SIZE: usize = 128 * 1024;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+const LINK_TIMEOUT: Duration = Duration::from_millis(10);
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// COMRESET has to be asserted for at least 1 ms.
+const COMRESET_DELAY: Duration = Duration::from_millis(1);
+
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct AtaCommand {
+    command: u8,
+    lba: u64,
+    sectors: u16,
+}
+
+/// What IDENTIFY DEVICE told us about a disk.
+struct Identity {
+    model: String,
+    serial: String,
+    sectors: u64,
+    sector_size: usize,
+    lba48: bool,
+    flush_command: Option<u8>,
+}
+
+impl Identity {
+    fn parse(data: &[u8]) -> Self {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        // Strings have the two characters of each word swapped.
+        let string = |words: core::ops::Range<usize>| {
+            let bytes = words
+                .flat_map(|index| word(index).to_be_bytes())
+                .collect::<Vec<_>>();
+
+            String::from_utf8_lossy(&bytes).trim().into()
+        };
+
+        let lba48 = word(83).get_bit(10);
+
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| sectors | (word(100 + i) as u64) << (16 * i))
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+
+        // Word 106 is only valid with bit 14 set and bit 15 clear. Words 117 and 118 count
+        // 16 bit words.
+        let sector_size_info = word(106);
+        let sector_size =
+            if sector_size_info.get_bits(14..16) == 0b01 && sector_size_info.get_bit(12) {
+                Some((word(117) as usize | (word(118) as usize) << 16) * 2)
+                    .filter(|size| size.is_power_of_two() && *size >= SECTOR_SIZE)
+                    .unwrap_or(SECTOR_SIZE)
+            } else {
+                SECTOR_SIZE
+            };
+
+        let flush_command = if lba48 && word(83).get_bit(13) {
+            Some(ATA_FLUSH_CACHE_EXT)
+        } else if word(83).get_bit(12) {
+            Some(ATA_FLUSH_CACHE)
+        } else {
+            None
+        };
+
+        Self {
+            model: string(27..47),
+            serial: string(10..20),
+            sectors,
+            sector_size,
+            lba48,
+            flush_command,
+        }
+    }
+}
+
+/// Whether an HBA without 64 bit addressing can reach the buffer.
+fn is_below_4_gib(buffer: &DmaBuffer) -> bool {
+    buffer.physical_address().as_u64() + buffer.len() as u64 <= 1 << 32
+}
+
+/// A SATA disk on one port of an AHCI controller. Commands are issued one at a time, through
+/// slot 0.
+pub struct AhciPort {
+    name: String,
+    number: u8,
+    registers: Registers,
+    memory: Spinlock<DmaBuffer>,
+    supports_64_bit: bool,
+    // Interrupt status bits collected but not yet looked at by the command waiting for them.
+    status: AtomicU32,
+    has_interrupts: AtomicBool,
+    identity: Identity,
+}
+
+#[allow(unused)]
+impl AhciPort {
+    /// Sets up a port and identifies its disk, `None` if there's no (supported) disk.
+    pub(super) fn new(
+        registers: Registers,
+        number: u8,
+        supports_64_bit: bool,
+        staggered_spin_up: bool,
+    ) -> Option<Self> {
+        if staggered_spin_up {
+            registers.update(PORT_COMMAND, |mut command| {
+                *command.set_bit(COMMAND_SPIN_UP, true)
+            });
+        }
+
+        if !wait_for(LINK_TIMEOUT, || {
+            registers
+                .read(PORT_SATA_STATUS)
+                .get_bits(SATA_STATUS_DETECTION)
+                == DETECTION_PRESENT
+        }) {
+            return None;
+        }
+
+        // The signature is only valid once the device sent its first D2H register FIS.
+        if !wait_for(READY_TIMEOUT, || is_ready(registers)) {
+            warn!("AHCI port {}: device didn't become ready", number);
+            return None;
+        }
+
+        match registers.read(PORT_SIGNATURE) {
+            SIGNATURE_ATA => {}
+            SIGNATURE_ATAPI => {
+                info!("AHCI port {}: ATAPI devices aren't supported", number);
+                return None;
+            }
+            signature => {
+                debug!("AHCI port {}: unknown signature {:#x}", number, signature);
+                return None;
+            }
+        }
+
+        if !stop(registers) {
+            warn!("AHCI port {}: couldn't stop the command engine", number);
+            return None;
+        }
+
+        let memory = DmaBuffer::new(PORT_MEMORY_SIZE)?;
+        let address = memory.physical_address().as_u64();
+
+        if !supports_64_bit && !is_below_4_gib(&memory) {
+            warn!("AHCI port {}: no DMA memory below 4 GiB", number);
+            return None;
+        }
+
+        let command_list = address + COMMAND_LIST_OFFSET as u64;
+        let received_fis = address + RECEIVED_FIS_OFFSET as u64;
+
+        registers.write(PORT_COMMAND_LIST_BASE, command_list as u32);
+        registers.write(PORT_COMMAND_LIST_BASE_UPPER, (command_list >> 32) as u32);
+        registers.write(PORT_FIS_BASE, received_fis as u32);
+        registers.write(PORT_FIS_BASE_UPPER, (received_fis >> 32) as u32);
+
+        registers.write(PORT_SATA_ERROR, u32::MAX);
+        registers.write(PORT_INTERRUPT_STATUS, u32::MAX);
+
+        start(registers);
+
+        let mut port = Self {
+            name: String::new(),
+            number,
+            registers,
+            memory: Spinlock::new(memory),
+            supports_64_bit,
+            status: AtomicU32::new(0),
+            has_interrupts: AtomicBool::new(false),
+            identity: Identity {
+                model: String::new(),
+                serial: String::new(),
+                sectors: 0,
+                sector_size: SECTOR_SIZE,
+                lba48: false,
+                flush_command: None,
+            },
+        };
+
+        let data = match port.identify() {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("AHCI port {}: IDENTIFY DEVICE failed: {:?}", number, e);
+                stop(registers);
+                return None;
+            }
+        };
+
+        port.identity = Identity::parse(&data.as_slice()[..SECTOR_SIZE]);
+
+        let index = DEVICE_COUNT.fetch_add(1, Ordering::Relaxed);
+        port.name = disk_name("sd", index);
+
+        info!(
+            "{}: {} on AHCI port {}, serial number {:?}{}",
+            port.name,
+            port.identity.model,
+            number,
+            port.identity.serial,
+            if port.identity.lba48 { "" } else { ", LBA28" }
+        );
+
+        Some(port)
+    }
+
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.identity.serial
+    }
+
+    /// Turns on the port's completion interrupts, commands halt while waiting from then on.
+    pub(super) fn enable_interrupts(&self) {
+        self.registers.write(
+            PORT_INTERRUPT_ENABLE,
+            INTERRUPT_DEVICE_TO_HOST
+                | INTERRUPT_PIO_SETUP
+                | INTERRUPT_DMA_SETUP
+                | INTERRUPT_SET_DEVICE_BITS
+                | INTERRUPT_ERRORS,
+        );
+        self.has_interrupts.store(true, Ordering::Release);
+    }
+
+    /// Moves the port's interrupt status into `status`, acknowledging it. Called from the
+    /// interrupt handler and by commands waiting without interrupts.
+    pub(super) fn collect_status(&self) {
+        let status = self.registers.read(PORT_INTERRUPT_STATUS);
+
+        if status != 0 {
+            self.registers.write(PORT_INTERRUPT_STATUS, status);
+            self.status.fetch_or(status, Ordering::AcqRel);
+        }
+    }
+
+    /// Allocates a buffer for the data of a command, which the HBA has to be able to reach.
+    fn data_buffer(&self, size: usize) -> Result<DmaBuffer, BlockError> {
+        let buffer = DmaBuffer::new(size).ok_or(BlockError::OutOfMemory)?;
+
+        if !self.supports_64_bit && !is_below_4_gib(&buffer) {
+            warn!("{}: no DMA memory below 4 GiB", self.name);
+            return Err(BlockError::OutOfMemory);
+        }
+
+        Ok(buffer)
+    }
+
+    fn identify(&self) -> Result<DmaBuffer, BlockError> {
+        let data = self.data_buffer(SECTOR_SIZE)?;
+
+        self.execute(
+            AtaCommand {
+                command: ATA_IDENTIFY_DEVICE,
+                lba: 0,
+                sectors: 0,
+            },
+            Some((&data, SECTOR_SIZE)),
+            false,
+        )?;
+
+        Ok(data)
+    }
+
+    /// Issues a command through slot 0 and waits for it. `data` is read by the device if
+    /// `write` is set, written otherwise.
+    fn execute(
+        &self,
+        command: AtaCommand,
+        data: Option<(&DmaBuffer, usize)>,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let memory = self.memory.lock();
+        let table = unsafe { memory.as_mut_ptr::<u8>().add(COMMAND_TABLE_OFFSET) };
+
+        let prdt_entries = data.map_or(0, |(_, length)| length.div_ceil(PRDT_MAX_BYTES));
+        assert!(prdt_entries <= MAX_PRDT_ENTRIES, "AHCI transfer too big");
+
+        unsafe {
+            table.write_bytes(0, PRDT_OFFSET - COMMAND_TABLE_OFFSET);
+
+            let lba = command.lba.to_le_bytes();
+            let count = command.sectors.to_le_bytes();
+            let fis = core::slice::from_raw_parts_mut(table, 16);
+
+            fis[0] = FIS_TYPE_REGISTER_HOST_TO_DEVICE;
+            fis[1] = FIS_COMMAND;
+            fis[2] = command.command;
+            fis[4..7].copy_from_slice(&lba[0..3]);
+            fis[8..11].copy_from_slice(&lba[3..6]);
+            fis[12..14].copy_from_slice(&count);
+
+            // LBA28 commands take the top four bits in the device register.
+            fis[7] = if self.identity.lba48 {
+                DEVICE_LBA_MODE
+            } else {
+                DEVICE_LBA_MODE | (lba[3] & 0xF)
+            };
+
+            if let Some((buffer, length)) = data {
+                let prdt = memory.as_mut_ptr::<u8>().add(PRDT_OFFSET) as *mut u32;
+
+                for entry in 0..prdt_entries {
+                    let offset = entry * PRDT_MAX_BYTES;
+                    let address = buffer.physical_address().as_u64() + offset as u64;
+                    let bytes = (length - offset).min(PRDT_MAX_BYTES);
+
+                    let entry_pointer = prdt.add(entry * PRDT_ENTRY_SIZE / 4);
+                    entry_pointer.write_volatile(address as u32);
+                    entry_pointer.add(1).write_volatile((address >> 32) as u32);
+                    entry_pointer.add(2).write_volatile(0);
+                    entry_pointer.add(3).write_volatile(
+                        (bytes as u32 - 1)
+                            | if entry == prdt_entries - 1 {
+                                PRDT_INTERRUPT_ON_COMPLETION
+                            } else {
+                                0
+                            },
+                    );
+                }
+            }
+
+            let table_address = memory.physical_address().as_u64() + COMMAND_TABLE_OFFSET as u64;
+            let header = memory.as_mut_ptr::<u32>().add(COMMAND_LIST_OFFSET / 4);
+
+            header.write_volatile(
+                FIS_LENGTH_DWORDS
+                    | if write { COMMAND_HEADER_WRITE } else { 0 }
+                    | (prdt_entries as u32) << 16,
+            );
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table_address as u32);
+            header.add(3).write_volatile((table_address >> 32) as u32);
+        }
+
+        if !wait_for(READY_TIMEOUT, || is_ready(self.registers)) {
+            warn!("{}: device stuck busy, resetting the port", self.name);
+            self.recover();
+            return Err(BlockError::Io);
+        }
+
+        self.status.store(0, Ordering::Release);
+        self.registers.write(PORT_COMMAND_ISSUE, 1);
+
+        self.wait_for_completion()
+    }
+
+    /// Waits for slot 0 to complete. Halts in between if the completion interrupt will wake us
+    /// up, polls otherwise.
+    fn wait_for_completion(&self) -> Result<(), BlockError> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+
+        loop {
+            // Checking and halting with interrupts off, so the completion can't slip in
+            // between.
+            interrupts::disable();
+
+            self.collect_status();
+
+            let status = self.status.load(Ordering::Acquire);
+            let issued = self.registers.read(PORT_COMMAND_ISSUE).get_bit(0);
+            let task_file = self.registers.read(PORT_TASK_FILE_DATA);
+
+            let result = if status & INTERRUPT_ERRORS != 0 || task_file.get_bit(TASK_FILE_ERROR) {
+                warn!(
+                    "{}: command failed, interrupt status {:#x}, task file {:#x}, SATA error {:#x}",
+                    self.name,
+                    status,
+                    task_file,
+                    self.registers.read(PORT_SATA_ERROR)
+                );
+                self.recover();
+                Some(Err(BlockError::Io))
+            } else if !issued {
+                Some(Ok(()))
+            } else if Instant::now() >= deadline {
+                warn!("{}: command timed out, resetting the port", self.name);
+                self.recover();
+                Some(Err(BlockError::Io))
+            } else {
+                None
+            };
+
+            if let Some(result) = result {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                return result;
+            }
+
+            if interrupts_were_enabled && self.has_interrupts.load(Ordering::Acquire) {
+                interrupts::enable_and_hlt();
+            } else {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Gets the port going again after an error, with a COMRESET if the device hangs.
+    fn recover(&self) {
+        stop(self.registers);
+
+        self.registers.write(PORT_SATA_ERROR, u32::MAX);
+        self.registers.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        self.status.store(0, Ordering::Release);
+
+        if !is_ready(self.registers) {
+            self.registers.update(PORT_SATA_CONTROL, |mut control| {
+                *control.set_bits(SATA_CONTROL_DETECTION, DETECTION_RESET)
+            });
+            busy_wait(COMRESET_DELAY);
+            self.registers.update(PORT_SATA_CONTROL, |mut control| {
+                *control.set_bits(SATA_CONTROL_DETECTION, 0)
+            });
+
+            let registers = self.registers;
+            let recovered = wait_for(READY_TIMEOUT, || {
+                registers
+                    .read(PORT_SATA_STATUS)
+                    .get_bits(SATA_STATUS_DETECTION)
+                    == DETECTION_PRESENT
+                    && is_ready(registers)
+            });
+
+            if !recovered {
+                warn!("{}: device didn't come back after COMRESET", self.name);
+            }
+
+            self.registers.write(PORT_SATA_ERROR, u32::MAX);
+            self.registers.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        }
+
+        start(self.registers);
+    }
+
+    fn max_transfer_size(&self) -> usize {
+        (MAX_TRANSFER_SIZE / self.identity.sector_size).max(1) * self.identity.sector_size
+    }
+
+    fn transfer(
+        &self,
+        start: u64,
+        length: usize,
+        data: &DmaBuffer,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let sectors = length / self.identity.sector_size;
+
+        let command = match (self.identity.lba48, write) {
+            (true, false) => ATA_READ_DMA_EXT,
+            (true, true) => ATA_WRITE_DMA_EXT,
+            (false, false) => ATA_READ_DMA,
+            (false, true) => ATA_WRITE_DMA,
+        };
+
+        if !self.identity.lba48 && start + sectors as u64 > LBA28_LIMIT {
+            return Err(BlockError::OutOfRange);
+        }
+
+        // A count of 0 means 256 sectors for LBA28 and 65536 for LBA48 commands, which the
+        // truncation gets right.
+        self.execute(
+            AtaCommand {
+                command,
+                lba: start,
+                sectors: sectors as u16,
+            },
+            Some((data, length)),
+            write,
+        )
+    }
+}
+
+fn is_ready(registers: Registers) -> bool {
+    let task_file = registers.read(PORT_TASK_FILE_DATA);
+
+    !task_file.get_bit(TASK_FILE_BUSY) && !task_file.get_bit(TASK_FILE_DATA_REQUEST)
+}
+
+/// Stops the command list and FIS receive engines, returns whether they stopped.
+fn stop(registers: Registers) -> bool {
+    registers.update(PORT_COMMAND, |mut command| {
+        *command.set_bit(COMMAND_START, false)
+    });
+
+    let list_stopped = wait_for(STOP_TIMEOUT, || {
+        !registers.read(PORT_COMMAND).get_bit(COMMAND_LIST_RUNNING)
+    });
+
+    registers.update(PORT_COMMAND, |mut command| {
+        *command.set_bit(COMMAND_FIS_RECEIVE_ENABLE, false)
+    });
+
+    let fis_stopped = wait_for(STOP_TIMEOUT, || {
+        !registers
+            .read(PORT_COMMAND)
+            .get_bit(COMMAND_FIS_RECEIVE_RUNNING)
+    });
+
+    list_stopped && fis_stopped
+}
+
+fn start(registers: Registers) {
+    registers.update(PORT_COMMAND, |mut command| {
+        *command.set_bit(COMMAND_FIS_RECEIVE_ENABLE, true)
+    });
+    registers.update(PORT_COMMAND, |mut command| {
+        *command.set_bit(COMMAND_START, true)
+    });
+}
+
+impl BlockDevice for AhciPort {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.identity.sector_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let max_transfer_size = self.max_transfer_size();
+        let data = self.data_buffer(max_transfer_size.min(buffer.len()))?;
+
+        for (index, chunk) in buffer.chunks_mut(max_transfer_size).enumerate() {
+            let sector = start + (index * max_transfer_size / self.identity.sector_size) as u64;
+
+            self.transfer(sector, chunk.len(), &data, false)?;
+            chunk.copy_from_slice(&data.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let max_transfer_size = self.max_transfer_size();
+        let mut data = self.data_buffer(max_transfer_size.min(buffer.len()))?;
+
+        for (index, chunk) in buffer.chunks(max_transfer_size).enumerate() {
+            let sector = start + (index * max_transfer_size / self.identity.sector_size) as u64;
+
+            data.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.transfer(sector, chunk.len(), &data, true)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let Some(command) = self.identity.flush_command else {
+            return Ok(());
+        };
+
+        self.execute(
+            AtaCommand {
+                command,
+                lba: 0,
+                sectors: 0,
+            },
+            None,
+            false,
+        )
+    }
+}
@@ -1,12 +1,89 @@
-use acpi::platform::interrupt::Apic;
+use crate::memory::mmio::map_mmio_region;
+use crate::pit::busy_wait;
+use acpi::platform::interrupt::{Apic, InterruptSourceOverride, Polarity, TriggerMode};
 use alloc::alloc::Global;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
 use conquer_once::spin::OnceCell;
+use core::time::Duration;
+use lazy_static::lazy_static;
 use log::{debug, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
-fn irq_fallback(_stack_frame: InterruptStackFrame, index: u8, _code: Option<u64>) {
-    warn!("Unhandled IRQ {}", index);
+#[allow(unused)]
+const LOCAL_APIC_ID_REGISTER: u64 = 0x20;
+const LOCAL_APIC_EOI_REGISTER: u64 = 0xB0;
+const LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR_REGISTER: u64 = 0xF0;
+const LOCAL_APIC_LVT_TIMER_REGISTER: u64 = 0x320;
+const LOCAL_APIC_TIMER_INITIAL_COUNT_REGISTER: u64 = 0x380;
+const LOCAL_APIC_TIMER_CURRENT_COUNT_REGISTER: u64 = 0x390;
+const LOCAL_APIC_TIMER_DIVIDE_CONFIGURATION_REGISTER: u64 = 0x3E0;
+
+const LVT_MASKED: usize = 16;
+const LVT_TIMER_PERIODIC: usize = 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const TIMER_CALIBRATION_DURATION: Duration = Duration::from_millis(10);
+
+const IO_APIC_REGISTER_SELECT: u64 = 0x00;
+const IO_APIC_REGISTER_WINDOW: u64 = 0x10;
+const IO_APIC_VERSION_REGISTER: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+const IO_APIC_REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// Vectors below this one are reserved for exceptions and the legacy IRQ range.
+pub const FIRST_DYNAMIC_VECTOR: u8 = 0x30;
+/// Vectors above this one are reserved for the local APIC itself (timer, spurious, ...).
+pub const LAST_DYNAMIC_VECTOR: u8 = 0xEF;
+pub const TIMER_VECTOR: u8 = 0xF0;
+/// Delivered when an interrupt went away before it was accepted, it takes no EOI.
+const SPURIOUS_VECTOR: u8 = 0xFF;
+// Below are exceptions, which take no EOI either.
+const FIRST_INTERRUPT_VECTOR: u8 = 32;
+
+pub type IrqHandler = Arc<dyn Fn(u8) + Send + Sync>;
+
+lazy_static! {
+    static ref IRQ_HANDLERS: Spinlock<BTreeMap<u8, IrqHandler>> = Spinlock::new(BTreeMap::new());
+}
+
+static ALLOCATED_VECTORS: Spinlock<[bool; 256]> = Spinlock::new([false; 256]);
+
+static LOCAL_APIC_ADDRESS: OnceCell<u64> = OnceCell::uninit();
+
+struct IoApic {
+    address: VirtAddr,
+    global_system_interrupt_base: u32,
+    redirection_entries: u32,
+}
+
+// Programming an IO APIC takes a select and a window access that mustn't be interleaved.
+static IO_APICS: OnceCell<Spinlock<Vec<IoApic>>> = OnceCell::uninit();
+
+static INTERRUPT_SOURCE_OVERRIDES: OnceCell<Vec<InterruptSourceOverride>> = OnceCell::uninit();
+
+fn irq_dispatch(_stack_frame: InterruptStackFrame, index: u8, _code: Option<u64>) {
+    let handler = IRQ_HANDLERS.lock().get(&index).cloned();
+
+    match handler {
+        Some(handler) => {
+            handler(index);
+            end_of_interrupt();
+        }
+        None => {
+            warn!("Unhandled IRQ {}", index);
+
+            // Otherwise the vector's priority class stays blocked.
+            if (FIRST_INTERRUPT_VECTOR..SPURIOUS_VECTOR).contains(&index) {
+                end_of_interrupt();
+            }
+        }
+    }
 }
 
 fn irq_page_fault(stack_frame: InterruptStackFrame, _index: u8, code: Option<u64>) {
@@ -33,24 +110,277 @@ pub fn init(apic: Apic<Global>) {
 
     debug!("Creating interrupt descriptor table..");
     let mut idt = InterruptDescriptorTable::new();
-    x86_64::set_general_handler!(&mut idt, irq_fallback);
+    x86_64::set_general_handler!(&mut idt, irq_dispatch);
     x86_64::set_general_handler!(&mut idt, irq_page_fault, 14);
 
     let idt = IDT.get_or_init(move || idt);
     idt.load();
 
+    LOCAL_APIC_ADDRESS.init_once(|| apic.local_apic_address);
+
+    init_io_apics(&apic);
+
     debug!("Loaded interrupt descriptor table, writing 0x1FF to the Spurious Interrupt Vector Register.");
 
     unsafe {
-        let reg = (apic.local_apic_address + 0xf0) as *mut u32;
+        let reg = local_apic_register(LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR_REGISTER);
 
         debug!(
             "Phys. local APIC address = {:?}, reg = {:?}, writing 0x1FF",
             apic.local_apic_address as *mut u32, reg
         );
 
-        reg.write_volatile(0x1FF);
+        reg.write_volatile(0x100 | SPURIOUS_VECTOR as u32);
     };
 
     debug!("Interrupts set up.");
 }
+
+fn local_apic_register(offset: u64) -> *mut u32 {
+    let address = LOCAL_APIC_ADDRESS
+        .get()
+        .expect("local APIC not initialized");
+
+    (address + offset) as *mut u32
+}
+
+pub fn local_apic_id() -> u8 {
+    let id = unsafe { local_apic_register(LOCAL_APIC_ID_REGISTER).read_volatile() };
+
+    (id >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    unsafe { local_apic_register(LOCAL_APIC_EOI_REGISTER).write_volatile(0) };
+}
+
+/// Makes the local APIC timer interrupt with [`TIMER_VECTOR`] every `period`. Its frequency
+/// isn't architectural, so it's measured against the PIT first.
+pub fn start_periodic_timer(period: Duration) {
+    let mut lvt_timer = 0u32;
+    lvt_timer.set_bit(LVT_MASKED, true);
+
+    let remaining = unsafe {
+        local_apic_register(LOCAL_APIC_TIMER_DIVIDE_CONFIGURATION_REGISTER)
+            .write_volatile(TIMER_DIVIDE_BY_16);
+        local_apic_register(LOCAL_APIC_LVT_TIMER_REGISTER).write_volatile(lvt_timer);
+        local_apic_register(LOCAL_APIC_TIMER_INITIAL_COUNT_REGISTER).write_volatile(u32::MAX);
+
+        busy_wait(TIMER_CALIBRATION_DURATION);
+
+        local_apic_register(LOCAL_APIC_TIMER_CURRENT_COUNT_REGISTER).read_volatile()
+    };
+
+    let frequency =
+        (u32::MAX - remaining) as u128 * 1_000_000_000 / TIMER_CALIBRATION_DURATION.as_nanos();
+    let initial_count = (frequency * period.as_nanos() / 1_000_000_000).clamp(1, u32::MAX as u128);
+
+    debug!(
+        "Local APIC timer runs at {} Hz, interrupting every {} counts",
+        frequency, initial_count
+    );
+
+    let mut lvt_timer = TIMER_VECTOR as u32;
+    lvt_timer.set_bit(LVT_TIMER_PERIODIC, true);
+
+    unsafe {
+        local_apic_register(LOCAL_APIC_LVT_TIMER_REGISTER).write_volatile(lvt_timer);
+        local_apic_register(LOCAL_APIC_TIMER_INITIAL_COUNT_REGISTER)
+            .write_volatile(initial_count as u32);
+    }
+}
+
+/// Reserves `count` consecutive interrupt vectors, the first of which is aligned to `count`
+/// rounded up to a power of two, as multi-message MSI requires.
+pub fn allocate_vectors(count: usize) -> Option<u8> {
+    // The caller can still try with fewer.
+    if count == 0 || count > (LAST_DYNAMIC_VECTOR - FIRST_DYNAMIC_VECTOR) as usize + 1 {
+        return None;
+    }
+
+    let alignment = count.next_power_of_two();
+    let mut allocated = ALLOCATED_VECTORS.lock();
+
+    let start = (FIRST_DYNAMIC_VECTOR as usize).next_multiple_of(alignment);
+    let first = (start..=LAST_DYNAMIC_VECTOR as usize + 1 - count)
+        .step_by(alignment)
+        .find(|first| !allocated[*first..*first + count].contains(&true))?;
+
+    allocated[first..first + count].fill(true);
+
+    Some(first as u8)
+}
+
+pub fn free_vectors(first: u8, count: usize) {
+    for vector in first..first + count as u8 {
+        unregister_handler(vector);
+    }
+
+    ALLOCATED_VECTORS.lock()[first as usize..first as usize + count].fill(false);
+}
+
+pub fn register_handler(vector: u8, handler: IrqHandler) {
+    without_interrupts(|| {
+        if IRQ_HANDLERS.lock().insert(vector, handler).is_some() {
+            warn!("Replaced the handler for IRQ {}", vector);
+        }
+    });
+}
+
+pub fn unregister_handler(vector: u8) {
+    without_interrupts(|| IRQ_HANDLERS.lock().remove(&vector));
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            (self.address + IO_APIC_REGISTER_SELECT)
+                .as_mut_ptr::<u32>()
+                .write_volatile(register);
+            (self.address + IO_APIC_REGISTER_WINDOW)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            (self.address + IO_APIC_REGISTER_SELECT)
+                .as_mut_ptr::<u32>()
+                .write_volatile(register);
+            (self.address + IO_APIC_REGISTER_WINDOW)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn handles(&self, global_system_interrupt: u32) -> bool {
+        (self.global_system_interrupt_base
+            ..self.global_system_interrupt_base + self.redirection_entries)
+            .contains(&global_system_interrupt)
+    }
+
+    fn write_redirection_entry(&self, global_system_interrupt: u32, entry: u64) {
+        let register = IO_APIC_REDIRECTION_TABLE
+            + (global_system_interrupt - self.global_system_interrupt_base) * 2;
+
+        // Mask the entry while it changes, so it never fires half written.
+        self.write(register, IO_APIC_REDIRECTION_MASKED);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Maps every IO APIC and masks all of their inputs until a driver routes them.
+fn init_io_apics(apic: &Apic<Global>) {
+    let io_apics = apic
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            let mut io_apic = IoApic {
+                address: map_mmio_region(io_apic.address as u64, 0x20),
+                global_system_interrupt_base: io_apic.global_system_interrupt_base,
+                redirection_entries: 0,
+            };
+
+            io_apic.redirection_entries =
+                io_apic.read(IO_APIC_VERSION_REGISTER).get_bits(16..24) + 1;
+
+            for index in 0..io_apic.redirection_entries {
+                io_apic.write(
+                    IO_APIC_REDIRECTION_TABLE + index * 2,
+                    IO_APIC_REDIRECTION_MASKED,
+                );
+            }
+
+            debug!(
+                "IO APIC at {:#x} handles GSIs {}..{}",
+                io_apic.address.as_u64(),
+                io_apic.global_system_interrupt_base,
+                io_apic.global_system_interrupt_base + io_apic.redirection_entries
+            );
+
+            io_apic
+        })
+        .collect();
+
+    IO_APICS.init_once(|| Spinlock::new(io_apics));
+    INTERRUPT_SOURCE_OVERRIDES
+        .init_once(|| apic.interrupt_source_overrides.iter().copied().collect());
+}
+
+/// The global system interrupt an ISA IRQ is connected to, and whether it's active low and
+/// level triggered. ISA interrupts are active high and edge triggered unless the MADT says
+/// otherwise.
+fn resolve_isa_irq(isa_irq: u8) -> (u32, bool, bool) {
+    let interrupt_source_override = INTERRUPT_SOURCE_OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.iter().find(|o| o.isa_source == isa_irq));
+
+    match interrupt_source_override {
+        Some(o) => (
+            o.global_system_interrupt,
+            o.polarity == Polarity::ActiveLow,
+            o.trigger_mode == TriggerMode::Level,
+        ),
+        None => (isa_irq as u32, false, false),
+    }
+}
+
+fn set_isa_irq_entry(isa_irq: u8, entry: impl FnOnce(u32, bool, bool) -> u64) {
+    let (global_system_interrupt, active_low, level_triggered) = resolve_isa_irq(isa_irq);
+
+    without_interrupts(|| {
+        let io_apics = IO_APICS.get().expect("IO APICs not initialized").lock();
+
+        match io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(global_system_interrupt))
+        {
+            Some(io_apic) => io_apic.write_redirection_entry(
+                global_system_interrupt,
+                entry(global_system_interrupt, active_low, level_triggered),
+            ),
+            None => warn!(
+                "No IO APIC handles GSI {} (ISA IRQ {})",
+                global_system_interrupt, isa_irq
+            ),
+        }
+    });
+}
+
+/// Delivers an ISA IRQ (e.g. 1 for the keyboard, 4 for COM1) to the given vector of the local
+/// APIC with the given ID.
+pub fn route_isa_irq(isa_irq: u8, vector: u8, destination_apic_id: u8) {
+    set_isa_irq_entry(
+        isa_irq,
+        |global_system_interrupt, active_low, level_triggered| {
+            debug!(
+                "Routing ISA IRQ {} (GSI {}) to vector {} on APIC {}",
+                isa_irq, global_system_interrupt, vector, destination_apic_id
+            );
+
+            let mut entry = vector as u64;
+            entry.set_bit(13, active_low);
+            entry.set_bit(15, level_triggered);
+            entry.set_bits(56..64, destination_apic_id as u64);
+            entry
+        },
+    );
+}
+
+#[allow(unused)]
+pub fn mask_isa_irq(isa_irq: u8) {
+    set_isa_irq_entry(isa_irq, |_, _, _| IO_APIC_REDIRECTION_MASKED as u64);
+}
+
+/// Allocates a vector for an ISA IRQ, registers the handler for it and routes the IRQ to this
+/// CPU. Returns the vector.
+pub fn register_isa_irq_handler(isa_irq: u8, handler: IrqHandler) -> Option<u8> {
+    let vector = allocate_vectors(1)?;
+
+    register_handler(vector, handler);
+    route_isa_irq(isa_irq, vector, local_apic_id());
+
+    Some(vector)
+}
@@ -0,0 +1,381 @@
+use crate::block::queue::{Direction, Request, RequestQueue};
+use crate::block::{check_request, BlockDevice, BlockError};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spinning_top::Spinlock;
+
+const PAGE_SIZE: usize = 4096;
+/// How many pages a device's cache holds by default, 4 MiB.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+struct CachePage {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState {
+    pages: BTreeMap<u64, CachePage>,
+    // Pages by when they were last used, the first one is evicted next.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl CacheState {
+    fn insert(&mut self, page: u64, data: Vec<u8>, dirty: bool) {
+        let last_used = self.clock;
+        self.clock += 1;
+
+        self.lru.insert(last_used, page);
+        self.pages.insert(
+            page,
+            CachePage {
+                data,
+                dirty,
+                last_used,
+            },
+        );
+    }
+
+    fn remove(&mut self, page: u64) -> Option<CachePage> {
+        let cache_page = self.pages.remove(&page)?;
+        self.lru.remove(&cache_page.last_used);
+
+        Some(cache_page)
+    }
+
+    fn touch(&mut self, page: u64) {
+        if let Some(cache_page) = self.pages.get_mut(&page) {
+            self.lru.remove(&cache_page.last_used);
+
+            cache_page.last_used = self.clock;
+            self.lru.insert(self.clock, page);
+            self.clock += 1;
+        }
+    }
+}
+
+/// A write-back LRU cache in front of a block device, in pages of 4 KiB (or the block size if
+/// that's bigger). Misses and write-backs go through a [`RequestQueue`], so adjacent pages are
+/// transferred together.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    page_size: usize,
+    capacity: usize,
+    state: Spinlock<CacheState>,
+}
+
+#[allow(unused)]
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        Self {
+            page_size: PAGE_SIZE.max(device.block_size()),
+            device,
+            capacity: capacity.max(2),
+            state: Spinlock::new(CacheState {
+                pages: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    fn blocks_per_page(&self) -> u64 {
+        (self.page_size / self.device.block_size()) as u64
+    }
+
+    /// The last page may be cut short by the end of the device.
+    fn page_length(&self, page: u64) -> usize {
+        let offset = page * self.page_size as u64;
+
+        (self.device.size() - offset).min(self.page_size as u64) as usize
+    }
+
+    fn page_request(&self, page: u64, direction: Direction, buffer: Vec<u8>) -> Request {
+        Request {
+            direction,
+            start: page * self.blocks_per_page(),
+            buffer,
+        }
+    }
+
+    /// Makes room for `incoming` pages, writing back the dirty ones that are evicted. Pages in
+    /// `keep` are in use and stay.
+    fn evict(
+        &self,
+        state: &mut CacheState,
+        incoming: usize,
+        keep: &Range<u64>,
+    ) -> Result<(), BlockError> {
+        let excess = (state.pages.len() + incoming).saturating_sub(self.capacity);
+
+        let victims = state
+            .lru
+            .values()
+            .filter(|page| !keep.contains(page))
+            .take(excess)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut queue = RequestQueue::new(self.device.clone());
+
+        for page in victims {
+            if let Some(cache_page) = state.remove(page) {
+                if cache_page.dirty {
+                    queue.push(self.page_request(page, Direction::Write, cache_page.data));
+                }
+            }
+        }
+
+        let mut result = Ok(());
+
+        // Pages that couldn't be written back stay cached, and dirty.
+        for completion in queue.run() {
+            if let Err(e) = completion.result {
+                let page = completion.request.start / self.blocks_per_page();
+                state.insert(page, completion.request.buffer, true);
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
+    /// Makes sure `pages` are cached, reading the missing ones except those `overwritten` is
+    /// true for, which start out zeroed.
+    fn load(
+        &self,
+        state: &mut CacheState,
+        pages: Range<u64>,
+        overwritten: impl Fn(u64) -> bool,
+    ) -> Result<(), BlockError> {
+        let mut missing = Vec::new();
+
+        for page in pages.clone() {
+            if state.pages.contains_key(&page) {
+                state.touch(page);
+            } else {
+                missing.push(page);
+            }
+        }
+
+        self.evict(state, missing.len(), &pages)?;
+
+        let mut queue = RequestQueue::new(self.device.clone());
+
+        for page in missing {
+            let buffer = vec![0; self.page_length(page)];
+
+            if overwritten(page) {
+                state.insert(page, buffer, false);
+            } else {
+                queue.push(self.page_request(page, Direction::Read, buffer));
+            }
+        }
+
+        for completion in queue.run() {
+            completion.result?;
+
+            let page = completion.request.start / self.blocks_per_page();
+            state.insert(page, completion.request.buffer, false);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` for every cached page overlapping the byte range, in chunks that fit the
+    /// cache, with the page, the offset into it and the offset into the range.
+    fn for_each_page(
+        &self,
+        offset: u64,
+        length: usize,
+        overwritten: impl Fn(u64, Range<u64>) -> bool,
+        mut f: impl FnMut(&mut CachePage, usize, usize),
+    ) -> Result<(), BlockError> {
+        if length == 0 {
+            return Ok(());
+        }
+
+        let page_size = self.page_size as u64;
+        let end = offset + length as u64;
+        let pages = offset / page_size..end.div_ceil(page_size);
+        let chunk = (self.capacity / 2) as u64;
+
+        let mut state = self.state.lock();
+        let mut first = pages.start;
+
+        while first < pages.end {
+            let chunk_pages = first..(first + chunk).min(pages.end);
+
+            self.load(&mut state, chunk_pages.clone(), |page| {
+                overwritten(page, offset..end)
+            })?;
+
+            for page in chunk_pages {
+                let page_start = page * page_size;
+                let start = offset.max(page_start);
+                let cache_page = state.pages.get_mut(&page).expect("loaded page missing");
+
+                f(
+                    cache_page,
+                    (start - page_start) as usize,
+                    (start - offset) as usize,
+                );
+            }
+
+            first += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all dirty pages back and flushes the device.
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        let mut queue = RequestQueue::new(self.device.clone());
+
+        for (page, cache_page) in &state.pages {
+            if cache_page.dirty {
+                queue.push(self.page_request(*page, Direction::Write, cache_page.data.clone()));
+            }
+        }
+
+        for completion in queue.run() {
+            completion.result?;
+
+            let page = completion.request.start / self.blocks_per_page();
+            if let Some(cache_page) = state.pages.get_mut(&page) {
+                cache_page.dirty = false;
+            }
+        }
+
+        self.device.flush()
+    }
+
+    /// Drops the cached pages a range of blocks covers completely without writing them back.
+    /// Pages it covers only partially keep their other blocks, dirty or not, the discarded
+    /// ones read as zeros.
+    fn invalidate(&self, start: u64, count: u64) {
+        let block_size = self.device.block_size() as u64;
+        let page_size = self.page_size as u64;
+        let range = start * block_size..(start + count) * block_size;
+
+        let mut state = self.state.lock();
+
+        // Only what's cached, the range may cover the whole device.
+        let pages: Vec<u64> = state
+            .pages
+            .range(range.start / page_size..range.end.div_ceil(page_size))
+            .map(|(page, _)| *page)
+            .collect();
+
+        for page in pages {
+            let page_start = page * page_size;
+            let page_end = page_start + self.page_length(page) as u64;
+
+            if range.start <= page_start && page_end <= range.end {
+                state.remove(page);
+            } else if let Some(cache_page) = state.pages.get_mut(&page) {
+                let from = range.start.max(page_start) - page_start;
+                let to = range.end.min(page_end) - page_start;
+
+                cache_page.data[from as usize..to as usize].fill(0);
+            }
+        }
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let offset = start * self.block_size() as u64;
+        let length = buffer.len();
+
+        self.for_each_page(
+            offset,
+            length,
+            |_, _| false,
+            |page, page_offset, buffer_offset| {
+                let count = (page.data.len() - page_offset).min(length - buffer_offset);
+
+                buffer[buffer_offset..buffer_offset + count]
+                    .copy_from_slice(&page.data[page_offset..page_offset + count]);
+            },
+        )
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        check_request(self, start, buffer.len())?;
+
+        let offset = start * self.block_size() as u64;
+        let page_size = self.page_size as u64;
+
+        // Pages that are written completely don't have to be read first.
+        let overwritten = |page: u64, range: Range<u64>| {
+            let page_start = page * page_size;
+            let page_end = page_start + self.page_length(page) as u64;
+
+            range.start <= page_start && page_end <= range.end
+        };
+
+        self.for_each_page(
+            offset,
+            buffer.len(),
+            overwritten,
+            |page, page_offset, buffer_offset| {
+                let count = (page.data.len() - page_offset).min(buffer.len() - buffer_offset);
+
+                page.data[page_offset..page_offset + count]
+                    .copy_from_slice(&buffer[buffer_offset..buffer_offset + count]);
+                page.dirty = true;
+            },
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        check_request(
+            self,
+            start,
+            count
+                .checked_mul(self.block_size() as u64)
+                .and_then(|length| usize::try_from(length).ok())
+                .ok_or(BlockError::OutOfRange)?,
+        )?;
+
+        self.invalidate(start, count);
+
+        self.device.discard(start, count)
+    }
+}
@@ -0,0 +1,212 @@
+use crate::block::cache::{BlockCache, DEFAULT_CAPACITY};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+use spinning_top::Spinlock;
+
+pub mod cache;
+pub mod partition;
+pub mod queue;
+pub mod ramdisk;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a multiple of the block size.
+    UnalignedBuffer,
+    ReadOnly,
+    Unsupported,
+    OutOfMemory,
+    /// The device reported an error.
+    Io,
+}
+
+/// A device that stores data in fixed size blocks, addressed by their index.
+#[allow(unused)]
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The size of a block in bytes, the unit of all requests.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buffer.len() / block_size()` blocks starting at `start`.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far is on stable storage.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Tells the device the blocks' contents aren't needed anymore.
+    fn discard(&self, _start: u64, _count: u64) -> Result<(), BlockError> {
+        Err(BlockError::Unsupported)
+    }
+
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Checks that a request for `length` bytes starting at block `start` fits the device and
+/// returns the number of blocks.
+pub fn check_request(
+    device: &dyn BlockDevice,
+    start: u64,
+    length: usize,
+) -> Result<u64, BlockError> {
+    if length % device.block_size() != 0 {
+        return Err(BlockError::UnalignedBuffer);
+    }
+
+    let count = (length / device.block_size()) as u64;
+
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Names the disk with the given index like Linux does, `sda` to `sdz`, then `sdaa` and on.
+pub fn disk_name(prefix: &str, index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut index = index;
+
+    loop {
+        letters.push(b'a' + (index % 26) as u8);
+
+        if index < 26 {
+            break;
+        }
+
+        index = index / 26 - 1;
+    }
+
+    letters.reverse();
+
+    let mut name = String::from(prefix);
+    name.extend(letters.into_iter().map(char::from));
+    name
+}
+
+/// Reads `buffer.len()` bytes from byte `offset` on, whether or not they're aligned to blocks.
+pub fn read_bytes(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(), BlockError> {
+    let block_size = device.block_size() as u64;
+    let start = offset / block_size;
+    let end = (offset + buffer.len() as u64).div_ceil(block_size);
+
+    let mut blocks = vec![0; ((end - start) * block_size) as usize];
+    device.read_blocks(start, &mut blocks)?;
+
+    let skip = (offset - start * block_size) as usize;
+    buffer.copy_from_slice(&blocks[skip..skip + buffer.len()]);
+
+    Ok(())
+}
+
+/// Writes `buffer` at byte `offset`, reading the blocks it only partially covers first.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buffer: &[u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size() as u64;
+
+    if offset % block_size == 0 && buffer.len() as u64 % block_size == 0 {
+        return device.write_blocks(offset / block_size, buffer);
+    }
+
+    let start = offset / block_size;
+    let end = (offset + buffer.len() as u64).div_ceil(block_size);
+
+    let mut blocks = vec![0; ((end - start) * block_size) as usize];
+    device.read_blocks(start, &mut blocks)?;
+
+    let skip = (offset - start * block_size) as usize;
+    blocks[skip..skip + buffer.len()].copy_from_slice(buffer);
+
+    device.write_blocks(start, &blocks)
+}
+
+static BLOCK_DEVICES: Spinlock<Vec<Arc<dyn BlockDevice>>> = Spinlock::new(Vec::new());
+static CACHES: Spinlock<Vec<Arc<BlockCache>>> = Spinlock::new(Vec::new());
+
+/// Makes a device available to the rest of the kernel, behind a [`BlockCache`], along with its
+/// partitions. Those share the device's cache.
+pub fn register_device(device: Arc<dyn BlockDevice>) {
+    log_device(device.as_ref());
+
+    let cache = Arc::new(BlockCache::new(device, DEFAULT_CAPACITY));
+    CACHES.lock().push(cache.clone());
+
+    add_device(cache);
+}
+
+/// Like [`register_device`], but for devices in memory, which a cache would only copy.
+pub fn register_memory_device(device: Arc<dyn BlockDevice>) {
+    log_device(device.as_ref());
+    add_device(device);
+}
+
+fn log_device(device: &dyn BlockDevice) {
+    info!(
+        "Block device {}: {} blocks of {} bytes ({} MiB){}",
+        device.name(),
+        device.block_count(),
+        device.block_size(),
+        device.size() / (1024 * 1024),
+        if device.is_read_only() {
+            ", read only"
+        } else {
+            ""
+        }
+    );
+}
+
+fn add_device(device: Arc<dyn BlockDevice>) {
+    BLOCK_DEVICES.lock().push(device.clone());
+
+    match partition::scan(&device) {
+        Ok(partitions) => BLOCK_DEVICES
+            .lock()
+            .extend(partitions.into_iter().map(|p| p as Arc<dyn BlockDevice>)),
+        Err(e) => warn!("Couldn't read partitions of {}: {:?}", device.name(), e),
+    }
+}
+
+#[allow(unused)]
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES.lock().clone()
+}
+
+#[allow(unused)]
+pub fn device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Writes back the dirty pages of all devices.
+#[allow(unused)]
+pub fn sync_all() {
+    let caches = CACHES.lock().clone();
+
+    for cache in caches {
+        if let Err(e) = cache.sync() {
+            warn!("Couldn't sync {}: {:?}", cache.name(), e);
+        }
+    }
+}
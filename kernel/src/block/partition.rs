@@ -0,0 +1,599 @@
+use crate::block::{check_request, BlockDevice, BlockError};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use log::{info, warn};
+use spinning_top::Spinlock;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Logical partitions are numbered from 5 on, after the four primary ones.
+const FIRST_LOGICAL_NUMBER: u32 = 5;
+// EBRs form a linked list on disk, this stops a corrupt one from looping forever.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+const GPT_ENTRY_NAME_OFFSET: usize = 56;
+const GPT_ENTRY_NAME_LENGTH: usize = 36;
+// Usually 128 entries of 128 bytes, anything much bigger is garbage.
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// A GUID, stored in the mixed endian layout GPT uses.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Guid([u8; 16]);
+
+#[allow(unused)]
+impl Guid {
+    pub const ZERO: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::new(
+        0xC12A7328,
+        0xF81F,
+        0x11D2,
+        [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+    );
+    pub const BASIC_DATA: Guid = Guid::new(
+        0xEBD0A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+    pub const LINUX_FILESYSTEM: Guid = Guid::new(
+        0x0FC63DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        let d = data4;
+
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self(bytes[..16].try_into().unwrap())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub enum PartitionKind {
+    Mbr {
+        system_id: u8,
+        bootable: bool,
+    },
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+        attributes: u64,
+    },
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionKind::Mbr {
+                system_id,
+                bootable,
+            } => write!(
+                f,
+                "MBR type {:#04x}{}",
+                system_id,
+                if *bootable { ", bootable" } else { "" }
+            ),
+            PartitionKind::Gpt {
+                type_guid, name, ..
+            } => write!(f, "GPT type {} \"{}\"", type_guid, name),
+        }
+    }
+}
+
+/// A range of blocks of another device.
+pub struct Partition {
+    name: String,
+    parent: Arc<dyn BlockDevice>,
+    number: u32,
+    start: u64,
+    count: u64,
+    kind: PartitionKind,
+}
+
+#[allow(unused)]
+impl Partition {
+    pub fn parent(&self) -> &Arc<dyn BlockDevice> {
+        &self.parent
+    }
+
+    /// Starts at 1, logical MBR partitions at 5.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The first block on the parent device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+
+    pub fn type_guid(&self) -> Option<Guid> {
+        match self.kind {
+            PartitionKind::Gpt { type_guid, .. } => Some(type_guid),
+            PartitionKind::Mbr { .. } => None,
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        match &self.kind {
+            PartitionKind::Gpt { name, .. } => Some(name),
+            PartitionKind::Mbr { .. } => None,
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.parent.is_read_only()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        self.parent.read_blocks(self.start + start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        self.parent.write_blocks(self.start + start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.parent.flush()
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        // In blocks, a discard can be longer than any buffer.
+        match start.checked_add(count) {
+            Some(end) if end <= self.block_count() => {}
+            _ => return Err(BlockError::OutOfRange),
+        }
+
+        self.parent.discard(self.start + start, count)
+    }
+}
+
+static PARTITIONS: Spinlock<Vec<Arc<Partition>>> = Spinlock::new(Vec::new());
+
+#[allow(unused)]
+pub fn partitions() -> Vec<Arc<Partition>> {
+    PARTITIONS.lock().clone()
+}
+
+#[allow(unused)]
+pub fn find_by_type(type_guid: Guid) -> Option<Arc<Partition>> {
+    PARTITIONS
+        .lock()
+        .iter()
+        .find(|partition| partition.type_guid() == Some(type_guid))
+        .cloned()
+}
+
+/// Reads the partition table of a device, if it has one, and returns its partitions.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Arc<Partition>>, BlockError> {
+    let mut table = PartitionTable {
+        device,
+        partitions: Vec::new(),
+    };
+
+    let sector = table.read(0, 1)?;
+
+    if u16::from_le_bytes([sector[510], sector[511]]) != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&sector);
+
+    if entries
+        .iter()
+        .any(|entry| entry.system_id == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        table.scan_gpt()?;
+    } else {
+        table.scan_mbr(&entries)?;
+    }
+
+    for partition in &table.partitions {
+        info!(
+            "Partition {}: {} blocks from block {}, {}",
+            partition.name, partition.count, partition.start, partition.kind
+        );
+    }
+
+    PARTITIONS.lock().extend(table.partitions.iter().cloned());
+
+    Ok(table.partitions)
+}
+
+#[derive(Copy, Clone)]
+struct MbrEntry {
+    bootable: bool,
+    system_id: u8,
+    start: u64,
+    count: u64,
+}
+
+fn mbr_entries(sector: &[u8]) -> [MbrEntry; 4] {
+    core::array::from_fn(|i| {
+        let entry = &sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+
+        MbrEntry {
+            bootable: entry[0] & 0x80 != 0,
+            system_id: entry[4],
+            start: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+            count: u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64,
+        }
+    })
+}
+
+struct GptHeader {
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    entries: Vec<u8>,
+    entry_size: usize,
+}
+
+struct PartitionTable<'a> {
+    device: &'a Arc<dyn BlockDevice>,
+    partitions: Vec<Arc<Partition>>,
+}
+
+impl PartitionTable<'_> {
+    fn read(&self, start: u64, count: u64) -> Result<Vec<u8>, BlockError> {
+        let mut buffer = vec![0; count as usize * self.device.block_size()];
+        self.device.read_blocks(start, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn add(&mut self, number: u32, start: u64, count: u64, kind: PartitionKind) {
+        let device_blocks = self.device.block_count();
+
+        if count == 0 || start == 0 || start.saturating_add(count) > device_blocks {
+            warn!(
+                "{}: partition {} ({} blocks from block {}) doesn't fit the device",
+                self.device.name(),
+                number,
+                count,
+                start
+            );
+            return;
+        }
+
+        // Names of devices ending in a digit would run into the number, "nvme0n1p1" like Linux.
+        let parent = self.device.name();
+        let name = if parent.ends_with(|c: char| c.is_ascii_digit()) {
+            format!("{}p{}", parent, number)
+        } else {
+            format!("{}{}", parent, number)
+        };
+
+        self.partitions.push(Arc::new(Partition {
+            name,
+            parent: self.device.clone(),
+            number,
+            start,
+            count,
+            kind,
+        }));
+    }
+
+    fn scan_mbr(&mut self, entries: &[MbrEntry; 4]) -> Result<(), BlockError> {
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.system_id == MBR_TYPE_EMPTY {
+                continue;
+            }
+
+            if MBR_TYPES_EXTENDED.contains(&entry.system_id) {
+                self.scan_extended(entry.start)?;
+                continue;
+            }
+
+            self.add(
+                i as u32 + 1,
+                entry.start,
+                entry.count,
+                PartitionKind::Mbr {
+                    system_id: entry.system_id,
+                    bootable: entry.bootable,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Follows the chain of EBRs in an extended partition. The first entry of each describes a
+    /// logical partition relative to the EBR, the second the next EBR relative to the extended
+    /// partition.
+    fn scan_extended(&mut self, extended_start: u64) -> Result<(), BlockError> {
+        let mut ebr = extended_start;
+
+        for number in FIRST_LOGICAL_NUMBER..FIRST_LOGICAL_NUMBER + MAX_LOGICAL_PARTITIONS {
+            if ebr >= self.device.block_count() {
+                warn!("{}: EBR past the end of the device", self.device.name());
+                return Ok(());
+            }
+
+            let sector = self.read(ebr, 1)?;
+
+            if u16::from_le_bytes([sector[510], sector[511]]) != MBR_SIGNATURE {
+                warn!("{}: invalid EBR at block {}", self.device.name(), ebr);
+                return Ok(());
+            }
+
+            let [logical, next, ..] = mbr_entries(&sector);
+
+            if logical.system_id != MBR_TYPE_EMPTY {
+                self.add(
+                    number,
+                    ebr + logical.start,
+                    logical.count,
+                    PartitionKind::Mbr {
+                        system_id: logical.system_id,
+                        bootable: logical.bootable,
+                    },
+                );
+            }
+
+            if next.start == 0 || !MBR_TYPES_EXTENDED.contains(&next.system_id) {
+                return Ok(());
+            }
+
+            ebr = extended_start + next.start;
+        }
+
+        warn!("{}: too many logical partitions", self.device.name());
+        Ok(())
+    }
+
+    fn scan_gpt(&mut self) -> Result<(), BlockError> {
+        let last_block = self.device.block_count() - 1;
+
+        let header = match self.read_gpt_header(1)? {
+            Some(header) => {
+                if self.read_gpt_header(header.alternate_lba)?.is_none() {
+                    warn!("{}: backup GPT header is invalid", self.device.name());
+                }
+
+                header
+            }
+            None => match self.read_gpt_header(last_block)? {
+                Some(header) => {
+                    warn!(
+                        "{}: primary GPT header is invalid, using the backup",
+                        self.device.name()
+                    );
+
+                    header
+                }
+                None => {
+                    warn!("{}: no valid GPT header", self.device.name());
+                    return Ok(());
+                }
+            },
+        };
+
+        for (i, entry) in header.entries.chunks_exact(header.entry_size).enumerate() {
+            let type_guid = Guid::from_bytes(&entry[0..16]);
+
+            if type_guid.is_zero() {
+                continue;
+            }
+
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+
+            if first_lba < header.first_usable_lba
+                || last_lba > header.last_usable_lba
+                || last_lba < first_lba
+            {
+                warn!(
+                    "{}: GPT entry {} is outside the usable blocks",
+                    self.device.name(),
+                    i
+                );
+                continue;
+            }
+
+            let name = char::decode_utf16(
+                entry[GPT_ENTRY_NAME_OFFSET..][..GPT_ENTRY_NAME_LENGTH * 2]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0),
+            )
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+            self.add(
+                i as u32 + 1,
+                first_lba,
+                last_lba - first_lba + 1,
+                PartitionKind::Gpt {
+                    type_guid,
+                    unique_guid: Guid::from_bytes(&entry[16..32]),
+                    name,
+                    attributes: u64::from_le_bytes(entry[48..56].try_into().unwrap()),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads the GPT header at `lba` and its entries, `None` if either is invalid.
+    fn read_gpt_header(&self, lba: u64) -> Result<Option<GptHeader>, BlockError> {
+        let block_size = self.device.block_size();
+        let block_count = self.device.block_count();
+
+        if lba == 0 || lba >= block_count {
+            return Ok(None);
+        }
+
+        let mut block = self.read(lba, 1)?;
+        let field =
+            |offset: usize| u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap());
+
+        let header_size = u32::from_le_bytes(block[12..16].try_into().unwrap()) as usize;
+
+        if &block[0..8] != GPT_SIGNATURE
+            || !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size)
+        {
+            return Ok(None);
+        }
+
+        let my_lba = field(24);
+        let alternate_lba = field(32);
+        let first_usable_lba = field(40);
+        let last_usable_lba = field(48);
+        let entries_lba = field(72);
+        let entry_count = u32::from_le_bytes(block[80..84].try_into().unwrap()) as usize;
+        let entry_size = u32::from_le_bytes(block[84..88].try_into().unwrap()) as usize;
+        let entries_crc = u32::from_le_bytes(block[88..92].try_into().unwrap());
+        let header_crc = u32::from_le_bytes(block[16..20].try_into().unwrap());
+
+        // The header's CRC is calculated with the CRC field itself zeroed.
+        block[16..20].fill(0);
+
+        if crc32(&block[..header_size]) != header_crc {
+            return Ok(None);
+        }
+
+        let entries_size = entry_count * entry_size;
+
+        if my_lba != lba
+            || entry_size < GPT_ENTRY_MIN_SIZE
+            || entry_size % 8 != 0
+            || entries_size > GPT_MAX_ENTRIES_SIZE
+            || first_usable_lba > last_usable_lba
+            || last_usable_lba >= block_count
+        {
+            return Ok(None);
+        }
+
+        let entry_blocks = entries_size.div_ceil(block_size) as u64;
+
+        if entries_lba == 0 || entries_lba.saturating_add(entry_blocks) > block_count {
+            return Ok(None);
+        }
+
+        let mut entries = self.read(entries_lba, entry_blocks)?;
+        entries.truncate(entries_size);
+
+        if crc32(&entries) != entries_crc {
+            return Ok(None);
+        }
+
+        Ok(Some(GptHeader {
+            alternate_lba,
+            first_usable_lba,
+            last_usable_lba,
+            entries,
+            entry_size,
+        }))
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// The CRC-32 used by GPT (and zlib and Ethernet).
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (crc >> 8) ^ CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize]
+    })
+}
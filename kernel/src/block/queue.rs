@@ -0,0 +1,152 @@
+use crate::block::{BlockDevice, BlockError};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Merged requests are split again past this size, so one big request can't hold up everything
+// else for too long.
+const MAX_MERGED_SIZE: usize = 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A read into or a write from `buffer`, which is a multiple of the block size long.
+pub struct Request {
+    pub direction: Direction,
+    pub start: u64,
+    pub buffer: Vec<u8>,
+}
+
+pub struct Completion {
+    pub request: Request,
+    pub result: Result<(), BlockError>,
+}
+
+/// Collects requests for a device and runs them in block order, merging adjacent requests in
+/// the same direction into one. Requests mustn't overlap, their order isn't kept.
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: Vec<Request>,
+}
+
+#[allow(unused)]
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            device,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, request: Request) {
+        self.pending.push(request);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn blocks(&self, request: &Request) -> u64 {
+        (request.buffer.len() / self.device.block_size()) as u64
+    }
+
+    /// Runs everything that's pending and returns the completions in the order the requests
+    /// were pushed.
+    pub fn run(&mut self) -> Vec<Completion> {
+        let mut pending = core::mem::take(&mut self.pending)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+
+        pending.sort_by_key(|(_, request)| request.start);
+
+        let mut completions = Vec::with_capacity(pending.len());
+        let mut group: Vec<(usize, Request)> = Vec::new();
+        let mut group_size = 0;
+
+        for (index, request) in pending {
+            let continues_group = group.last().map_or(false, |(_, last)| {
+                last.direction == request.direction
+                    && last.start + self.blocks(last) == request.start
+                    && group_size + request.buffer.len() <= MAX_MERGED_SIZE
+            });
+
+            if !continues_group && !group.is_empty() {
+                completions.append(&mut self.execute(core::mem::take(&mut group)));
+                group_size = 0;
+            }
+
+            group_size += request.buffer.len();
+            group.push((index, request));
+        }
+
+        if !group.is_empty() {
+            completions.append(&mut self.execute(group));
+        }
+
+        completions.sort_by_key(|(index, _)| *index);
+        completions
+            .into_iter()
+            .map(|(_, completion)| completion)
+            .collect()
+    }
+
+    /// Runs a group of adjacent requests as a single one.
+    fn execute(&self, mut group: Vec<(usize, Request)>) -> Vec<(usize, Completion)> {
+        let result = if let [(_, request)] = group.as_mut_slice() {
+            match request.direction {
+                Direction::Read => self.device.read_blocks(request.start, &mut request.buffer),
+                Direction::Write => self.device.write_blocks(request.start, &request.buffer),
+            }
+        } else {
+            let start = group[0].1.start;
+            let size = group.iter().map(|(_, request)| request.buffer.len()).sum();
+
+            match group[0].1.direction {
+                Direction::Read => {
+                    let mut buffer = vec![0; size];
+                    let result = self.device.read_blocks(start, &mut buffer);
+
+                    let mut offset = 0;
+                    for (_, request) in &mut group {
+                        let length = request.buffer.len();
+                        request
+                            .buffer
+                            .copy_from_slice(&buffer[offset..offset + length]);
+                        offset += length;
+                    }
+
+                    result
+                }
+                Direction::Write => {
+                    let mut buffer = Vec::with_capacity(size);
+                    for (_, request) in &group {
+                        buffer.extend_from_slice(&request.buffer);
+                    }
+
+                    self.device.write_blocks(start, &buffer)
+                }
+            }
+        };
+
+        group
+            .into_iter()
+            .map(|(index, request)| {
+                (
+                    index,
+                    Completion {
+                        request,
+                        result: result.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
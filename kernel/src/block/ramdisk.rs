@@ -0,0 +1,79 @@
+use crate::block::{check_request, register_memory_device, BlockDevice, BlockError};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::info;
+
+const BLOCK_SIZE: usize = 512;
+
+static NEXT_NUMBER: AtomicUsize = AtomicUsize::new(0);
+static INITRD: OnceCell<&'static [u8]> = OnceCell::uninit();
+
+/// Registers the ramdisk the bootloader loaded, `addr` is where it's mapped.
+pub fn init(addr: u64, length: u64) {
+    let data = unsafe { core::slice::from_raw_parts(addr as *const u8, length as usize) };
+
+    info!("Ramdisk at {:#x}, {} bytes", addr, length);
+
+    INITRD.init_once(|| data);
+    register_memory_device(Arc::new(RamDisk::new(data)));
+}
+
+/// The contents of the ramdisk the bootloader loaded, if there is one. Archives can be read
+/// from here directly, without going through blocks.
+#[allow(unused)]
+pub fn initrd() -> Option<&'static [u8]> {
+    INITRD.get().copied()
+}
+
+/// A read only block device backed by memory, named like `ram0`.
+pub struct RamDisk {
+    name: String,
+    data: &'static [u8],
+}
+
+impl RamDisk {
+    pub fn new(data: &'static [u8]) -> Self {
+        Self {
+            name: format!("ram{}", NEXT_NUMBER.fetch_add(1, Ordering::Relaxed)),
+            data,
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        // The last block is padded with zeros.
+        self.data.len().div_ceil(BLOCK_SIZE) as u64
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let offset = start as usize * BLOCK_SIZE;
+        let available = self.data.len().saturating_sub(offset).min(buffer.len());
+
+        buffer[..available].copy_from_slice(&self.data[offset..offset + available]);
+        buffer[available..].fill(0);
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, _start: u64, _buffer: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::memory::mmio::map_mmio_region;
+use acpi::hpet::HpetInfo;
+use acpi::{AcpiHandler, AcpiTables};
+use bit_field::BitField;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::{debug, warn};
+use x86_64::VirtAddr;
+
+const REGISTER_CAPABILITIES: u64 = 0x000;
+const REGISTER_CONFIGURATION: u64 = 0x010;
+const REGISTER_MAIN_COUNTER: u64 = 0x0F0;
+const REGISTER_BLOCK_SIZE: usize = 0x400;
+
+const CAPABILITIES_64_BIT_COUNTER: usize = 13;
+const CONFIGURATION_ENABLE: usize = 0;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The main counter of the High Precision Event Timer, used as a reference clock.
+pub struct Hpet {
+    base: VirtAddr,
+    frequency: u64,
+    is_64_bit: bool,
+    // The last value read from a 32 bit counter, with the wraparounds counted in the upper
+    // half.
+    last_counter: AtomicU64,
+}
+
+#[allow(unused)]
+impl Hpet {
+    /// Finds the HPET through ACPI and starts its main counter.
+    pub fn new<H>(tables: &AcpiTables<H>) -> Option<Self>
+    where
+        H: AcpiHandler,
+    {
+        let info = match HpetInfo::new(tables) {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("No HPET: {:?}", e);
+                return None;
+            }
+        };
+
+        let base = map_mmio_region(info.base_address as u64, REGISTER_BLOCK_SIZE);
+
+        let mut hpet = Self {
+            base,
+            frequency: 0,
+            is_64_bit: false,
+            last_counter: AtomicU64::new(0),
+        };
+
+        let capabilities = hpet.read(REGISTER_CAPABILITIES);
+        let period = capabilities.get_bits(32..64);
+
+        if period == 0 || period > 100_000_000 {
+            warn!("HPET reports an invalid period of {} fs", period);
+            return None;
+        }
+
+        hpet.frequency = FEMTOSECONDS_PER_SECOND / period;
+        hpet.is_64_bit = capabilities.get_bit(CAPABILITIES_64_BIT_COUNTER);
+
+        let mut configuration = hpet.read(REGISTER_CONFIGURATION);
+        configuration.set_bit(CONFIGURATION_ENABLE, true);
+        hpet.write(REGISTER_CONFIGURATION, configuration);
+
+        debug!(
+            "HPET at {:#x} runs at {} Hz, {} bit counter",
+            info.base_address,
+            hpet.frequency,
+            if hpet.is_64_bit { 64 } else { 32 }
+        );
+
+        Some(hpet)
+    }
+
+    fn read(&self, register: u64) -> u64 {
+        unsafe { (self.base + register).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        unsafe {
+            (self.base + register)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// The main counter, extended to 64 bits if the hardware only has 32. Has to be read at
+    /// least once per wraparound (minutes) to stay monotonic.
+    pub fn counter(&self) -> u64 {
+        let counter = self.read(REGISTER_MAIN_COUNTER);
+
+        if self.is_64_bit {
+            return counter;
+        }
+
+        let low = counter & u32::MAX as u64;
+
+        let extend = |last: u64| {
+            let extended = (last & !(u32::MAX as u64)) | low;
+
+            if extended < last {
+                extended + (1 << 32)
+            } else {
+                extended
+            }
+        };
+
+        let previous = self
+            .last_counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(extend(last))
+            })
+            .unwrap();
+
+        extend(previous)
+    }
+}
@@ -0,0 +1,210 @@
+use crate::clocksource::hpet::Hpet;
+use crate::pit::busy_wait;
+use crate::rtc;
+use acpi::{AcpiHandler, AcpiTables};
+use conquer_once::spin::OnceCell;
+use core::ops::{Add, AddAssign, Sub};
+use core::time::Duration;
+use log::{info, warn};
+
+pub mod hpet;
+mod tsc;
+
+const CALIBRATION_DURATION: Duration = Duration::from_millis(10);
+const CALIBRATION_ROUNDS: usize = 5;
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// What the monotonic clock is read from, the first usable one in this order.
+pub enum ClockSource {
+    /// Invariant TSC, calibrated at boot.
+    Tsc { frequency: u64, start: u64 },
+    /// HPET main counter, if the TSC isn't invariant.
+    Hpet { hpet: Hpet, start: u64 },
+    /// The RTC's periodic interrupt, with a resolution of about a millisecond.
+    Rtc,
+}
+
+struct Clock {
+    source: ClockSource,
+    // The uptime when this clock took over, so time doesn't go backwards at the switch.
+    offset: u64,
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+
+fn ticks_to_nanos(ticks: u64, frequency: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SECOND / frequency as u128) as u64
+}
+
+impl ClockSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Tsc { .. } => "TSC",
+            Self::Hpet { .. } => "HPET",
+            Self::Rtc => "RTC",
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        match self {
+            Self::Tsc { frequency, start } => ticks_to_nanos(tsc::read() - start, *frequency),
+            Self::Hpet { hpet, start } => ticks_to_nanos(hpet.counter() - start, hpet.frequency()),
+            Self::Rtc => rtc::uptime().as_nanos() as u64,
+        }
+    }
+}
+
+/// Measures the TSC frequency against the HPET if there is one, the PIT otherwise. Waiting
+/// can only take longer than asked for, so the lowest measurement is the most accurate.
+fn calibrate_tsc(hpet: Option<&Hpet>) -> u64 {
+    (0..CALIBRATION_ROUNDS)
+        .map(|_| match hpet {
+            Some(hpet) => {
+                let ticks = (hpet.frequency() as u128 * CALIBRATION_DURATION.as_nanos()
+                    / NANOS_PER_SECOND) as u64;
+
+                let hpet_start = hpet.counter();
+                let tsc_start = tsc::read();
+
+                while hpet.counter() - hpet_start < ticks {
+                    core::hint::spin_loop();
+                }
+
+                let tsc_elapsed = tsc::read() - tsc_start;
+                let hpet_elapsed = hpet.counter() - hpet_start;
+
+                (tsc_elapsed as u128 * hpet.frequency() as u128 / hpet_elapsed as u128) as u64
+            }
+            None => {
+                let tsc_start = tsc::read();
+                busy_wait(CALIBRATION_DURATION);
+                let tsc_elapsed = tsc::read() - tsc_start;
+
+                (tsc_elapsed as u128 * NANOS_PER_SECOND / CALIBRATION_DURATION.as_nanos()) as u64
+            }
+        })
+        .min()
+        .unwrap()
+}
+
+/// Picks and calibrates the clock source behind [`Instant`] and [`uptime`].
+pub fn init<H>(tables: &AcpiTables<H>)
+where
+    H: AcpiHandler,
+{
+    let hpet = Hpet::new(tables);
+
+    let source = if tsc::is_present() && tsc::is_invariant() {
+        let frequency = calibrate_tsc(hpet.as_ref());
+
+        info!(
+            "TSC runs at {}.{:03} MHz (calibrated against the {})",
+            frequency / 1_000_000,
+            frequency / 1_000 % 1_000,
+            if hpet.is_some() { "HPET" } else { "PIT" }
+        );
+
+        ClockSource::Tsc {
+            frequency,
+            start: tsc::read(),
+        }
+    } else if let Some(hpet) = hpet {
+        warn!("TSC isn't invariant, falling back to the HPET");
+
+        ClockSource::Hpet {
+            start: hpet.counter(),
+            hpet,
+        }
+    } else {
+        warn!("Neither an invariant TSC nor an HPET, falling back to the RTC");
+
+        ClockSource::Rtc
+    };
+
+    let offset = nanos_since_boot();
+
+    let clock = CLOCK.get_or_init(|| Clock { source, offset });
+    info!("Using the {} as clock source", clock.source.name());
+}
+
+/// Nanoseconds since the clock started. Before [`init`], the RTC's count is used.
+pub fn nanos_since_boot() -> u64 {
+    match CLOCK.get() {
+        Some(clock) => clock.offset + clock.source.nanos(),
+        None => rtc::uptime().as_nanos() as u64,
+    }
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos_since_boot())
+}
+
+/// A point in time of the monotonic clock.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+#[allow(unused)]
+impl Instant {
+    pub fn now() -> Self {
+        Self(nanos_since_boot())
+    }
+
+    /// Zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Self)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Self)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
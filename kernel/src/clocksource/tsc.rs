@@ -0,0 +1,35 @@
+use bit_field::BitField;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+const CPUID_ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+const FEATURES_EDX_TSC: usize = 4;
+const ADVANCED_POWER_MANAGEMENT_EDX_INVARIANT_TSC: usize = 8;
+
+pub fn is_present() -> bool {
+    let features = unsafe { __cpuid(CPUID_FEATURES) };
+
+    features.edx.get_bit(FEATURES_EDX_TSC)
+}
+
+/// Whether the TSC runs at a constant rate in all power states, which is what makes it
+/// usable as a clock.
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(CPUID_MAX_EXTENDED_LEAF) }.eax;
+
+    if max_extended_leaf < CPUID_ADVANCED_POWER_MANAGEMENT {
+        return false;
+    }
+
+    let power_management = unsafe { __cpuid(CPUID_ADVANCED_POWER_MANAGEMENT) };
+
+    power_management
+        .edx
+        .get_bit(ADVANCED_POWER_MANAGEMENT_EDX_INVARIANT_TSC)
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
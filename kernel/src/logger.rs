@@ -1,7 +1,10 @@
+use crate::clocksource::uptime;
+use crate::serial::COM1;
 use crate::SCREEN;
 use conquer_once::spin::OnceCell;
 use core::fmt::Write;
 use log::{Metadata, Record};
+use x86_64::instructions::interrupts::without_interrupts;
 
 pub struct Logger {}
 
@@ -22,10 +25,36 @@ impl log::Log for Logger {
         true
     }
 
+    /// Writes to the serial port and the screen, whichever are available. Interrupts are off
+    /// while the sinks are locked, so interrupt handlers can log too.
     fn log(&self, record: &Record) {
-        let mut screen = SCREEN.get().unwrap().lock();
-
-        writeln!(screen, "{:5}: {}", record.level(), record.args()).unwrap()
+        let uptime = uptime();
+        let (seconds, nanoseconds) = (uptime.as_secs(), uptime.subsec_nanos());
+
+        without_interrupts(|| {
+            if let Some(com1) = COM1.get() {
+                let _ = writeln!(
+                    com1.lock(),
+                    "[{:5}.{:09}] {:5}: {}",
+                    seconds,
+                    nanoseconds,
+                    record.level(),
+                    record.args()
+                );
+            }
+
+            if let Some(screen) = SCREEN.get() {
+                writeln!(
+                    screen.lock(),
+                    "[{:5}.{:09}] {:5}: {}",
+                    seconds,
+                    nanoseconds,
+                    record.level(),
+                    record.args()
+                )
+                .unwrap()
+            }
+        });
     }
 
     fn flush(&self) {}
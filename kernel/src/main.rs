@@ -10,14 +10,26 @@
 extern crate alloc;
 
 mod acpi;
+mod ahci;
 mod apic;
+mod block;
+mod clocksource;
 mod color;
 mod framebuffer;
 mod logger;
 mod memory;
+mod nvme;
 mod pci_express;
+mod pit;
+mod ps2;
+mod ring_buffer;
+mod rtc;
 mod screen;
+mod serial;
 mod text_writer;
+mod timer;
+mod vfs;
+mod virtio;
 
 use crate::acpi::AcpiMapper;
 use crate::color::Color;
@@ -26,6 +38,7 @@ use crate::logger::Logger;
 use crate::memory::frame_allocator::BootInfoFrameAllocator;
 use crate::memory::{heap, map_physical_to_virtual_mut};
 use crate::screen::Screen;
+use crate::serial::COM1;
 use ::acpi::{AcpiTables, InterruptModel};
 use alloc::alloc::Global;
 use bootloader_api::config::Mapping;
@@ -55,18 +68,32 @@ lazy_static! {
     pub static ref SCREEN: OnceCell<Spinlock<Screen>> = OnceCell::uninit();
 }
 
+fn write_panic_message(writer: &mut impl Write, info: &PanicInfo) {
+    info.message()
+        .map(|message| writeln!(writer, "\n\nKernel panic\n\n{}", message));
+
+    let _ = writeln!(writer, "{:?}", info.location());
+
+    let _ = writeln!(writer, "\n\nDisabling interrupts and halting CPU.");
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let screen = SCREEN.get().unwrap();
-    unsafe { screen.force_unlock() };
+    x86_64::instructions::interrupts::disable();
 
-    let mut screen_inner = screen.lock();
-    info.message()
-        .map(|message| writeln!(screen_inner, "\n\nKernel panic\n\n{}", message));
+    if let Some(com1) = COM1.get() {
+        unsafe { com1.force_unlock() };
+
+        let mut com1 = com1.lock();
+        com1.disable_interrupts();
+        write_panic_message(&mut *com1, info);
+    }
 
-    let _ = writeln!(screen_inner, "{:?}", info.location());
+    if let Some(screen) = SCREEN.get() {
+        unsafe { screen.force_unlock() };
 
-    let _ = writeln!(screen_inner, "\n\nDisabling interrupts and halting CPU.");
+        write_panic_message(&mut *screen.lock(), info);
+    }
 
     loop {
         x86_64::instructions::interrupts::disable();
@@ -164,10 +191,12 @@ unsafe fn find_page_table() -> &'static mut PageTable {
     &mut *page_table_ptr
 }
 
+/// Idles forever. Interrupts stay enabled, so buffered serial output still gets written and
+/// input still gets handled. What interrupts left to be logged is logged in between.
 fn halt() -> ! {
     loop {
-        x86_64::instructions::interrupts::disable();
-        x86_64::instructions::hlt();
+        pci_express::aer::log_pending_errors();
+        x86_64::instructions::interrupts::enable_and_hlt();
     }
 }
 
@@ -187,9 +216,11 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         };
 
         SCREEN.get_or_init(|| Spinlock::new(Screen::new(wrapper)));
-        Logger::init();
     }
 
+    serial::init();
+    Logger::init();
+
     let mut offset_table = unsafe {
         OffsetPageTable::new(
             find_page_table(),
@@ -224,7 +255,14 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
             .unwrap();
     }
 
-    heap::init_heap(offset_table, &mut frame_allocator);
+    heap::init_heap(&mut offset_table, &mut frame_allocator);
+    memory::init(offset_table, frame_allocator);
+
+    if let Optional::Some(addr) = boot_info.ramdisk_addr {
+        block::ramdisk::init(addr, boot_info.ramdisk_len);
+    }
+
+    vfs::init();
 
     if let Some(offset) = PHYSICAL_MEMORY_OFFSET.get() {
         let handler = AcpiMapper {
@@ -252,9 +290,18 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         debug!("Found APIC {:?}", apic);
         apic::init(apic);
 
-        let mut pcie = pci_express::PCIe::new();
-
-        pcie.scan(&acpi);
+        serial::enable_interrupts();
+        rtc::init(&acpi);
+        clocksource::init(&acpi);
+        timer::init();
+        ps2::init();
+        x86_64::instructions::interrupts::enable();
+
+        ahci::init();
+        nvme::init();
+        virtio::block::init();
+        pci_express::init(&acpi);
+        vfs::mount_block_devices();
 
         info!("Startup done!\n");
         info!("If you're looking for the roing, comment out the call to the halt function following line {} in file {}", line!(), file!());
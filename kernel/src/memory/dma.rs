@@ -0,0 +1,94 @@
+use crate::memory::{map_physical_to_virtual, FRAME_ALLOCATOR};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: usize = 4096;
+
+// The frame allocator can't take frames back, so freed buffers are kept here by their size in
+// pages for the next allocation of that size.
+static FREE_BUFFERS: Spinlock<BTreeMap<usize, Vec<PhysAddr>>> = Spinlock::new(BTreeMap::new());
+
+/// Physically contiguous, page aligned memory for devices to read and write. x86 keeps DMA
+/// cache coherent, so it's accessed through the normal physical memory mapping.
+pub struct DmaBuffer {
+    physical_address: PhysAddr,
+    pages: usize,
+}
+
+#[allow(unused)]
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of at least `size` bytes. Sizes are rounded up to a power of
+    /// two pages so freed buffers can be reused.
+    pub fn new(size: usize) -> Option<Self> {
+        let pages = size.max(1).div_ceil(PAGE_SIZE).next_power_of_two();
+
+        let reused = without_interrupts(|| {
+            FREE_BUFFERS
+                .lock()
+                .get_mut(&pages)
+                .and_then(|buffers| buffers.pop())
+        });
+
+        let physical_address = match reused {
+            Some(physical_address) => physical_address,
+            None => FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock()
+                .allocate_contiguous(pages)?
+                .start_address(),
+        };
+
+        let buffer = Self {
+            physical_address,
+            pages,
+        };
+
+        unsafe { buffer.as_mut_ptr::<u8>().write_bytes(0, buffer.len()) };
+
+        Some(buffer)
+    }
+
+    pub fn physical_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    pub fn virtual_address(&self) -> VirtAddr {
+        map_physical_to_virtual(self.physical_address.as_u64())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.virtual_address().as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virtual_address().as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            FREE_BUFFERS
+                .lock()
+                .entry(self.pages)
+                .or_default()
+                .push(self.physical_address)
+        });
+    }
+}
@@ -30,6 +30,30 @@ impl BootInfoFrameAllocator {
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocates `count` physically contiguous frames and returns the first. Frames skipped
+    /// while looking for a long enough run are lost.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut run: Option<(PhysFrame, usize)> = None;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            run = match run {
+                Some((start, length)) if frame == start + length as u64 => {
+                    Some((start, length + 1))
+                }
+                _ => Some((frame, 1)),
+            };
+
+            if let Some((start, length)) = run {
+                if length == count {
+                    self.next = index + 1;
+                    return Some(start);
+                }
+            }
+        }
+
+        None
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
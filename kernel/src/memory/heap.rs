@@ -10,7 +10,7 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 pub const HEAP_START: *mut u8 = 0x_4444_4444_0000 as *mut u8;
 pub const HEAP_SIZE: usize = 9000 * 1024;
 
-fn map_heap<M, A>(mut mapper: M, frame_allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
+fn map_heap<M, A>(mapper: &mut M, frame_allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
 where
     M: Mapper<Size4KiB>,
     A: FrameAllocator<Size4KiB> + ?Sized,
@@ -38,7 +38,7 @@ where
     Ok(())
 }
 
-pub fn init_heap<M, A>(mapper: M, frame_allocator: &mut A)
+pub fn init_heap<M, A>(mapper: &mut M, frame_allocator: &mut A)
 where
     M: Mapper<Size4KiB>,
     A: FrameAllocator<Size4KiB> + ?Sized,
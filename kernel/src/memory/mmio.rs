@@ -0,0 +1,54 @@
+use crate::memory::{map_physical_to_virtual, FRAME_ALLOCATOR, PAGE_TABLE};
+use log::debug;
+use x86_64::structures::paging::mapper::TranslateError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Makes sure a device memory region is reachable through the physical memory offset mapping.
+///
+/// The bootloader only maps physical memory up to the highest address in the memory map, which
+/// doesn't necessarily include device memory such as PCI BARs. Pages that aren't mapped yet are
+/// mapped uncached.
+pub fn map_mmio_region(physical_address: u64, size: usize) -> VirtAddr {
+    let virtual_address = map_physical_to_virtual(physical_address);
+
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(physical_address));
+    let end_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
+        physical_address + size.max(1) as u64 - 1,
+    ));
+
+    let mut page_table = PAGE_TABLE.get().expect("page table not initialized").lock();
+    let mut frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator not initialized")
+        .lock();
+
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::<Size4KiB>::containing_address(map_physical_to_virtual(
+            frame.start_address().as_u64(),
+        ));
+
+        match page_table.translate_page(page) {
+            Ok(_) | Err(TranslateError::ParentEntryHugePage) => continue,
+            Err(_) => {}
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+        unsafe {
+            page_table
+                .map_to(page, frame, flags, &mut *frame_allocator)
+                .expect("Couldn't map MMIO region")
+                .flush();
+        }
+    }
+
+    debug!(
+        "MMIO region {:#x}..{:#x} mapped at {:?}",
+        physical_address,
+        physical_address + size as u64,
+        virtual_address
+    );
+
+    virtual_address
+}
@@ -1,8 +1,24 @@
+use crate::memory::frame_allocator::BootInfoFrameAllocator;
 use crate::PHYSICAL_MEMORY_OFFSET;
+use conquer_once::spin::OnceCell;
+use spinning_top::Spinlock;
+use x86_64::structures::paging::OffsetPageTable;
 use x86_64::VirtAddr;
 
+pub mod dma;
 pub mod frame_allocator;
 pub mod heap;
+pub mod mmio;
+
+pub static PAGE_TABLE: OnceCell<Spinlock<OffsetPageTable<'static>>> = OnceCell::uninit();
+pub static FRAME_ALLOCATOR: OnceCell<Spinlock<BootInfoFrameAllocator>> = OnceCell::uninit();
+
+/// Hands the page table and frame allocator over to the rest of the kernel once the early
+/// boot mappings are in place.
+pub fn init(page_table: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    PAGE_TABLE.init_once(move || Spinlock::new(page_table));
+    FRAME_ALLOCATOR.init_once(move || Spinlock::new(frame_allocator));
+}
 
 pub fn map_physical_to_virtual(address: u64) -> VirtAddr {
     let offset = PHYSICAL_MEMORY_OFFSET.get().unwrap();
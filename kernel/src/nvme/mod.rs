@@ -0,0 +1,550 @@
+use crate::apic;
+use crate::block::register_device;
+use crate::memory::dma::DmaBuffer;
+use crate::nvme::namespace::NvmeNamespace;
+use crate::nvme::queue::{Command, Queue};
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::driver::{PciDeviceId, PciDriver, PciProbeError};
+use crate::pci_express::interrupts::PciInterrupts;
+use crate::pci_express::register_driver;
+use crate::pit::busy_wait;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+pub mod namespace;
+pub mod queue;
+
+const MASS_STORAGE_CLASS: u8 = 0x01;
+const NON_VOLATILE_MEMORY_SUBCLASS: u8 = 0x08;
+const NVME_PROGRAMMING_INTERFACE: u8 = 0x02;
+
+const REGISTER_CAPABILITIES: u64 = 0x00;
+const REGISTER_VERSION: u64 = 0x08;
+const REGISTER_CONFIGURATION: u64 = 0x14;
+const REGISTER_STATUS: u64 = 0x1C;
+const REGISTER_ADMIN_QUEUE_ATTRIBUTES: u64 = 0x24;
+const REGISTER_ADMIN_SUBMISSION_QUEUE: u64 = 0x28;
+const REGISTER_ADMIN_COMPLETION_QUEUE: u64 = 0x30;
+const DOORBELLS_OFFSET: u64 = 0x1000;
+
+const CAPABILITY_MAX_QUEUE_ENTRIES: core::ops::Range<usize> = 0..16;
+const CAPABILITY_TIMEOUT: core::ops::Range<usize> = 24..32;
+const CAPABILITY_DOORBELL_STRIDE: core::ops::Range<usize> = 32..36;
+const CAPABILITY_NVM_COMMAND_SET: usize = 37;
+const CAPABILITY_MIN_PAGE_SIZE: core::ops::Range<usize> = 48..52;
+
+const CONFIGURATION_ENABLE: usize = 0;
+const CONFIGURATION_SUBMISSION_ENTRY_SIZE: core::ops::Range<usize> = 16..20;
+const CONFIGURATION_COMPLETION_ENTRY_SIZE: core::ops::Range<usize> = 20..24;
+
+const STATUS_READY: usize = 0;
+const STATUS_FATAL: usize = 1;
+
+const ADMIN_CREATE_SUBMISSION_QUEUE: u8 = 0x01;
+const ADMIN_CREATE_COMPLETION_QUEUE: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const IDENTIFY_SIZE: usize = 4096;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 256;
+// One CPU, so a single I/O queue pair gets us everything there is to get.
+const IO_QUEUE_ID: u16 = 1;
+
+/// The memory page size, set in CC.MPS. Data pointers are in units of it.
+pub const PAGE_SIZE: usize = 4096;
+const MAX_TRANSFER_SIZE: usize = 128 * 1024;
+
+const TIMEOUT_UNIT: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static NVME_DRIVER: NvmeDriver = NvmeDriver;
+
+static DEVICE_IDS: [PciDeviceId; 1] = [PciDeviceId::class_with_interface(
+    MASS_STORAGE_CLASS,
+    NON_VOLATILE_MEMORY_SUBCLASS,
+    NVME_PROGRAMMING_INTERFACE,
+)];
+
+pub fn init() {
+    register_driver(&NVME_DRIVER);
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum NvmeError {
+    NotReady,
+    /// The controller set CSTS.CFS.
+    ControllerFatal,
+    OutOfMemory,
+    Timeout,
+    /// The status field of a failed command's completion.
+    Command(u16),
+}
+
+#[derive(Copy, Clone)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        unsafe { (self.0 + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    /// Waits for CSTS.RDY to become `ready`.
+    fn wait_ready(&self, ready: bool, timeout: Duration) -> Result<(), NvmeError> {
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let status = self.read(REGISTER_STATUS);
+
+            if status.get_bit(STATUS_FATAL) {
+                return Err(NvmeError::ControllerFatal);
+            }
+
+            if status.get_bit(STATUS_READY) == ready {
+                return Ok(());
+            }
+
+            if waited >= timeout {
+                return Err(NvmeError::NotReady);
+            }
+
+            busy_wait(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+    }
+}
+
+/// An NVMe controller with its admin queue and one I/O queue pair.
+pub struct NvmeController {
+    name: String,
+    admin: Arc<Queue>,
+    io: Arc<Queue>,
+    model: String,
+    serial: String,
+    firmware: String,
+    max_transfer_size: usize,
+    volatile_write_cache: bool,
+    supports_discard: bool,
+    namespace_count: u32,
+    _interrupts: Option<PciInterrupts>,
+}
+
+#[allow(unused)]
+impl NvmeController {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn firmware(&self) -> &str {
+        &self.firmware
+    }
+
+    pub fn admin_queue(&self) -> &Queue {
+        &self.admin
+    }
+
+    pub fn io_queue(&self) -> &Queue {
+        &self.io
+    }
+
+    /// The most data a single read or write may transfer, a multiple of the page size.
+    pub fn max_transfer_size(&self) -> usize {
+        self.max_transfer_size
+    }
+
+    /// Whether writes may sit in a cache until flushed.
+    pub fn has_volatile_write_cache(&self) -> bool {
+        self.volatile_write_cache
+    }
+
+    pub fn supports_discard(&self) -> bool {
+        self.supports_discard
+    }
+}
+
+fn identify(admin: &Queue, cns: u32, namespace: u32) -> Result<DmaBuffer, NvmeError> {
+    let data = DmaBuffer::new(IDENTIFY_SIZE).ok_or(NvmeError::OutOfMemory)?;
+
+    let result = admin.execute(Command {
+        opcode: ADMIN_IDENTIFY,
+        namespace,
+        prp1: data.physical_address().as_u64(),
+        cdw10: cns,
+        ..Default::default()
+    });
+
+    match result {
+        Ok(_) => Ok(data),
+        Err(NvmeError::Timeout) => {
+            // The controller may still write the data.
+            core::mem::forget(data);
+            Err(NvmeError::Timeout)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().into()
+}
+
+/// Tells the controller about an I/O queue pair, which interrupts with the MSI-X table entry
+/// `interrupt`, if any.
+fn create_io_queue(admin: &Queue, queue: &Queue, interrupt: Option<u16>) -> Result<(), NvmeError> {
+    let size_and_id = (queue.size() as u32 - 1) << 16 | queue.id() as u32;
+
+    let interrupts = match interrupt {
+        Some(index) => (index as u32) << 16 | QUEUE_INTERRUPTS_ENABLED,
+        None => 0,
+    };
+
+    // The completion queue has to exist before a submission queue can post to it.
+    admin.execute(Command {
+        opcode: ADMIN_CREATE_COMPLETION_QUEUE,
+        prp1: queue.completion_address().as_u64(),
+        cdw10: size_and_id,
+        cdw11: interrupts | QUEUE_PHYSICALLY_CONTIGUOUS,
+        ..Default::default()
+    })?;
+
+    admin.execute(Command {
+        opcode: ADMIN_CREATE_SUBMISSION_QUEUE,
+        prp1: queue.submission_address().as_u64(),
+        cdw10: size_and_id,
+        cdw11: (queue.id() as u32) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS,
+        ..Default::default()
+    })?;
+
+    Ok(())
+}
+
+fn failed(what: &'static str) -> impl FnOnce(NvmeError) -> PciProbeError {
+    move |e| {
+        warn!("NVMe: {} failed: {:?}", what, e);
+        PciProbeError::InitializationFailed(what)
+    }
+}
+
+/// Like [`failed`], for once the controller is enabled. It's disabled again, so it doesn't
+/// access the queues after they're freed. If it doesn't stop, they're leaked instead.
+fn failed_enabled(
+    what: &'static str,
+    registers: Registers,
+    timeout: Duration,
+    queues: Arc<Spinlock<Vec<Arc<Queue>>>>,
+) -> impl FnOnce(NvmeError) -> PciProbeError {
+    move |e| {
+        registers.write(REGISTER_CONFIGURATION, 0);
+
+        if let Err(e) = registers.wait_ready(false, timeout) {
+            warn!("NVMe: disabling the controller failed: {:?}", e);
+            core::mem::forget(queues);
+        }
+
+        failed(what)(e)
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<Arc<NvmeController>, PciProbeError> {
+    let address = device
+        .bar(0)
+        .and_then(|bar| bar.map())
+        .ok_or(PciProbeError::InitializationFailed("BAR 0 not mapped"))?;
+    let registers = Registers(address);
+
+    device.set_memory_space(true);
+    device.enable_bus_master();
+
+    let capabilities = registers.read_u64(REGISTER_CAPABILITIES);
+
+    if !capabilities.get_bit(CAPABILITY_NVM_COMMAND_SET) {
+        return Err(PciProbeError::InitializationFailed("no NVM command set"));
+    }
+
+    if capabilities.get_bits(CAPABILITY_MIN_PAGE_SIZE) != 0 {
+        return Err(PciProbeError::InitializationFailed(
+            "4 KiB pages unsupported",
+        ));
+    }
+
+    let timeout = TIMEOUT_UNIT * capabilities.get_bits(CAPABILITY_TIMEOUT).max(1) as u32;
+    let stride = 4 << capabilities.get_bits(CAPABILITY_DOORBELL_STRIDE);
+    // Zero based, so 0xFFFF means one entry more than a u16 holds.
+    let max_queue_size =
+        (capabilities.get_bits(CAPABILITY_MAX_QUEUE_ENTRIES) + 1).min(u16::MAX as u64) as u16;
+    let doorbells = address + DOORBELLS_OFFSET;
+
+    registers.write(REGISTER_CONFIGURATION, 0);
+    registers
+        .wait_ready(false, timeout)
+        .map_err(failed("disabling the controller"))?;
+
+    // The interrupt handler needs the queues, which need their vectors. Queues are added as
+    // they're created.
+    let queues: Arc<Spinlock<Vec<Arc<Queue>>>> = Arc::new(Spinlock::new(Vec::new()));
+    let handler_queues = queues.clone();
+
+    let interrupts = match device.enable_msix(
+        1 + IO_QUEUE_ID as usize,
+        apic::local_apic_id(),
+        Arc::new(move |vector| {
+            for queue in handler_queues.lock().iter() {
+                if queue.vector() == Some(vector) {
+                    queue.process_completions();
+                }
+            }
+        }),
+    ) {
+        Ok(interrupts) => Some(interrupts),
+        Err(e) => {
+            warn!(
+                "No MSI-X for NVMe controller {}, polling: {:?}",
+                device.address(),
+                e
+            );
+            None
+        }
+    };
+
+    // With fewer vectors than queues, the I/O queue shares the admin queue's.
+    let interrupt_index = |index: u16| {
+        let interrupts = interrupts.as_ref()?;
+        Some(if (index as usize) < interrupts.count() {
+            index
+        } else {
+            0
+        })
+    };
+    let vector = |index: u16| {
+        interrupts
+            .as_ref()?
+            .vector(interrupt_index(index)? as usize)
+    };
+
+    let admin = Arc::new(
+        Queue::new(
+            0,
+            ADMIN_QUEUE_SIZE.min(max_queue_size),
+            doorbells,
+            stride,
+            vector(0),
+        )
+        .map_err(failed("allocating the admin queue"))?,
+    );
+    without_interrupts(|| queues.lock().push(admin.clone()));
+
+    let admin_size = admin.size() as u32 - 1;
+    registers.write(
+        REGISTER_ADMIN_QUEUE_ATTRIBUTES,
+        admin_size << 16 | admin_size,
+    );
+    registers.write_u64(
+        REGISTER_ADMIN_SUBMISSION_QUEUE,
+        admin.submission_address().as_u64(),
+    );
+    registers.write_u64(
+        REGISTER_ADMIN_COMPLETION_QUEUE,
+        admin.completion_address().as_u64(),
+    );
+
+    let mut configuration = 0u32;
+    configuration.set_bits(
+        CONFIGURATION_SUBMISSION_ENTRY_SIZE,
+        queue::SUBMISSION_ENTRY_SIZE.trailing_zeros(),
+    );
+    configuration.set_bits(
+        CONFIGURATION_COMPLETION_ENTRY_SIZE,
+        queue::COMPLETION_ENTRY_SIZE.trailing_zeros(),
+    );
+    configuration.set_bit(CONFIGURATION_ENABLE, true);
+
+    registers.write(REGISTER_CONFIGURATION, configuration);
+
+    let failed = |what| failed_enabled(what, registers, timeout, queues.clone());
+
+    registers
+        .wait_ready(true, timeout)
+        .map_err(failed("enabling the controller"))?;
+
+    let version = registers.read(REGISTER_VERSION);
+    let data = identify(&admin, IDENTIFY_CONTROLLER, 0).map_err(failed("IDENTIFY controller"))?;
+    let data = data.as_slice();
+
+    // MDTS is a power of two in units of the minimum page size, 0 means no limit.
+    let max_data_transfer = data[77];
+    let max_transfer_size = match max_data_transfer {
+        0 => MAX_TRANSFER_SIZE,
+        exponent => MAX_TRANSFER_SIZE.min(PAGE_SIZE << exponent),
+    };
+
+    let namespace_count = u32::from_le_bytes(data[516..520].try_into().unwrap());
+    let optional_commands = u16::from_le_bytes([data[520], data[521]]);
+
+    let allocated_queues = admin
+        .execute(Command {
+            opcode: ADMIN_SET_FEATURES,
+            cdw10: FEATURE_NUMBER_OF_QUEUES,
+            // Both counts are zero based.
+            cdw11: (IO_QUEUE_ID as u32 - 1) << 16 | (IO_QUEUE_ID as u32 - 1),
+            ..Default::default()
+        })
+        .map_err(failed("requesting I/O queues"))?
+        .result;
+
+    debug!(
+        "NVMe controller {} allows {} submission and {} completion queues",
+        device.address(),
+        allocated_queues.get_bits(0..16) + 1,
+        allocated_queues.get_bits(16..32) + 1
+    );
+
+    let io = Arc::new(
+        Queue::new(
+            IO_QUEUE_ID,
+            IO_QUEUE_SIZE.min(max_queue_size),
+            doorbells,
+            stride,
+            vector(IO_QUEUE_ID),
+        )
+        .map_err(failed("allocating the I/O queue"))?,
+    );
+    without_interrupts(|| queues.lock().push(io.clone()));
+
+    create_io_queue(&admin, &io, interrupt_index(IO_QUEUE_ID))
+        .map_err(failed("creating the I/O queue"))?;
+
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    let controller = Arc::new(NvmeController {
+        name: format!("nvme{}", index),
+        admin,
+        io,
+        model: ascii(&data[24..64]),
+        serial: ascii(&data[4..24]),
+        firmware: ascii(&data[64..72]),
+        max_transfer_size,
+        volatile_write_cache: data[525].get_bit(0),
+        supports_discard: optional_commands.get_bit(2),
+        namespace_count,
+        _interrupts: interrupts,
+    });
+
+    info!(
+        "{}: {} (firmware {}, serial number {:?}), NVMe {}.{}, {} namespace(s)",
+        controller.name,
+        controller.model,
+        controller.firmware,
+        controller.serial,
+        version >> 16,
+        (version >> 8) & 0xFF,
+        controller.namespace_count
+    );
+
+    debug!(
+        "{}: transfers up to {} bytes, {} entry I/O queue, volatile write cache {}",
+        controller.name,
+        max_transfer_size,
+        controller.io.size(),
+        controller.volatile_write_cache
+    );
+
+    Ok(controller)
+}
+
+/// The IDs of the active namespaces. Controllers before NVMe 1.1 can't list them, then all
+/// possible IDs are tried.
+fn namespace_ids(controller: &NvmeController) -> Vec<u32> {
+    match identify(&controller.admin, IDENTIFY_ACTIVE_NAMESPACES, 0) {
+        Ok(list) => list
+            .as_slice()
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .take_while(|id| *id != 0)
+            .collect(),
+        Err(_) => (1..=controller.namespace_count).collect(),
+    }
+}
+
+fn probe_namespaces(controller: &Arc<NvmeController>) -> Vec<Arc<NvmeNamespace>> {
+    namespace_ids(controller)
+        .into_iter()
+        .filter_map(|id| {
+            let data = match identify(&controller.admin, IDENTIFY_NAMESPACE, id) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(
+                        "{}: IDENTIFY namespace {} failed: {:?}",
+                        controller.name, id, e
+                    );
+                    return None;
+                }
+            };
+
+            NvmeNamespace::new(controller.clone(), id, data.as_slice())
+        })
+        .map(Arc::new)
+        .collect()
+}
+
+struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &DEVICE_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), PciProbeError> {
+        let controller = probe(device)?;
+
+        for namespace in probe_namespaces(&controller) {
+            register_device(namespace);
+        }
+
+        Ok(())
+    }
+}
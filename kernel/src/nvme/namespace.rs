@@ -0,0 +1,365 @@
+use crate::block::{check_request, BlockDevice, BlockError};
+use crate::memory::dma::DmaBuffer;
+use crate::nvme::queue::Command;
+use crate::nvme::{NvmeController, NvmeError, PAGE_SIZE};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use log::{debug, warn};
+
+const COMMAND_FLUSH: u8 = 0x00;
+const COMMAND_WRITE: u8 = 0x01;
+const COMMAND_READ: u8 = 0x02;
+const COMMAND_DATASET_MANAGEMENT: u8 = 0x09;
+
+const DATASET_MANAGEMENT_DEALLOCATE: u32 = 1 << 2;
+const DATASET_RANGE_SIZE: usize = 16;
+const MAX_DATASET_RANGES: usize = 256;
+
+const IDENTIFY_SIZE_OFFSET: usize = 0;
+const IDENTIFY_FORMATTED_LBA_SIZE_OFFSET: usize = 26;
+const IDENTIFY_LBA_FORMATS_OFFSET: usize = 128;
+
+fn to_block_error(error: NvmeError) -> BlockError {
+    match error {
+        NvmeError::OutOfMemory => BlockError::OutOfMemory,
+        _ => BlockError::Io,
+    }
+}
+
+/// The data pointers of a command: the first page, then either the second page or a PRP list
+/// with all the following ones. The list has to live as long as the command.
+fn data_pointers(
+    buffer: &DmaBuffer,
+    length: usize,
+) -> Result<(u64, u64, Option<DmaBuffer>), BlockError> {
+    let address = buffer.physical_address().as_u64();
+    let pages = length.div_ceil(PAGE_SIZE);
+
+    match pages {
+        0 | 1 => Ok((address, 0, None)),
+        2 => Ok((address, address + PAGE_SIZE as u64, None)),
+        _ => {
+            // A single page of list covers 2 MiB, more than any transfer we do.
+            let list = DmaBuffer::new((pages - 1) * 8).ok_or(BlockError::OutOfMemory)?;
+
+            for page in 1..pages {
+                unsafe {
+                    list.as_mut_ptr::<u64>()
+                        .add(page - 1)
+                        .write_volatile(address + (page * PAGE_SIZE) as u64);
+                }
+            }
+
+            Ok((address, list.physical_address().as_u64(), Some(list)))
+        }
+    }
+}
+
+/// A command on the I/O queue together with the memory the controller accesses for it.
+struct Transfer {
+    command_id: u16,
+    data: DmaBuffer,
+    prp_list: Option<DmaBuffer>,
+}
+
+/// A namespace of an NVMe controller, named like `nvme0n1`.
+pub struct NvmeNamespace {
+    name: String,
+    controller: Arc<NvmeController>,
+    id: u32,
+    block_count: u64,
+    block_size: usize,
+}
+
+#[allow(unused)]
+impl NvmeNamespace {
+    /// Creates the namespace from its IDENTIFY data, `None` if it's unusable.
+    pub fn new(controller: Arc<NvmeController>, id: u32, identify: &[u8]) -> Option<Self> {
+        let block_count = u64::from_le_bytes(
+            identify[IDENTIFY_SIZE_OFFSET..IDENTIFY_SIZE_OFFSET + 8]
+                .try_into()
+                .unwrap(),
+        );
+
+        let format = identify[IDENTIFY_FORMATTED_LBA_SIZE_OFFSET].get_bits(0..4) as usize;
+        let lba_format = &identify[IDENTIFY_LBA_FORMATS_OFFSET + format * 4..][..4];
+        let metadata_size = u16::from_le_bytes([lba_format[0], lba_format[1]]);
+        let block_shift = lba_format[2];
+
+        let name = format!("{}n{}", controller.name(), id);
+
+        if block_count == 0 {
+            debug!("{}: namespace is empty", name);
+            return None;
+        }
+
+        // Blocks have to fit into a page for the data pointers to work out, and metadata
+        // interleaved with the data isn't something we deal with.
+        if !(9..=12).contains(&block_shift) || metadata_size != 0 {
+            warn!(
+                "{}: unsupported format, 2^{} byte blocks with {} bytes of metadata",
+                name, block_shift, metadata_size
+            );
+            return None;
+        }
+
+        Some(Self {
+            name,
+            controller,
+            id,
+            block_count,
+            block_size: 1 << block_shift,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn controller(&self) -> &Arc<NvmeController> {
+        &self.controller
+    }
+
+    fn max_transfer_size(&self) -> usize {
+        (self.controller.max_transfer_size() / self.block_size).max(1) * self.block_size
+    }
+
+    fn submit(
+        &self,
+        opcode: u8,
+        start: u64,
+        data: DmaBuffer,
+        length: usize,
+    ) -> Result<Transfer, BlockError> {
+        let (prp1, prp2, prp_list) = data_pointers(&data, length)?;
+        let blocks = (length / self.block_size) as u32;
+
+        let command_id = self.controller.io_queue().submit(Command {
+            opcode,
+            namespace: self.id,
+            prp1,
+            prp2,
+            cdw10: start as u32,
+            cdw11: (start >> 32) as u32,
+            // Zero based.
+            cdw12: blocks - 1,
+            ..Default::default()
+        });
+
+        Ok(Transfer {
+            command_id,
+            data,
+            prp_list,
+        })
+    }
+
+    /// Waits for a transfer and returns its data buffer.
+    fn wait(&self, transfer: Transfer) -> Result<DmaBuffer, BlockError> {
+        match self.controller.io_queue().wait(transfer.command_id) {
+            Ok(_) => Ok(transfer.data),
+            Err(NvmeError::Timeout) => {
+                // The controller may still access the memory, so it can't be reused.
+                warn!("{}: command timed out", self.name);
+                core::mem::forget(transfer.data);
+                core::mem::forget(transfer.prp_list);
+
+                Err(BlockError::Io)
+            }
+            Err(e) => {
+                warn!("{}: command failed: {:?}", self.name, e);
+                Err(to_block_error(e))
+            }
+        }
+    }
+
+    /// Splits a request into transfers, submits as many as the queue takes so the controller
+    /// can work on them at once and calls `f` with each one's buffer once it's done. `fill`
+    /// prepares the buffers for writes.
+    fn transfer(
+        &self,
+        opcode: u8,
+        start: u64,
+        length: usize,
+        mut fill: impl FnMut(usize, &mut DmaBuffer),
+        mut f: impl FnMut(usize, &DmaBuffer),
+    ) -> Result<(), BlockError> {
+        let max_transfer_size = self.max_transfer_size();
+        let blocks_per_transfer = (max_transfer_size / self.block_size) as u64;
+
+        // Beyond that, submitting waits for a free entry while nobody takes the completions.
+        let max_outstanding = (self.controller.io_queue().size() as usize - 1).max(1);
+
+        let mut transfers = VecDeque::new();
+        let mut result = Ok(());
+
+        for (index, offset) in (0..length).step_by(max_transfer_size).enumerate() {
+            let chunk_length = (length - offset).min(max_transfer_size);
+
+            if transfers.len() >= max_outstanding {
+                let (offset, transfer) = transfers.pop_front().unwrap();
+
+                match self.wait(transfer) {
+                    Ok(data) => f(offset, &data),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+
+            let Some(mut data) = DmaBuffer::new(chunk_length) else {
+                result = Err(BlockError::OutOfMemory);
+                break;
+            };
+
+            fill(offset, &mut data);
+
+            let block = start + index as u64 * blocks_per_transfer;
+
+            match self.submit(opcode, block, data, chunk_length) {
+                Ok(transfer) => transfers.push_back((offset, transfer)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // Everything submitted has to be waited for, even after an error, the controller
+        // might still be writing into the buffers.
+        for (offset, transfer) in transfers {
+            match self.wait(transfer) {
+                Ok(data) => f(offset, &data),
+                Err(e) => result = result.and(Err(e)),
+            }
+        }
+
+        result
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let length = buffer.len();
+
+        self.transfer(
+            COMMAND_READ,
+            start,
+            length,
+            |_, _| {},
+            |offset, data| {
+                let chunk = &mut buffer[offset..];
+                let chunk_length = chunk.len().min(self.max_transfer_size());
+
+                chunk[..chunk_length].copy_from_slice(&data.as_slice()[..chunk_length]);
+            },
+        )
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        self.transfer(
+            COMMAND_WRITE,
+            start,
+            buffer.len(),
+            |offset, data| {
+                let chunk = &buffer[offset..];
+                let chunk_length = chunk.len().min(self.max_transfer_size());
+
+                data.as_mut_slice()[..chunk_length].copy_from_slice(&chunk[..chunk_length]);
+            },
+            |_, _| {},
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Without a volatile write cache everything written is already stable.
+        if !self.controller.has_volatile_write_cache() {
+            return Ok(());
+        }
+
+        self.controller
+            .io_queue()
+            .execute(Command {
+                opcode: COMMAND_FLUSH,
+                namespace: self.id,
+                ..Default::default()
+            })
+            .map(|_| ())
+            .map_err(to_block_error)
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        if !self.controller.supports_discard() {
+            return Err(BlockError::Unsupported);
+        }
+
+        let length = count
+            .checked_mul(self.block_size as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        check_request(self, start, length as usize)?;
+
+        // Ranges are at most u32::MAX blocks long, a command takes up to 256 of them.
+        let ranges = (0..count)
+            .step_by(u32::MAX as usize)
+            .map(|offset| (start + offset, (count - offset).min(u32::MAX as u64) as u32))
+            .collect::<Vec<_>>();
+
+        for ranges in ranges.chunks(MAX_DATASET_RANGES) {
+            let data =
+                DmaBuffer::new(ranges.len() * DATASET_RANGE_SIZE).ok_or(BlockError::OutOfMemory)?;
+
+            for (index, (block, blocks)) in ranges.iter().enumerate() {
+                unsafe {
+                    let range = data.as_mut_ptr::<u8>().add(index * DATASET_RANGE_SIZE);
+                    (range as *mut u32).write_volatile(0);
+                    (range.add(4) as *mut u32).write_volatile(*blocks);
+                    (range.add(8) as *mut u64).write_volatile(*block);
+                }
+            }
+
+            let result = self.controller.io_queue().execute(Command {
+                opcode: COMMAND_DATASET_MANAGEMENT,
+                namespace: self.id,
+                prp1: data.physical_address().as_u64(),
+                // Zero based.
+                cdw10: ranges.len() as u32 - 1,
+                cdw11: DATASET_MANAGEMENT_DEALLOCATE,
+                ..Default::default()
+            });
+
+            match result {
+                Ok(_) => {}
+                Err(NvmeError::Timeout) => {
+                    // The controller may still read the ranges.
+                    warn!("{}: discard timed out", self.name);
+                    core::mem::forget(data);
+
+                    return Err(BlockError::Io);
+                }
+                Err(e) => return Err(to_block_error(e)),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+use crate::clocksource::Instant;
+use crate::memory::dma::DmaBuffer;
+use crate::nvme::NvmeError;
+use alloc::collections::{BTreeMap, BTreeSet};
+use bit_field::BitField;
+use core::time::Duration;
+use log::debug;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SUBMISSION_ENTRY_SIZE: usize = 64;
+pub const COMPLETION_ENTRY_SIZE: usize = 16;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A submission queue entry. Data is always described by PRPs.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Command {
+    pub opcode: u8,
+    pub namespace: u32,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Completion {
+    /// Command specific, DW0 of the completion queue entry.
+    pub result: u32,
+    /// Status code and status code type, without the phase bit.
+    pub status: u16,
+}
+
+impl Completion {
+    fn into_result(self) -> Result<Completion, NvmeError> {
+        // Status code type and status code both zero mean success.
+        if self.status.get_bits(0..11) == 0 {
+            Ok(self)
+        } else {
+            Err(NvmeError::Command(self.status))
+        }
+    }
+}
+
+struct QueuePair {
+    submission: DmaBuffer,
+    completion: DmaBuffer,
+    size: u16,
+    submission_tail: u16,
+    // As last reported by the controller in a completion.
+    submission_head: u16,
+    completion_head: u16,
+    // The phase bit new completion entries have, it flips every time the queue wraps.
+    phase: bool,
+    submission_doorbell: VirtAddr,
+    completion_doorbell: VirtAddr,
+    next_command_id: u16,
+    // `None` until the command completed.
+    in_flight: BTreeMap<u16, Option<Completion>>,
+    // Commands nobody waits for anymore, their IDs stay reserved until they complete.
+    timed_out: BTreeSet<u16>,
+}
+
+impl QueuePair {
+    fn is_full(&self) -> bool {
+        // Completions waiting to be taken don't count, they're off the completion queue
+        // already, and whoever takes them may be waiting for this submission.
+        let outstanding = self
+            .in_flight
+            .values()
+            .filter(|completion| completion.is_none())
+            .count();
+
+        (self.submission_tail + 1) % self.size == self.submission_head
+            || outstanding + self.timed_out.len() >= self.size as usize - 1
+    }
+
+    fn submit(&mut self, command: Command) -> Option<u16> {
+        if self.is_full() {
+            return None;
+        }
+
+        let mut command_id = self.next_command_id;
+        while self.in_flight.contains_key(&command_id) || self.timed_out.contains(&command_id) {
+            command_id = command_id.wrapping_add(1);
+        }
+        self.next_command_id = command_id.wrapping_add(1);
+
+        unsafe {
+            let entry = self
+                .submission
+                .as_mut_ptr::<u32>()
+                .add(self.submission_tail as usize * SUBMISSION_ENTRY_SIZE / 4);
+
+            let dwords = [
+                command.opcode as u32 | (command_id as u32) << 16,
+                command.namespace,
+                0,
+                0,
+                0,
+                0,
+                command.prp1 as u32,
+                (command.prp1 >> 32) as u32,
+                command.prp2 as u32,
+                (command.prp2 >> 32) as u32,
+                command.cdw10,
+                command.cdw11,
+                command.cdw12,
+                command.cdw13,
+                command.cdw14,
+                command.cdw15,
+            ];
+
+            for (index, dword) in dwords.iter().enumerate() {
+                entry.add(index).write_volatile(*dword);
+            }
+        }
+
+        self.in_flight.insert(command_id, None);
+        self.submission_tail = (self.submission_tail + 1) % self.size;
+
+        unsafe {
+            self.submission_doorbell
+                .as_mut_ptr::<u32>()
+                .write_volatile(self.submission_tail as u32);
+        }
+
+        Some(command_id)
+    }
+
+    /// Takes new entries off the completion queue and records them for their commands.
+    fn process_completions(&mut self) {
+        let mut processed = false;
+
+        loop {
+            let entry = unsafe {
+                self.completion
+                    .as_ptr::<u32>()
+                    .add(self.completion_head as usize * COMPLETION_ENTRY_SIZE / 4)
+            };
+
+            let (result, dword2, dword3) = unsafe {
+                (
+                    entry.read_volatile(),
+                    entry.add(2).read_volatile(),
+                    entry.add(3).read_volatile(),
+                )
+            };
+
+            if dword3.get_bit(16) != self.phase {
+                break;
+            }
+
+            let command_id = dword3.get_bits(0..16) as u16;
+            self.submission_head = dword2.get_bits(0..16) as u16;
+
+            match self.in_flight.get_mut(&command_id) {
+                Some(completion) => {
+                    *completion = Some(Completion {
+                        result,
+                        status: dword3.get_bits(17..32) as u16,
+                    })
+                }
+                None if self.timed_out.remove(&command_id) => {}
+                None => debug!("NVMe completion for unknown command {}", command_id),
+            }
+
+            self.completion_head = (self.completion_head + 1) % self.size;
+            if self.completion_head == 0 {
+                self.phase = !self.phase;
+            }
+
+            processed = true;
+        }
+
+        if processed {
+            unsafe {
+                self.completion_doorbell
+                    .as_mut_ptr::<u32>()
+                    .write_volatile(self.completion_head as u32);
+            }
+        }
+    }
+
+    fn take_completion(&mut self, command_id: u16) -> Option<Completion> {
+        let completion = (*self.in_flight.get(&command_id)?)?;
+        self.in_flight.remove(&command_id);
+
+        Some(completion)
+    }
+}
+
+/// A submission queue and the completion queue it posts to, with the same ID and size. Several
+/// commands can be in flight at once, see [`Queue::submit`].
+pub struct Queue {
+    id: u16,
+    size: u16,
+    vector: Option<u8>,
+    submission_address: PhysAddr,
+    completion_address: PhysAddr,
+    pair: Spinlock<QueuePair>,
+}
+
+#[allow(unused)]
+impl Queue {
+    /// Allocates the queues' memory. `doorbells` is the first doorbell register, `stride` the
+    /// distance between two. The controller still has to be told about the queues.
+    pub fn new(
+        id: u16,
+        size: u16,
+        doorbells: VirtAddr,
+        stride: u64,
+        vector: Option<u8>,
+    ) -> Result<Self, NvmeError> {
+        let submission =
+            DmaBuffer::new(size as usize * SUBMISSION_ENTRY_SIZE).ok_or(NvmeError::OutOfMemory)?;
+        let completion =
+            DmaBuffer::new(size as usize * COMPLETION_ENTRY_SIZE).ok_or(NvmeError::OutOfMemory)?;
+
+        Ok(Self {
+            id,
+            size,
+            vector,
+            submission_address: submission.physical_address(),
+            completion_address: completion.physical_address(),
+            pair: Spinlock::new(QueuePair {
+                submission,
+                completion,
+                size,
+                submission_tail: 0,
+                submission_head: 0,
+                completion_head: 0,
+                phase: true,
+                submission_doorbell: doorbells + 2 * id as u64 * stride,
+                completion_doorbell: doorbells + (2 * id as u64 + 1) * stride,
+                next_command_id: 0,
+                in_flight: BTreeMap::new(),
+                timed_out: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The interrupt vector completions are signaled on, `None` if the queue is polled.
+    pub fn vector(&self) -> Option<u8> {
+        self.vector
+    }
+
+    pub fn submission_address(&self) -> PhysAddr {
+        self.submission_address
+    }
+
+    pub fn completion_address(&self) -> PhysAddr {
+        self.completion_address
+    }
+
+    pub fn process_completions(&self) {
+        interrupts::without_interrupts(|| self.pair.lock().process_completions());
+    }
+
+    /// Collects completions until `f` returns something. Halts in between if the completion
+    /// interrupt will wake us up, polls otherwise. Gives up at `deadline`.
+    fn wait_until<R>(
+        &self,
+        deadline: Option<Instant>,
+        mut f: impl FnMut(&mut QueuePair) -> Option<R>,
+    ) -> Option<R> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+
+        loop {
+            // Checking and halting with interrupts off, so the completion can't slip in
+            // between.
+            interrupts::disable();
+
+            let result = {
+                let mut pair = self.pair.lock();
+                pair.process_completions();
+                f(&mut pair)
+            };
+
+            let timed_out = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+
+            if result.is_some() || timed_out {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                return result;
+            }
+
+            if interrupts_were_enabled && self.vector.is_some() {
+                interrupts::enable_and_hlt();
+            } else {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Hands a command to the controller, waiting for a free entry if the queue is full, and
+    /// returns its command ID for [`Queue::wait`].
+    pub fn submit(&self, command: Command) -> u16 {
+        self.wait_until(None, |pair| pair.submit(command))
+            .expect("submitting without a deadline failed")
+    }
+
+    pub fn wait(&self, command_id: u16) -> Result<Completion, NvmeError> {
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+
+        match self.wait_until(Some(deadline), |pair| pair.take_completion(command_id)) {
+            Some(completion) => completion.into_result(),
+            None => {
+                interrupts::without_interrupts(|| {
+                    let mut pair = self.pair.lock();
+                    pair.in_flight.remove(&command_id);
+                    pair.timed_out.insert(command_id);
+                });
+
+                Err(NvmeError::Timeout)
+            }
+        }
+    }
+
+    pub fn execute(&self, command: Command) -> Result<Completion, NvmeError> {
+        let command_id = self.submit(command);
+
+        self.wait(command_id)
+    }
+}
@@ -0,0 +1,415 @@
+use crate::apic;
+use crate::pci_express::device::{PciAddress, PciDevice};
+use crate::pci_express::device_capabilities::{
+    PciAdvancedErrorReportingCapability, PciDeviceCapability, PciExpressDeviceType,
+};
+use crate::pci_express::interrupts::PciInterrupts;
+use crate::pci_express::{PCIe, PciTopologyNode};
+use crate::ring_buffer::RingBuffer;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::fmt::{Display, Formatter};
+use log::{debug, error, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// Correctable, non-fatal, fatal and unsupported request reporting in the Device Control
+// register of the PCI Express capability.
+const DEVICE_CONTROL_ERROR_REPORTING: u16 = 0b1111;
+
+// Correctable, non-fatal and fatal error reporting in the Root Error Command register.
+const ROOT_ERROR_COMMAND_INTERRUPTS: u32 = 0b111;
+
+const ROOT_ERROR_STATUS_CORRECTABLE_RECEIVED: usize = 0;
+const ROOT_ERROR_STATUS_UNCORRECTABLE_RECEIVED: usize = 2;
+const ROOT_ERROR_STATUS_BITS: u32 = 0x7F;
+
+const PENDING_ERRORS_SIZE: usize = 32;
+
+const UNCORRECTABLE_ERRORS: &[(usize, &str)] = &[
+    (4, "Data Link Protocol Error"),
+    (5, "Surprise Down"),
+    (12, "Poisoned TLP"),
+    (13, "Flow Control Protocol Error"),
+    (14, "Completion Timeout"),
+    (15, "Completer Abort"),
+    (16, "Unexpected Completion"),
+    (17, "Receiver Overflow"),
+    (18, "Malformed TLP"),
+    (19, "ECRC Error"),
+    (20, "Unsupported Request"),
+    (21, "ACS Violation"),
+    (22, "Uncorrectable Internal Error"),
+    (23, "MC Blocked TLP"),
+    (24, "AtomicOp Egress Blocked"),
+    (25, "TLP Prefix Blocked"),
+    (26, "Poisoned TLP Egress Blocked"),
+];
+
+const CORRECTABLE_ERRORS: &[(usize, &str)] = &[
+    (0, "Receiver Error"),
+    (6, "Bad TLP"),
+    (7, "Bad DLLP"),
+    (8, "REPLAY_NUM Rollover"),
+    (12, "Replay Timer Timeout"),
+    (13, "Advisory Non-Fatal Error"),
+    (14, "Corrected Internal Error"),
+    (15, "Header Log Overflow"),
+];
+
+// The interrupts of the root ports, kept so they stay allocated.
+static ERROR_INTERRUPTS: Spinlock<Vec<PciInterrupts>> = Spinlock::new(Vec::new());
+
+/// Formats the names of the errors set in an AER status register.
+struct ErrorNames(u32, &'static [(usize, &'static str)]);
+
+impl Display for ErrorNames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut separator = "";
+
+        for bit in (0..32).filter(|bit| self.0.get_bit(*bit)) {
+            match self.1.iter().find(|(error_bit, _)| *error_bit == bit) {
+                Some((_, name)) => write!(f, "{}{}", separator, name)?,
+                None => write!(f, "{}Unknown error (bit {})", separator, bit)?,
+            }
+
+            separator = ", ";
+        }
+
+        Ok(())
+    }
+}
+
+fn requester_address(segment_group: u16, requester_id: u16) -> PciAddress {
+    PciAddress::new(
+        segment_group,
+        requester_id.get_bits(8..16) as u8,
+        requester_id.get_bits(3..8) as u8,
+        requester_id.get_bits(0..3) as u8,
+    )
+}
+
+/// The header of the TLP that caused the first uncorrectable error, as captured in the
+/// header log.
+struct TlpHeader([u32; 4], u16);
+
+impl Display for TlpHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let [dword0, dword1, dword2, dword3] = self.0;
+        let segment_group = self.1;
+
+        write!(
+            f,
+            "{:08x} {:08x} {:08x} {:08x}",
+            dword0, dword1, dword2, dword3
+        )?;
+
+        let format = dword0.get_bits(29..32);
+        let tlp_type = dword0.get_bits(24..29);
+        let has_data = format.get_bit(1);
+        let is_64_bit = format.get_bit(0);
+        let requester = requester_address(segment_group, dword1.get_bits(16..32) as u16);
+
+        match tlp_type {
+            0b00000 | 0b00001 => {
+                let address = if is_64_bit {
+                    (dword2 as u64) << 32 | (dword3 & !0b11) as u64
+                } else {
+                    (dword2 & !0b11) as u64
+                };
+
+                write!(
+                    f,
+                    " ({} from {} to {:#x})",
+                    if has_data { "MemWr" } else { "MemRd" },
+                    requester,
+                    address
+                )
+            }
+            0b00010 => write!(
+                f,
+                " ({} from {} to port {:#x})",
+                if has_data { "IOWr" } else { "IORd" },
+                requester,
+                dword2 & !0b11
+            ),
+            0b00100 | 0b00101 => write!(
+                f,
+                " ({}{} from {} to {})",
+                if has_data { "CfgWr" } else { "CfgRd" },
+                tlp_type.get_bit(0) as u8,
+                requester,
+                requester_address(segment_group, dword2.get_bits(16..32) as u16)
+            ),
+            0b01010 | 0b01011 => write!(
+                f,
+                " ({} from {} to {})",
+                if has_data { "CplD" } else { "Cpl" },
+                requester,
+                requester_address(segment_group, dword2.get_bits(16..32) as u16)
+            ),
+            _ if tlp_type.get_bits(3..5) == 0b10 => write!(f, " (Msg from {})", requester),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PciDevice {
+    fn aer_capability(&self) -> Option<PciAdvancedErrorReportingCapability> {
+        self.capabilities().find_map(|capability| match capability {
+            PciDeviceCapability::AdvancedErrorReporting(capability) => Some(capability),
+            _ => None,
+        })
+    }
+
+    fn collects_errors(&self) -> bool {
+        self.pci_express_capability().map_or(false, |capability| {
+            matches!(
+                capability.device_type(),
+                PciExpressDeviceType::RootPort | PciExpressDeviceType::RootComplexEventCollector
+            )
+        })
+    }
+
+    /// Makes the function send error messages upstream, after clearing whatever was logged
+    /// before, e.g. unsupported requests caused by probing for devices.
+    fn enable_error_reporting(&self) {
+        let Some(pci_express) = self.pci_express_capability() else {
+            return;
+        };
+
+        if let Some(aer) = self.aer_capability() {
+            aer.clear_uncorrectable_error_status(aer.uncorrectable_error_status());
+            aer.clear_correctable_error_status(aer.correctable_error_status());
+        }
+
+        pci_express
+            .set_device_control(pci_express.device_control() | DEVICE_CONTROL_ERROR_REPORTING);
+    }
+
+    /// Reads and clears the errors recorded in the function's AER capability, `None` if there
+    /// were none.
+    fn collect_errors(&self) -> Option<ErrorRecord> {
+        let aer = self.aer_capability()?;
+
+        let correctable = aer.correctable_error_status();
+        let uncorrectable = aer.uncorrectable_error_status();
+
+        if correctable == 0 && uncorrectable == 0 {
+            return None;
+        }
+
+        let severity = aer.uncorrectable_error_severity();
+        let first_error = aer.first_error_pointer() as usize;
+        let header_log = aer.header_log();
+
+        let first_error = (uncorrectable.get_bit(first_error)
+            && header_log.iter().any(|dword| *dword != 0))
+        .then_some((first_error, header_log));
+
+        aer.clear_correctable_error_status(correctable);
+        aer.clear_uncorrectable_error_status(uncorrectable);
+
+        Some(ErrorRecord::Errors {
+            address: self.address(),
+            correctable,
+            fatal: uncorrectable & severity,
+            non_fatal: uncorrectable & !severity,
+            first_error,
+        })
+    }
+
+    /// Logs and clears the errors recorded in the function's AER capability. Returns whether
+    /// there were any.
+    #[allow(unused)]
+    pub fn report_errors(&self) -> bool {
+        match self.collect_errors() {
+            Some(record) => {
+                record.log();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// What an error interrupt found, logged later by [`log_pending_errors`]. Logging from the
+/// interrupt could deadlock on whatever the interrupted code was logging to.
+#[derive(Copy, Clone)]
+enum ErrorRecord {
+    Errors {
+        address: PciAddress,
+        correctable: u32,
+        fatal: u32,
+        non_fatal: u32,
+        // The first uncorrectable error and the header of the TLP that caused it.
+        first_error: Option<(usize, [u32; 4])>,
+    },
+    /// A root port received an error message from a function that had nothing logged.
+    NothingLogged {
+        root_port: PciAddress,
+        source: PciAddress,
+    },
+}
+
+impl ErrorRecord {
+    fn log(&self) {
+        match *self {
+            ErrorRecord::Errors {
+                address,
+                correctable,
+                fatal,
+                non_fatal,
+                first_error,
+            } => {
+                if correctable != 0 {
+                    warn!(
+                        "{} reported correctable errors: {}",
+                        address,
+                        ErrorNames(correctable, CORRECTABLE_ERRORS)
+                    );
+                }
+
+                if fatal != 0 {
+                    error!(
+                        "{} reported fatal errors: {}",
+                        address,
+                        ErrorNames(fatal, UNCORRECTABLE_ERRORS)
+                    );
+                }
+
+                if non_fatal != 0 {
+                    warn!(
+                        "{} reported non-fatal errors: {}",
+                        address,
+                        ErrorNames(non_fatal, UNCORRECTABLE_ERRORS)
+                    );
+                }
+
+                if let Some((first_error, header_log)) = first_error {
+                    warn!(
+                        "  first error: {}, TLP header {}",
+                        ErrorNames(1 << first_error, UNCORRECTABLE_ERRORS),
+                        TlpHeader(header_log, address.segment_group)
+                    );
+                }
+            }
+            ErrorRecord::NothingLogged { root_port, source } => warn!(
+                "{} received an error message from {}, which has no errors logged",
+                root_port, source
+            ),
+        }
+    }
+}
+
+// Filled by the error interrupts. If they come faster than they're logged, the oldest are lost.
+static PENDING_ERRORS: Spinlock<RingBuffer<ErrorRecord, PENDING_ERRORS_SIZE>> =
+    Spinlock::new(RingBuffer::new());
+
+/// Logs the errors the error interrupts collected since the last call. Called outside of
+/// interrupt handlers, like from the idle loop.
+pub fn log_pending_errors() {
+    while let Some(record) = without_interrupts(|| PENDING_ERRORS.lock().pop()) {
+        record.log();
+    }
+}
+
+/// Handles the error interrupt of a root port. `devices` are the functions below it, one of
+/// which sent the error message.
+fn handle_error_interrupt(root_port: &PciDevice, devices: &[PciDevice]) {
+    let Some(aer) = root_port.aer_capability() else {
+        return;
+    };
+
+    let status = aer.root_error_status() & ROOT_ERROR_STATUS_BITS;
+
+    if status == 0 {
+        return;
+    }
+
+    let (correctable_source, uncorrectable_source) = aer.error_source_identification();
+    let segment_group = root_port.address().segment_group;
+
+    let sources = [
+        (ROOT_ERROR_STATUS_CORRECTABLE_RECEIVED, correctable_source),
+        (
+            ROOT_ERROR_STATUS_UNCORRECTABLE_RECEIVED,
+            uncorrectable_source,
+        ),
+    ];
+
+    for (_, source) in sources.iter().filter(|(bit, _)| status.get_bit(*bit)) {
+        let address = requester_address(segment_group, *source);
+
+        let record = devices
+            .iter()
+            .find(|device| device.address() == address)
+            .and_then(|device| device.collect_errors())
+            .unwrap_or(ErrorRecord::NothingLogged {
+                root_port: root_port.address(),
+                source: address,
+            });
+
+        PENDING_ERRORS.lock().push_overwrite(record);
+    }
+
+    aer.clear_root_error_status(status);
+}
+
+impl PCIe {
+    /// Turns on error reporting for every PCI Express function and routes the error messages
+    /// collected by root ports to an interrupt that logs them.
+    pub fn enable_error_reporting(&self) {
+        for device in self.devices() {
+            device.enable_error_reporting();
+        }
+
+        for node in &self.topology {
+            enable_error_interrupts(node);
+        }
+    }
+}
+
+fn enable_error_interrupts(node: &PciTopologyNode) {
+    let root_port = &node.device;
+
+    if !root_port.collects_errors() || root_port.aer_capability().is_none() {
+        for child in &node.children {
+            enable_error_interrupts(child);
+        }
+
+        return;
+    }
+
+    let mut devices = vec![];
+    node.visit(0, &mut |device, _| devices.push(device.clone()));
+
+    let handler_root_port = root_port.clone();
+    let handler = Arc::new(move |_| handle_error_interrupt(&handler_root_port, &devices));
+
+    match root_port.enable_interrupts(1, apic::local_apic_id(), handler) {
+        Ok(interrupts) => {
+            let aer = root_port
+                .aer_capability()
+                .expect("error collectors have an AER capability");
+
+            aer.clear_root_error_status(aer.root_error_status());
+            aer.set_root_error_command(aer.root_error_command() | ROOT_ERROR_COMMAND_INTERRUPTS);
+
+            info!(
+                "Enabled error reporting for {} on vector {}",
+                root_port.address(),
+                interrupts.vector(0).unwrap_or_default()
+            );
+
+            ERROR_INTERRUPTS.lock().push(interrupts);
+        }
+        Err(e) => debug!(
+            "Couldn't enable error interrupts for {}: {:?}",
+            root_port.address(),
+            e
+        ),
+    }
+}
@@ -0,0 +1,76 @@
+use crate::memory::mmio::map_mmio_region;
+use bit_field::BitField;
+use x86_64::VirtAddr;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum PciBar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+#[allow(unused)]
+impl PciBar {
+    /// Decodes a BAR from its raw value(s) and the value(s) read back after writing all ones
+    /// to it.
+    pub fn decode(raw: u32, raw_upper: Option<u32>, sized: u32, sized_upper: Option<u32>) -> Self {
+        if raw.get_bit(0) {
+            let mask = sized & !0b11;
+
+            return Self::Io {
+                port: (raw & !0b11) as u16,
+                size: (!mask).wrapping_add(1) & 0xFFFF,
+            };
+        }
+
+        let is_64_bit = raw.get_bits(1..3) == 0b10;
+        let prefetchable = raw.get_bit(3);
+
+        let (address, mask) = match (is_64_bit, raw_upper, sized_upper) {
+            (true, Some(raw_upper), Some(sized_upper)) => (
+                ((raw_upper as u64) << 32) | (raw & !0b1111) as u64,
+                ((sized_upper as u64) << 32) | (sized & !0b1111) as u64,
+            ),
+            _ => (
+                (raw & !0b1111) as u64,
+                0xFFFF_FFFF_0000_0000 | (sized & !0b1111) as u64,
+            ),
+        };
+
+        Self::Memory {
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable,
+            is_64_bit,
+        }
+    }
+
+    pub fn is_64_bit(&self) -> bool {
+        matches!(
+            self,
+            Self::Memory {
+                is_64_bit: true,
+                ..
+            }
+        )
+    }
+
+    /// Maps a memory BAR and returns its virtual address, or `None` for I/O BARs and BARs that
+    /// haven't been assigned an address.
+    pub fn map(&self) -> Option<VirtAddr> {
+        match *self {
+            Self::Memory { address, size, .. } if address != 0 && size != 0 => {
+                Some(map_mmio_region(address, size as usize))
+            }
+            _ => None,
+        }
+    }
+}
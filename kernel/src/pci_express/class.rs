@@ -0,0 +1,169 @@
+use crate::pci_express::ids;
+use core::fmt::{Debug, Display, Formatter};
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciBaseClass {
+    Unclassified,
+    MassStorageController,
+    NetworkController,
+    DisplayController,
+    MultimediaController,
+    MemoryController,
+    Bridge,
+    CommunicationController,
+    GenericSystemPeripheral,
+    InputDeviceController,
+    DockingStation,
+    Processor,
+    SerialBusController,
+    WirelessController,
+    IntelligentController,
+    SatelliteCommunicationsController,
+    EncryptionController,
+    SignalProcessingController,
+    ProcessingAccelerator,
+    NonEssentialInstrumentation,
+    Coprocessor,
+    Unassigned,
+    Reserved(u8),
+}
+
+impl From<u8> for PciBaseClass {
+    fn from(class: u8) -> Self {
+        match class {
+            0x00 => Self::Unclassified,
+            0x01 => Self::MassStorageController,
+            0x02 => Self::NetworkController,
+            0x03 => Self::DisplayController,
+            0x04 => Self::MultimediaController,
+            0x05 => Self::MemoryController,
+            0x06 => Self::Bridge,
+            0x07 => Self::CommunicationController,
+            0x08 => Self::GenericSystemPeripheral,
+            0x09 => Self::InputDeviceController,
+            0x0A => Self::DockingStation,
+            0x0B => Self::Processor,
+            0x0C => Self::SerialBusController,
+            0x0D => Self::WirelessController,
+            0x0E => Self::IntelligentController,
+            0x0F => Self::SatelliteCommunicationsController,
+            0x10 => Self::EncryptionController,
+            0x11 => Self::SignalProcessingController,
+            0x12 => Self::ProcessingAccelerator,
+            0x13 => Self::NonEssentialInstrumentation,
+            0x40 => Self::Coprocessor,
+            0xFF => Self::Unassigned,
+            other => Self::Reserved(other),
+        }
+    }
+}
+
+/// The subclasses the kernel cares about. Everything else is still named through the class
+/// database, see [`PciClass::subclass_name`].
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciDeviceKind {
+    IdeController,
+    SataController,
+    NvmeController,
+    EthernetController,
+    VgaController,
+    HostBridge,
+    IsaBridge,
+    PciToPciBridge,
+    SerialController,
+    UsbController(UsbControllerKind),
+    SmBusController,
+    Other,
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UsbControllerKind {
+    Uhci,
+    Ohci,
+    Ehci,
+    Xhci,
+    Other(u8),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct PciClass {
+    pub class: u8,
+    pub subclass: u8,
+    pub programming_interface: u8,
+}
+
+#[allow(unused)]
+impl PciClass {
+    pub fn new(class: u8, subclass: u8, programming_interface: u8) -> Self {
+        Self {
+            class,
+            subclass,
+            programming_interface,
+        }
+    }
+
+    pub fn base_class(&self) -> PciBaseClass {
+        PciBaseClass::from(self.class)
+    }
+
+    pub fn kind(&self) -> PciDeviceKind {
+        match (self.class, self.subclass, self.programming_interface) {
+            (0x01, 0x01, _) => PciDeviceKind::IdeController,
+            (0x01, 0x06, _) => PciDeviceKind::SataController,
+            (0x01, 0x08, 0x02) => PciDeviceKind::NvmeController,
+            (0x02, 0x00, _) => PciDeviceKind::EthernetController,
+            (0x03, 0x00, _) => PciDeviceKind::VgaController,
+            (0x06, 0x00, _) => PciDeviceKind::HostBridge,
+            (0x06, 0x01, _) => PciDeviceKind::IsaBridge,
+            (0x06, 0x04, _) | (0x06, 0x09, _) => PciDeviceKind::PciToPciBridge,
+            (0x07, 0x00, _) => PciDeviceKind::SerialController,
+            (0x0C, 0x03, programming_interface) => {
+                PciDeviceKind::UsbController(match programming_interface {
+                    0x00 => UsbControllerKind::Uhci,
+                    0x10 => UsbControllerKind::Ohci,
+                    0x20 => UsbControllerKind::Ehci,
+                    0x30 => UsbControllerKind::Xhci,
+                    other => UsbControllerKind::Other(other),
+                })
+            }
+            (0x0C, 0x05, _) => PciDeviceKind::SmBusController,
+            _ => PciDeviceKind::Other,
+        }
+    }
+
+    pub fn subclass_name(&self) -> Option<&'static str> {
+        ids::subclass_name(self.class, self.subclass)
+    }
+
+    pub fn programming_interface_name(&self) -> Option<&'static str> {
+        ids::programming_interface_name(self.class, self.subclass, self.programming_interface)
+    }
+}
+
+impl Debug for PciClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}",
+            self.class, self.subclass, self.programming_interface
+        )
+    }
+}
+
+impl Display for PciClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.subclass_name().or_else(|| ids::class_name(self.class)) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{:?}", self.base_class())?,
+        }
+
+        if let Some(name) = self.programming_interface_name() {
+            write!(f, " ({})", name)?;
+        }
+
+        Ok(())
+    }
+}
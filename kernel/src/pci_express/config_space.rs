@@ -0,0 +1,170 @@
+use crate::pci_express::device::PciAddress;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+const LEGACY_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const LEGACY_CONFIG_DATA_PORT: u16 = 0xCFC;
+
+pub const ECAM_CONFIG_SPACE_SIZE: u16 = 4096;
+pub const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+
+// A legacy config cycle takes two port accesses and an ECAM read-modify-write two memory
+// accesses, neither of which may be interleaved with another driver's.
+static CONFIG_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// How a function's configuration space is reached.
+#[derive(Copy, Clone, Debug)]
+pub enum PciConfigSpace {
+    /// Memory mapped through the enhanced configuration access mechanism (PCIe, from MCFG).
+    Ecam(VirtAddr),
+    /// Configuration mechanism #1 through I/O ports 0xCF8 and 0xCFC, limited to the first 256
+    /// bytes and to segment group 0.
+    Legacy(PciAddress),
+}
+
+pub trait ConfigValue: Copy {
+    const SIZE: u16;
+
+    fn from_dword(dword: u32, byte_offset: u16) -> Self;
+
+    fn into_u32(self) -> u32;
+}
+
+macro_rules! impl_config_value {
+    ($type:ty, $size:expr) => {
+        impl ConfigValue for $type {
+            const SIZE: u16 = $size;
+
+            fn from_dword(dword: u32, byte_offset: u16) -> Self {
+                (dword >> (byte_offset * 8)) as $type
+            }
+
+            fn into_u32(self) -> u32 {
+                self as u32
+            }
+        }
+    };
+}
+
+impl_config_value!(u8, 1);
+impl_config_value!(u16, 2);
+impl_config_value!(u32, 4);
+
+/// Config space access while holding the config lock, so several accesses can be made
+/// without another driver's cycles in between.
+pub struct LockedPciConfigSpace(PciConfigSpace);
+
+impl LockedPciConfigSpace {
+    fn legacy_address(address: &PciAddress, offset: u16) -> u32 {
+        0x8000_0000
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    fn check_access<T: ConfigValue>(&self, offset: u16) {
+        assert_eq!(
+            offset % T::SIZE,
+            0,
+            "unaligned {} byte config space access at {:#x}",
+            T::SIZE,
+            offset
+        );
+        assert!(
+            offset + T::SIZE <= self.0.size(),
+            "config space access at {:#x} is out of bounds",
+            offset
+        );
+    }
+
+    pub fn read<T: ConfigValue>(&self, offset: u16) -> T {
+        self.check_access::<T>(offset);
+
+        match self.0 {
+            PciConfigSpace::Ecam(base) => unsafe {
+                (base + offset as u64).as_ptr::<T>().read_volatile()
+            },
+            PciConfigSpace::Legacy(address) => {
+                let mut address_port = Port::<u32>::new(LEGACY_CONFIG_ADDRESS_PORT);
+                let mut data_port = Port::<u32>::new(LEGACY_CONFIG_DATA_PORT);
+
+                let dword = unsafe {
+                    address_port.write(Self::legacy_address(&address, offset));
+                    data_port.read()
+                };
+
+                T::from_dword(dword, offset % 4)
+            }
+        }
+    }
+
+    pub fn write<T: ConfigValue>(&self, offset: u16, value: T) {
+        self.check_access::<T>(offset);
+
+        match self.0 {
+            PciConfigSpace::Ecam(base) => unsafe {
+                (base + offset as u64)
+                    .as_mut_ptr::<T>()
+                    .write_volatile(value)
+            },
+            PciConfigSpace::Legacy(address) => {
+                let mut address_port = Port::<u32>::new(LEGACY_CONFIG_ADDRESS_PORT);
+
+                // The data port can be accessed with the width of the value, which keeps the
+                // neighbouring bytes (e.g. write-one-to-clear status bits) untouched.
+                unsafe {
+                    address_port.write(Self::legacy_address(&address, offset));
+
+                    let data_port = LEGACY_CONFIG_DATA_PORT + offset % 4;
+                    match T::SIZE {
+                        1 => Port::<u8>::new(data_port).write(value.into_u32() as u8),
+                        2 => Port::<u16>::new(data_port).write(value.into_u32() as u16),
+                        _ => Port::<u32>::new(data_port).write(value.into_u32()),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn update<T: ConfigValue, F: FnOnce(T) -> T>(&self, offset: u16, f: F) {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+}
+
+#[allow(unused)]
+impl PciConfigSpace {
+    pub fn size(&self) -> u16 {
+        match self {
+            Self::Ecam(_) => ECAM_CONFIG_SPACE_SIZE,
+            Self::Legacy(_) => LEGACY_CONFIG_SPACE_SIZE,
+        }
+    }
+
+    pub fn has_extended_config_space(&self) -> bool {
+        self.size() > LEGACY_CONFIG_SPACE_SIZE
+    }
+
+    pub fn with_lock<R, F: FnOnce(&LockedPciConfigSpace) -> R>(&self, f: F) -> R {
+        without_interrupts(|| {
+            let _guard = CONFIG_LOCK.lock();
+
+            f(&LockedPciConfigSpace(*self))
+        })
+    }
+
+    pub fn read<T: ConfigValue>(&self, offset: u16) -> T {
+        self.with_lock(|config| config.read(offset))
+    }
+
+    pub fn write<T: ConfigValue>(&self, offset: u16, value: T) {
+        self.with_lock(|config| config.write(offset, value));
+    }
+
+    pub fn update<T: ConfigValue, F: FnOnce(T) -> T>(&self, offset: u16, f: F) {
+        self.with_lock(|config| config.update(offset, f));
+    }
+}
@@ -1,33 +1,118 @@
-use crate::pci_express::device_capabilities::{PciDeviceCapability, PciDeviceMsiCapability};
+use crate::pci_express::bar::PciBar;
+use crate::pci_express::class::PciClass;
+use crate::pci_express::config_space::{ConfigValue, PciConfigSpace};
+use crate::pci_express::device_capabilities::{PciCapabilityIterator, PciDeviceCapability};
+use crate::pci_express::ids;
 use crate::pci_express::registers::ConfigRegionHeaderRegister;
 use bit_field::BitField;
-use core::fmt::{Debug, Formatter};
-use core::mem::size_of;
-use core::ops::BitAnd;
-use x86_64::VirtAddr;
+use core::fmt::{Debug, Display, Formatter};
 
 pub const PCI_DEVICE_NOT_EXIST_VENDOR_ID: u16 = 0xFFFF;
 
-pub struct PciDevice(VirtAddr);
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PciAddress {
+    pub segment_group: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(segment_group: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment_group,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment_group, self.bus, self.device, self.function
+        )
+    }
+}
+
+impl Debug for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Clone)]
+pub struct PciDevice {
+    config_space: PciConfigSpace,
+    address: PciAddress,
+}
 
 impl PciDevice {
-    pub fn new(pci_address: VirtAddr) -> Self {
-        Self(pci_address)
+    pub fn new(config_space: PciConfigSpace, address: PciAddress) -> Self {
+        Self {
+            config_space,
+            address,
+        }
     }
 
-    fn read_register<T>(&self, register: ConfigRegionHeaderRegister) -> T
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    #[allow(unused)]
+    pub fn config_space(&self) -> &PciConfigSpace {
+        &self.config_space
+    }
+
+    pub fn read_register<T>(&self, register: ConfigRegionHeaderRegister) -> T
     where
-        T: BitAnd + TryFrom<u32>,
+        T: TryFrom<u32>,
         <T as TryFrom<u32>>::Error: Debug,
     {
         let (offset, bit_range) = register.register_location_info();
 
-        let actual_address = (self.0 + offset as u64).as_ptr::<u32>();
+        let bits = self
+            .config_space
+            .read::<u32>(offset as u16)
+            .get_bits(bit_range);
 
-        let bits = unsafe { *actual_address }.get_bits(bit_range);
-        let mask = (1 << (size_of::<T>() * 8)) as u32 - 1;
+        T::try_from(bits).expect("register doesn't fit the requested type")
+    }
 
-        T::try_from(bits & mask).unwrap()
+    /// Writes a header register with the register's own width, so neighbouring registers
+    /// (especially the write-one-to-clear Status register) aren't touched.
+    #[allow(unused)]
+    pub fn write_register<T>(&self, register: ConfigRegionHeaderRegister, value: T)
+    where
+        T: Into<u32>,
+    {
+        let (offset, bit_range) = register.register_location_info();
+        let offset = offset as u16 + (*bit_range.start() / 8) as u16;
+        let value = value.into();
+
+        match bit_range.end() - bit_range.start() + 1 {
+            8 => self.config_space.write(offset, value as u8),
+            16 => self.config_space.write(offset, value as u16),
+            32 => self.config_space.write(offset, value),
+            width => unreachable!("header registers aren't {} bits wide", width),
+        }
+    }
+
+    pub fn read_config<T: ConfigValue>(&self, offset: u16) -> T {
+        self.config_space.read(offset)
+    }
+
+    #[allow(unused)]
+    pub fn write_config<T: ConfigValue>(&self, offset: u16, value: T) {
+        self.config_space.write(offset, value);
     }
 
     pub fn vendor_id(&self) -> u16 {
@@ -38,7 +123,55 @@ impl PciDevice {
         self.read_register(ConfigRegionHeaderRegister::DeviceId)
     }
 
-    #[allow(unused)]
+    pub fn class(&self) -> PciClass {
+        PciClass::new(
+            self.read_register(ConfigRegionHeaderRegister::ClassCode),
+            self.read_register(ConfigRegionHeaderRegister::SubClass),
+            self.read_register(ConfigRegionHeaderRegister::ProgrammingInterface),
+        )
+    }
+
+    pub fn revision_id(&self) -> u8 {
+        self.read_register(ConfigRegionHeaderRegister::RevisionId)
+    }
+
+    pub fn header_type(&self) -> u8 {
+        self.read_register::<u8>(ConfigRegionHeaderRegister::HeaderType) & 0x7F
+    }
+
+    /// The subsystem vendor and subsystem IDs, only present in header type 0.
+    pub fn subsystem(&self) -> Option<(u16, u16)> {
+        (self.header_type() == 0x00).then(|| {
+            (
+                self.read_register(ConfigRegionHeaderRegister::SubsystemVendorId),
+                self.read_register(ConfigRegionHeaderRegister::SubsystemId),
+            )
+        })
+    }
+
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        ids::vendor_name(self.vendor_id())
+    }
+
+    pub fn device_name(&self) -> Option<&'static str> {
+        ids::device_name(self.vendor_id(), self.device_id())
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type() == 0x01
+    }
+
+    /// The secondary and subordinate bus numbers of a PCI-to-PCI bridge, i.e. the first and
+    /// last bus behind it.
+    pub fn bridge_bus_range(&self) -> Option<(u8, u8)> {
+        self.is_bridge().then(|| {
+            (
+                self.read_register(ConfigRegionHeaderRegister::SecondaryBusNumber),
+                self.read_register(ConfigRegionHeaderRegister::SubordinateBusNumber),
+            )
+        })
+    }
+
     pub fn has_capabilities_list(&self) -> bool {
         self.read_register::<u16>(ConfigRegionHeaderRegister::Status)
             .get_bit(4)
@@ -53,19 +186,111 @@ impl PciDevice {
         self.vendor_id() != PCI_DEVICE_NOT_EXIST_VENDOR_ID
     }
 
+    pub fn capabilities(&self) -> PciCapabilityIterator {
+        let capabilities_pointer = self
+            .has_capabilities_list()
+            .then(|| self.read_register::<u8>(ConfigRegionHeaderRegister::CapabilitiesPointer));
+
+        let is_pci_express = self.config_space.has_extended_config_space()
+            && PciCapabilityIterator::new(self.config_space, capabilities_pointer, false)
+                .any(|capability| matches!(capability, PciDeviceCapability::PciExpress(_)));
+
+        PciCapabilityIterator::new(self.config_space, capabilities_pointer, is_pci_express)
+    }
+
     #[allow(unused)]
-    pub fn capabilities(&self) -> Option<PciDeviceCapability> {
-        if self.has_capabilities_list() {
-            let offset =
-                self.read_register::<u8>(ConfigRegionHeaderRegister::CapabilitiesPointer) & !0b11u8;
+    pub fn command(&self) -> u16 {
+        self.read_register(ConfigRegionHeaderRegister::Command)
+    }
 
-            let capability_offset = unsafe { *(self.0 + offset as u64).as_ptr() };
-            let capability_option = PciDeviceMsiCapability::new(self.0, capability_offset);
+    /// Sets or clears the given Command register bits in one locked read-modify-write.
+    pub fn set_command_bits(&self, bits: u16, enabled: bool) {
+        self.config_space.update::<u16, _>(0x04, |command| {
+            if enabled {
+                command | bits
+            } else {
+                command & !bits
+            }
+        });
+    }
+
+    /// Allows the device to issue memory requests, which includes MSI writes.
+    pub fn enable_bus_master(&self) {
+        self.set_command_bits(COMMAND_BUS_MASTER | COMMAND_MEMORY_SPACE, true);
+    }
+
+    #[allow(unused)]
+    pub fn set_bus_master(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_BUS_MASTER, enabled);
+    }
+
+    #[allow(unused)]
+    pub fn set_memory_space(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_MEMORY_SPACE, enabled);
+    }
+
+    #[allow(unused)]
+    pub fn set_io_space(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_IO_SPACE, enabled);
+    }
 
-            capability_option.map(|capability| PciDeviceCapability::MSI(capability))
-        } else {
-            None
+    /// Stops the device from asserting its legacy INTx pin, needed once MSI(-X) is in use.
+    pub fn set_interrupt_disable(&self, disabled: bool) {
+        self.set_command_bits(COMMAND_INTERRUPT_DISABLE, disabled);
+    }
+
+    fn bar_count(&self) -> u8 {
+        match self.header_type() {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn bar(&self, index: u8) -> Option<PciBar> {
+        if index >= self.bar_count() {
+            return None;
         }
+
+        let offset = 0x10 + index as u16 * 4;
+        let raw = self.read_config::<u32>(offset);
+
+        if raw == 0 {
+            return None;
+        }
+
+        let is_64_bit = !raw.get_bit(0) && raw.get_bits(1..3) == 0b10;
+        let upper_offset = (is_64_bit && index + 1 < self.bar_count()).then_some(offset + 4);
+
+        let (raw_upper, sized, sized_upper) = self.config_space.with_lock(|config| {
+            let size_bar_register = |offset: u16| {
+                let raw = config.read::<u32>(offset);
+
+                config.write(offset, u32::MAX);
+                let sized = config.read::<u32>(offset);
+                config.write(offset, raw);
+
+                (raw, sized)
+            };
+
+            // Decoding has to be turned off while sizing, otherwise the device would briefly
+            // claim whatever address range the all ones pattern corresponds to.
+            let command = config.read::<u16>(0x04);
+            config.write(0x04, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+            let (_, sized) = size_bar_register(offset);
+            let upper = upper_offset.map(size_bar_register);
+
+            config.write(0x04, command);
+
+            (
+                upper.map(|(raw, _)| raw),
+                sized,
+                upper.map(|(_, sized)| sized),
+            )
+        });
+
+        Some(PciBar::decode(raw, raw_upper, sized, sized_upper))
     }
 }
 
@@ -74,12 +299,31 @@ impl Debug for PciDevice {
         let vendor_id = self.vendor_id();
         let device_id = self.device_id();
 
-        match (vendor_id, device_id) {
-            (0x8086, 0x2934) => write!(f, "82801I (ICH9 Family) USB UHCI Controller #1"),
-            (0x8086, 0x2935) => write!(f, "82801I (ICH9 Family) USB UHCI Controller #2"),
-            (0x8086, 0x2936) => write!(f, "82801I (ICH9 Family) USB UHCI Controller #3"),
-            (0x8086, 0x293a) => write!(f, "82801I (ICH9 Family) USB2 EHCI Controller #1"),
-            _ => write!(f, "({:04x}:{:04x})", vendor_id, device_id),
+        write!(f, "{} ", self.address)?;
+
+        match (self.vendor_name(), self.device_name()) {
+            (Some(vendor), Some(device)) => write!(f, "{} {}", vendor, device)?,
+            (Some(vendor), None) => write!(f, "{} device", vendor)?,
+            _ => write!(f, "Unknown device")?,
         }
+
+        write!(
+            f,
+            " ({:04x}:{:04x} rev {:02x}, {}",
+            vendor_id,
+            device_id,
+            self.revision_id(),
+            self.class()
+        )?;
+
+        if let Some((subsystem_vendor_id, subsystem_id)) = self.subsystem() {
+            write!(
+                f,
+                ", subsystem {:04x}:{:04x}",
+                subsystem_vendor_id, subsystem_id
+            )?;
+        }
+
+        write!(f, ")")
     }
 }
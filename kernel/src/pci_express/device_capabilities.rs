@@ -1,65 +1,740 @@
+use crate::pci_express::config_space::{ConfigValue, PciConfigSpace};
 use alloc::format;
+use alloc::vec::Vec;
+use bit_field::BitField;
 use core::fmt::{Debug, Formatter};
-use x86_64::VirtAddr;
+
+const CAPABILITY_ID_POWER_MANAGEMENT: u8 = 0x01;
+const CAPABILITY_ID_MSI: u8 = 0x05;
+const CAPABILITY_ID_VENDOR_SPECIFIC: u8 = 0x09;
+const CAPABILITY_ID_PCI_EXPRESS: u8 = 0x10;
+const CAPABILITY_ID_MSIX: u8 = 0x11;
+
+const EXTENDED_CAPABILITY_ID_AER: u16 = 0x0001;
+const EXTENDED_CAPABILITY_ID_SR_IOV: u16 = 0x0010;
+
+pub const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+
+// A standard capability is at least 4 bytes big and lives in the 192 bytes following the
+// header, an extended one in the 3840 bytes following the standard config space. Walking more
+// entries than fit means the list loops.
+const MAX_CAPABILITIES: usize = (256 - 64) / 4;
+const MAX_EXTENDED_CAPABILITIES: usize = (4096 - 256) / 4;
 
 #[allow(unused)]
 #[derive(Debug)]
 pub enum PciDeviceCapability {
+    PowerManagement(PciPowerManagementCapability),
     MSI(PciDeviceMsiCapability),
-    MSIX,
+    MSIX(PciDeviceMsixCapability),
+    PciExpress(PciExpressCapability),
+    VendorSpecific(PciVendorSpecificCapability),
+    AdvancedErrorReporting(PciAdvancedErrorReportingCapability),
+    SingleRootIoVirtualization(PciSrIovCapability),
+    Unknown { id: u8, offset: u16 },
+    UnknownExtended { id: u16, version: u8, offset: u16 },
+}
+
+#[allow(unused)]
+impl PciDeviceCapability {
+    fn from_standard(config_space: PciConfigSpace, offset: u16) -> Self {
+        let id: u8 = config_space.read(offset);
+        let capability = PciCapabilityHeader {
+            config_space,
+            capability_offset: offset,
+        };
+
+        match id {
+            CAPABILITY_ID_POWER_MANAGEMENT => {
+                Self::PowerManagement(PciPowerManagementCapability(capability))
+            }
+            CAPABILITY_ID_MSI => Self::MSI(PciDeviceMsiCapability(capability)),
+            CAPABILITY_ID_MSIX => Self::MSIX(PciDeviceMsixCapability(capability)),
+            CAPABILITY_ID_PCI_EXPRESS => Self::PciExpress(PciExpressCapability(capability)),
+            CAPABILITY_ID_VENDOR_SPECIFIC => {
+                Self::VendorSpecific(PciVendorSpecificCapability(capability))
+            }
+            id => Self::Unknown { id, offset },
+        }
+    }
+
+    fn from_extended(config_space: PciConfigSpace, offset: u16, header: u32) -> Self {
+        let capability = PciCapabilityHeader {
+            config_space,
+            capability_offset: offset,
+        };
+
+        match header.get_bits(0..16) as u16 {
+            EXTENDED_CAPABILITY_ID_AER => {
+                Self::AdvancedErrorReporting(PciAdvancedErrorReportingCapability(capability))
+            }
+            EXTENDED_CAPABILITY_ID_SR_IOV => {
+                Self::SingleRootIoVirtualization(PciSrIovCapability(capability))
+            }
+            id => Self::UnknownExtended {
+                id,
+                version: header.get_bits(16..20) as u8,
+                offset,
+            },
+        }
+    }
+
+    pub fn offset(&self) -> u16 {
+        match self {
+            Self::PowerManagement(capability) => capability.0.capability_offset,
+            Self::MSI(capability) => capability.0.capability_offset,
+            Self::MSIX(capability) => capability.0.capability_offset,
+            Self::PciExpress(capability) => capability.0.capability_offset,
+            Self::VendorSpecific(capability) => capability.0.capability_offset,
+            Self::AdvancedErrorReporting(capability) => capability.0.capability_offset,
+            Self::SingleRootIoVirtualization(capability) => capability.0.capability_offset,
+            Self::Unknown { offset, .. } => *offset,
+            Self::UnknownExtended { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Walks the standard capability list and, if the device has one, the PCI Express extended
+/// capability list that starts at offset 0x100.
+pub struct PciCapabilityIterator {
+    config_space: PciConfigSpace,
+    next_offset: Option<u16>,
+    next_extended_offset: Option<u16>,
+    visited: usize,
+}
+
+impl PciCapabilityIterator {
+    pub fn new(
+        config_space: PciConfigSpace,
+        capabilities_pointer: Option<u8>,
+        has_extended_capabilities: bool,
+    ) -> Self {
+        Self {
+            config_space,
+            next_offset: capabilities_pointer
+                .map(|pointer| (pointer & !0b11) as u16)
+                .filter(|offset| *offset != 0),
+            next_extended_offset: has_extended_capabilities.then_some(EXTENDED_CAPABILITIES_OFFSET),
+            visited: 0,
+        }
+    }
+
+    fn next_standard(&mut self) -> Option<PciDeviceCapability> {
+        let offset = self.next_offset.take()?;
+
+        if self.visited >= MAX_CAPABILITIES || offset < 0x40 {
+            return None;
+        }
+        self.visited += 1;
+
+        let next: u8 = self.config_space.read(offset + 1);
+        self.next_offset = Some((next & !0b11) as u16).filter(|offset| *offset != 0);
+
+        Some(PciDeviceCapability::from_standard(
+            self.config_space,
+            offset,
+        ))
+    }
+
+    fn next_extended(&mut self) -> Option<PciDeviceCapability> {
+        let offset = self.next_extended_offset.take()?;
+
+        if self.visited >= MAX_CAPABILITIES + MAX_EXTENDED_CAPABILITIES
+            || offset < EXTENDED_CAPABILITIES_OFFSET
+        {
+            return None;
+        }
+        self.visited += 1;
+
+        let header: u32 = self.config_space.read(offset);
+
+        // An empty list is indicated by a header of 0, a device without extended config space
+        // reads as all ones.
+        if header == 0 || header == u32::MAX {
+            return None;
+        }
+
+        self.next_extended_offset =
+            Some((header.get_bits(20..32) as u16) & !0b11).filter(|offset| *offset != 0);
+
+        Some(PciDeviceCapability::from_extended(
+            self.config_space,
+            offset,
+            header,
+        ))
+    }
+}
+
+impl Iterator for PciCapabilityIterator {
+    type Item = PciDeviceCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_offset.is_some() {
+            if let Some(capability) = self.next_standard() {
+                return Some(capability);
+            }
+        }
+
+        self.next_extended()
+    }
 }
 
 #[derive(Copy, Clone)]
-pub struct PciDeviceMsiCapability {
-    device_address: VirtAddr,
-    capability_offset: usize,
+struct PciCapabilityHeader {
+    config_space: PciConfigSpace,
+    capability_offset: u16,
 }
 
+impl PciCapabilityHeader {
+    fn read<T: ConfigValue>(&self, register_offset: u16) -> T {
+        self.config_space
+            .read(self.capability_offset + register_offset)
+    }
+
+    fn write<T: ConfigValue>(&self, register_offset: u16, value: T) {
+        self.config_space
+            .write(self.capability_offset + register_offset, value);
+    }
+
+    fn dump(&self, f: &mut Formatter<'_>, dwords: u16) -> core::fmt::Result {
+        let regs = (0..dwords)
+            .map(|index| format!("{:08x}", self.read::<u32>(index * 4)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "@{:03x}: {}", self.capability_offset, regs)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciPowerManagementCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciPowerManagementCapability {
+    pub fn capabilities(&self) -> u16 {
+        self.0.read(0x02)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.capabilities().get_bits(0..3) as u8
+    }
+
+    pub fn supports_d1(&self) -> bool {
+        self.capabilities().get_bit(9)
+    }
+
+    pub fn supports_d2(&self) -> bool {
+        self.capabilities().get_bit(10)
+    }
+
+    pub fn pme_support(&self) -> u8 {
+        self.capabilities().get_bits(11..16) as u8
+    }
+
+    pub fn control_status(&self) -> u16 {
+        self.0.read(0x04)
+    }
+
+    /// The current power state, 0 through 3 meaning D0 through D3hot.
+    pub fn power_state(&self) -> u8 {
+        self.control_status().get_bits(0..2) as u8
+    }
+
+    /// Moves the function to the given power state. The PME status bit is write-one-to-clear,
+    /// so it's masked out to avoid clearing it along the way.
+    pub fn set_power_state(&self, state: u8) {
+        let mut control_status = self.control_status();
+        control_status.set_bit(15, false);
+        control_status.set_bits(0..2, state as u16);
+        self.0.write(0x04, control_status);
+    }
+
+    pub fn no_soft_reset(&self) -> bool {
+        self.control_status().get_bit(3)
+    }
+}
+
+impl Debug for PciPowerManagementCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Power Management v{} (D{})",
+            self.version(),
+            self.power_state()
+        )
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciDeviceMsiCapability(PciCapabilityHeader);
+
+#[allow(unused)]
 impl PciDeviceMsiCapability {
-    pub fn new(device_address: VirtAddr, capability_offset: u8) -> Option<Self> {
-        let capability_id = unsafe { *device_address.as_ptr::<u8>() };
+    pub fn message_control(&self) -> u16 {
+        self.0.read(0x02)
+    }
 
-        if capability_id == 0x05 {
-            let capability = Self {
-                device_address,
-                capability_offset: capability_offset as usize,
-            };
+    fn set_message_control(&self, message_control: u16) {
+        self.0.write(0x02, message_control);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.message_control().get_bit(0)
+    }
 
-            Some(capability)
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut message_control = self.message_control();
+        message_control.set_bit(0, enabled);
+        self.set_message_control(message_control);
+    }
+
+    /// Log2 of the amount of vectors the function can request.
+    pub fn multiple_message_capable(&self) -> u8 {
+        self.message_control().get_bits(1..4) as u8
+    }
+
+    /// Log2 of the amount of vectors the function has been granted.
+    pub fn multiple_message_enable(&self) -> u8 {
+        self.message_control().get_bits(4..7) as u8
+    }
+
+    pub fn set_multiple_message_enable(&self, log2_vectors: u8) {
+        let mut message_control = self.message_control();
+        message_control.set_bits(
+            4..7,
+            log2_vectors.min(self.multiple_message_capable()) as u16,
+        );
+        self.set_message_control(message_control);
+    }
+
+    pub fn is_64_bit(&self) -> bool {
+        self.message_control().get_bit(7)
+    }
+
+    pub fn supports_per_vector_masking(&self) -> bool {
+        self.message_control().get_bit(8)
+    }
+
+    pub fn message_address(&self) -> u64 {
+        let lower = self.0.read::<u32>(0x04) as u64;
+
+        if self.is_64_bit() {
+            lower | ((self.0.read::<u32>(0x08) as u64) << 32)
         } else {
-            None
+            lower
         }
     }
 
-    #[allow(unused)]
-    pub fn next(&self) -> PciDeviceMsiCapability {
-        let next_offset: u8 = unsafe {
-            *self
-                .device_address
-                .as_ptr::<u8>()
-                .byte_add(self.capability_offset)
-        };
+    pub fn set_message_address(&self, address: u64) {
+        self.0.write(0x04, address as u32);
 
-        unsafe {
-            *self
-                .device_address
-                .as_ptr::<PciDeviceMsiCapability>()
-                .byte_add(next_offset as usize)
+        if self.is_64_bit() {
+            self.0.write(0x08, (address >> 32) as u32);
+        }
+    }
+
+    fn message_data_offset(&self) -> u16 {
+        if self.is_64_bit() {
+            0x0C
+        } else {
+            0x08
         }
     }
+
+    pub fn message_data(&self) -> u16 {
+        self.0.read(self.message_data_offset())
+    }
+
+    pub fn set_message_data(&self, data: u16) {
+        self.0.write(self.message_data_offset(), data);
+    }
+
+    pub fn mask_bits(&self) -> Option<u32> {
+        self.supports_per_vector_masking()
+            .then(|| self.0.read(self.message_data_offset() + 0x04))
+    }
+
+    pub fn set_mask_bits(&self, mask_bits: u32) {
+        if self.supports_per_vector_masking() {
+            self.0.write(self.message_data_offset() + 0x04, mask_bits);
+        }
+    }
+
+    pub fn pending_bits(&self) -> Option<u32> {
+        self.supports_per_vector_masking()
+            .then(|| self.0.read(self.message_data_offset() + 0x08))
+    }
 }
 
 impl Debug for PciDeviceMsiCapability {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        let regs = unsafe {
-            *self
-                .device_address
-                .as_ptr::<[u32; 6]>()
-                .byte_add(self.capability_offset)
-        };
+        write!(f, "MSI ")?;
+        self.0.dump(f, 6)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciDeviceMsixCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciDeviceMsixCapability {
+    pub fn message_control(&self) -> u16 {
+        self.0.read(0x02)
+    }
+
+    fn set_message_control(&self, message_control: u16) {
+        self.0.write(0x02, message_control);
+    }
+
+    pub fn table_size(&self) -> u16 {
+        self.message_control().get_bits(0..11) + 1
+    }
+
+    pub fn is_function_masked(&self) -> bool {
+        self.message_control().get_bit(14)
+    }
+
+    pub fn set_function_masked(&self, masked: bool) {
+        let mut message_control = self.message_control();
+        message_control.set_bit(14, masked);
+        self.set_message_control(message_control);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.message_control().get_bit(15)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut message_control = self.message_control();
+        message_control.set_bit(15, enabled);
+        self.set_message_control(message_control);
+    }
+
+    pub fn table_bar(&self) -> u8 {
+        self.0.read::<u32>(0x04).get_bits(0..3) as u8
+    }
+
+    pub fn table_offset(&self) -> u32 {
+        self.0.read::<u32>(0x04) & !0b111
+    }
+
+    pub fn pending_bit_array_bar(&self) -> u8 {
+        self.0.read::<u32>(0x08).get_bits(0..3) as u8
+    }
+
+    pub fn pending_bit_array_offset(&self) -> u32 {
+        self.0.read::<u32>(0x08) & !0b111
+    }
+}
+
+impl Debug for PciDeviceMsixCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "MSI-X ({} vectors, table in BAR{} at {:#x}, PBA in BAR{} at {:#x})",
+            self.table_size(),
+            self.table_bar(),
+            self.table_offset(),
+            self.pending_bit_array_bar(),
+            self.pending_bit_array_offset()
+        )
+    }
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciExpressDeviceType {
+    Endpoint,
+    LegacyEndpoint,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PciExpressToPciBridge,
+    PciToPciExpressBridge,
+    Unknown(u8),
+}
+
+#[derive(Copy, Clone)]
+pub struct PciExpressCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciExpressCapability {
+    pub fn capabilities(&self) -> u16 {
+        self.0.read(0x02)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.capabilities().get_bits(0..4) as u8
+    }
+
+    pub fn device_type(&self) -> PciExpressDeviceType {
+        match self.capabilities().get_bits(4..8) as u8 {
+            0b0000 => PciExpressDeviceType::Endpoint,
+            0b0001 => PciExpressDeviceType::LegacyEndpoint,
+            0b1001 => PciExpressDeviceType::RootComplexIntegratedEndpoint,
+            0b1010 => PciExpressDeviceType::RootComplexEventCollector,
+            0b0100 => PciExpressDeviceType::RootPort,
+            0b0101 => PciExpressDeviceType::UpstreamSwitchPort,
+            0b0110 => PciExpressDeviceType::DownstreamSwitchPort,
+            0b0111 => PciExpressDeviceType::PciExpressToPciBridge,
+            0b1000 => PciExpressDeviceType::PciToPciExpressBridge,
+            other => PciExpressDeviceType::Unknown(other),
+        }
+    }
+
+    pub fn device_capabilities(&self) -> u32 {
+        self.0.read(0x04)
+    }
+
+    pub fn supports_function_level_reset(&self) -> bool {
+        self.device_capabilities().get_bit(28)
+    }
+
+    pub fn device_control(&self) -> u16 {
+        self.0.read(0x08)
+    }
+
+    pub fn set_device_control(&self, device_control: u16) {
+        self.0.write(0x08, device_control);
+    }
+
+    /// Only there from version 2 on.
+    pub fn device_control_2(&self) -> Option<u16> {
+        (self.version() >= 2).then(|| self.0.read(0x28))
+    }
+
+    pub fn set_device_control_2(&self, device_control_2: u16) {
+        if self.version() >= 2 {
+            self.0.write(0x28, device_control_2);
+        }
+    }
+
+    pub fn initiate_function_level_reset(&self) {
+        let mut device_control = self.device_control();
+        device_control.set_bit(15, true);
+        self.set_device_control(device_control);
+    }
+
+    pub fn device_status(&self) -> u16 {
+        self.0.read(0x0A)
+    }
+
+    pub fn transactions_pending(&self) -> bool {
+        self.device_status().get_bit(5)
+    }
+
+    pub fn link_capabilities(&self) -> u32 {
+        self.0.read(0x0C)
+    }
 
-        let to_write = regs.map(|reg| format!("{:08x}", reg)).join(", ");
+    pub fn link_status(&self) -> u16 {
+        self.0.read(0x12)
+    }
+
+    pub fn link_speed(&self) -> u8 {
+        self.link_status().get_bits(0..4) as u8
+    }
 
-        write!(f, "{}", to_write)
+    pub fn link_width(&self) -> u8 {
+        self.link_status().get_bits(4..10) as u8
+    }
+}
+
+impl Debug for PciExpressCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "PCI Express v{} {:?}",
+            self.version(),
+            self.device_type()
+        )?;
+
+        if self.supports_function_level_reset() {
+            write!(f, ", FLR")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciVendorSpecificCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciVendorSpecificCapability {
+    /// The length of the capability in bytes, including the ID, next pointer and length fields.
+    pub fn length(&self) -> u8 {
+        self.0.read(0x02)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        self.0.read(offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        self.0.read(offset)
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        (3..self.length() as u16)
+            .map(|offset| self.read_u8(offset))
+            .collect()
+    }
+}
+
+impl Debug for PciVendorSpecificCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Vendor Specific ({} bytes)", self.length())
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciAdvancedErrorReportingCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciAdvancedErrorReportingCapability {
+    pub fn uncorrectable_error_status(&self) -> u32 {
+        self.0.read(0x04)
+    }
+
+    pub fn uncorrectable_error_mask(&self) -> u32 {
+        self.0.read(0x08)
+    }
+
+    pub fn uncorrectable_error_severity(&self) -> u32 {
+        self.0.read(0x0C)
+    }
+
+    pub fn correctable_error_status(&self) -> u32 {
+        self.0.read(0x10)
+    }
+
+    pub fn correctable_error_mask(&self) -> u32 {
+        self.0.read(0x14)
+    }
+
+    pub fn capabilities_and_control(&self) -> u32 {
+        self.0.read(0x18)
+    }
+
+    pub fn first_error_pointer(&self) -> u8 {
+        self.capabilities_and_control().get_bits(0..5) as u8
+    }
+
+    pub fn header_log(&self) -> [u32; 4] {
+        [0x1C, 0x20, 0x24, 0x28].map(|offset| self.0.read(offset))
+    }
+
+    /// The status registers are write-one-to-clear, so writing back what was read clears
+    /// exactly the errors that were seen.
+    pub fn clear_uncorrectable_error_status(&self, status: u32) {
+        self.0.write(0x04, status);
+    }
+
+    pub fn set_uncorrectable_error_mask(&self, mask: u32) {
+        self.0.write(0x08, mask);
+    }
+
+    pub fn clear_correctable_error_status(&self, status: u32) {
+        self.0.write(0x10, status);
+    }
+
+    pub fn set_correctable_error_mask(&self, mask: u32) {
+        self.0.write(0x14, mask);
+    }
+
+    /// Only present in root ports and root complex event collectors.
+    pub fn root_error_command(&self) -> u32 {
+        self.0.read(0x2C)
+    }
+
+    pub fn set_root_error_command(&self, command: u32) {
+        self.0.write(0x2C, command);
+    }
+
+    pub fn root_error_status(&self) -> u32 {
+        self.0.read(0x30)
+    }
+
+    pub fn clear_root_error_status(&self, status: u32) {
+        self.0.write(0x30, status);
+    }
+
+    /// The requester IDs of the functions that sent the last correctable and the last
+    /// uncorrectable error message.
+    pub fn error_source_identification(&self) -> (u16, u16) {
+        let sources: u32 = self.0.read(0x34);
+
+        (
+            sources.get_bits(0..16) as u16,
+            sources.get_bits(16..32) as u16,
+        )
+    }
+}
+
+impl Debug for PciAdvancedErrorReportingCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Advanced Error Reporting (uncorrectable {:08x}, correctable {:08x})",
+            self.uncorrectable_error_status(),
+            self.correctable_error_status()
+        )
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PciSrIovCapability(PciCapabilityHeader);
+
+#[allow(unused)]
+impl PciSrIovCapability {
+    pub fn capabilities(&self) -> u32 {
+        self.0.read(0x04)
+    }
+
+    pub fn control(&self) -> u16 {
+        self.0.read(0x08)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.0.read(0x0A)
+    }
+
+    pub fn initial_virtual_functions(&self) -> u16 {
+        self.0.read(0x0C)
+    }
+
+    pub fn total_virtual_functions(&self) -> u16 {
+        self.0.read(0x0E)
+    }
+
+    pub fn number_of_virtual_functions(&self) -> u16 {
+        self.0.read(0x10)
+    }
+
+    pub fn first_virtual_function_offset(&self) -> u16 {
+        self.0.read(0x14)
+    }
+
+    pub fn virtual_function_stride(&self) -> u16 {
+        self.0.read(0x16)
+    }
+
+    pub fn virtual_function_device_id(&self) -> u16 {
+        self.0.read(0x1A)
+    }
+}
+
+impl Debug for PciSrIovCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "SR-IOV ({}/{} VFs, device {:04x})",
+            self.number_of_virtual_functions(),
+            self.total_virtual_functions(),
+            self.virtual_function_device_id()
+        )
     }
 }
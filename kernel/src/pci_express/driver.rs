@@ -0,0 +1,107 @@
+use crate::pci_express::device::PciDevice;
+
+/// One entry of a driver's ID table. Fields that are `None` match anything.
+#[derive(Copy, Clone, Debug)]
+pub struct PciDeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub programming_interface: Option<u8>,
+}
+
+#[allow(unused)]
+impl PciDeviceId {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            programming_interface: None,
+        }
+    }
+
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: None,
+            class: None,
+            subclass: None,
+            programming_interface: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            programming_interface: None,
+        }
+    }
+
+    pub const fn class_with_interface(class: u8, subclass: u8, programming_interface: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            programming_interface: Some(programming_interface),
+        }
+    }
+
+    /// How specifically this entry matches the device, `None` if it doesn't match at all.
+    /// Exact vendor and device IDs always beat class matches.
+    pub fn match_score(&self, device: &PciDevice) -> Option<u32> {
+        let class = device.class();
+
+        let fields = [
+            (self.device_id.map(|id| id == device.device_id()), 32),
+            (self.vendor_id.map(|id| id == device.vendor_id()), 16),
+            (
+                self.programming_interface.map(|programming_interface| {
+                    programming_interface == class.programming_interface
+                }),
+                4,
+            ),
+            (self.subclass.map(|subclass| subclass == class.subclass), 2),
+            (self.class.map(|base_class| base_class == class.class), 1),
+        ];
+
+        fields
+            .iter()
+            .try_fold(0, |score, (matches, weight)| match matches {
+                Some(true) => Some(score + weight),
+                Some(false) => None,
+                None => Some(score),
+            })
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum PciProbeError {
+    /// The driver matched the device but can't handle it after all, binding moves on to the
+    /// next best driver.
+    NotSupported,
+    InitializationFailed(&'static str),
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    fn id_table(&self) -> &'static [PciDeviceId];
+
+    fn probe(&self, device: &PciDevice) -> Result<(), PciProbeError>;
+
+    fn remove(&self, _device: &PciDevice) {}
+
+    fn match_score(&self, device: &PciDevice) -> Option<u32> {
+        self.id_table()
+            .iter()
+            .filter_map(|id| id.match_score(device))
+            .max()
+    }
+}
@@ -0,0 +1,78 @@
+pub struct PciVendorEntry {
+    pub id: u16,
+    pub name: &'static str,
+    pub devices: &'static [(u16, &'static str)],
+}
+
+pub struct PciClassEntry {
+    pub id: u8,
+    pub name: &'static str,
+    pub subclasses: &'static [PciSubclassEntry],
+}
+
+pub struct PciSubclassEntry {
+    pub id: u8,
+    pub name: &'static str,
+    pub programming_interfaces: &'static [(u8, &'static str)],
+}
+
+// Generated by build.rs from assets/pci.ids
+include!(concat!(env!("OUT_DIR"), "/pci_ids.rs"));
+
+fn find_vendor(vendor_id: u16) -> Option<&'static PciVendorEntry> {
+    VENDORS
+        .binary_search_by_key(&vendor_id, |vendor| vendor.id)
+        .ok()
+        .map(|index| &VENDORS[index])
+}
+
+fn find_class(class: u8) -> Option<&'static PciClassEntry> {
+    CLASSES
+        .binary_search_by_key(&class, |entry| entry.id)
+        .ok()
+        .map(|index| &CLASSES[index])
+}
+
+fn find_subclass(class: u8, subclass: u8) -> Option<&'static PciSubclassEntry> {
+    let class = find_class(class)?;
+
+    class
+        .subclasses
+        .binary_search_by_key(&subclass, |entry| entry.id)
+        .ok()
+        .map(|index| &class.subclasses[index])
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    find_vendor(vendor_id).map(|vendor| vendor.name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    let devices = find_vendor(vendor_id)?.devices;
+
+    devices
+        .binary_search_by_key(&device_id, |(id, _)| *id)
+        .ok()
+        .map(|index| devices[index].1)
+}
+
+pub fn class_name(class: u8) -> Option<&'static str> {
+    find_class(class).map(|class| class.name)
+}
+
+pub fn subclass_name(class: u8, subclass: u8) -> Option<&'static str> {
+    find_subclass(class, subclass).map(|subclass| subclass.name)
+}
+
+pub fn programming_interface_name(
+    class: u8,
+    subclass: u8,
+    programming_interface: u8,
+) -> Option<&'static str> {
+    let programming_interfaces = find_subclass(class, subclass)?.programming_interfaces;
+
+    programming_interfaces
+        .binary_search_by_key(&programming_interface, |(id, _)| *id)
+        .ok()
+        .map(|index| programming_interfaces[index].1)
+}
@@ -0,0 +1,364 @@
+use crate::apic;
+use crate::apic::IrqHandler;
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::device_capabilities::{
+    PciDeviceCapability, PciDeviceMsiCapability, PciDeviceMsixCapability,
+};
+use alloc::vec::Vec;
+use bit_field::BitField;
+use log::debug;
+use x86_64::VirtAddr;
+
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1;
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum PciInterruptError {
+    NoInterruptCapability,
+    NoVectorsAvailable,
+    TableNotMapped,
+}
+
+/// The address a device has to write to in order to deliver a message signaled interrupt to
+/// the local APIC with the given ID.
+pub fn message_address(destination_apic_id: u8) -> u64 {
+    MSI_ADDRESS_BASE | ((destination_apic_id as u64) << 12)
+}
+
+/// Fixed delivery, edge triggered.
+pub fn message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+enum PciInterruptKind {
+    Msi(PciDeviceMsiCapability),
+    MsiX {
+        capability: PciDeviceMsixCapability,
+        table: VirtAddr,
+    },
+}
+
+/// The MSI or MSI-X configuration of a function, which a reset clears.
+pub enum SavedInterruptState {
+    Msi {
+        multiple_message_enable: u8,
+        address: u64,
+        data: u16,
+        mask_bits: Option<u32>,
+    },
+    MsiX {
+        function_masked: bool,
+        table: VirtAddr,
+        entries: Vec<[u32; 4]>,
+    },
+}
+
+/// Interrupt vectors that were allocated for and programmed into a device.
+pub struct PciInterrupts {
+    kind: PciInterruptKind,
+    first_vector: u8,
+    count: usize,
+}
+
+#[allow(unused)]
+impl PciInterrupts {
+    pub fn vector(&self, index: usize) -> Option<u8> {
+        (index < self.count).then(|| self.first_vector + index as u8)
+    }
+
+    pub fn vectors(&self) -> Vec<u8> {
+        (0..self.count)
+            .map(|index| self.first_vector + index as u8)
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_msix(&self) -> bool {
+        matches!(self.kind, PciInterruptKind::MsiX { .. })
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) {
+        assert!(index < self.count, "interrupt index out of range");
+
+        match &self.kind {
+            PciInterruptKind::Msi(capability) => {
+                if let Some(mut mask_bits) = capability.mask_bits() {
+                    mask_bits.set_bit(index, masked);
+                    capability.set_mask_bits(mask_bits);
+                } else if index == 0 {
+                    // Without per vector masking the best we can do is turning MSI off.
+                    capability.set_enabled(!masked);
+                }
+            }
+            PciInterruptKind::MsiX { table, .. } => unsafe {
+                let vector_control =
+                    (*table + (index as u64 * MSIX_TABLE_ENTRY_SIZE + 12)).as_mut_ptr::<u32>();
+                let mut control = vector_control.read_volatile();
+                control.set_bit(0, masked);
+                vector_control.write_volatile(control);
+            },
+        }
+    }
+
+    pub fn mask(&self, index: usize) {
+        self.set_masked(index, true);
+    }
+
+    pub fn unmask(&self, index: usize) {
+        self.set_masked(index, false);
+    }
+
+    /// Turns the device's message signaled interrupts off and gives the vectors back.
+    pub fn release(self) {
+        match &self.kind {
+            PciInterruptKind::Msi(capability) => capability.set_enabled(false),
+            PciInterruptKind::MsiX { capability, .. } => capability.set_enabled(false),
+        }
+
+        apic::free_vectors(self.first_vector, self.count);
+    }
+}
+
+fn register_vectors(first_vector: u8, count: usize, handler: &IrqHandler) {
+    for vector in first_vector..first_vector + count as u8 {
+        apic::register_handler(vector, handler.clone());
+    }
+}
+
+impl PciDevice {
+    fn msi_capability(&self) -> Option<PciDeviceMsiCapability> {
+        self.capabilities().find_map(|capability| match capability {
+            PciDeviceCapability::MSI(capability) => Some(capability),
+            _ => None,
+        })
+    }
+
+    fn msix_capability(&self) -> Option<PciDeviceMsixCapability> {
+        self.capabilities().find_map(|capability| match capability {
+            PciDeviceCapability::MSIX(capability) => Some(capability),
+            _ => None,
+        })
+    }
+
+    /// Whichever of MSI and MSI-X is enabled, with the whole MSI-X table.
+    pub fn save_interrupt_state(&self) -> Option<SavedInterruptState> {
+        if let Some(capability) = self
+            .msix_capability()
+            .filter(|capability| capability.is_enabled())
+        {
+            let table = self.bar(capability.table_bar()).and_then(|bar| bar.map())?
+                + capability.table_offset() as u64;
+
+            let entries = (0..capability.table_size() as u64)
+                .map(|index| {
+                    let entry = (table + index * MSIX_TABLE_ENTRY_SIZE).as_ptr::<u32>();
+                    core::array::from_fn(|dword| unsafe { entry.add(dword).read_volatile() })
+                })
+                .collect();
+
+            return Some(SavedInterruptState::MsiX {
+                function_masked: capability.is_function_masked(),
+                table,
+                entries,
+            });
+        }
+
+        let capability = self
+            .msi_capability()
+            .filter(|capability| capability.is_enabled())?;
+
+        Some(SavedInterruptState::Msi {
+            multiple_message_enable: capability.multiple_message_enable(),
+            address: capability.message_address(),
+            data: capability.message_data(),
+            mask_bits: capability.mask_bits(),
+        })
+    }
+
+    /// Programs and enables MSI or MSI-X again. Memory decoding has to be on already for the
+    /// MSI-X table.
+    pub fn restore_interrupt_state(&self, state: &SavedInterruptState) {
+        match state {
+            SavedInterruptState::Msi {
+                multiple_message_enable,
+                address,
+                data,
+                mask_bits,
+            } => {
+                let Some(capability) = self.msi_capability() else {
+                    return;
+                };
+
+                capability.set_message_address(*address);
+                capability.set_message_data(*data);
+
+                if let Some(mask_bits) = mask_bits {
+                    capability.set_mask_bits(*mask_bits);
+                }
+
+                capability.set_multiple_message_enable(*multiple_message_enable);
+                capability.set_enabled(true);
+            }
+            SavedInterruptState::MsiX {
+                function_masked,
+                table,
+                entries,
+            } => {
+                let Some(capability) = self.msix_capability() else {
+                    return;
+                };
+
+                capability.set_function_masked(true);
+                capability.set_enabled(true);
+
+                for (index, saved) in entries.iter().enumerate() {
+                    let entry = (*table + index as u64 * MSIX_TABLE_ENTRY_SIZE).as_mut_ptr::<u32>();
+
+                    for (dword, value) in saved.iter().enumerate() {
+                        unsafe { entry.add(dword).write_volatile(*value) };
+                    }
+                }
+
+                capability.set_function_masked(*function_masked);
+            }
+        }
+    }
+
+    /// Allocates up to `count` vectors using MSI-X if the device supports it, MSI otherwise.
+    #[allow(unused)]
+    pub fn enable_interrupts(
+        &self,
+        count: usize,
+        destination_apic_id: u8,
+        handler: IrqHandler,
+    ) -> Result<PciInterrupts, PciInterruptError> {
+        if self.msix_capability().is_some() {
+            self.enable_msix(count, destination_apic_id, handler)
+        } else {
+            self.enable_msi(count, destination_apic_id, handler)
+        }
+    }
+
+    /// Sets up MSI, rounding `count` down to what the device supports. The vectors are
+    /// allocated as one aligned block, the device selects one by modifying the low bits of the
+    /// message data.
+    pub fn enable_msi(
+        &self,
+        count: usize,
+        destination_apic_id: u8,
+        handler: IrqHandler,
+    ) -> Result<PciInterrupts, PciInterruptError> {
+        let capability = self
+            .msi_capability()
+            .ok_or(PciInterruptError::NoInterruptCapability)?;
+
+        if let Some(msix) = self.msix_capability() {
+            msix.set_enabled(false);
+        }
+
+        let supported = 1usize << capability.multiple_message_capable();
+        let count = count.clamp(1, supported);
+        let count = if count.is_power_of_two() {
+            count
+        } else {
+            count.next_power_of_two() >> 1
+        };
+
+        let first_vector =
+            apic::allocate_vectors(count).ok_or(PciInterruptError::NoVectorsAvailable)?;
+
+        register_vectors(first_vector, count, &handler);
+
+        capability.set_enabled(false);
+        capability.set_message_address(message_address(destination_apic_id));
+        capability.set_message_data(message_data(first_vector) as u16);
+        capability.set_multiple_message_enable(count.trailing_zeros() as u8);
+        capability.set_mask_bits(0);
+
+        self.enable_bus_master();
+        self.set_interrupt_disable(true);
+        capability.set_enabled(true);
+
+        debug!(
+            "Enabled {} MSI vector(s) starting at {} for {:?}",
+            count, first_vector, self
+        );
+
+        Ok(PciInterrupts {
+            kind: PciInterruptKind::Msi(capability),
+            first_vector,
+            count,
+        })
+    }
+
+    /// Sets up MSI-X with the first `count` table entries, each with its own vector.
+    pub fn enable_msix(
+        &self,
+        count: usize,
+        destination_apic_id: u8,
+        handler: IrqHandler,
+    ) -> Result<PciInterrupts, PciInterruptError> {
+        let capability = self
+            .msix_capability()
+            .ok_or(PciInterruptError::NoInterruptCapability)?;
+
+        let table_base = self
+            .bar(capability.table_bar())
+            .and_then(|bar| bar.map())
+            .ok_or(PciInterruptError::TableNotMapped)?;
+        let table = table_base + capability.table_offset() as u64;
+
+        if let Some(msi) = self.msi_capability() {
+            msi.set_enabled(false);
+        }
+
+        let count = count.clamp(1, capability.table_size() as usize);
+        let first_vector =
+            apic::allocate_vectors(count).ok_or(PciInterruptError::NoVectorsAvailable)?;
+
+        register_vectors(first_vector, count, &handler);
+
+        // Keep the whole function masked while the table is in an inconsistent state.
+        capability.set_function_masked(true);
+        capability.set_enabled(true);
+
+        for index in 0..capability.table_size() as u64 {
+            let entry = (table + index * MSIX_TABLE_ENTRY_SIZE).as_mut_ptr::<u32>();
+
+            unsafe {
+                if index < count as u64 {
+                    let address = message_address(destination_apic_id);
+
+                    entry.write_volatile(address as u32);
+                    entry.add(1).write_volatile((address >> 32) as u32);
+                    entry
+                        .add(2)
+                        .write_volatile(message_data(first_vector + index as u8));
+                    entry.add(3).write_volatile(0);
+                } else {
+                    entry.add(3).write_volatile(MSIX_VECTOR_CONTROL_MASKED);
+                }
+            }
+        }
+
+        self.enable_bus_master();
+        self.set_interrupt_disable(true);
+        capability.set_function_masked(false);
+
+        debug!(
+            "Enabled {} MSI-X vector(s) starting at {} for {:?}",
+            count, first_vector, self
+        );
+
+        Ok(PciInterrupts {
+            kind: PciInterruptKind::MsiX { capability, table },
+            first_vector,
+            count,
+        })
+    }
+}
@@ -1,116 +1,251 @@
 use crate::memory::map_physical_to_virtual;
-use crate::pci_express::device::PciDevice;
-use acpi::mcfg::PciConfigEntry;
+use crate::pci_express::config_space::PciConfigSpace;
+use crate::pci_express::device::{PciAddress, PciDevice};
+use crate::pci_express::driver::{PciDriver, PciProbeError};
 use acpi::{AcpiHandler, AcpiTables, PciConfigRegions};
 use alloc::alloc::Global;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec;
 use alloc::vec::Vec;
-use log::debug;
+use conquer_once::spin::OnceCell;
+use core::cmp::Reverse;
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
 
-mod device;
-mod device_capabilities;
+pub mod aer;
+mod bar;
+mod class;
+mod config_space;
+pub mod device;
+pub mod device_capabilities;
+pub mod driver;
+mod ids;
+pub mod interrupts;
+mod power;
 mod registers;
 
+pub static PCI: OnceCell<PCIe> = OnceCell::uninit();
+
+static DRIVERS: Spinlock<Vec<&'static dyn PciDriver>> = Spinlock::new(Vec::new());
+
+/// Scans the PCI hierarchy, makes it available through [`PCI`] and binds the drivers that
+/// were registered so far.
+pub fn init<H>(tables: &AcpiTables<H>) -> &'static PCIe
+where
+    H: AcpiHandler,
+{
+    let pci = PCI.get_or_init(|| {
+        let mut pcie = PCIe::new();
+        pcie.scan(tables);
+        pcie.enable_error_reporting();
+        pcie
+    });
+
+    pci.bind_drivers();
+    pci
+}
+
+/// Makes a driver available for binding. Drivers registered after the scan are bound to
+/// matching devices that don't have a driver yet right away.
+#[allow(unused)]
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    debug!("Registered PCI driver {}", driver.name());
+    DRIVERS.lock().push(driver);
+
+    if let Some(pci) = PCI.get() {
+        pci.bind_drivers();
+    }
+}
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// A function in the PCI hierarchy. Bridges have the functions on their secondary bus as
+/// children.
+pub struct PciTopologyNode {
+    pub device: PciDevice,
+    pub children: Vec<PciTopologyNode>,
+}
+
+impl PciTopologyNode {
+    fn visit<'a>(&'a self, depth: usize, visitor: &mut impl FnMut(&'a PciDevice, usize)) {
+        visitor(&self.device, depth);
+
+        for child in &self.children {
+            child.visit(depth + 1, visitor);
+        }
+    }
+}
+
 pub struct PCIe {
-    devices: Option<Vec<PciDevice>>,
+    topology: Vec<PciTopologyNode>,
+    bindings: Spinlock<BTreeMap<PciAddress, &'static dyn PciDriver>>,
 }
 
 impl PCIe {
     pub fn new() -> Self {
-        Self { devices: None }
+        Self {
+            topology: vec![],
+            bindings: Spinlock::new(BTreeMap::new()),
+        }
     }
 
-    fn check_device(
-        &mut self,
-        devices: &mut Vec<PciDevice>,
-        config_regions: &PciConfigRegions<Global>,
-        segment_group_number: u16,
-        bus: u8,
-        device: u8,
-        function: u8,
-        check_if_multiple_functions: bool,
-    ) {
-        let address =
-            match config_regions.physical_address(segment_group_number, bus, device, function) {
-                Some(address) => map_physical_to_virtual(address),
-                None => {
-                    debug!("No entry in MCFG that manages device.");
-                    return;
-                }
-            };
+    fn probe_function(
+        config_regions: Option<&PciConfigRegions<Global>>,
+        address: PciAddress,
+    ) -> Option<PciDevice> {
+        let config_space = match config_regions {
+            Some(config_regions) => config_regions
+                .physical_address(
+                    address.segment_group,
+                    address.bus,
+                    address.device,
+                    address.function,
+                )
+                .map(|physical_address| {
+                    PciConfigSpace::Ecam(map_physical_to_virtual(physical_address))
+                })?,
+            None if address.segment_group == 0 => PciConfigSpace::Legacy(address),
+            None => return None,
+        };
 
-        let pci_device = PciDevice::new(address);
+        let pci_device = PciDevice::new(config_space, address);
 
-        if !pci_device.exists() {
-            return;
-        }
+        pci_device.exists().then_some(pci_device)
+    }
 
+    fn scan_function(
+        &self,
+        config_regions: Option<&PciConfigRegions<Global>>,
+        scanned_buses: &mut BTreeSet<(u16, u8)>,
+        pci_device: PciDevice,
+    ) -> PciTopologyNode {
         debug!("Found device {:?}.", pci_device);
+        for capability in pci_device.capabilities() {
+            debug!("  {:?}", capability);
+        }
 
-        if check_if_multiple_functions && pci_device.has_multiple_functions() {
-            debug!("Device has multiple functions:");
-            let mut other_functions =
-                self.scan_device_for_functions(config_regions, segment_group_number, bus, device);
+        let mut children = vec![];
 
-            devices.append(&mut other_functions);
-            debug!("End of device's other functions.")
+        if let Some((secondary_bus, subordinate_bus)) = pci_device.bridge_bus_range() {
+            if secondary_bus == 0 || secondary_bus <= pci_device.address().bus {
+                warn!(
+                    "Bridge {} has no valid secondary bus assigned ({}), skipping",
+                    pci_device.address(),
+                    secondary_bus
+                );
+            } else {
+                debug!(
+                    "Bridge {} forwards buses {:02x}..={:02x}",
+                    pci_device.address(),
+                    secondary_bus,
+                    subordinate_bus
+                );
+
+                children = self.scan_bus(
+                    config_regions,
+                    scanned_buses,
+                    pci_device.address().segment_group,
+                    secondary_bus,
+                );
+            }
         }
 
-        devices.push(pci_device);
+        PciTopologyNode {
+            device: pci_device,
+            children,
+        }
     }
 
-    fn scan_device_for_functions(
-        &mut self,
-        config_regions: &PciConfigRegions<Global>,
-        segment_group_number: u16,
+    fn scan_device(
+        &self,
+        config_regions: Option<&PciConfigRegions<Global>>,
+        scanned_buses: &mut BTreeSet<(u16, u8)>,
+        segment_group: u16,
         bus: u8,
         device: u8,
-    ) -> Vec<PciDevice> {
-        let mut devices = vec![];
+    ) -> Vec<PciTopologyNode> {
+        let mut nodes = vec![];
 
-        for function in 1..8u8 {
-            self.check_device(
-                &mut devices,
-                config_regions,
-                segment_group_number,
-                bus,
-                device,
-                function,
-                false,
-            );
+        let Some(pci_device) = Self::probe_function(
+            config_regions,
+            PciAddress::new(segment_group, bus, device, 0),
+        ) else {
+            return nodes;
+        };
+
+        let has_multiple_functions = pci_device.has_multiple_functions();
+        nodes.push(self.scan_function(config_regions, scanned_buses, pci_device));
+
+        if has_multiple_functions {
+            for function in 1..FUNCTIONS_PER_DEVICE {
+                if let Some(pci_device) = Self::probe_function(
+                    config_regions,
+                    PciAddress::new(segment_group, bus, device, function),
+                ) {
+                    nodes.push(self.scan_function(config_regions, scanned_buses, pci_device));
+                }
+            }
         }
 
-        devices
+        nodes
     }
 
-    fn scan_pci_config_entry(
-        &mut self,
-        config_regions: &PciConfigRegions<Global>,
-        entry: PciConfigEntry,
-    ) -> Vec<PciDevice> {
-        let mut devices = vec![];
+    fn scan_bus(
+        &self,
+        config_regions: Option<&PciConfigRegions<Global>>,
+        scanned_buses: &mut BTreeSet<(u16, u8)>,
+        segment_group: u16,
+        bus: u8,
+    ) -> Vec<PciTopologyNode> {
+        if !scanned_buses.insert((segment_group, bus)) {
+            warn!(
+                "Bus {:04x}:{:02x} was already scanned, the bridge configuration loops",
+                segment_group, bus
+            );
+            return vec![];
+        }
+
+        (0..DEVICES_PER_BUS)
+            .flat_map(|device| {
+                self.scan_device(config_regions, scanned_buses, segment_group, bus, device)
+            })
+            .collect()
+    }
 
-        debug!(
-            "Scanning bus range {:?} for region with segment group {}, address {:08x}",
-            entry.bus_range, entry.segment_group, entry.physical_address
+    fn scan_segment_group(
+        &self,
+        config_regions: Option<&PciConfigRegions<Global>>,
+        scanned_buses: &mut BTreeSet<(u16, u8)>,
+        segment_group: u16,
+        root_bus: u8,
+    ) -> Vec<PciTopologyNode> {
+        // If the host bridge is a multi-function device, every function is a separate host
+        // bridge responsible for the bus with the same number as the function.
+        let host_bridge = Self::probe_function(
+            config_regions,
+            PciAddress::new(segment_group, root_bus, 0, 0),
         );
 
-        for bus in entry.bus_range {
-            for device in 0..31u8 {
-                self.check_device(
-                    &mut devices,
-                    config_regions,
-                    entry.segment_group,
-                    bus,
-                    device,
-                    0,
-                    true,
-                );
-            }
-        }
+        let root_buses = match host_bridge {
+            Some(host_bridge) if host_bridge.has_multiple_functions() => (0..FUNCTIONS_PER_DEVICE)
+                .filter(|function| {
+                    Self::probe_function(
+                        config_regions,
+                        PciAddress::new(segment_group, root_bus, 0, *function),
+                    )
+                    .is_some()
+                })
+                .map(|function| root_bus + function)
+                .collect(),
+            _ => vec![root_bus],
+        };
 
-        debug!("Done scanning config entry");
-        devices
+        // Everything else is found through the bridges on these.
+        root_buses
+            .into_iter()
+            .flat_map(|bus| self.scan_bus(config_regions, scanned_buses, segment_group, bus))
+            .collect()
     }
 
     pub fn scan<H>(&mut self, tables: &AcpiTables<H>)
@@ -118,25 +253,147 @@ impl PCIe {
         H: AcpiHandler,
     {
         let config_regions = match PciConfigRegions::new_in(tables, &Global) {
-            Ok(config_regions) => config_regions,
+            Ok(config_regions) => Some(config_regions),
             Err(e) => {
-                panic!("Couldn't get PCI config regions because of the following error. Make sure the device supports PCIe. {:#?}", e);
+                warn!("Couldn't get PCI config regions, falling back to the legacy configuration mechanism. Extended configuration space won't be available. {:#?}", e);
+                None
             }
         };
 
-        let mut devices = vec![];
-        for entry in config_regions.iter() {
-            let mut found_devices = self.scan_pci_config_entry(&config_regions, entry);
+        let mut scanned_buses = BTreeSet::new();
+        let mut topology = vec![];
+
+        match &config_regions {
+            Some(config_regions) => {
+                for entry in config_regions.iter() {
+                    debug!(
+                        "Scanning bus range {:?} for region with segment group {}, address {:08x}",
+                        entry.bus_range, entry.segment_group, entry.physical_address
+                    );
 
-            devices.append(&mut found_devices);
+                    let mut found_devices = self.scan_segment_group(
+                        Some(config_regions),
+                        &mut scanned_buses,
+                        entry.segment_group,
+                        *entry.bus_range.start(),
+                    );
+
+                    topology.append(&mut found_devices);
+                    debug!("Done scanning config entry");
+                }
+            }
+            None => {
+                topology = self.scan_segment_group(None, &mut scanned_buses, 0, 0);
+            }
         }
 
-        self.devices = Some(devices);
+        self.topology = topology;
         debug!("Done scanning for devices.");
+
+        self.log_topology();
+    }
+
+    fn log_topology(&self) {
+        info!("PCI topology:");
+
+        for node in &self.topology {
+            node.visit(0, &mut |device, depth| {
+                info!("{:indent$}{:?}", "", device, indent = depth * 2);
+            });
+        }
+    }
+
+    #[allow(unused)]
+    pub fn topology(&self) -> &[PciTopologyNode] {
+        &self.topology
+    }
+
+    /// All functions, in depth-first order.
+    #[allow(unused)]
+    pub fn devices(&self) -> Vec<&PciDevice> {
+        let mut devices = vec![];
+
+        for node in &self.topology {
+            node.visit(0, &mut |device, _| devices.push(device));
+        }
+
+        devices
+    }
+
+    #[allow(unused)]
+    pub fn device(&self, address: PciAddress) -> Option<&PciDevice> {
+        self.devices()
+            .into_iter()
+            .find(|device| device.address() == address)
+    }
+
+    /// Binds every device without a driver to the best matching registered driver. If a
+    /// driver's probe fails, the next best match gets a chance.
+    pub fn bind_drivers(&self) {
+        let drivers = DRIVERS.lock().clone();
+
+        for device in self.devices() {
+            if self.bindings.lock().contains_key(&device.address()) {
+                continue;
+            }
+
+            let mut candidates = drivers
+                .iter()
+                .filter_map(|driver| driver.match_score(device).map(|score| (score, *driver)))
+                .collect::<Vec<_>>();
+
+            // stable, so drivers registered earlier win ties
+            candidates.sort_by_key(|(score, _)| Reverse(*score));
+
+            for (_, driver) in candidates {
+                match driver.probe(device) {
+                    Ok(()) => {
+                        info!("Bound {} to {:?}", driver.name(), device);
+                        self.bindings.lock().insert(device.address(), driver);
+                        break;
+                    }
+                    Err(PciProbeError::NotSupported) => {}
+                    Err(e) => warn!(
+                        "Driver {} failed to probe {}: {:?}",
+                        driver.name(),
+                        device.address(),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub fn unbind(&self, address: PciAddress) {
+        let driver = self.bindings.lock().remove(&address);
+
+        if let (Some(driver), Some(device)) = (driver, self.device(address)) {
+            driver.remove(device);
+            info!("Unbound {} from {}", driver.name(), address);
+        }
+    }
+
+    #[allow(unused)]
+    pub fn driver_name(&self, address: PciAddress) -> Option<&'static str> {
+        self.bindings
+            .lock()
+            .get(&address)
+            .map(|driver| driver.name())
     }
 
+    /// The devices that are currently bound to the driver with the given name.
     #[allow(unused)]
-    pub fn devices(self) -> Option<Vec<PciDevice>> {
-        self.devices
+    pub fn bound_devices(&self, driver_name: &str) -> Vec<&PciDevice> {
+        let bindings = self.bindings.lock();
+
+        self.devices()
+            .into_iter()
+            .filter(|device| {
+                bindings
+                    .get(&device.address())
+                    .map_or(false, |driver| driver.name() == driver_name)
+            })
+            .collect()
     }
 }
@@ -0,0 +1,286 @@
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::device_capabilities::{
+    PciDeviceCapability, PciExpressCapability, PciPowerManagementCapability,
+};
+use crate::pci_express::interrupts::SavedInterruptState;
+use crate::pci_express::registers::ConfigRegionHeaderRegister;
+use crate::pit::busy_wait;
+use bit_field::BitField;
+use core::time::Duration;
+use log::{debug, warn};
+
+// Delays required by the PCI Express Base and PCI Power Management specifications.
+const D3HOT_TO_D0_DELAY: Duration = Duration::from_millis(10);
+const D2_DELAY: Duration = Duration::from_micros(200);
+const FUNCTION_LEVEL_RESET_DELAY: Duration = Duration::from_millis(100);
+const SECONDARY_BUS_RESET_ASSERT_DELAY: Duration = Duration::from_millis(2);
+const SECONDARY_BUS_RESET_DELAY: Duration = Duration::from_millis(100);
+const TRANSACTIONS_PENDING_TIMEOUT: Duration = Duration::from_millis(100);
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Returned by functions that aren't ready yet when configuration request retry status
+// software visibility is enabled.
+const CRS_VENDOR_ID: u16 = 0x0001;
+
+const BRIDGE_CONTROL_SECONDARY_BUS_RESET: usize = 6;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciPowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PciPowerState {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            Self::D0 => 0,
+            Self::D1 => 1,
+            Self::D2 => 2,
+            Self::D3Hot => 3,
+        }
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum PciPowerError {
+    NoPowerManagement,
+    StateNotSupported(PciPowerState),
+    /// Only D0 can be reached from D3hot.
+    InvalidTransition(PciPowerState, PciPowerState),
+    NotReady,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum PciResetError {
+    FunctionLevelResetNotSupported,
+    NotABridge,
+    NotReady,
+}
+
+/// The configuration that's lost when a function is reset: the header, MSI or MSI-X and the
+/// PCI Express Device Control registers.
+pub struct PciSavedState {
+    header: [u32; 16],
+    interrupts: Option<SavedInterruptState>,
+    device_control: Option<(u16, Option<u16>)>,
+}
+
+#[allow(unused)]
+impl PciDevice {
+    fn power_management_capability(&self) -> Option<PciPowerManagementCapability> {
+        self.capabilities().find_map(|capability| match capability {
+            PciDeviceCapability::PowerManagement(capability) => Some(capability),
+            _ => None,
+        })
+    }
+
+    pub fn pci_express_capability(&self) -> Option<PciExpressCapability> {
+        self.capabilities().find_map(|capability| match capability {
+            PciDeviceCapability::PciExpress(capability) => Some(capability),
+            _ => None,
+        })
+    }
+
+    pub fn save_state(&self) -> PciSavedState {
+        let mut header = [0u32; 16];
+
+        for (index, dword) in header.iter_mut().enumerate() {
+            *dword = self.read_config(index as u16 * 4);
+        }
+
+        PciSavedState {
+            header,
+            interrupts: self.save_interrupt_state(),
+            device_control: self
+                .pci_express_capability()
+                .map(|capability| (capability.device_control(), capability.device_control_2())),
+        }
+    }
+
+    /// Writes a saved state back. The command register goes after the rest of the header, so
+    /// decoding only turns back on once the BARs are in place again, and before MSI-X, whose
+    /// table is in memory space.
+    pub fn restore_state(&self, state: &PciSavedState) {
+        // the identification and class code dwords are read-only
+        for index in 3..state.header.len() {
+            self.write_config(index as u16 * 4, state.header[index]);
+        }
+
+        if let (Some(capability), Some((device_control, device_control_2))) =
+            (self.pci_express_capability(), state.device_control)
+        {
+            capability.set_device_control(device_control);
+
+            if let Some(device_control_2) = device_control_2 {
+                capability.set_device_control_2(device_control_2);
+            }
+        }
+
+        self.write_register(ConfigRegionHeaderRegister::Command, state.header[1] as u16);
+
+        if let Some(interrupts) = &state.interrupts {
+            self.restore_interrupt_state(interrupts);
+        }
+    }
+
+    pub fn power_state(&self) -> Option<PciPowerState> {
+        self.power_management_capability()
+            .map(|capability| PciPowerState::from_bits(capability.power_state()))
+    }
+
+    pub fn set_power_state(&self, state: PciPowerState) -> Result<(), PciPowerError> {
+        let capability = self
+            .power_management_capability()
+            .ok_or(PciPowerError::NoPowerManagement)?;
+
+        let current = PciPowerState::from_bits(capability.power_state());
+
+        if current == state {
+            return Ok(());
+        }
+
+        match state {
+            PciPowerState::D1 if !capability.supports_d1() => {
+                return Err(PciPowerError::StateNotSupported(state))
+            }
+            PciPowerState::D2 if !capability.supports_d2() => {
+                return Err(PciPowerError::StateNotSupported(state))
+            }
+            _ => {}
+        }
+
+        if current == PciPowerState::D3Hot && state != PciPowerState::D0 {
+            return Err(PciPowerError::InvalidTransition(current, state));
+        }
+
+        // Leaving D3hot resets the function unless it says otherwise.
+        let saved_state = (current == PciPowerState::D3Hot && !capability.no_soft_reset())
+            .then(|| self.save_state());
+
+        capability.set_power_state(state.bits());
+
+        if current == PciPowerState::D3Hot || state == PciPowerState::D3Hot {
+            busy_wait(D3HOT_TO_D0_DELAY);
+        } else if current == PciPowerState::D2 || state == PciPowerState::D2 {
+            busy_wait(D2_DELAY);
+        }
+
+        if let Some(saved_state) = saved_state {
+            self.wait_for_ready(DEFAULT_READY_TIMEOUT)
+                .map_err(|_| PciPowerError::NotReady)?;
+            self.restore_state(&saved_state);
+        }
+
+        debug!("{} went from {:?} to {:?}", self.address(), current, state);
+
+        Ok(())
+    }
+
+    /// Polls the vendor ID until the function responds to config requests again, which it
+    /// doesn't right after a reset.
+    pub fn wait_for_ready(&self, timeout: Duration) -> Result<(), PciResetError> {
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let vendor_id = self.vendor_id();
+
+            if self.exists() && vendor_id != CRS_VENDOR_ID {
+                return Ok(());
+            }
+
+            if waited >= timeout {
+                warn!(
+                    "{} didn't become ready within {:?}",
+                    self.address(),
+                    timeout
+                );
+                return Err(PciResetError::NotReady);
+            }
+
+            busy_wait(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+    }
+
+    pub fn supports_function_level_reset(&self) -> bool {
+        self.pci_express_capability().map_or(false, |capability| {
+            capability.supports_function_level_reset()
+        })
+    }
+
+    /// Resets just this function through the PCI Express Device Control register, keeping
+    /// its configuration.
+    pub fn function_level_reset(&self) -> Result<(), PciResetError> {
+        let capability = self
+            .pci_express_capability()
+            .filter(|capability| capability.supports_function_level_reset())
+            .ok_or(PciResetError::FunctionLevelResetNotSupported)?;
+
+        let saved_state = self.save_state();
+
+        // Stop new requests and give outstanding ones a chance to complete.
+        self.set_bus_master(false);
+
+        let mut waited = Duration::ZERO;
+        while capability.transactions_pending() && waited < TRANSACTIONS_PENDING_TIMEOUT {
+            busy_wait(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+
+        if capability.transactions_pending() {
+            warn!(
+                "{} still has transactions pending, resetting anyway",
+                self.address()
+            );
+        }
+
+        capability.initiate_function_level_reset();
+        busy_wait(FUNCTION_LEVEL_RESET_DELAY);
+
+        self.wait_for_ready(DEFAULT_READY_TIMEOUT)?;
+        self.restore_state(&saved_state);
+
+        debug!("{} was reset", self.address());
+
+        Ok(())
+    }
+
+    /// Resets everything below a bridge by toggling the Secondary Bus Reset bit. The
+    /// functions on the secondary bus have to be reconfigured by their drivers afterwards.
+    pub fn secondary_bus_reset(&self) -> Result<(), PciResetError> {
+        if !self.is_bridge() {
+            return Err(PciResetError::NotABridge);
+        }
+
+        let mut bridge_control =
+            self.read_register::<u16>(ConfigRegionHeaderRegister::BridgeControl);
+
+        bridge_control.set_bit(BRIDGE_CONTROL_SECONDARY_BUS_RESET, true);
+        self.write_register(ConfigRegionHeaderRegister::BridgeControl, bridge_control);
+        busy_wait(SECONDARY_BUS_RESET_ASSERT_DELAY);
+
+        bridge_control.set_bit(BRIDGE_CONTROL_SECONDARY_BUS_RESET, false);
+        self.write_register(ConfigRegionHeaderRegister::BridgeControl, bridge_control);
+        busy_wait(SECONDARY_BUS_RESET_DELAY);
+
+        debug!("Reset the bus behind {}", self.address());
+
+        Ok(())
+    }
+}
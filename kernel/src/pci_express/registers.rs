@@ -38,6 +38,14 @@ pub enum ConfigRegionHeaderRegister {
     InterruptPIN,
     MinGrant,
     MaxLatency,
+
+    // Header Type 1
+    PrimaryBusNumber,
+    SecondaryBusNumber,
+    SubordinateBusNumber,
+    SecondaryLatencyTimer,
+
+    BridgeControl,
 }
 
 impl ConfigRegionHeaderRegister {
@@ -70,6 +78,11 @@ impl ConfigRegionHeaderRegister {
             Self::InterruptPIN => (0x3C, 8..=15),
             Self::MinGrant => (0x3C, 16..=23),
             Self::MaxLatency => (0x3C, 24..=31),
+            Self::PrimaryBusNumber => (0x18, 0..=7),
+            Self::SecondaryBusNumber => (0x18, 8..=15),
+            Self::SubordinateBusNumber => (0x18, 16..=23),
+            Self::SecondaryLatencyTimer => (0x18, 24..=31),
+            Self::BridgeControl => (0x3C, 16..=31),
         }
     }
 }
@@ -0,0 +1,65 @@
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const SPEAKER_CONTROL_PORT: u16 = 0x61;
+
+// channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+const SPEAKER_CONTROL_GATE: u8 = 1 << 0;
+const SPEAKER_CONTROL_SPEAKER: u8 = 1 << 1;
+const SPEAKER_CONTROL_OUTPUT: u8 = 1 << 5;
+
+static PIT_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// Counts `ticks` PIT ticks down on channel 2, which isn't wired to an interrupt, and spins
+/// until the output goes high. Interrupt handlers busy-wait too, so interrupts are off while
+/// the lock is held.
+fn wait_ticks(ticks: u16) {
+    without_interrupts(|| wait_ticks_locked(ticks));
+}
+
+fn wait_ticks_locked(ticks: u16) {
+    let _guard = PIT_LOCK.lock();
+
+    let mut speaker_control = Port::<u8>::new(SPEAKER_CONTROL_PORT);
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut data = Port::<u8>::new(CHANNEL_2_DATA_PORT);
+
+    unsafe {
+        // gate low while programming, speaker off
+        let control = speaker_control.read() & !(SPEAKER_CONTROL_GATE | SPEAKER_CONTROL_SPEAKER);
+        speaker_control.write(control);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(ticks as u8);
+        data.write((ticks >> 8) as u8);
+
+        // a rising edge on the gate starts counting
+        speaker_control.write(control | SPEAKER_CONTROL_GATE);
+
+        while speaker_control.read() & SPEAKER_CONTROL_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+
+        speaker_control.write(control);
+    }
+}
+
+/// Busy-waits for at least `duration`. Only meant for short hardware delays before a proper
+/// clock is available.
+pub fn busy_wait(duration: Duration) {
+    let mut ticks = (duration.as_nanos() * PIT_FREQUENCY as u128).div_ceil(1_000_000_000);
+
+    while ticks > 0 {
+        let chunk = ticks.min(u16::MAX as u128);
+        wait_ticks(chunk as u16);
+        ticks -= chunk;
+    }
+}
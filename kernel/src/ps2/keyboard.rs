@@ -0,0 +1,303 @@
+use crate::apic;
+use crate::ps2::keymap::{translate, GermanKeymap, Keymap, UsKeymap};
+use crate::ps2::scancode::{KeyCode, KeyState, ScancodeDecoder, ScancodeSet};
+use crate::ps2::{read_data, Ps2Controller, Ps2Error, Ps2Port, DEVICE_ACK, TIMEOUT};
+use crate::ring_buffer::RingBuffer;
+use alloc::sync::Arc;
+use conquer_once::spin::OnceCell;
+use log::{debug, info, trace, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const KEYBOARD_IRQ: u8 = 1;
+
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_SCANCODE_SET: u8 = 0xF0;
+const COMMAND_ENABLE_SCANNING: u8 = 0xF4;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+const EVENT_BUFFER_SIZE: usize = 256;
+
+pub static KEYMAPS: [&dyn Keymap; 2] = [&UsKeymap, &GermanKeymap];
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_control: bool,
+    pub right_control: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub left_gui: bool,
+    pub right_gui: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+#[allow(unused)]
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn control(&self) -> bool {
+        self.left_control || self.right_control
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt
+    }
+
+    pub fn alt_gr(&self) -> bool {
+        self.right_alt
+    }
+
+    pub fn gui(&self) -> bool {
+        self.left_gui || self.right_gui
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+
+        leds
+    }
+
+    /// Updates the state for a key event, returns whether a lock key was toggled.
+    fn update(&mut self, key: KeyCode, state: KeyState) -> bool {
+        let pressed = state == KeyState::Pressed;
+
+        match key {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftControl => self.left_control = pressed,
+            KeyCode::RightControl => self.right_control = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::LeftGui => self.left_gui = pressed,
+            KeyCode::RightGui => self.right_gui = pressed,
+            KeyCode::CapsLock if pressed => {
+                self.caps_lock = !self.caps_lock;
+                return true;
+            }
+            KeyCode::NumLock if pressed => {
+                self.num_lock = !self.num_lock;
+                return true;
+            }
+            KeyCode::ScrollLock if pressed => {
+                self.scroll_lock = !self.scroll_lock;
+                return true;
+            }
+            _ => {}
+        }
+
+        false
+    }
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub state: KeyState,
+    /// The modifiers after this event was applied.
+    pub modifiers: Modifiers,
+    /// The character the key produces in the current keymap, only set for presses.
+    pub character: Option<char>,
+}
+
+/// How far updating the LEDs got. The command and the LED state are sent one at a time, each
+/// once the keyboard acknowledged the byte before.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum LedUpdate {
+    Idle,
+    CommandSent,
+    LedsSent,
+}
+
+struct Keyboard {
+    controller: &'static Ps2Controller,
+    decoder: ScancodeDecoder,
+    modifiers: Modifiers,
+    keymap: &'static dyn Keymap,
+    // The LED state that still has to be sent.
+    pending_leds: Option<u8>,
+    led_update: LedUpdate,
+    events: RingBuffer<KeyEvent, EVENT_BUFFER_SIZE>,
+}
+
+static KEYBOARD: OnceCell<Spinlock<Keyboard>> = OnceCell::uninit();
+
+impl Keyboard {
+    /// Sends the set LEDs command if there's LED state to send and no update in progress. As
+    /// this runs in the interrupt handler, nothing is sent unless the controller can take it
+    /// right away, it's tried again with the next key.
+    fn update_leds(&mut self) {
+        if self.led_update == LedUpdate::Idle
+            && self.pending_leds.is_some()
+            && self.controller.try_write_first_port(COMMAND_SET_LEDS)
+        {
+            self.led_update = LedUpdate::CommandSent;
+        }
+    }
+
+    fn handle_byte(&mut self, byte: u8) {
+        if byte == DEVICE_ACK {
+            self.led_update = if self.led_update == LedUpdate::CommandSent {
+                let leds = self.pending_leds.take().unwrap_or(self.modifiers.leds());
+
+                if self.controller.try_write_first_port(leds) {
+                    LedUpdate::LedsSent
+                } else {
+                    self.pending_leds = Some(leds);
+                    LedUpdate::Idle
+                }
+            } else {
+                LedUpdate::Idle
+            };
+
+            // The locks may have been toggled again meanwhile.
+            self.update_leds();
+            return;
+        }
+
+        let Some((key, state)) = self.decoder.feed(byte) else {
+            return;
+        };
+
+        if self.modifiers.update(key, state) {
+            self.pending_leds = Some(self.modifiers.leds());
+        }
+
+        self.update_leds();
+
+        let character = (state == KeyState::Pressed)
+            .then(|| translate(self.keymap, key, &self.modifiers))
+            .flatten();
+
+        let event = KeyEvent {
+            key,
+            state,
+            modifiers: self.modifiers,
+            character,
+        };
+
+        trace!("{:?}", event);
+        self.events.push_overwrite(event);
+    }
+}
+
+/// Asks for scancode set 2 and returns the set the keyboard ended up using, as some only
+/// support set 1.
+fn select_scancode_set(controller: &Ps2Controller) -> Result<ScancodeSet, Ps2Error> {
+    if let Err(e) = controller
+        .device_command(Ps2Port::First, COMMAND_SCANCODE_SET)
+        .and_then(|_| controller.device_command(Ps2Port::First, 2))
+    {
+        debug!("Keyboard refused scancode set 2: {:?}", e);
+    }
+
+    controller.device_command(Ps2Port::First, COMMAND_SCANCODE_SET)?;
+    controller.device_command(Ps2Port::First, 0)?;
+
+    match controller.read_device(Ps2Port::First, TIMEOUT)? {
+        1 => Ok(ScancodeSet::Set1),
+        2 => Ok(ScancodeSet::Set2),
+        other => Err(Ps2Error::UnexpectedResponse(other)),
+    }
+}
+
+pub fn init(controller: &'static Ps2Controller) -> Result<(), Ps2Error> {
+    controller.reset_device(Ps2Port::First)?;
+
+    let set = select_scancode_set(controller)?;
+    controller.device_command(Ps2Port::First, COMMAND_SET_LEDS)?;
+    controller.device_command(Ps2Port::First, 0)?;
+    controller.device_command(Ps2Port::First, COMMAND_ENABLE_SCANNING)?;
+
+    KEYBOARD.get_or_init(|| {
+        Spinlock::new(Keyboard {
+            controller,
+            decoder: ScancodeDecoder::new(set),
+            modifiers: Modifiers::default(),
+            keymap: KEYMAPS[0],
+            pending_leds: None,
+            led_update: LedUpdate::Idle,
+            events: RingBuffer::new(),
+        })
+    });
+
+    let handler = Arc::new(|_| {
+        let Some(byte) = read_data(Ps2Port::First) else {
+            return;
+        };
+
+        if let Some(keyboard) = KEYBOARD.get() {
+            keyboard.lock().handle_byte(byte);
+        }
+    });
+
+    let Some(vector) = apic::register_isa_irq_handler(KEYBOARD_IRQ, handler) else {
+        warn!("No vector available for the keyboard");
+        return Ok(());
+    };
+
+    controller.set_interrupt(Ps2Port::First, true)?;
+
+    info!(
+        "PS/2 keyboard initialized, scancode {:?}, vector {}",
+        set, vector
+    );
+
+    Ok(())
+}
+
+fn with_keyboard<R>(f: impl FnOnce(&mut Keyboard) -> R) -> Option<R> {
+    let keyboard = KEYBOARD.get()?;
+
+    Some(without_interrupts(|| f(&mut keyboard.lock())))
+}
+
+#[allow(unused)]
+pub fn read_event() -> Option<KeyEvent> {
+    with_keyboard(|keyboard| keyboard.events.pop()).flatten()
+}
+
+/// The next typed character, skipping releases and keys without one.
+#[allow(unused)]
+pub fn read_char() -> Option<char> {
+    with_keyboard(|keyboard| {
+        while let Some(event) = keyboard.events.pop() {
+            if event.character.is_some() {
+                return event.character;
+            }
+        }
+
+        None
+    })
+    .flatten()
+}
+
+#[allow(unused)]
+pub fn set_keymap(keymap: &'static dyn Keymap) {
+    with_keyboard(|keyboard| keyboard.keymap = keymap);
+    info!("Keyboard layout set to {}", keymap.name());
+}
+
+#[allow(unused)]
+pub fn keymap(name: &str) -> Option<&'static dyn Keymap> {
+    KEYMAPS.iter().copied().find(|keymap| keymap.name() == name)
+}
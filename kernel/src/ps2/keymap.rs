@@ -0,0 +1,184 @@
+use crate::ps2::keyboard::Modifiers;
+use crate::ps2::scancode::KeyCode;
+
+/// A keyboard layout, mapping physical keys to the characters printed on them.
+pub trait Keymap: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The character a key produces on its own, with shift and with AltGr. `None` for keys
+    /// that are the same in every layout, e.g. Enter or the numpad.
+    fn characters(&self, key: KeyCode) -> Option<(char, char, Option<char>)>;
+}
+
+/// The character a key press produces with the given modifiers, if any.
+pub fn translate(keymap: &dyn Keymap, key: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    use KeyCode::*;
+
+    let numpad_digit = |digit: char| modifiers.num_lock.then_some(digit);
+
+    match key {
+        Enter | NumpadEnter => return Some('\n'),
+        Tab => return Some('\t'),
+        Backspace => return Some('\x08'),
+        Escape => return Some('\x1b'),
+        Space => return Some(' '),
+        NumpadDivide => return Some('/'),
+        NumpadMultiply => return Some('*'),
+        NumpadMinus => return Some('-'),
+        NumpadPlus => return Some('+'),
+        NumpadPeriod => return numpad_digit('.'),
+        Numpad0 => return numpad_digit('0'),
+        Numpad1 => return numpad_digit('1'),
+        Numpad2 => return numpad_digit('2'),
+        Numpad3 => return numpad_digit('3'),
+        Numpad4 => return numpad_digit('4'),
+        Numpad5 => return numpad_digit('5'),
+        Numpad6 => return numpad_digit('6'),
+        Numpad7 => return numpad_digit('7'),
+        Numpad8 => return numpad_digit('8'),
+        Numpad9 => return numpad_digit('9'),
+        _ => {}
+    }
+
+    let (normal, shifted, alt_gr) = keymap.characters(key)?;
+
+    if modifiers.alt_gr() {
+        return alt_gr;
+    }
+
+    // Caps lock only affects letters, and shift undoes it.
+    let shift = if normal.is_alphabetic() {
+        modifiers.shift() != modifiers.caps_lock
+    } else {
+        modifiers.shift()
+    };
+
+    let character = if shift { shifted } else { normal };
+
+    if modifiers.control() && character.is_ascii_alphabetic() {
+        return Some((character.to_ascii_uppercase() as u8 & 0x1F) as char);
+    }
+
+    Some(character)
+}
+
+pub struct UsKeymap;
+
+impl Keymap for UsKeymap {
+    fn name(&self) -> &'static str {
+        "us"
+    }
+
+    fn characters(&self, key: KeyCode) -> Option<(char, char, Option<char>)> {
+        use KeyCode::*;
+
+        let (normal, shifted) = match key {
+            Backtick => ('`', '~'),
+            Key1 => ('1', '!'),
+            Key2 => ('2', '@'),
+            Key3 => ('3', '#'),
+            Key4 => ('4', '$'),
+            Key5 => ('5', '%'),
+            Key6 => ('6', '^'),
+            Key7 => ('7', '&'),
+            Key8 => ('8', '*'),
+            Key9 => ('9', '('),
+            Key0 => ('0', ')'),
+            Minus => ('-', '_'),
+            Equals => ('=', '+'),
+            LeftBracket => ('[', '{'),
+            RightBracket => (']', '}'),
+            Backslash | NonUsBackslash => ('\\', '|'),
+            Semicolon => (';', ':'),
+            Quote => ('\'', '"'),
+            Comma => (',', '<'),
+            Period => ('.', '>'),
+            Slash => ('/', '?'),
+            _ => return letter(key).map(|letter| (letter, letter.to_ascii_uppercase(), None)),
+        };
+
+        Some((normal, shifted, None))
+    }
+}
+
+/// German QWERTZ layout. Dead keys produce their accent right away.
+pub struct GermanKeymap;
+
+impl Keymap for GermanKeymap {
+    fn name(&self) -> &'static str {
+        "de"
+    }
+
+    fn characters(&self, key: KeyCode) -> Option<(char, char, Option<char>)> {
+        use KeyCode::*;
+
+        Some(match key {
+            Backtick => ('^', '°', None),
+            Key1 => ('1', '!', None),
+            Key2 => ('2', '"', Some('²')),
+            Key3 => ('3', '§', Some('³')),
+            Key4 => ('4', '$', None),
+            Key5 => ('5', '%', None),
+            Key6 => ('6', '&', None),
+            Key7 => ('7', '/', Some('{')),
+            Key8 => ('8', '(', Some('[')),
+            Key9 => ('9', ')', Some(']')),
+            Key0 => ('0', '=', Some('}')),
+            Minus => ('ß', '?', Some('\\')),
+            Equals => ('´', '`', None),
+            LeftBracket => ('ü', 'Ü', None),
+            RightBracket => ('+', '*', Some('~')),
+            Backslash => ('#', '\'', None),
+            Semicolon => ('ö', 'Ö', None),
+            Quote => ('ä', 'Ä', None),
+            NonUsBackslash => ('<', '>', Some('|')),
+            Comma => (',', ';', None),
+            Period => ('.', ':', None),
+            Slash => ('-', '_', None),
+            Q => ('q', 'Q', Some('@')),
+            E => ('e', 'E', Some('€')),
+            M => ('m', 'M', Some('µ')),
+            Y => ('z', 'Z', None),
+            Z => ('y', 'Y', None),
+            _ => {
+                let letter = letter(key)?;
+                (letter, letter.to_ascii_uppercase(), None)
+            }
+        })
+    }
+}
+
+/// The letter on a key in the US layout.
+fn letter(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+
+    Some(match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    })
+}
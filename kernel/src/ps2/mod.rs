@@ -0,0 +1,342 @@
+use crate::pit::busy_wait;
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use core::time::Duration;
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub mod keyboard;
+pub mod keymap;
+pub mod mouse;
+pub mod scancode;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: usize = 0;
+const STATUS_INPUT_FULL: usize = 1;
+const STATUS_SECOND_PORT_DATA: usize = 5;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xA7;
+const COMMAND_ENABLE_SECOND_PORT: u8 = 0xA8;
+const COMMAND_TEST_SECOND_PORT: u8 = 0xA9;
+const COMMAND_TEST_CONTROLLER: u8 = 0xAA;
+const COMMAND_TEST_FIRST_PORT: u8 = 0xAB;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xAD;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xAE;
+const COMMAND_WRITE_SECOND_PORT: u8 = 0xD4;
+
+const CONFIG_FIRST_PORT_INTERRUPT: usize = 0;
+const CONFIG_SECOND_PORT_INTERRUPT: usize = 1;
+const CONFIG_SECOND_PORT_CLOCK_DISABLED: usize = 5;
+const CONFIG_TRANSLATION: usize = 6;
+
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+pub const DEVICE_ACK: u8 = 0xFA;
+pub const DEVICE_RESEND: u8 = 0xFE;
+pub const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+const DEVICE_COMMAND_RETRIES: usize = 3;
+
+pub const TIMEOUT: Duration = Duration::from_millis(100);
+// Resetting a device takes a while, it has to run its self test.
+pub const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+const POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum Ps2Error {
+    Timeout,
+    ControllerTestFailed(u8),
+    PortTestFailed(Ps2Port, u8),
+    NoAck(u8),
+    UnexpectedResponse(u8),
+}
+
+/// The i8042 PS/2 controller. The keyboard is on the first port, the mouse (if any) on the
+/// second.
+pub struct Ps2Controller {
+    has_second_port: bool,
+    // Commands and their responses go through the same two ports for both devices.
+    lock: Spinlock<()>,
+}
+
+pub static PS2_CONTROLLER: OnceCell<Ps2Controller> = OnceCell::uninit();
+
+/// Initializes the controller, the keyboard and the mouse. Logs and gives up if there's no
+/// controller.
+pub fn init() {
+    let controller = match Ps2Controller::new() {
+        Ok(controller) => PS2_CONTROLLER.get_or_init(|| controller),
+        Err(e) => {
+            warn!("No usable PS/2 controller: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = keyboard::init(controller) {
+        warn!("Couldn't initialize the PS/2 keyboard: {:?}", e);
+    }
+
+    if controller.has_second_port() {
+        if let Err(e) = mouse::init(controller) {
+            warn!("Couldn't initialize the PS/2 mouse: {:?}", e);
+        }
+    }
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn wait_until(condition: impl Fn() -> bool, timeout: Duration) -> Result<(), Ps2Error> {
+    let mut waited = Duration::ZERO;
+
+    while !condition() {
+        if waited >= timeout {
+            return Err(Ps2Error::Timeout);
+        }
+
+        busy_wait(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    }
+
+    Ok(())
+}
+
+fn write_port(port: u16, value: u8) -> Result<(), Ps2Error> {
+    wait_until(|| !status().get_bit(STATUS_INPUT_FULL), TIMEOUT)?;
+
+    unsafe { Port::<u8>::new(port).write(value) };
+
+    Ok(())
+}
+
+/// Waits for a byte from the device on `port`. Bytes from the other port that arrive
+/// meanwhile are dropped, as nothing can handle them while the controller is locked.
+fn read_data_timeout(port: Ps2Port, timeout: Duration) -> Result<u8, Ps2Error> {
+    let mut waited = Duration::ZERO;
+
+    loop {
+        let status = status();
+
+        if status.get_bit(STATUS_OUTPUT_FULL) {
+            let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+
+            if status.get_bit(STATUS_SECOND_PORT_DATA) == (port == Ps2Port::Second) {
+                return Ok(byte);
+            }
+
+            continue;
+        }
+
+        if waited >= timeout {
+            return Err(Ps2Error::Timeout);
+        }
+
+        busy_wait(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    }
+}
+
+/// Reads a byte the device on `port` sent without waiting, for interrupt handlers.
+pub fn read_data(port: Ps2Port) -> Option<u8> {
+    let status = status();
+
+    (status.get_bit(STATUS_OUTPUT_FULL)
+        && status.get_bit(STATUS_SECOND_PORT_DATA) == (port == Ps2Port::Second))
+        .then(|| unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+fn flush_output_buffer() {
+    while status().get_bit(STATUS_OUTPUT_FULL) {
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+}
+
+#[allow(unused)]
+impl Ps2Controller {
+    fn new() -> Result<Self, Ps2Error> {
+        let controller = Self {
+            has_second_port: false,
+            lock: Spinlock::new(()),
+        };
+
+        controller.command(COMMAND_DISABLE_FIRST_PORT)?;
+        controller.command(COMMAND_DISABLE_SECOND_PORT)?;
+        flush_output_buffer();
+
+        // Interrupts stay off until the drivers are ready, responses are polled meanwhile.
+        let mut config = controller.read_config()?;
+        config.set_bit(CONFIG_FIRST_PORT_INTERRUPT, false);
+        config.set_bit(CONFIG_SECOND_PORT_INTERRUPT, false);
+        config.set_bit(CONFIG_TRANSLATION, false);
+        controller.write_config(config)?;
+
+        let result = controller.command_with_response(COMMAND_TEST_CONTROLLER)?;
+        if result != CONTROLLER_TEST_PASSED {
+            return Err(Ps2Error::ControllerTestFailed(result));
+        }
+
+        // The self test may reset the configuration.
+        controller.write_config(config)?;
+
+        // If the second port's clock turns on when it's enabled, the controller has one.
+        controller.command(COMMAND_ENABLE_SECOND_PORT)?;
+        let has_second_port = !controller
+            .read_config()?
+            .get_bit(CONFIG_SECOND_PORT_CLOCK_DISABLED);
+        controller.command(COMMAND_DISABLE_SECOND_PORT)?;
+
+        let result = controller.command_with_response(COMMAND_TEST_FIRST_PORT)?;
+        if result != PORT_TEST_PASSED {
+            return Err(Ps2Error::PortTestFailed(Ps2Port::First, result));
+        }
+
+        let has_second_port = has_second_port && {
+            let result = controller.command_with_response(COMMAND_TEST_SECOND_PORT)?;
+
+            if result != PORT_TEST_PASSED {
+                warn!("PS/2 second port test failed with {:#x}", result);
+            }
+
+            result == PORT_TEST_PASSED
+        };
+
+        controller.command(COMMAND_ENABLE_FIRST_PORT)?;
+        if has_second_port {
+            controller.command(COMMAND_ENABLE_SECOND_PORT)?;
+        }
+
+        info!(
+            "PS/2 controller initialized, {}",
+            if has_second_port {
+                "two ports"
+            } else {
+                "one port"
+            }
+        );
+
+        Ok(Self {
+            has_second_port,
+            ..controller
+        })
+    }
+
+    pub fn has_second_port(&self) -> bool {
+        self.has_second_port
+    }
+
+    /// Runs `f` holding the controller lock. Interrupts are off meanwhile, so the keyboard
+    /// and mouse handlers can't deadlock on it.
+    fn locked<R>(&self, f: impl FnOnce() -> R) -> R {
+        without_interrupts(|| {
+            let _guard = self.lock.lock();
+            f()
+        })
+    }
+
+    fn command(&self, command: u8) -> Result<(), Ps2Error> {
+        self.locked(|| write_port(COMMAND_PORT, command))
+    }
+
+    fn command_with_response(&self, command: u8) -> Result<u8, Ps2Error> {
+        self.locked(|| {
+            write_port(COMMAND_PORT, command)?;
+            read_data_timeout(Ps2Port::First, TIMEOUT)
+        })
+    }
+
+    fn read_config(&self) -> Result<u8, Ps2Error> {
+        self.command_with_response(COMMAND_READ_CONFIG)
+    }
+
+    fn write_config(&self, config: u8) -> Result<(), Ps2Error> {
+        self.locked(|| {
+            write_port(COMMAND_PORT, COMMAND_WRITE_CONFIG)?;
+            write_port(DATA_PORT, config)
+        })
+    }
+
+    /// Turns the interrupt of a port on or off in the controller configuration.
+    pub fn set_interrupt(&self, port: Ps2Port, enabled: bool) -> Result<(), Ps2Error> {
+        let mut config = self.read_config()?;
+
+        config.set_bit(
+            match port {
+                Ps2Port::First => CONFIG_FIRST_PORT_INTERRUPT,
+                Ps2Port::Second => CONFIG_SECOND_PORT_INTERRUPT,
+            },
+            enabled,
+        );
+
+        self.write_config(config)
+    }
+
+    /// Sends a byte to the device on a port without waiting for a response.
+    pub fn write_device(&self, port: Ps2Port, value: u8) -> Result<(), Ps2Error> {
+        self.locked(|| {
+            if port == Ps2Port::Second {
+                write_port(COMMAND_PORT, COMMAND_WRITE_SECOND_PORT)?;
+            }
+
+            write_port(DATA_PORT, value)
+        })
+    }
+
+    /// Sends a byte to the device on the first port if the controller can take it right away,
+    /// for interrupt handlers, which can't wait. Returns whether it was sent.
+    pub fn try_write_first_port(&self, value: u8) -> bool {
+        self.locked(|| {
+            if status().get_bit(STATUS_INPUT_FULL) {
+                return false;
+            }
+
+            unsafe { Port::<u8>::new(DATA_PORT).write(value) };
+
+            true
+        })
+    }
+
+    pub fn read_device(&self, port: Ps2Port, timeout: Duration) -> Result<u8, Ps2Error> {
+        self.locked(|| read_data_timeout(port, timeout))
+    }
+
+    /// Sends a command byte to a device and waits for it to be acknowledged, resending it if
+    /// the device asks for that. Only usable while the port's interrupt is off.
+    pub fn device_command(&self, port: Ps2Port, command: u8) -> Result<(), Ps2Error> {
+        for _ in 0..DEVICE_COMMAND_RETRIES {
+            self.write_device(port, command)?;
+
+            match self.read_device(port, TIMEOUT)? {
+                DEVICE_ACK => return Ok(()),
+                DEVICE_RESEND => debug!("PS/2 device asked to resend {:#x}", command),
+                response => return Err(Ps2Error::UnexpectedResponse(response)),
+            }
+        }
+
+        Err(Ps2Error::NoAck(command))
+    }
+
+    /// Resets a device and waits for its self test to pass.
+    pub fn reset_device(&self, port: Ps2Port) -> Result<(), Ps2Error> {
+        self.device_command(port, 0xFF)?;
+
+        match self.read_device(port, RESET_TIMEOUT)? {
+            DEVICE_SELF_TEST_PASSED => Ok(()),
+            response => Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+}
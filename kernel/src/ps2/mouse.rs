@@ -0,0 +1,260 @@
+use crate::apic;
+use crate::ps2::{read_data, Ps2Controller, Ps2Error, Ps2Port, TIMEOUT};
+use crate::ring_buffer::RingBuffer;
+use alloc::sync::Arc;
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use log::{debug, info, trace, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const MOUSE_IRQ: u8 = 12;
+
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xF3;
+const COMMAND_GET_DEVICE_ID: u8 = 0xF2;
+const COMMAND_ENABLE_DATA_REPORTING: u8 = 0xF4;
+const COMMAND_SET_DEFAULTS: u8 = 0xF6;
+
+// Setting these sample rates in a row is the "magic knock" that unlocks the IntelliMouse
+// modes.
+const INTELLIMOUSE_SEQUENCE: [u8; 3] = [200, 100, 80];
+const INTELLIMOUSE_EXPLORER_SEQUENCE: [u8; 3] = [200, 200, 80];
+const SAMPLE_RATE: u8 = 100;
+
+const DEVICE_ID_STANDARD: u8 = 0x00;
+const DEVICE_ID_INTELLIMOUSE: u8 = 0x03;
+const DEVICE_ID_INTELLIMOUSE_EXPLORER: u8 = 0x04;
+
+const PACKET_ALWAYS_SET: usize = 3;
+const PACKET_X_SIGN: usize = 4;
+const PACKET_Y_SIGN: usize = 5;
+const PACKET_X_OVERFLOW: usize = 6;
+const PACKET_Y_OVERFLOW: usize = 7;
+
+const EVENT_BUFFER_SIZE: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MouseKind {
+    Standard,
+    /// Scroll wheel, 4 byte packets.
+    IntelliMouse,
+    /// Scroll wheel and two extra buttons, 4 byte packets.
+    IntelliMouseExplorer,
+}
+
+impl MouseKind {
+    fn packet_size(&self) -> usize {
+        match self {
+            Self::Standard => 3,
+            Self::IntelliMouse | Self::IntelliMouseExplorer => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub back: bool,
+    pub forward: bool,
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub struct MouseEvent {
+    /// Movement in screen direction, i.e. positive `dy` is down.
+    pub dx: i16,
+    pub dy: i16,
+    /// Positive is scrolling down, towards the user.
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+struct Mouse {
+    kind: MouseKind,
+    packet: [u8; 4],
+    received: usize,
+    events: RingBuffer<MouseEvent, EVENT_BUFFER_SIZE>,
+}
+
+static MOUSE: OnceCell<Spinlock<Mouse>> = OnceCell::uninit();
+
+/// Sign extends a 9 bit movement value whose sign is in the first packet byte.
+fn movement(value: u8, negative: bool) -> i16 {
+    if negative {
+        value as i16 - 0x100
+    } else {
+        value as i16
+    }
+}
+
+impl Mouse {
+    fn handle_byte(&mut self, byte: u8) {
+        // The first byte always has bit 3 set, anything else means we lost track of the
+        // packet boundaries.
+        if self.received == 0 && !byte.get_bit(PACKET_ALWAYS_SET) {
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+
+        if self.received < self.kind.packet_size() {
+            return;
+        }
+
+        self.received = 0;
+
+        if let Some(event) = self.decode_packet() {
+            trace!("{:?}", event);
+            self.events.push_overwrite(event);
+        }
+    }
+
+    fn decode_packet(&self) -> Option<MouseEvent> {
+        let [flags, x, y, extra] = self.packet;
+
+        // The movement is garbage if it overflowed.
+        if flags.get_bit(PACKET_X_OVERFLOW) || flags.get_bit(PACKET_Y_OVERFLOW) {
+            return None;
+        }
+
+        let (wheel, back, forward) = match self.kind {
+            MouseKind::Standard => (0, false, false),
+            MouseKind::IntelliMouse => (extra as i8, false, false),
+            MouseKind::IntelliMouseExplorer => {
+                // 4 bit two's complement
+                let wheel = ((extra.get_bits(0..4) << 4) as i8) >> 4;
+                (wheel, extra.get_bit(4), extra.get_bit(5))
+            }
+        };
+
+        Some(MouseEvent {
+            dx: movement(x, flags.get_bit(PACKET_X_SIGN)),
+            dy: -movement(y, flags.get_bit(PACKET_Y_SIGN)),
+            wheel,
+            buttons: MouseButtons {
+                left: flags.get_bit(0),
+                right: flags.get_bit(1),
+                middle: flags.get_bit(2),
+                back,
+                forward,
+            },
+        })
+    }
+}
+
+fn set_sample_rate(controller: &Ps2Controller, rate: u8) -> Result<(), Ps2Error> {
+    controller.device_command(Ps2Port::Second, COMMAND_SET_SAMPLE_RATE)?;
+    controller.device_command(Ps2Port::Second, rate)
+}
+
+fn device_id(controller: &Ps2Controller) -> Result<u8, Ps2Error> {
+    controller.device_command(Ps2Port::Second, COMMAND_GET_DEVICE_ID)?;
+    controller.read_device(Ps2Port::Second, TIMEOUT)
+}
+
+/// Tries to switch the mouse into the wheel (and then five button) mode and returns what it
+/// ended up as.
+fn negotiate_kind(controller: &Ps2Controller) -> Result<MouseKind, Ps2Error> {
+    for rate in INTELLIMOUSE_SEQUENCE {
+        set_sample_rate(controller, rate)?;
+    }
+
+    if device_id(controller)? != DEVICE_ID_INTELLIMOUSE {
+        return Ok(MouseKind::Standard);
+    }
+
+    for rate in INTELLIMOUSE_EXPLORER_SEQUENCE {
+        set_sample_rate(controller, rate)?;
+    }
+
+    Ok(match device_id(controller)? {
+        DEVICE_ID_INTELLIMOUSE_EXPLORER => MouseKind::IntelliMouseExplorer,
+        _ => MouseKind::IntelliMouse,
+    })
+}
+
+pub fn init(controller: &'static Ps2Controller) -> Result<(), Ps2Error> {
+    controller.reset_device(Ps2Port::Second)?;
+
+    // After its self test the mouse sends its device ID.
+    match controller.read_device(Ps2Port::Second, TIMEOUT) {
+        Ok(DEVICE_ID_STANDARD) => {}
+        Ok(id) => debug!("Mouse reported device ID {:#x} after reset", id),
+        Err(e) => debug!("Mouse didn't send its device ID after reset: {:?}", e),
+    }
+
+    controller.device_command(Ps2Port::Second, COMMAND_SET_DEFAULTS)?;
+
+    let kind = negotiate_kind(controller)?;
+    set_sample_rate(controller, SAMPLE_RATE)?;
+
+    MOUSE.get_or_init(|| {
+        Spinlock::new(Mouse {
+            kind,
+            packet: [0; 4],
+            received: 0,
+            events: RingBuffer::new(),
+        })
+    });
+
+    let handler = Arc::new(|_| {
+        let Some(byte) = read_data(Ps2Port::Second) else {
+            return;
+        };
+
+        if let Some(mouse) = MOUSE.get() {
+            mouse.lock().handle_byte(byte);
+        }
+    });
+
+    let Some(vector) = apic::register_isa_irq_handler(MOUSE_IRQ, handler) else {
+        warn!("No vector available for the mouse");
+        return Ok(());
+    };
+
+    // Commands have to be done before the interrupt handler starts taking the responses.
+    controller.device_command(Ps2Port::Second, COMMAND_ENABLE_DATA_REPORTING)?;
+    controller.set_interrupt(Ps2Port::Second, true)?;
+
+    info!("PS/2 mouse initialized as {:?}, vector {}", kind, vector);
+
+    Ok(())
+}
+
+#[allow(unused)]
+pub fn read_event() -> Option<MouseEvent> {
+    let mouse = MOUSE.get()?;
+
+    without_interrupts(|| mouse.lock().events.pop())
+}
+
+/// A cursor position kept inside the screen, for drawing a pointer on the frame buffer.
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub struct Cursor {
+    pub x: usize,
+    pub y: usize,
+    width: usize,
+    height: usize,
+}
+
+#[allow(unused)]
+impl Cursor {
+    /// Starts in the middle of a screen with the given size.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            x: width / 2,
+            y: height / 2,
+            width,
+            height,
+        }
+    }
+
+    pub fn apply(&mut self, event: &MouseEvent) {
+        self.x = (self.x as isize + event.dx as isize).clamp(0, self.width as isize - 1) as usize;
+        self.y = (self.y as isize + event.dy as isize).clamp(0, self.height as isize - 1) as usize;
+    }
+}
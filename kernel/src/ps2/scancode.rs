@@ -0,0 +1,484 @@
+/// A physical key, named after its label on a US keyboard.
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// The extra key next to left shift on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftControl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightControl,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadMinus,
+    NumpadPlus,
+    NumpadEnter,
+    NumpadPeriod,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+const EXTENDED_PREFIX: u8 = 0xE0;
+const PAUSE_PREFIX: u8 = 0xE1;
+const SET_2_RELEASE_PREFIX: u8 = 0xF0;
+const SET_1_RELEASE_BIT: u8 = 0x80;
+
+// The bytes of the pause sequence following its E1 prefix. Pause has no break code.
+const SET_1_PAUSE_LENGTH: u8 = 5;
+const SET_2_PAUSE_LENGTH: u8 = 7;
+
+// Keyboard responses and errors, not scancodes.
+const SET_2_NON_SCANCODES: [u8; 5] = [0x00, 0xAA, 0xFA, 0xFE, 0xFF];
+const SET_1_NON_SCANCODES: [u8; 4] = [0x00, 0xFA, 0xFE, 0xFF];
+
+fn set_1_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0A => Key9,
+        0x0B => Key0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftControl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => NumpadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Numpad7,
+        0x48 => Numpad8,
+        0x49 => Numpad9,
+        0x4A => NumpadMinus,
+        0x4B => Numpad4,
+        0x4C => Numpad5,
+        0x4D => Numpad6,
+        0x4E => NumpadPlus,
+        0x4F => Numpad1,
+        0x50 => Numpad2,
+        0x51 => Numpad3,
+        0x52 => Numpad0,
+        0x53 => NumpadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+fn set_1_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x1C => NumpadEnter,
+        0x1D => RightControl,
+        0x35 => NumpadDivide,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4B => ArrowLeft,
+        0x4D => ArrowRight,
+        0x4F => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftGui,
+        0x5C => RightGui,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+fn set_2_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftControl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Key7,
+        0x3E => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Numpad1,
+        0x6B => Numpad4,
+        0x6C => Numpad7,
+        0x70 => Numpad0,
+        0x71 => NumpadPeriod,
+        0x72 => Numpad2,
+        0x73 => Numpad5,
+        0x74 => Numpad6,
+        0x75 => Numpad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => NumpadPlus,
+        0x7A => Numpad3,
+        0x7B => NumpadMinus,
+        0x7C => NumpadMultiply,
+        0x7D => Numpad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set_2_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightControl,
+        0x1F => LeftGui,
+        0x27 => RightGui,
+        0x2F => Menu,
+        0x4A => NumpadDivide,
+        0x5A => NumpadEnter,
+        0x69 => End,
+        0x6B => ArrowLeft,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => ArrowDown,
+        0x74 => ArrowRight,
+        0x75 => ArrowUp,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+/// Turns the byte stream from the keyboard into key presses and releases.
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    pause_bytes_remaining: u8,
+}
+
+impl ScancodeDecoder {
+    pub fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            pause_bytes_remaining: 0,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        if self.pause_bytes_remaining > 0 {
+            self.pause_bytes_remaining -= 1;
+
+            return (self.pause_bytes_remaining == 0)
+                .then_some((KeyCode::Pause, KeyState::Pressed));
+        }
+
+        match self.set {
+            ScancodeSet::Set1 => self.feed_set_1(byte),
+            ScancodeSet::Set2 => self.feed_set_2(byte),
+        }
+    }
+
+    fn feed_set_1(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match byte {
+            _ if SET_1_NON_SCANCODES.contains(&byte) => None,
+            PAUSE_PREFIX => {
+                self.pause_bytes_remaining = SET_1_PAUSE_LENGTH;
+                None
+            }
+            EXTENDED_PREFIX => {
+                self.extended = true;
+                None
+            }
+            _ => {
+                let extended = core::mem::take(&mut self.extended);
+                let code = byte & !SET_1_RELEASE_BIT;
+                let state = if byte & SET_1_RELEASE_BIT != 0 {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+
+                // Print screen and the navigation keys are wrapped in fake shift presses.
+                if extended && (code == 0x2A || code == 0x36) {
+                    return None;
+                }
+
+                let key = if extended {
+                    set_1_extended_key(code)
+                } else {
+                    set_1_key(code)
+                };
+
+                key.map(|key| (key, state))
+            }
+        }
+    }
+
+    fn feed_set_2(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match byte {
+            _ if SET_2_NON_SCANCODES.contains(&byte) => None,
+            PAUSE_PREFIX => {
+                self.pause_bytes_remaining = SET_2_PAUSE_LENGTH;
+                None
+            }
+            EXTENDED_PREFIX => {
+                self.extended = true;
+                None
+            }
+            SET_2_RELEASE_PREFIX => {
+                self.release = true;
+                None
+            }
+            code => {
+                let extended = core::mem::take(&mut self.extended);
+                let state = if core::mem::take(&mut self.release) {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+
+                if extended && (code == 0x12 || code == 0x59) {
+                    return None;
+                }
+
+                let key = if extended {
+                    set_2_extended_key(code)
+                } else {
+                    set_2_key(code)
+                };
+
+                key.map(|key| (key, state))
+            }
+        }
+    }
+}
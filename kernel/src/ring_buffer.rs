@@ -0,0 +1,66 @@
+/// A fixed size FIFO that doesn't allocate, so it can be filled from interrupt handlers.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    buffer: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+#[allow(unused)]
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns the value back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+
+        self.buffer[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Pushes the value, dropping the oldest one if the buffer is full.
+    pub fn push_overwrite(&mut self, value: T) {
+        if self.is_full() {
+            self.pop();
+        }
+
+        let _ = self.push(value);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = self.buffer[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+
+        value
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
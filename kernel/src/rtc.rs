@@ -0,0 +1,364 @@
+use crate::apic;
+use acpi::fadt::Fadt;
+use acpi::{AcpiHandler, AcpiTables};
+use alloc::sync::Arc;
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+// Keeps NMIs disabled while a register is selected.
+const CMOS_NMI_DISABLE: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_SECONDS_ALARM: u8 = 0x01;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_MINUTES_ALARM: u8 = 0x03;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_HOURS_ALARM: u8 = 0x05;
+const REGISTER_DAY_OF_MONTH: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+const REGISTER_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: usize = 7;
+const STATUS_B_24_HOUR: usize = 1;
+const STATUS_B_BINARY: usize = 2;
+const STATUS_B_ALARM_INTERRUPT: usize = 5;
+const STATUS_B_PERIODIC_INTERRUPT: usize = 6;
+const STATUS_C_ALARM: usize = 5;
+const STATUS_C_PERIODIC: usize = 6;
+
+const HOURS_PM: usize = 7;
+
+// Rate 6 divides the 32.768 kHz base clock down to 1024 Hz.
+const PERIODIC_RATE: u8 = 6;
+pub const PERIODIC_FREQUENCY: u64 = 32768 >> (PERIODIC_RATE - 1);
+
+const DEFAULT_CENTURY: u16 = 20;
+
+static CMOS_LOCK: Spinlock<()> = Spinlock::new(());
+
+// The CMOS register holding the century, from the FADT. 0 if there is none.
+static CENTURY_REGISTER: OnceCell<u8> = OnceCell::uninit();
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Seconds since the Unix epoch when the periodic interrupt started counting.
+static BOOT_TIME: OnceCell<u64> = OnceCell::uninit();
+
+pub type AlarmHandler = Arc<dyn Fn() + Send + Sync>;
+
+static ALARM_HANDLER: Spinlock<Option<AlarmHandler>> = Spinlock::new(None);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[allow(unused)]
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, the RTC is assumed to run in UTC.
+    pub fn unix_timestamp(&self) -> u64 {
+        // Days from civil, counting years from March so the leap day is last.
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+            as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64 + 719468;
+        let seconds_of_day = timestamp % 86400;
+
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+/// Accesses CMOS registers. Selecting and reading a register mustn't be interleaved with
+/// another access, including one from the RTC interrupt.
+fn with_cmos<R>(f: impl FnOnce(&dyn Fn(u8) -> u8, &dyn Fn(u8, u8)) -> R) -> R {
+    without_interrupts(|| {
+        let _guard = CMOS_LOCK.lock();
+
+        let read = |register: u8| unsafe {
+            Port::<u8>::new(CMOS_INDEX_PORT).write(CMOS_NMI_DISABLE | register);
+            Port::<u8>::new(CMOS_DATA_PORT).read()
+        };
+        let write = |register: u8, value: u8| unsafe {
+            Port::<u8>::new(CMOS_INDEX_PORT).write(CMOS_NMI_DISABLE | register);
+            Port::<u8>::new(CMOS_DATA_PORT).write(value)
+        };
+
+        f(&read, &write)
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+// seconds, minutes, hours, day, month, year, century
+type RawTime = [u8; 7];
+
+fn read_raw_time() -> RawTime {
+    let century_register = CENTURY_REGISTER.get().copied().unwrap_or(0);
+
+    with_cmos(|read, _| {
+        while read(REGISTER_STATUS_A).get_bit(STATUS_A_UPDATE_IN_PROGRESS) {
+            core::hint::spin_loop();
+        }
+
+        [
+            read(REGISTER_SECONDS),
+            read(REGISTER_MINUTES),
+            read(REGISTER_HOURS),
+            read(REGISTER_DAY_OF_MONTH),
+            read(REGISTER_MONTH),
+            read(REGISTER_YEAR),
+            if century_register != 0 {
+                read(century_register)
+            } else {
+                0
+            },
+        ]
+    })
+}
+
+/// Reads the date and time from the RTC. The registers are read until two reads in a row
+/// agree, so an update can't happen halfway through.
+pub fn read_date_time() -> DateTime {
+    let mut raw = read_raw_time();
+
+    loop {
+        let again = read_raw_time();
+
+        if again == raw {
+            break;
+        }
+
+        raw = again;
+    }
+
+    let status_b = with_cmos(|read, _| read(REGISTER_STATUS_B));
+    let is_binary = status_b.get_bit(STATUS_B_BINARY);
+    let is_24_hour = status_b.get_bit(STATUS_B_24_HOUR);
+
+    let convert = |value: u8| if is_binary { value } else { from_bcd(value) };
+
+    let [second, minute, hours, day, month, year, century] = raw;
+
+    let is_pm = hours.get_bit(HOURS_PM);
+    let mut hour = convert(hours & !(1 << HOURS_PM));
+
+    if !is_24_hour {
+        hour = match (hour, is_pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (hour, true) => hour + 12,
+            (hour, false) => hour,
+        };
+    }
+
+    let century = if CENTURY_REGISTER
+        .get()
+        .map_or(false, |register| *register != 0)
+    {
+        convert(century) as u16
+    } else {
+        DEFAULT_CENTURY
+    };
+
+    DateTime {
+        year: century * 100 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Time since the periodic interrupt was started.
+pub fn uptime() -> Duration {
+    let ticks = PERIODIC_TICKS.load(Ordering::Relaxed);
+
+    Duration::from_secs(ticks / PERIODIC_FREQUENCY)
+        + Duration::from_nanos((ticks % PERIODIC_FREQUENCY) * 1_000_000_000 / PERIODIC_FREQUENCY)
+}
+
+/// The current wall-clock time, from the RTC time at boot and the monotonic clock since.
+#[allow(unused)]
+pub fn now() -> DateTime {
+    match BOOT_TIME.get() {
+        Some(boot_time) => DateTime::from_unix_timestamp(*boot_time + uptime().as_secs()),
+        None => read_date_time(),
+    }
+}
+
+fn handle_interrupt() {
+    let status_c = with_cmos(|read, _| read(REGISTER_STATUS_C));
+
+    if status_c.get_bit(STATUS_C_PERIODIC) {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if status_c.get_bit(STATUS_C_ALARM) {
+        let handler = ALARM_HANDLER.lock().clone();
+
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+}
+
+fn set_status_b_bit(bit: usize, enabled: bool) {
+    with_cmos(|read, write| {
+        let mut status_b = read(REGISTER_STATUS_B);
+        status_b.set_bit(bit, enabled);
+        write(REGISTER_STATUS_B, status_b);
+    });
+}
+
+/// Calls `handler` from the RTC interrupt every day at the given time.
+#[allow(unused)]
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: AlarmHandler) {
+    without_interrupts(|| *ALARM_HANDLER.lock() = Some(handler));
+
+    with_cmos(|read, write| {
+        let status_b = read(REGISTER_STATUS_B);
+        let convert = |value: u8| {
+            if status_b.get_bit(STATUS_B_BINARY) {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+
+        let hour = if status_b.get_bit(STATUS_B_24_HOUR) {
+            convert(hour)
+        } else {
+            let pm = (hour >= 12) as u8;
+            let hour = match hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+
+            convert(hour) | pm << HOURS_PM
+        };
+
+        write(REGISTER_SECONDS_ALARM, convert(second));
+        write(REGISTER_MINUTES_ALARM, convert(minute));
+        write(REGISTER_HOURS_ALARM, hour);
+    });
+
+    set_status_b_bit(STATUS_B_ALARM_INTERRUPT, true);
+}
+
+#[allow(unused)]
+pub fn clear_alarm() {
+    set_status_b_bit(STATUS_B_ALARM_INTERRUPT, false);
+    without_interrupts(|| *ALARM_HANDLER.lock() = None);
+}
+
+/// Reads the wall-clock time and starts the periodic interrupt that drives [`uptime`].
+pub fn init<H>(tables: &AcpiTables<H>)
+where
+    H: AcpiHandler,
+{
+    let century_register = match tables.find_table::<Fadt>() {
+        Ok(fadt) => fadt.century,
+        Err(e) => {
+            warn!(
+                "Couldn't get the FADT, assuming the {}00s: {:?}",
+                DEFAULT_CENTURY, e
+            );
+            0
+        }
+    };
+
+    CENTURY_REGISTER.init_once(|| century_register);
+
+    let now = read_date_time();
+    info!("RTC time is {}", now);
+
+    let handler = Arc::new(|_| handle_interrupt());
+    let Some(vector) = apic::register_isa_irq_handler(RTC_IRQ, handler) else {
+        warn!("No vector available for the RTC");
+        return;
+    };
+
+    with_cmos(|read, write| {
+        let status_a = read(REGISTER_STATUS_A);
+        write(REGISTER_STATUS_A, (status_a & 0xF0) | PERIODIC_RATE);
+
+        // Acknowledge anything pending, otherwise the RTC won't raise another interrupt.
+        read(REGISTER_STATUS_C);
+    });
+
+    BOOT_TIME.init_once(|| now.unix_timestamp());
+    set_status_b_bit(STATUS_B_PERIODIC_INTERRUPT, true);
+
+    debug!(
+        "RTC periodic interrupt at {} Hz on vector {}",
+        PERIODIC_FREQUENCY, vector
+    );
+}
@@ -0,0 +1,269 @@
+use crate::apic;
+use crate::ring_buffer::RingBuffer;
+use alloc::sync::Arc;
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use core::fmt::Write;
+use log::{debug, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const COM1_PORT: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+
+const UART_CLOCK: u32 = 115_200;
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+const FIFO_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 4096;
+
+// Register offsets from the base port. The divisor latch overlays the data and interrupt enable
+// registers while DLAB is set.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const INTERRUPT_IDENTIFICATION: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+const INTERRUPT_ENABLE_RX_AVAILABLE: u8 = 1 << 0;
+const INTERRUPT_ENABLE_TX_EMPTY: u8 = 1 << 1;
+
+const LINE_CONTROL_8N1: u8 = 0b0000_0011;
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+
+// Enable and clear both FIFOs, interrupt once 14 bytes were received.
+const FIFO_CONTROL_ENABLE: u8 = 0xC7;
+
+const MODEM_CONTROL_DTR_RTS_OUT2: u8 = 0x0B;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1E;
+// OUT2 gates the UART's interrupt line on PC compatible machines.
+const MODEM_CONTROL_NORMAL: u8 = 0x0F;
+
+const LINE_STATUS_DATA_READY: usize = 0;
+const LINE_STATUS_TX_EMPTY: usize = 5;
+
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
+pub static COM1: OnceCell<Spinlock<SerialPort>> = OnceCell::uninit();
+
+/// Sets up COM1 in polling mode, so it can be used for logging right away. Does nothing if
+/// there's no UART.
+pub fn init() {
+    if let Some(port) = SerialPort::new(COM1_PORT) {
+        COM1.get_or_init(|| Spinlock::new(port));
+    }
+}
+
+/// Switches COM1 to interrupt driven operation. Requires the APIC to be set up.
+pub fn enable_interrupts() {
+    let Some(com1) = COM1.get() else {
+        return;
+    };
+
+    let handler = Arc::new(|_| {
+        if let Some(com1) = COM1.get() {
+            com1.lock().handle_interrupt();
+        }
+    });
+
+    match apic::register_isa_irq_handler(COM1_IRQ, handler) {
+        Some(vector) => {
+            without_interrupts(|| com1.lock().enable_interrupts());
+            debug!("COM1 uses vector {}", vector);
+        }
+        None => warn!("No vector available for COM1, staying in polling mode"),
+    }
+}
+
+/// A 16550 compatible UART. Until interrupts are enabled, reads and writes poll the line status
+/// register; afterwards they go through ring buffers that the interrupt handler drains and
+/// fills.
+pub struct SerialPort {
+    base: u16,
+    interrupts_enabled: bool,
+    rx: RingBuffer<u8, BUFFER_SIZE>,
+    tx: RingBuffer<u8, BUFFER_SIZE>,
+}
+
+#[allow(unused)]
+impl SerialPort {
+    /// Initializes the UART at the given base port with 115200 baud 8N1, returns `None` if it
+    /// doesn't pass a loopback test.
+    pub fn new(base: u16) -> Option<Self> {
+        let mut port = Self {
+            base,
+            interrupts_enabled: false,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+        };
+
+        port.write_register(INTERRUPT_ENABLE, 0);
+        port.set_baud_rate(DEFAULT_BAUD_RATE);
+        port.write_register(FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+        port.write_register(MODEM_CONTROL, MODEM_CONTROL_DTR_RTS_OUT2);
+
+        port.write_register(MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+        port.write_register(DATA, LOOPBACK_TEST_BYTE);
+
+        if port.read_register(DATA) != LOOPBACK_TEST_BYTE {
+            return None;
+        }
+
+        port.write_register(MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+
+        Some(port)
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        let divisor = (UART_CLOCK / baud_rate).clamp(1, u16::MAX as u32) as u16;
+
+        self.write_register(LINE_CONTROL, LINE_CONTROL_DLAB);
+        self.write_register(DIVISOR_LOW, divisor as u8);
+        self.write_register(DIVISOR_HIGH, (divisor >> 8) as u8);
+        self.write_register(LINE_CONTROL, LINE_CONTROL_8N1);
+    }
+
+    fn line_status(&self) -> u8 {
+        self.read_register(LINE_STATUS)
+    }
+
+    fn is_transmit_empty(&self) -> bool {
+        self.line_status().get_bit(LINE_STATUS_TX_EMPTY)
+    }
+
+    fn is_data_ready(&self) -> bool {
+        self.line_status().get_bit(LINE_STATUS_DATA_READY)
+    }
+
+    fn write_byte_polling(&mut self, byte: u8) {
+        while !self.is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+
+        self.write_register(DATA, byte);
+    }
+
+    fn set_transmit_interrupt(&mut self, enabled: bool) {
+        let interrupt_enable = self.read_register(INTERRUPT_ENABLE);
+
+        self.write_register(
+            INTERRUPT_ENABLE,
+            if enabled {
+                interrupt_enable | INTERRUPT_ENABLE_TX_EMPTY
+            } else {
+                interrupt_enable & !INTERRUPT_ENABLE_TX_EMPTY
+            },
+        );
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.interrupts_enabled {
+            self.write_byte_polling(byte);
+            return;
+        }
+
+        // The interrupt can't drain the buffer while interrupts are disabled, e.g. when
+        // logging from an interrupt handler, so make room by hand.
+        if self.tx.is_full() {
+            if let Some(oldest) = self.tx.pop() {
+                self.write_byte_polling(oldest);
+            }
+        }
+
+        let _ = self.tx.push(byte);
+        self.set_transmit_interrupt(true);
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.interrupts_enabled {
+            self.rx.pop()
+        } else {
+            self.is_data_ready().then(|| self.read_register(DATA))
+        }
+    }
+
+    fn enable_interrupts(&mut self) {
+        self.interrupts_enabled = true;
+        self.write_register(INTERRUPT_ENABLE, INTERRUPT_ENABLE_RX_AVAILABLE);
+    }
+
+    /// Goes back to polling, writing out everything that's still buffered. Used by the panic
+    /// handler, which can't rely on interrupts anymore.
+    pub fn disable_interrupts(&mut self) {
+        self.write_register(INTERRUPT_ENABLE, 0);
+        self.interrupts_enabled = false;
+
+        while let Some(byte) = self.tx.pop() {
+            self.write_byte_polling(byte);
+        }
+    }
+
+    fn handle_interrupt(&mut self) {
+        // Several causes can be pending, bit 0 is clear as long as any of them is.
+        loop {
+            let interrupt_identification = self.read_register(INTERRUPT_IDENTIFICATION);
+
+            if interrupt_identification.get_bit(0) {
+                break;
+            }
+
+            match interrupt_identification.get_bits(1..4) {
+                // line status
+                0b011 => {
+                    self.line_status();
+                }
+                // received data available, character timeout
+                0b010 | 0b110 => {
+                    while self.is_data_ready() {
+                        let byte = self.read_register(DATA);
+                        self.rx.push_overwrite(byte);
+                    }
+                }
+                // transmitter holding register empty
+                0b001 => {
+                    for _ in 0..FIFO_SIZE {
+                        match self.tx.pop() {
+                            Some(byte) => self.write_register(DATA, byte),
+                            None => break,
+                        }
+                    }
+
+                    if self.tx.is_empty() {
+                        self.set_transmit_interrupt(false);
+                    }
+                }
+                // modem status
+                _ => {
+                    self.read_register(MODEM_STATUS);
+                }
+            }
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
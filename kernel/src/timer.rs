@@ -0,0 +1,397 @@
+use crate::apic;
+use crate::pit::busy_wait;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use log::info;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+/// How often the local APIC timer interrupts, the resolution of all timers.
+pub const TICK: Duration = Duration::from_millis(1);
+
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+// Four levels cover 2^24 ticks, a bit over four and a half hours. Later timers wait in the
+// overflow list.
+const LEVELS: usize = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    expires: u64,
+    period: Option<u64>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+/// A hierarchical timer wheel. Each level has `SLOTS` slots, each covering `SLOTS` times as
+/// many ticks as one on the level below. Only a single slot of the lowest level is looked at
+/// per tick; the higher levels' timers are cascaded down whenever the level below wraps.
+struct TimerWheel {
+    ticks: u64,
+    levels: [[Vec<Timer>; SLOTS]; LEVELS],
+    overflow: Vec<Timer>,
+    next_id: u64,
+    // Expired timers whose callbacks are about to run or running. A callback only runs if its
+    // timer is still here, and periodic ones are re-armed afterwards unless they have been
+    // cancelled meanwhile.
+    running: Vec<TimerId>,
+}
+
+static TIMER_WHEEL: OnceCell<Spinlock<TimerWheel>> = OnceCell::uninit();
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            ticks: 0,
+            levels: core::array::from_fn(|_| core::array::from_fn(|_| Vec::new())),
+            overflow: Vec::new(),
+            next_id: 0,
+            running: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, mut timer: Timer) {
+        timer.expires = timer.expires.max(self.ticks);
+        let delta = timer.expires - self.ticks;
+
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level;
+
+            if delta < 1 << (shift + SLOT_BITS) {
+                let slot = (timer.expires >> shift) as usize % SLOTS;
+                self.levels[level][slot].push(timer);
+                return;
+            }
+        }
+
+        self.overflow.push(timer);
+    }
+
+    fn add(
+        &mut self,
+        ticks: u64,
+        period: Option<u64>,
+        callback: Box<dyn FnMut() + Send>,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        // The slot of the current tick has already been handled.
+        let expires = self.ticks + ticks.max(1);
+
+        self.insert(Timer {
+            id,
+            expires,
+            period,
+            callback,
+        });
+
+        id
+    }
+
+    /// Takes the timer off the list of expired ones, returns whether it was there.
+    fn take_running(&mut self, id: TimerId) -> bool {
+        match self.running.iter().position(|running| *running == id) {
+            Some(index) => {
+                self.running.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        if self.take_running(id) {
+            return true;
+        }
+
+        let slots = self
+            .levels
+            .iter_mut()
+            .flatten()
+            .chain(core::iter::once(&mut self.overflow));
+
+        for slot in slots {
+            if let Some(index) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Moves on by a tick and returns the timers that expire with it.
+    fn advance(&mut self) -> Vec<Timer> {
+        self.ticks += 1;
+
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level;
+
+            if self.ticks % (1 << shift) != 0 {
+                break;
+            }
+
+            let slot = (self.ticks >> shift) as usize % SLOTS;
+
+            for timer in core::mem::take(&mut self.levels[level][slot]) {
+                self.insert(timer);
+            }
+
+            if level == LEVELS - 1 && slot == 0 {
+                for timer in core::mem::take(&mut self.overflow) {
+                    self.insert(timer);
+                }
+            }
+        }
+
+        core::mem::take(&mut self.levels[0][self.ticks as usize % SLOTS])
+    }
+}
+
+/// The timer interrupt handler. Callbacks run without the wheel locked, so they can add and
+/// cancel timers themselves.
+fn tick() {
+    let Some(wheel) = TIMER_WHEEL.get() else {
+        return;
+    };
+
+    let expired = {
+        let mut wheel = wheel.lock();
+        let expired = wheel.advance();
+
+        wheel.running.extend(expired.iter().map(|timer| timer.id));
+
+        expired
+    };
+
+    for mut timer in expired {
+        // An earlier callback may have cancelled it. Periodic timers stay listed while their
+        // callback runs, so cancelling them then stops the next period.
+        let cancelled = {
+            let mut wheel = wheel.lock();
+
+            match timer.period {
+                Some(_) => !wheel.running.contains(&timer.id),
+                None => !wheel.take_running(timer.id),
+            }
+        };
+
+        if cancelled {
+            continue;
+        }
+
+        (timer.callback)();
+
+        let Some(period) = timer.period else {
+            continue;
+        };
+
+        let mut wheel = wheel.lock();
+
+        if wheel.take_running(timer.id) {
+            // Skip the periods that a slow callback made us miss.
+            timer.expires = (timer.expires + period).max(wheel.ticks + 1);
+            wheel.insert(timer);
+        }
+    }
+}
+
+fn ticks(duration: Duration) -> u64 {
+    duration.as_nanos().div_ceil(TICK.as_nanos()) as u64
+}
+
+fn add(ticks: u64, period: Option<u64>, callback: Box<dyn FnMut() + Send>) -> TimerId {
+    let wheel = TIMER_WHEEL.get().expect("timer wheel not initialized");
+
+    without_interrupts(|| wheel.lock().add(ticks, period, callback))
+}
+
+/// Sets up the timer wheel and starts the local APIC timer that drives it.
+pub fn init() {
+    TIMER_WHEEL.init_once(|| Spinlock::new(TimerWheel::new()));
+
+    apic::register_handler(apic::TIMER_VECTOR, Arc::new(|_| tick()));
+    apic::start_periodic_timer(TICK);
+
+    info!("Timer wheel running with a tick of {:?}", TICK);
+}
+
+#[allow(unused)]
+pub fn is_running() -> bool {
+    TIMER_WHEEL.get().is_some()
+}
+
+/// Calls `callback` once after `delay`, from the timer interrupt.
+#[allow(unused)]
+pub fn add_timer(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let mut callback = Some(callback);
+
+    add(
+        ticks(delay),
+        None,
+        Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        }),
+    )
+}
+
+/// Calls `callback` every `period`, from the timer interrupt, until the timer is cancelled.
+#[allow(unused)]
+pub fn add_periodic_timer(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let period = ticks(period).max(1);
+
+    add(period, Some(period), Box::new(callback))
+}
+
+/// Returns whether the timer was still pending. A callback that's already running is not
+/// interrupted, but a periodic timer won't fire again.
+#[allow(unused)]
+pub fn cancel_timer(id: TimerId) -> bool {
+    match TIMER_WHEEL.get() {
+        Some(wheel) => without_interrupts(|| wheel.lock().remove(id)),
+        None => false,
+    }
+}
+
+/// Blocks for at least `duration`, halting the CPU until the timer wakes it up. Busy-waits if
+/// there's no timer interrupt to wait for.
+#[allow(unused)]
+pub fn sleep(duration: Duration) {
+    if !is_running() || !interrupts::are_enabled() {
+        busy_wait(duration);
+        return;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let timer_done = done.clone();
+    add_timer(duration, move || timer_done.store(true, Ordering::Release));
+
+    loop {
+        // Checking and halting with interrupts off, so the wake-up can't slip in between.
+        interrupts::disable();
+
+        if done.load(Ordering::Acquire) {
+            interrupts::enable();
+            return;
+        }
+
+        interrupts::enable_and_hlt();
+    }
+}
+
+#[allow(unused)]
+struct SleepState {
+    done: AtomicBool,
+    waker: Spinlock<Option<Waker>>,
+}
+
+/// A future that completes after a duration, see [`sleep_async`].
+#[allow(unused)]
+pub struct Sleep {
+    ticks: u64,
+    state: Arc<SleepState>,
+    timer: Option<TimerId>,
+}
+
+/// The async version of [`sleep`]. The timer starts at the first poll.
+#[allow(unused)]
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep {
+        ticks: ticks(duration),
+        state: Arc::new(SleepState {
+            done: AtomicBool::new(false),
+            waker: Spinlock::new(None),
+        }),
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if self.state.done.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+
+        without_interrupts(|| *self.state.waker.lock() = Some(context.waker().clone()));
+
+        if self.timer.is_none() {
+            let state = self.state.clone();
+
+            let timer = add(
+                self.ticks,
+                None,
+                Box::new(move || {
+                    state.done.store(true, Ordering::Release);
+
+                    if let Some(waker) = state.waker.lock().take() {
+                        waker.wake();
+                    }
+                }),
+            );
+
+            self.timer = Some(timer);
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            cancel_timer(timer);
+        }
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct Elapsed;
+
+/// A future that gives up on another one after a duration, see [`timeout`].
+#[allow(unused)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Runs `future` for at most `duration`.
+#[allow(unused)]
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep_async(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        // The inner future is never moved out of the pinned timeout.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut this.sleep)
+            .poll(context)
+            .map(|()| Err(Elapsed))
+    }
+}
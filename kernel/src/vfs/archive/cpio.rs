@@ -0,0 +1,114 @@
+use crate::vfs::archive::{Builder, Entry};
+use crate::vfs::{FsError, NodeKind};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use log::debug;
+
+const MAGIC: &[u8; 6] = b"070701";
+// The same format, with a checksum of the contents we don't check.
+const MAGIC_CRC: &[u8; 6] = b"070702";
+const HEADER_SIZE: usize = 110;
+const ALIGNMENT: usize = 4;
+const TRAILER: &str = "TRAILER!!!";
+
+// Indices of the 8 hex digit fields following the magic.
+const FIELD_INODE: usize = 0;
+const FIELD_MODE: usize = 1;
+const FIELD_LINKS: usize = 4;
+const FIELD_MODIFIED: usize = 5;
+const FIELD_SIZE: usize = 6;
+const FIELD_NAME_SIZE: usize = 11;
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && (data.starts_with(MAGIC) || data.starts_with(MAGIC_CRC))
+}
+
+fn field(header: &[u8], index: usize) -> Result<u32, FsError> {
+    let start = MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| FsError::Corrupted)?;
+
+    u32::from_str_radix(digits, 16).map_err(|_| FsError::Corrupted)
+}
+
+pub fn read(data: &'static [u8], builder: &mut Builder) -> Result<(), FsError> {
+    let mut offset = 0;
+    // Hard links share an inode number, only the last of them carries the contents.
+    let mut hard_links = BTreeMap::new();
+
+    loop {
+        let header = data
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(FsError::Corrupted)?;
+
+        if !header.starts_with(MAGIC) && !header.starts_with(MAGIC_CRC) {
+            return Err(FsError::Corrupted);
+        }
+
+        let name_size = field(header, FIELD_NAME_SIZE)? as usize;
+        let size = field(header, FIELD_SIZE)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let contents_start = (name_start + name_size).next_multiple_of(ALIGNMENT);
+
+        // The name size counts the NUL.
+        let name = data
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .ok_or(FsError::Corrupted)?;
+        let contents = data
+            .get(contents_start..contents_start + size)
+            .ok_or(FsError::Corrupted)?;
+
+        offset = (contents_start + size).next_multiple_of(ALIGNMENT);
+
+        let path = String::from_utf8_lossy(name);
+
+        if path == TRAILER {
+            return Ok(());
+        }
+
+        let mode = field(header, FIELD_MODE)?;
+        let inode = field(header, FIELD_INODE)?;
+        let links = field(header, FIELD_LINKS)?;
+
+        let kind = match mode & MODE_TYPE_MASK {
+            MODE_FILE => NodeKind::File,
+            MODE_DIRECTORY => NodeKind::Directory,
+            MODE_SYMLINK => NodeKind::Symlink,
+            _ => {
+                debug!("initramfs: skipping {}, mode {:o}", path, mode);
+                continue;
+            }
+        };
+
+        if kind == NodeKind::File && links > 1 {
+            if let Some(&index) = hard_links.get(&inode) {
+                builder.add_link(&path, index);
+
+                if !contents.is_empty() {
+                    builder.nodes[index].data = contents;
+                }
+
+                continue;
+            }
+        }
+
+        let index = builder.add(
+            &path,
+            Entry {
+                kind,
+                permissions: (mode & 0o7777) as u16,
+                modified: field(header, FIELD_MODIFIED)? as u64,
+                data: contents,
+            },
+        );
+
+        if let Some(index) = index.filter(|_| kind == NodeKind::File && links > 1) {
+            hard_links.insert(inode, index);
+        }
+    }
+}
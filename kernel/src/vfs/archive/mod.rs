@@ -0,0 +1,352 @@
+use crate::vfs::{DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::{debug, warn};
+
+mod cpio;
+mod tar;
+
+const DEFAULT_DIRECTORY_PERMISSIONS: u16 = 0o755;
+
+/// A node of the archive. Contents point right into the archive, nothing is copied.
+struct Node {
+    kind: NodeKind,
+    permissions: u16,
+    modified: u64,
+    // The file's contents or the symlink's target.
+    data: &'static [u8],
+    children: BTreeMap<String, usize>,
+    links: u32,
+}
+
+impl Node {
+    fn directory(permissions: u16, modified: u64) -> Self {
+        Self {
+            kind: NodeKind::Directory,
+            permissions,
+            modified,
+            data: &[],
+            children: BTreeMap::new(),
+            links: 1,
+        }
+    }
+}
+
+/// An entry read from the archive, before it has its place in the tree.
+struct Entry {
+    kind: NodeKind,
+    permissions: u16,
+    modified: u64,
+    data: &'static [u8],
+}
+
+/// Assembles the tree while the archive is read. Nodes are addressed by their index, the root is
+/// the first one.
+struct Builder {
+    nodes: Vec<Node>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self {
+            nodes: alloc::vec![Node::directory(DEFAULT_DIRECTORY_PERMISSIONS, 0)],
+        }
+    }
+
+    /// Splits a path from the archive into names, which are relative to the root no matter
+    /// how they're written. `None` for paths leaving the root.
+    fn components(path: &str) -> Option<Vec<&str>> {
+        let components = path
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect::<Vec<_>>();
+
+        if components.contains(&"..") {
+            return None;
+        }
+
+        Some(components)
+    }
+
+    fn find(&self, path: &str) -> Option<usize> {
+        Self::components(path)?
+            .into_iter()
+            .try_fold(0, |index, name| {
+                self.nodes[index].children.get(name).copied()
+            })
+    }
+
+    /// The directory the last component of `path` goes into, created with everything above it
+    /// if the archive didn't list them before.
+    fn parent<'a>(&mut self, path: &'a str) -> Option<(usize, &'a str)> {
+        let components = Self::components(path)?;
+        let (name, directories) = components.split_last()?;
+
+        let mut index = 0;
+
+        for directory in directories {
+            index = match self.nodes[index].children.get(*directory).copied() {
+                Some(child) if self.nodes[child].kind == NodeKind::Directory => child,
+                Some(_) => return None,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes
+                        .push(Node::directory(DEFAULT_DIRECTORY_PERMISSIONS, 0));
+                    self.nodes[index]
+                        .children
+                        .insert(directory.to_string(), child);
+                    child
+                }
+            };
+        }
+
+        Some((index, name))
+    }
+
+    /// Adds an entry at `path`, replacing what was there, and returns its index.
+    fn add(&mut self, path: &str, entry: Entry) -> Option<usize> {
+        let Some((parent, name)) = self.parent(path) else {
+            // Only the root itself has no parent, archives usually list it as `.`.
+            if Self::components(path).map_or(false, |components| components.is_empty()) {
+                self.nodes[0].permissions = entry.permissions;
+                self.nodes[0].modified = entry.modified;
+                return Some(0);
+            }
+
+            warn!("initramfs: skipping {}, its path is invalid", path);
+            return None;
+        };
+
+        // Directories are often created implicitly before their own entry shows up, that only
+        // adds what was missing.
+        if let Some(&existing) = self.nodes[parent].children.get(name) {
+            let node = &mut self.nodes[existing];
+
+            if node.kind == NodeKind::Directory && entry.kind == NodeKind::Directory {
+                node.permissions = entry.permissions;
+                node.modified = entry.modified;
+                return Some(existing);
+            }
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            kind: entry.kind,
+            permissions: entry.permissions,
+            modified: entry.modified,
+            data: entry.data,
+            children: BTreeMap::new(),
+            links: 1,
+        });
+        self.nodes[parent].children.insert(name.to_string(), index);
+
+        Some(index)
+    }
+
+    /// Adds an empty directory at `path` unless there's something already.
+    fn add_missing_directory(&mut self, path: &str) {
+        if self.find(path).is_none() {
+            let entry = Entry {
+                kind: NodeKind::Directory,
+                permissions: DEFAULT_DIRECTORY_PERMISSIONS,
+                modified: 0,
+                data: &[],
+            };
+
+            self.add(path, entry);
+        }
+    }
+
+    /// Adds `path` as another name for the node at `index`.
+    fn add_link(&mut self, path: &str, index: usize) {
+        match self.parent(path) {
+            Some((parent, name)) => {
+                self.nodes[parent].children.insert(name.to_string(), index);
+                self.nodes[index].links += 1;
+            }
+            None => warn!("initramfs: skipping link {}", path),
+        }
+    }
+}
+
+/// A read only filesystem with the contents of a USTAR or newc cpio archive in memory, like the
+/// initramfs the bootloader loaded.
+pub struct ArchiveFs {
+    format: &'static str,
+    nodes: Arc<Vec<Node>>,
+}
+
+#[allow(unused)]
+impl ArchiveFs {
+    /// Reads the archive, [`FsError::Unsupported`] if it's neither tar nor cpio.
+    pub fn new(data: &'static [u8]) -> Result<Self, FsError> {
+        let mut builder = Builder::new();
+
+        let format = if cpio::is_cpio(data) {
+            cpio::read(data, &mut builder)?;
+            "cpio"
+        } else if tar::is_tar(data) {
+            tar::read(data, &mut builder)?;
+            "tar"
+        } else {
+            return Err(FsError::Unsupported);
+        };
+
+        // Nothing can be created in the archive, other filesystems need a place to be mounted.
+        builder.add_missing_directory("mnt");
+
+        debug!(
+            "initramfs: {} archive with {} entries",
+            format,
+            builder.nodes.len() - 1
+        );
+
+        Ok(Self {
+            format,
+            nodes: Arc::new(builder.nodes),
+        })
+    }
+
+    pub fn format(&self) -> &'static str {
+        self.format
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    fn name(&self) -> &str {
+        self.format
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ArchiveInode {
+            nodes: self.nodes.clone(),
+            index: 0,
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+struct ArchiveInode {
+    nodes: Arc<Vec<Node>>,
+    index: usize,
+}
+
+impl ArchiveInode {
+    fn node(&self) -> &Node {
+        &self.nodes[self.index]
+    }
+
+    fn child(&self, index: usize) -> ArchiveInode {
+        ArchiveInode {
+            nodes: self.nodes.clone(),
+            index,
+        }
+    }
+}
+
+impl Inode for ArchiveInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node();
+
+        Metadata {
+            // Inode numbers start at 1.
+            inode: self.index as u64 + 1,
+            kind: node.kind,
+            size: match node.kind {
+                NodeKind::Directory => node.children.len() as u64,
+                _ => node.data.len() as u64,
+            },
+            permissions: node.permissions,
+            links: node.links,
+            modified: node.modified,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node();
+
+        if node.kind != NodeKind::File {
+            return Err(match node.kind {
+                NodeKind::Directory => FsError::IsADirectory,
+                _ => FsError::Unsupported,
+            });
+        }
+
+        let offset = (offset as usize).min(node.data.len());
+        let length = buffer.len().min(node.data.len() - offset);
+        buffer[..length].copy_from_slice(&node.data[offset..offset + length]);
+
+        Ok(length)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let node = self.node();
+
+        if node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        node.children
+            .get(name)
+            .map(|&index| Arc::new(self.child(index)) as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self, position: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let node = self.node();
+
+        if node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        // Positions are indices, the entries are sorted by name.
+        Ok(node
+            .children
+            .iter()
+            .nth(position as usize)
+            .map(|(name, &index)| {
+                let entry = DirEntry {
+                    name: name.clone(),
+                    inode: index as u64 + 1,
+                    kind: self.nodes[index].kind,
+                };
+
+                (entry, position + 1)
+            }))
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let node = self.node();
+
+        if node.kind != NodeKind::Symlink {
+            return Err(FsError::NotASymlink);
+        }
+
+        Ok(String::from_utf8_lossy(node.data).into_owned())
+    }
+}
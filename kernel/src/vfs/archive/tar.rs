@@ -0,0 +1,217 @@
+use crate::vfs::archive::{Builder, Entry};
+use crate::vfs::{FsError, NodeKind};
+use alloc::string::String;
+use log::debug;
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const SIZE: core::ops::Range<usize> = 124..136;
+const MODIFIED: core::ops::Range<usize> = 136..148;
+const CHECKSUM: core::ops::Range<usize> = 148..156;
+const TYPE_FLAG: usize = 156;
+const LINK_NAME: core::ops::Range<usize> = 157..257;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+const TYPE_FILE: u8 = b'0';
+// Pre-POSIX archives mark files with a NUL.
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_CONTIGUOUS_FILE: u8 = b'7';
+const TYPE_PAX_HEADER: u8 = b'x';
+const TYPE_PAX_GLOBAL_HEADER: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK_NAME: u8 = b'K';
+
+/// Both POSIX `ustar\0` and GNU `ustar ` start like this.
+pub fn is_tar(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && &data[MAGIC] == b"ustar"
+}
+
+/// Parses a number field, octal or, for large values in GNU archives, base-256 when the high
+/// bit of the first byte is set.
+fn number(field: &[u8]) -> Result<u64, FsError> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold((field[0] & 0x7F) as u64, |value, byte| {
+                value.checked_mul(256).map(|value| value | *byte as u64)
+            })
+            .ok_or(FsError::Corrupted);
+    }
+
+    let digits = field
+        .iter()
+        .skip_while(|byte| **byte == b' ')
+        .take_while(|byte| (b'0'..=b'7').contains(*byte));
+
+    digits
+        .fold(Some(0u64), |value, byte| {
+            value?
+                .checked_mul(8)
+                .map(|value| value + (byte - b'0') as u64)
+        })
+        .ok_or(FsError::Corrupted)
+}
+
+/// A NUL terminated field, without the NUL.
+fn terminated(field: &[u8]) -> &[u8] {
+    let length = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+
+    &field[..length]
+}
+
+fn string(field: &[u8]) -> String {
+    String::from_utf8_lossy(terminated(field)).into_owned()
+}
+
+fn checksum_matches(header: &[u8]) -> Result<bool, FsError> {
+    let expected = number(&header[CHECKSUM])?;
+
+    // Calculated with the checksum field itself as spaces.
+    let sum = header
+        .iter()
+        .enumerate()
+        .map(|(offset, byte)| {
+            if CHECKSUM.contains(&offset) {
+                b' ' as u64
+            } else {
+                *byte as u64
+            }
+        })
+        .sum::<u64>();
+
+    Ok(sum == expected)
+}
+
+/// Finds a record like `path=...` in a pax extended header, records look like
+/// `<length> <key>=<value>\n`.
+fn pax_record<'a>(data: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let space = rest.iter().position(|byte| *byte == b' ')?;
+        let length = core::str::from_utf8(&rest[..space])
+            .ok()?
+            .parse::<usize>()
+            .ok()?;
+        let record = rest.get(space + 1..length)?;
+
+        if let Some(value) = record
+            .strip_prefix(key.as_bytes())
+            .and_then(|record| record.strip_prefix(b"="))
+        {
+            return Some(value.strip_suffix(b"\n").unwrap_or(value));
+        }
+
+        rest = &rest[length..];
+    }
+
+    None
+}
+
+pub fn read(data: &'static [u8], builder: &mut Builder) -> Result<(), FsError> {
+    let mut offset = 0;
+    // Set by the headers preceding an entry for names that don't fit into it. Link names are
+    // kept as they are in the archive, they become symlink targets.
+    let mut long_name = None;
+    let mut long_link_name: Option<&'static [u8]> = None;
+
+    while offset + BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + BLOCK_SIZE];
+
+        // The archive ends with two zero blocks, one is enough for us.
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+
+        if !checksum_matches(header)? {
+            return Err(FsError::Corrupted);
+        }
+
+        let size = number(&header[SIZE])? as usize;
+        let contents_start = offset + BLOCK_SIZE;
+        let contents = contents_start
+            .checked_add(size)
+            .and_then(|end| data.get(contents_start..end))
+            .ok_or(FsError::Corrupted)?;
+
+        offset = contents_start + size.next_multiple_of(BLOCK_SIZE);
+
+        let type_flag = header[TYPE_FLAG];
+
+        match type_flag {
+            TYPE_GNU_LONG_NAME => {
+                long_name = Some(string(contents));
+                continue;
+            }
+            TYPE_GNU_LONG_LINK_NAME => {
+                long_link_name = Some(terminated(contents));
+                continue;
+            }
+            TYPE_PAX_HEADER => {
+                long_name = pax_record(contents, "path")
+                    .map(|path| String::from_utf8_lossy(path).into_owned());
+                long_link_name = pax_record(contents, "linkpath");
+                continue;
+            }
+            TYPE_PAX_GLOBAL_HEADER => continue,
+            _ => {}
+        }
+
+        let path = long_name.take().unwrap_or_else(|| {
+            let name = string(&header[NAME]);
+            let prefix = string(&header[PREFIX]);
+
+            if prefix.is_empty() {
+                name
+            } else {
+                prefix + "/" + &name
+            }
+        });
+        let link_name = long_link_name
+            .take()
+            .unwrap_or_else(|| terminated(&header[LINK_NAME]));
+
+        let permissions = (number(&header[MODE])? & 0o7777) as u16;
+        let modified = number(&header[MODIFIED])?;
+
+        let (kind, node_data) = match type_flag {
+            TYPE_FILE | TYPE_FILE_OLD | TYPE_CONTIGUOUS_FILE => (NodeKind::File, contents),
+            TYPE_DIRECTORY => (NodeKind::Directory, &[][..]),
+            TYPE_SYMLINK => (NodeKind::Symlink, link_name),
+            TYPE_HARD_LINK => {
+                let target = String::from_utf8_lossy(link_name);
+
+                match builder.find(&target) {
+                    Some(index) => builder.add_link(&path, index),
+                    None => debug!("initramfs: {} links to missing {}", path, target),
+                }
+                continue;
+            }
+            _ => {
+                debug!("initramfs: skipping {}, type {:?}", path, type_flag as char);
+                continue;
+            }
+        };
+
+        builder.add(
+            &path,
+            Entry {
+                kind,
+                permissions,
+                modified,
+                data: node_data,
+            },
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use crate::vfs::{FileSystem, FsError, Inode, NodeKind};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+
+/// A name in the directory tree, binding an inode to its place. Children are cached once looked
+/// up, so resolving the same path again doesn't go to the filesystem.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    // `None` for the root of everything. The root of a mounted filesystem has the mount
+    // point's parent, so `..` leaves the filesystem.
+    parent: Option<Arc<Dentry>>,
+    filesystem: Arc<dyn FileSystem>,
+    children: Spinlock<BTreeMap<String, Arc<Dentry>>>,
+}
+
+#[allow(unused)]
+impl Dentry {
+    pub fn new(
+        name: &str,
+        inode: Arc<dyn Inode>,
+        parent: Option<Arc<Dentry>>,
+        filesystem: Arc<dyn FileSystem>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            inode,
+            parent,
+            filesystem,
+            children: Spinlock::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn parent(&self) -> Option<&Arc<Dentry>> {
+        self.parent.as_ref()
+    }
+
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.filesystem
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.inode.metadata().kind
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.filesystem.is_read_only()
+    }
+
+    /// The absolute path, without symlinks, `.` or `..`.
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self;
+
+        while let Some(parent) = &dentry.parent {
+            names.push(dentry.name.as_str());
+            dentry = parent;
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+
+        names.iter().rev().fold(String::new(), |mut path, name| {
+            path.push('/');
+            path.push_str(name);
+            path
+        })
+    }
+
+    /// The entry `name` of this directory. Doesn't cross into filesystems mounted there, path
+    /// resolution takes care of that.
+    pub fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+
+        // Not holding the lock while the filesystem is busy, possibly with the disk.
+        let inode = self.inode.lookup(name)?;
+        let child = Dentry::new(name, inode, Some(self.clone()), self.filesystem.clone());
+
+        Ok(self
+            .children
+            .lock()
+            .entry(name.to_string())
+            .or_insert(child)
+            .clone())
+    }
+
+    /// Drops the cached entry `name`, after it was removed.
+    pub fn forget_child(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+}
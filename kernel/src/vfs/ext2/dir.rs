@@ -0,0 +1,330 @@
+use crate::vfs::ext2::node::{
+    RawInode, MODE_BLOCK_DEVICE, MODE_CHAR_DEVICE, MODE_DIRECTORY, MODE_FIFO, MODE_FILE,
+    MODE_SOCKET, MODE_SYMLINK, MODE_TYPE,
+};
+use crate::vfs::ext2::{set_u16, set_u32, u16_at, u32_at, Ext2};
+use crate::vfs::{FsError, NodeKind};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ENTRY_INODE: usize = 0;
+const ENTRY_LENGTH: usize = 4;
+const ENTRY_NAME_LENGTH: usize = 6;
+// The high byte of the name length without the file type feature, always 0 as names are short.
+const ENTRY_FILE_TYPE: usize = 7;
+const ENTRY_NAME: usize = 8;
+
+const MAX_NAME_LENGTH: usize = 255;
+
+const FILE_TYPE_UNKNOWN: u8 = 0;
+const FILE_TYPE_FILE: u8 = 1;
+const FILE_TYPE_DIRECTORY: u8 = 2;
+const FILE_TYPE_CHAR_DEVICE: u8 = 3;
+const FILE_TYPE_BLOCK_DEVICE: u8 = 4;
+const FILE_TYPE_FIFO: u8 = 5;
+const FILE_TYPE_SOCKET: u8 = 6;
+const FILE_TYPE_SYMLINK: u8 = 7;
+
+/// The file type of a directory entry for a node of `mode`.
+pub fn file_type(mode: u16) -> u8 {
+    match mode & MODE_TYPE {
+        MODE_FILE => FILE_TYPE_FILE,
+        MODE_DIRECTORY => FILE_TYPE_DIRECTORY,
+        MODE_CHAR_DEVICE => FILE_TYPE_CHAR_DEVICE,
+        MODE_BLOCK_DEVICE => FILE_TYPE_BLOCK_DEVICE,
+        MODE_FIFO => FILE_TYPE_FIFO,
+        MODE_SOCKET => FILE_TYPE_SOCKET,
+        MODE_SYMLINK => FILE_TYPE_SYMLINK,
+        _ => FILE_TYPE_UNKNOWN,
+    }
+}
+
+/// The kind of node behind a directory entry, if its file type says.
+pub fn kind(file_type: u8) -> Option<NodeKind> {
+    match file_type {
+        FILE_TYPE_FILE => Some(NodeKind::File),
+        FILE_TYPE_DIRECTORY => Some(NodeKind::Directory),
+        FILE_TYPE_CHAR_DEVICE => Some(NodeKind::CharDevice),
+        FILE_TYPE_BLOCK_DEVICE => Some(NodeKind::BlockDevice),
+        FILE_TYPE_SYMLINK => Some(NodeKind::Symlink),
+        _ => None,
+    }
+}
+
+/// The space an entry with a name of `length` bytes needs, entries are 4 byte aligned.
+fn entry_size(length: usize) -> usize {
+    (ENTRY_NAME + length).next_multiple_of(4)
+}
+
+fn write_entry(
+    block: &mut [u8],
+    offset: usize,
+    length: usize,
+    inode: u32,
+    name: &str,
+    file_type: u8,
+) {
+    set_u32(block, offset + ENTRY_INODE, inode);
+    set_u16(block, offset + ENTRY_LENGTH, length as u16);
+    block[offset + ENTRY_NAME_LENGTH] = name.len() as u8;
+    block[offset + ENTRY_FILE_TYPE] = file_type;
+    block[offset + ENTRY_NAME..offset + ENTRY_NAME + name.len()].copy_from_slice(name.as_bytes());
+}
+
+/// The first block of a new directory, with its `.` and `..` entries.
+pub fn dot_entries(block_size: usize, inode: u32, parent: u32) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    let dot = entry_size(1);
+
+    write_entry(&mut block, 0, dot, inode, ".", FILE_TYPE_DIRECTORY);
+    write_entry(
+        &mut block,
+        dot,
+        block_size - dot,
+        parent,
+        "..",
+        FILE_TYPE_DIRECTORY,
+    );
+
+    block
+}
+
+/// An entry of a directory.
+pub struct DirectoryEntry {
+    pub name: String,
+    pub inode: u32,
+    pub file_type: u8,
+    // Where the entry is within the directory.
+    pub position: u64,
+}
+
+impl DirectoryEntry {
+    fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+/// Calls `f` with each entry's block, the block's number and index in the directory and the
+/// entry's offset in it, until it returns `Some`.
+fn walk<T>(
+    ext2: &Ext2,
+    directory: &mut RawInode,
+    mut f: impl FnMut(&mut [u8], u32, u64, usize) -> Result<Option<T>, FsError>,
+) -> Result<Option<T>, FsError> {
+    let blocks = directory.size().div_ceil(ext2.block_size);
+
+    for index in 0..blocks {
+        let block = ext2
+            .map_block(directory, index, false)?
+            .ok_or(FsError::Corrupted)?;
+        let mut data = ext2.read_block(block)?;
+
+        let mut offset = 0;
+        while offset < data.len() {
+            // Not even room for the header.
+            if offset + ENTRY_NAME > data.len() {
+                return Err(FsError::Corrupted);
+            }
+
+            let length = u16_at(&data, offset + ENTRY_LENGTH) as usize;
+
+            // Entries can't cross blocks or be too short for their names.
+            if length < ENTRY_NAME
+                || length % 4 != 0
+                || offset + length > data.len()
+                || entry_size(data[offset + ENTRY_NAME_LENGTH] as usize) > length
+            {
+                return Err(FsError::Corrupted);
+            }
+
+            if let Some(result) = f(&mut data, block, index, offset)? {
+                return Ok(Some(result));
+            }
+
+            offset += length;
+        }
+    }
+
+    Ok(None)
+}
+
+fn entry(ext2: &Ext2, data: &[u8], index: u64, offset: usize) -> Option<DirectoryEntry> {
+    let inode = u32_at(data, offset + ENTRY_INODE);
+
+    // Unused, what's left of removed entries.
+    if inode == 0 {
+        return None;
+    }
+
+    let length = data[offset + ENTRY_NAME_LENGTH] as usize;
+    let name = &data[offset + ENTRY_NAME..offset + ENTRY_NAME + length];
+
+    Some(DirectoryEntry {
+        name: String::from_utf8_lossy(name).into(),
+        inode,
+        file_type: if ext2.has_file_types {
+            data[offset + ENTRY_FILE_TYPE]
+        } else {
+            FILE_TYPE_UNKNOWN
+        },
+        position: index * ext2.block_size + offset as u64,
+    })
+}
+
+impl Ext2 {
+    /// The first entry at `position` or after it other than `.` and `..`, along with where the
+    /// next one is.
+    pub fn entry_at(
+        &self,
+        directory: &mut RawInode,
+        position: u64,
+    ) -> Result<Option<(DirectoryEntry, u64)>, FsError> {
+        walk(self, directory, |data, _, index, offset| {
+            let next = index * self.block_size
+                + offset as u64
+                + u16_at(data, offset + ENTRY_LENGTH) as u64;
+
+            if next <= position {
+                return Ok(None);
+            }
+
+            Ok(entry(self, data, index, offset)
+                .filter(|entry| !entry.is_dot())
+                .map(|entry| (entry, next)))
+        })
+    }
+
+    pub fn find(
+        &self,
+        directory: &mut RawInode,
+        name: &str,
+    ) -> Result<Option<DirectoryEntry>, FsError> {
+        walk(self, directory, |data, _, index, offset| {
+            Ok(entry(self, data, index, offset).filter(|entry| entry.name == name))
+        })
+    }
+
+    /// Whether the directory has nothing but `.` and `..`.
+    pub fn is_empty(&self, directory: &mut RawInode) -> Result<bool, FsError> {
+        Ok(self.entry_at(directory, 0)?.is_none())
+    }
+
+    /// Adds an entry, in the first gap big enough or a new block at the end. The directory's
+    /// inode is changed in memory only.
+    pub fn add_entry(
+        &self,
+        directory: &mut RawInode,
+        name: &str,
+        inode: u32,
+        file_type: u8,
+    ) -> Result<(), FsError> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+
+        let file_type = if self.has_file_types {
+            file_type
+        } else {
+            FILE_TYPE_UNKNOWN
+        };
+        let needed = entry_size(name.len());
+
+        let added = walk(self, directory, |data, block, _, offset| {
+            let length = u16_at(data, offset + ENTRY_LENGTH) as usize;
+
+            let (start, free) = if u32_at(data, offset + ENTRY_INODE) == 0 {
+                (offset, length)
+            } else {
+                let used = entry_size(data[offset + ENTRY_NAME_LENGTH] as usize);
+                (offset + used, length - used)
+            };
+
+            if free < needed {
+                return Ok(None);
+            }
+
+            if start != offset {
+                set_u16(data, offset + ENTRY_LENGTH, (start - offset) as u16);
+            }
+
+            write_entry(data, start, free, inode, name, file_type);
+            self.write_block(block, data)?;
+
+            Ok(Some(()))
+        })?;
+
+        if added.is_none() {
+            let index = directory.size() / self.block_size;
+            let block = self
+                .map_block(directory, index, true)?
+                .ok_or(FsError::NoSpace)?;
+
+            let mut data = vec![0; self.block_size as usize];
+            write_entry(
+                &mut data,
+                0,
+                self.block_size as usize,
+                inode,
+                name,
+                file_type,
+            );
+            self.write_block(block, &data)?;
+
+            directory.set_size((index + 1) * self.block_size);
+        }
+
+        directory.clear_index();
+        directory.touch();
+
+        Ok(())
+    }
+
+    /// Removes the entry, merging its space into the one before it. The directory's inode is
+    /// changed in memory only.
+    pub fn remove_entry(
+        &self,
+        directory: &mut RawInode,
+        entry: &DirectoryEntry,
+    ) -> Result<(), FsError> {
+        let index = entry.position / self.block_size;
+        let within = (entry.position % self.block_size) as usize;
+
+        let block = self
+            .map_block(directory, index, false)?
+            .ok_or(FsError::Corrupted)?;
+        let mut data = self.read_block(block)?;
+
+        if within == 0 {
+            // The first entry of a block has none to merge with.
+            set_u32(&mut data, ENTRY_INODE, 0);
+        } else {
+            let mut previous = 0;
+
+            loop {
+                let next = previous + u16_at(&data, previous + ENTRY_LENGTH) as usize;
+
+                if next == within {
+                    break;
+                }
+
+                if next <= previous || next > within {
+                    return Err(FsError::Corrupted);
+                }
+
+                previous = next;
+            }
+
+            let length = u16_at(&data, previous + ENTRY_LENGTH) as usize
+                + u16_at(&data, within + ENTRY_LENGTH) as usize;
+            set_u16(&mut data, previous + ENTRY_LENGTH, length as u16);
+        }
+
+        self.write_block(block, &data)?;
+
+        directory.clear_index();
+        directory.touch();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use crate::vfs::ext2::dir::{self, dot_entries};
+use crate::vfs::ext2::node::{
+    RawInode, FAST_SYMLINK_LENGTH, MODE_DIRECTORY, MODE_FILE, MODE_PERMISSIONS, MODE_SYMLINK,
+};
+use crate::vfs::ext2::{Ext2, READ_ONLY_LARGE_FILES};
+use crate::vfs::{DirEntry, FsError, Inode, Metadata, NodeKind};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// A node of an ext2 volume. The inode is read from the device whenever it's needed, so several
+/// of these for the same node don't disagree.
+pub struct Ext2Inode {
+    ext2: Arc<Ext2>,
+    number: u32,
+}
+
+impl Ext2Inode {
+    pub fn new(ext2: Arc<Ext2>, number: u32) -> Self {
+        Self { ext2, number }
+    }
+
+    /// The inode, read fresh from the device.
+    fn inode(&self) -> Result<RawInode, FsError> {
+        let inode = self.ext2.read_inode(self.number)?;
+
+        // Removed since it was looked up.
+        if inode.links() == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(inode)
+    }
+
+    fn directory(&self) -> Result<RawInode, FsError> {
+        let inode = self.inode()?;
+
+        if !inode.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(inode)
+    }
+
+    fn file(&self) -> Result<RawInode, FsError> {
+        let inode = self.inode()?;
+
+        match inode.kind() {
+            Some(NodeKind::File) => Ok(inode),
+            Some(NodeKind::Directory) => Err(FsError::IsADirectory),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn child(&self, number: u32) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode::new(self.ext2.clone(), number))
+    }
+
+    /// Creates a node of `mode` and links it into this directory, `init` fills it in before the
+    /// entry is added. Needs the write lock.
+    fn create_locked(
+        &self,
+        name: &str,
+        mode: u16,
+        links: u16,
+        init: impl FnOnce(&mut RawInode) -> Result<(), FsError>,
+    ) -> Result<RawInode, FsError> {
+        let mut directory = self.directory()?;
+
+        if self.ext2.find(&mut directory, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mut inode = self.ext2.new_inode(self.number, mode, links)?;
+
+        let result = init(&mut inode)
+            .and_then(|_| self.ext2.write_inode(&inode))
+            .and_then(|_| {
+                let result =
+                    self.ext2
+                        .add_entry(&mut directory, name, inode.number, dir::file_type(mode));
+
+                // Whatever blocks the directory got, even if it failed.
+                self.ext2.write_inode(&directory)?;
+                result
+            });
+
+        if let Err(e) = result {
+            self.ext2.delete_inode(&mut inode)?;
+            return Err(e);
+        }
+
+        Ok(inode)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let inode = self.inode().ok();
+
+        Metadata {
+            inode: self.number as u64,
+            kind: inode
+                .as_ref()
+                .and_then(RawInode::kind)
+                .unwrap_or(NodeKind::File),
+            size: inode.as_ref().map_or(0, RawInode::size),
+            permissions: inode
+                .as_ref()
+                .map_or(0, |inode| inode.mode() & MODE_PERMISSIONS),
+            links: inode.as_ref().map_or(0, |inode| inode.links() as u32),
+            modified: inode.as_ref().map_or(0, |inode| inode.modified() as u64),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut inode = self.file()?;
+
+        self.ext2.read_data(&mut inode, offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        let mut inode = self.file()?;
+
+        // Blocks allocated before a failure are the file's, so the inode is written anyway.
+        let result = self.ext2.write_data(&mut inode, offset, buffer);
+        self.ext2.write_inode(&inode)?;
+        result?;
+
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        let mut inode = self.file()?;
+
+        // Older drivers take sizes of 2 GiB and more as negative.
+        if size > i32::MAX as u64 {
+            self.ext2.enable_read_only_feature(READ_ONLY_LARGE_FILES)?;
+        }
+
+        // Growing leaves a hole, which reads as zeros.
+        if size < inode.size() {
+            let block_size = self.ext2.block_size;
+            let result = self
+                .ext2
+                .free_blocks(&mut inode, size.div_ceil(block_size))
+                .and_then(|_| {
+                    // The rest of the last block has to read as zeros if the file grows again.
+                    let within = size % block_size;
+
+                    if within == 0 {
+                        return Ok(());
+                    }
+
+                    match self.ext2.map_block(&mut inode, size / block_size, false)? {
+                        Some(block) => self.ext2.write(
+                            self.ext2.block_offset(block) + within,
+                            &vec![0; (block_size - within) as usize],
+                        ),
+                        None => Ok(()),
+                    }
+                });
+
+            if let Err(e) = result {
+                self.ext2.write_inode(&inode)?;
+                return Err(e);
+            }
+        }
+
+        inode.set_size(size);
+        inode.touch();
+
+        self.ext2.write_inode(&inode)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let entry = self
+            .ext2
+            .find(&mut self.directory()?, name)?
+            .ok_or(FsError::NotFound)?;
+
+        Ok(self.child(entry.inode))
+    }
+
+    fn read_dir(&self, position: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let Some((entry, next)) = self.ext2.entry_at(&mut self.directory()?, position)? else {
+            return Ok(None);
+        };
+
+        // Without file types in the entries, the inode knows.
+        let kind = match dir::kind(entry.file_type) {
+            Some(kind) => kind,
+            None => self
+                .ext2
+                .read_inode(entry.inode)?
+                .kind()
+                .unwrap_or(NodeKind::File),
+        };
+
+        let entry = DirEntry {
+            name: entry.name,
+            inode: entry.inode as u64,
+            kind,
+        };
+
+        Ok(Some((entry, next)))
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        let inode = match kind {
+            NodeKind::File => self.create_locked(name, MODE_FILE | 0o644, 1, |_| Ok(()))?,
+            NodeKind::Directory => {
+                // Its `.` and the entry in the parent.
+                let inode = self.create_locked(name, MODE_DIRECTORY | 0o755, 2, |inode| {
+                    let block = self
+                        .ext2
+                        .map_block(inode, 0, true)?
+                        .ok_or(FsError::NoSpace)?;
+                    let entries =
+                        dot_entries(self.ext2.block_size as usize, inode.number, self.number);
+
+                    inode.set_size(self.ext2.block_size);
+                    self.ext2.write_block(block, &entries)
+                })?;
+
+                // The new directory's `..`.
+                let mut directory = self.directory()?;
+                directory.set_links(directory.links() + 1);
+                self.ext2.write_inode(&directory)?;
+
+                inode
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+
+        Ok(self.child(inode.number))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        // A slow symlink's target has to fit in its one block.
+        if target.len() as u64 > self.ext2.block_size {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let inode = self.create_locked(name, MODE_SYMLINK | 0o777, 1, |inode| {
+            if target.len() < FAST_SYMLINK_LENGTH {
+                inode.set_fast_symlink_target(target.as_bytes());
+                Ok(())
+            } else {
+                self.ext2.write_data(inode, 0, target.as_bytes())
+            }
+        })?;
+
+        Ok(self.child(inode.number))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        let mut directory = self.directory()?;
+        let entry = self
+            .ext2
+            .find(&mut directory, name)?
+            .ok_or(FsError::NotFound)?;
+
+        let mut inode = self.ext2.read_inode(entry.inode)?;
+
+        if inode.is_directory() {
+            if !self.ext2.is_empty(&mut inode)? {
+                return Err(FsError::NotEmpty);
+            }
+
+            // Its `..` goes with it.
+            directory.set_links(directory.links().saturating_sub(1));
+        }
+
+        self.ext2.remove_entry(&mut directory, &entry)?;
+        self.ext2.write_inode(&directory)?;
+
+        // A directory's other link is its own `.`.
+        let links = if inode.is_directory() {
+            0
+        } else {
+            inode.links().saturating_sub(1)
+        };
+
+        if links == 0 {
+            self.ext2.delete_inode(&mut inode)
+        } else {
+            inode.set_links(links);
+            inode.touch();
+            self.ext2.write_inode(&inode)
+        }
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let mut inode = self.inode()?;
+
+        if inode.kind() != Some(NodeKind::Symlink) {
+            return Err(FsError::NotASymlink);
+        }
+
+        let target = if inode.is_fast_symlink(self.ext2.block_size) {
+            inode.fast_symlink_target().to_vec()
+        } else {
+            // Targets are at most a block, anything bigger is garbage.
+            if inode.size() > self.ext2.block_size {
+                return Err(FsError::Corrupted);
+            }
+
+            let mut target: Vec<u8> = vec![0; inode.size() as usize];
+            let length = self.ext2.read_data(&mut inode, 0, &mut target)?;
+            target.truncate(length);
+            target
+        };
+
+        String::from_utf8(target).map_err(|_| FsError::Corrupted)
+    }
+}
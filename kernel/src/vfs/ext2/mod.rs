@@ -0,0 +1,509 @@
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::rtc;
+use crate::vfs::ext2::inode::Ext2Inode;
+use crate::vfs::{FileSystem, FsError, Inode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+use spinning_top::Spinlock;
+
+mod dir;
+mod inode;
+mod node;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+const SUPERBLOCK_INODES_COUNT: usize = 0;
+const SUPERBLOCK_BLOCKS_COUNT: usize = 4;
+const SUPERBLOCK_FREE_BLOCKS: usize = 12;
+const SUPERBLOCK_FREE_INODES: usize = 16;
+const SUPERBLOCK_FIRST_DATA_BLOCK: usize = 20;
+const SUPERBLOCK_LOG_BLOCK_SIZE: usize = 24;
+const SUPERBLOCK_BLOCKS_PER_GROUP: usize = 32;
+const SUPERBLOCK_INODES_PER_GROUP: usize = 40;
+const SUPERBLOCK_MAGIC: usize = 56;
+const SUPERBLOCK_STATE: usize = 58;
+const SUPERBLOCK_REVISION: usize = 76;
+const SUPERBLOCK_FIRST_INODE: usize = 84;
+const SUPERBLOCK_INODE_SIZE: usize = 88;
+const SUPERBLOCK_FEATURES_INCOMPATIBLE: usize = 96;
+const SUPERBLOCK_FEATURES_READ_ONLY: usize = 100;
+const SUPERBLOCK_VOLUME_NAME: usize = 120;
+const VOLUME_NAME_LENGTH: usize = 16;
+
+const MAX_LOG_BLOCK_SIZE: u32 = 5;
+
+const STATE_CLEAN: u16 = 1;
+
+// Features we can't ignore, anything else and we don't touch the filesystem.
+const INCOMPATIBLE_FILE_TYPE: u32 = 0x0002;
+// Only moves the bitmaps and inode tables, the group descriptors still point to them.
+const INCOMPATIBLE_FLEXIBLE_GROUPS: u32 = 0x0200;
+const SUPPORTED_INCOMPATIBLE: u32 = INCOMPATIBLE_FILE_TYPE | INCOMPATIBLE_FLEXIBLE_GROUPS;
+
+// Features we have to know about to write, others only allow reading.
+const READ_ONLY_SPARSE_SUPERBLOCKS: u32 = 0x0001;
+const READ_ONLY_LARGE_FILES: u32 = 0x0002;
+const SUPPORTED_READ_ONLY: u32 = READ_ONLY_SPARSE_SUPERBLOCKS | READ_ONLY_LARGE_FILES;
+
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const GROUP_BLOCK_BITMAP: usize = 0;
+const GROUP_INODE_BITMAP: usize = 4;
+const GROUP_INODE_TABLE: usize = 8;
+const GROUP_FREE_BLOCKS: usize = 12;
+const GROUP_FREE_INODES: usize = 14;
+const GROUP_DIRECTORIES: usize = 16;
+
+const ROOT_INODE: u32 = 2;
+// Revision 0 has fixed inodes, later ones say in the superblock.
+const REVISION_0_INODE_SIZE: u16 = 128;
+const REVISION_0_FIRST_INODE: u32 = 11;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Seconds since the Unix epoch, the way ext2 stores times.
+fn now() -> u32 {
+    rtc::now().unix_timestamp() as u32
+}
+
+/// The layout of an ext2 volume and allocation of its blocks and inodes. Bitmaps, group
+/// descriptors and the superblock are updated on the device right away, the block cache keeps
+/// that cheap.
+struct Ext2 {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    blocks_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u16,
+    first_inode: u32,
+    group_count: u32,
+    has_file_types: bool,
+    read_only: bool,
+    label: String,
+    // Held by everything that changes the filesystem, reading doesn't need it.
+    write_lock: Spinlock<()>,
+}
+
+impl Ext2 {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        if device.size() < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(FsError::Unsupported);
+        }
+
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        read_bytes(device.as_ref(), SUPERBLOCK_OFFSET, &mut superblock)?;
+
+        if u16_at(&superblock, SUPERBLOCK_MAGIC) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+
+        let log_block_size = u32_at(&superblock, SUPERBLOCK_LOG_BLOCK_SIZE);
+        let blocks_count = u32_at(&superblock, SUPERBLOCK_BLOCKS_COUNT);
+        let first_data_block = u32_at(&superblock, SUPERBLOCK_FIRST_DATA_BLOCK);
+        let blocks_per_group = u32_at(&superblock, SUPERBLOCK_BLOCKS_PER_GROUP);
+        let inodes_count = u32_at(&superblock, SUPERBLOCK_INODES_COUNT);
+        let inodes_per_group = u32_at(&superblock, SUPERBLOCK_INODES_PER_GROUP);
+        let revision = u32_at(&superblock, SUPERBLOCK_REVISION);
+
+        // Blocks from 1 KiB up to 32 KiB. 64 KiB blocks need another encoding of directory
+        // entry lengths, which are 16 bits.
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            warn!(
+                "{}: ext2 blocks over 32 KiB aren't supported",
+                device.name()
+            );
+            return Err(FsError::Unsupported);
+        }
+
+        let block_size = 1024 << log_block_size;
+        // A group's bitmaps are a block each.
+        let bits_per_block = block_size as u32 * 8;
+
+        if blocks_per_group == 0
+            || blocks_per_group > bits_per_block
+            || inodes_per_group == 0
+            || inodes_per_group > bits_per_block
+            || blocks_count <= first_data_block
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+
+        // Every group has a full inode table.
+        if inodes_count as u64 != inodes_per_group as u64 * group_count as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        let (inode_size, first_inode, incompatible, read_only_features) = if revision == 0 {
+            (REVISION_0_INODE_SIZE, REVISION_0_FIRST_INODE, 0, 0)
+        } else {
+            (
+                u16_at(&superblock, SUPERBLOCK_INODE_SIZE),
+                u32_at(&superblock, SUPERBLOCK_FIRST_INODE),
+                u32_at(&superblock, SUPERBLOCK_FEATURES_INCOMPATIBLE),
+                u32_at(&superblock, SUPERBLOCK_FEATURES_READ_ONLY),
+            )
+        };
+
+        if inode_size < REVISION_0_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size as u64 > block_size
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let name = device.name();
+
+        // Extents, 64 bit block numbers and the like, ext4 really.
+        if incompatible & !SUPPORTED_INCOMPATIBLE != 0 {
+            warn!(
+                "{}: ext2 with unsupported features {:#x}",
+                name,
+                incompatible & !SUPPORTED_INCOMPATIBLE
+            );
+            return Err(FsError::Unsupported);
+        }
+
+        let mut read_only = device.is_read_only();
+
+        if read_only_features & !SUPPORTED_READ_ONLY != 0 {
+            warn!(
+                "{}: features {:#x} are only supported read only",
+                name,
+                read_only_features & !SUPPORTED_READ_ONLY
+            );
+            read_only = true;
+        }
+
+        if u16_at(&superblock, SUPERBLOCK_STATE) != STATE_CLEAN {
+            warn!(
+                "{}: ext2 wasn't unmounted cleanly, mounting read only",
+                name
+            );
+            read_only = true;
+        }
+
+        let label =
+            String::from_utf8_lossy(&superblock[SUPERBLOCK_VOLUME_NAME..][..VOLUME_NAME_LENGTH])
+                .trim_end_matches('\0')
+                .into();
+
+        Ok(Self {
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            group_count,
+            has_file_types: incompatible & INCOMPATIBLE_FILE_TYPE != 0,
+            read_only,
+            label,
+            write_lock: Spinlock::new(()),
+            device,
+        })
+    }
+
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(read_bytes(self.device.as_ref(), offset, buffer)?)
+    }
+
+    fn write(&self, offset: u64, buffer: &[u8]) -> Result<(), FsError> {
+        Ok(write_bytes(self.device.as_ref(), offset, buffer)?)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsError> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut data = vec![0; self.block_size as usize];
+        self.read(self.block_offset(block), &mut data)?;
+
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<(), FsError> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        self.write(self.block_offset(block), data)
+    }
+
+    fn group_descriptor_offset(&self, group: u32) -> u64 {
+        // The table follows the superblock's block.
+        self.block_offset(self.first_data_block + 1) + group as u64 * GROUP_DESCRIPTOR_SIZE
+    }
+
+    fn group_descriptor(
+        &self,
+        group: u32,
+    ) -> Result<[u8; GROUP_DESCRIPTOR_SIZE as usize], FsError> {
+        let mut descriptor = [0; GROUP_DESCRIPTOR_SIZE as usize];
+        self.read(self.group_descriptor_offset(group), &mut descriptor)?;
+
+        Ok(descriptor)
+    }
+
+    /// Where the inode is on the device.
+    fn inode_offset(&self, number: u32) -> Result<u64, FsError> {
+        if number == 0 || number > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        let table = u32_at(&self.group_descriptor(group)?, GROUP_INODE_TABLE);
+
+        Ok(self.block_offset(table) + index as u64 * self.inode_size as u64)
+    }
+
+    fn group_of_inode(&self, number: u32) -> u32 {
+        (number - 1) / self.inodes_per_group
+    }
+
+    /// Updates the free counts of a group and the whole filesystem by the given amounts.
+    fn adjust_counts(
+        &self,
+        group: u32,
+        blocks: i16,
+        inodes: i16,
+        directories: i16,
+    ) -> Result<(), FsError> {
+        let mut descriptor = self.group_descriptor(group)?;
+
+        for (offset, delta) in [
+            (GROUP_FREE_BLOCKS, blocks),
+            (GROUP_FREE_INODES, inodes),
+            (GROUP_DIRECTORIES, directories),
+        ] {
+            let value = u16_at(&descriptor, offset).wrapping_add_signed(delta);
+            set_u16(&mut descriptor, offset, value);
+        }
+
+        self.write(self.group_descriptor_offset(group), &descriptor)?;
+
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        self.read(SUPERBLOCK_OFFSET, &mut superblock)?;
+
+        for (offset, delta) in [
+            (SUPERBLOCK_FREE_BLOCKS, blocks),
+            (SUPERBLOCK_FREE_INODES, inodes),
+        ] {
+            let value = u32_at(&superblock, offset).wrapping_add_signed(delta as i32);
+            set_u32(&mut superblock, offset, value);
+        }
+
+        // Backup superblocks and descriptors are left alone, they don't need the counts.
+        self.write(SUPERBLOCK_OFFSET, &superblock)
+    }
+
+    /// Sets the first clear bit of the bitmap in `block` among the first `count` and returns it.
+    fn allocate_bit(&self, block: u32, count: u32) -> Result<Option<u32>, FsError> {
+        let mut bitmap = self.read_block(block)?;
+
+        let bit = (0..count).find(|bit| bitmap[*bit as usize / 8] & (1 << (bit % 8)) == 0);
+
+        if let Some(bit) = bit {
+            bitmap[bit as usize / 8] |= 1 << (bit % 8);
+            self.write_block(block, &bitmap)?;
+        }
+
+        Ok(bit)
+    }
+
+    fn free_bit(&self, block: u32, bit: u32) -> Result<(), FsError> {
+        let mut bitmap = self.read_block(block)?;
+
+        if bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+            warn!("ext2: freeing bit {} of bitmap {} twice", bit, block);
+        }
+
+        bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+        self.write_block(block, &bitmap)
+    }
+
+    /// Allocates a block, preferably in `group`, and fills it with zeros.
+    fn allocate_block(&self, group: u32) -> Result<u32, FsError> {
+        for i in 0..self.group_count {
+            let group = (group + i) % self.group_count;
+            let descriptor = self.group_descriptor(group)?;
+
+            if u16_at(&descriptor, GROUP_FREE_BLOCKS) == 0 {
+                continue;
+            }
+
+            let first = self.first_data_block + group * self.blocks_per_group;
+            let count = self.blocks_per_group.min(self.blocks_count - first);
+
+            if let Some(bit) = self.allocate_bit(u32_at(&descriptor, GROUP_BLOCK_BITMAP), count)? {
+                self.adjust_counts(group, -1, 0, 0)?;
+
+                let block = first + bit;
+                self.write_block(block, &vec![0; self.block_size as usize])?;
+
+                return Ok(block);
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), FsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+
+        let descriptor = self.group_descriptor(group)?;
+        self.free_bit(u32_at(&descriptor, GROUP_BLOCK_BITMAP), bit)?;
+
+        self.adjust_counts(group, 1, 0, 0)
+    }
+
+    /// Allocates an inode number, preferably in `group`. The inode itself is up to the caller.
+    fn allocate_inode(&self, group: u32, directory: bool) -> Result<u32, FsError> {
+        for i in 0..self.group_count {
+            let group = (group + i) % self.group_count;
+            let descriptor = self.group_descriptor(group)?;
+
+            if u16_at(&descriptor, GROUP_FREE_INODES) == 0 {
+                continue;
+            }
+
+            let bitmap = u32_at(&descriptor, GROUP_INODE_BITMAP);
+
+            if let Some(bit) = self.allocate_bit(bitmap, self.inodes_per_group)? {
+                let number = group * self.inodes_per_group + bit + 1;
+
+                // The reserved inodes are marked used when the filesystem is made.
+                if number < self.first_inode {
+                    return Err(FsError::Corrupted);
+                }
+
+                self.adjust_counts(group, 0, -1, directory as i16)?;
+
+                return Ok(number);
+            }
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, number: u32, directory: bool) -> Result<(), FsError> {
+        let group = self.group_of_inode(number);
+        let descriptor = self.group_descriptor(group)?;
+
+        self.free_bit(
+            u32_at(&descriptor, GROUP_INODE_BITMAP),
+            (number - 1) % self.inodes_per_group,
+        )?;
+
+        self.adjust_counts(group, 0, 1, -(directory as i16))
+    }
+
+    /// Sets a read only compatible feature once something needs it, like a file of more than
+    /// 2 GiB.
+    fn enable_read_only_feature(&self, feature: u32) -> Result<(), FsError> {
+        let mut features = [0; 4];
+        self.read(
+            SUPERBLOCK_OFFSET + SUPERBLOCK_FEATURES_READ_ONLY as u64,
+            &mut features,
+        )?;
+
+        let features = u32::from_le_bytes(features);
+
+        if features & feature == 0 {
+            self.write(
+                SUPERBLOCK_OFFSET + SUPERBLOCK_FEATURES_READ_ONLY as u64,
+                &(features | feature).to_le_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An ext2 volume. Revision 1 filesystems as made by `mke2fs` are supported, ext3 ones as long
+/// as the journal is clean.
+pub struct Ext2Fs {
+    ext2: Arc<Ext2>,
+}
+
+#[allow(unused)]
+impl Ext2Fs {
+    /// Reads the superblock, [`FsError::Unsupported`] if the device doesn't contain an ext2
+    /// filesystem or one with features we don't know.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let ext2 = Ext2::new(device)?;
+
+        info!(
+            "{}: ext2 volume \"{}\", {} blocks of {} bytes in {} groups, {} inodes",
+            ext2.device.name(),
+            ext2.label,
+            ext2.blocks_count,
+            ext2.block_size,
+            ext2.group_count,
+            ext2.inodes_count
+        );
+
+        Ok(Self {
+            ext2: Arc::new(ext2),
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        &self.ext2.label
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode::new(self.ext2.clone(), ROOT_INODE))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.ext2.read_only
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _lock = self.ext2.write_lock.lock();
+
+        Ok(self.ext2.device.flush()?)
+    }
+}
@@ -0,0 +1,458 @@
+use crate::vfs::ext2::{now, set_u16, set_u32, u16_at, u32_at, Ext2, READ_ONLY_LARGE_FILES};
+use crate::vfs::{FsError, NodeKind};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const INODE_MODE: usize = 0;
+const INODE_SIZE: usize = 4;
+const INODE_ACCESSED: usize = 8;
+const INODE_CHANGED: usize = 12;
+const INODE_MODIFIED: usize = 16;
+const INODE_DELETED: usize = 20;
+const INODE_LINKS: usize = 26;
+// Counted in 512 byte sectors, whatever the block size.
+const INODE_SECTORS: usize = 28;
+const INODE_FLAGS: usize = 32;
+const INODE_BLOCKS: usize = 40;
+const INODE_FILE_ACL: usize = 104;
+const INODE_SIZE_HIGH: usize = 108;
+
+const SECTOR_SIZE: u64 = 512;
+
+pub const MODE_TYPE: u16 = 0xF000;
+pub const MODE_FIFO: u16 = 0x1000;
+pub const MODE_CHAR_DEVICE: u16 = 0x2000;
+pub const MODE_DIRECTORY: u16 = 0x4000;
+pub const MODE_BLOCK_DEVICE: u16 = 0x6000;
+pub const MODE_FILE: u16 = 0x8000;
+pub const MODE_SYMLINK: u16 = 0xA000;
+pub const MODE_SOCKET: u16 = 0xC000;
+pub const MODE_PERMISSIONS: u16 = 0o7777;
+
+// The directory has a hashed index, which writing it without updating the index breaks.
+const FLAG_INDEX: u32 = 0x1000;
+
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_BLOCK: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+// The most levels of indirection, a triple indirect block.
+const MAX_LEVELS: usize = 3;
+
+/// Symlink targets shorter than this are kept in the block pointers.
+pub const FAST_SYMLINK_LENGTH: usize = BLOCK_POINTERS * 4;
+
+/// An inode as it is on the device. Fields we don't know about are written back unchanged.
+pub struct RawInode {
+    pub number: u32,
+    data: Vec<u8>,
+}
+
+#[allow(unused)]
+impl RawInode {
+    pub fn mode(&self) -> u16 {
+        u16_at(&self.data, INODE_MODE)
+    }
+
+    pub fn kind(&self) -> Option<NodeKind> {
+        match self.mode() & MODE_TYPE {
+            MODE_FILE => Some(NodeKind::File),
+            MODE_DIRECTORY => Some(NodeKind::Directory),
+            MODE_SYMLINK => Some(NodeKind::Symlink),
+            MODE_BLOCK_DEVICE => Some(NodeKind::BlockDevice),
+            MODE_CHAR_DEVICE => Some(NodeKind::CharDevice),
+            // Fifos and sockets aren't something the VFS knows.
+            _ => None,
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_DIRECTORY
+    }
+
+    pub fn size(&self) -> u64 {
+        let low = u32_at(&self.data, INODE_SIZE) as u64;
+
+        // The high half is the directory ACL for anything but regular files.
+        if self.mode() & MODE_TYPE == MODE_FILE {
+            low | (u32_at(&self.data, INODE_SIZE_HIGH) as u64) << 32
+        } else {
+            low
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        set_u32(&mut self.data, INODE_SIZE, size as u32);
+
+        if self.mode() & MODE_TYPE == MODE_FILE {
+            set_u32(&mut self.data, INODE_SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    pub fn links(&self) -> u16 {
+        u16_at(&self.data, INODE_LINKS)
+    }
+
+    pub fn set_links(&mut self, links: u16) {
+        set_u16(&mut self.data, INODE_LINKS, links);
+    }
+
+    pub fn modified(&self) -> u32 {
+        u32_at(&self.data, INODE_MODIFIED)
+    }
+
+    /// Sets the modification and change times to now.
+    pub fn touch(&mut self) {
+        let now = now();
+        set_u32(&mut self.data, INODE_MODIFIED, now);
+        set_u32(&mut self.data, INODE_CHANGED, now);
+    }
+
+    pub fn sectors(&self) -> u32 {
+        u32_at(&self.data, INODE_SECTORS)
+    }
+
+    fn add_sectors(&mut self, sectors: i32) {
+        let value = self.sectors().wrapping_add_signed(sectors);
+        set_u32(&mut self.data, INODE_SECTORS, value);
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        u32_at(&self.data, INODE_BLOCKS + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        set_u32(&mut self.data, INODE_BLOCKS + index * 4, block);
+    }
+
+    /// Whether the block pointers point to blocks. Device inodes keep their device number there
+    /// instead, fast symlinks their target.
+    pub fn has_blocks(&self, block_size: u64) -> bool {
+        match self.mode() & MODE_TYPE {
+            MODE_FILE | MODE_DIRECTORY => true,
+            MODE_SYMLINK => !self.is_fast_symlink(block_size),
+            _ => false,
+        }
+    }
+
+    /// Whether a symlink's target is kept in the inode itself.
+    pub fn is_fast_symlink(&self, block_size: u64) -> bool {
+        // The block with extended attributes counts towards the sectors too.
+        let attribute_sectors = if u32_at(&self.data, INODE_FILE_ACL) != 0 {
+            (block_size / SECTOR_SIZE) as u32
+        } else {
+            0
+        };
+
+        self.mode() & MODE_TYPE == MODE_SYMLINK && self.sectors() == attribute_sectors
+    }
+
+    pub fn fast_symlink_target(&self) -> &[u8] {
+        let length = (self.size() as usize).min(FAST_SYMLINK_LENGTH);
+        &self.data[INODE_BLOCKS..INODE_BLOCKS + length]
+    }
+
+    pub fn set_fast_symlink_target(&mut self, target: &[u8]) {
+        self.data[INODE_BLOCKS..INODE_BLOCKS + target.len()].copy_from_slice(target);
+        self.set_size(target.len() as u64);
+    }
+
+    /// Drops the hashed index of a directory, it's a normal directory for anyone else.
+    pub fn clear_index(&mut self) {
+        let flags = u32_at(&self.data, INODE_FLAGS);
+        set_u32(&mut self.data, INODE_FLAGS, flags & !FLAG_INDEX);
+    }
+}
+
+impl Ext2 {
+    pub fn read_inode(&self, number: u32) -> Result<RawInode, FsError> {
+        let mut data = vec![0; self.inode_size as usize];
+        self.read(self.inode_offset(number)?, &mut data)?;
+
+        Ok(RawInode { number, data })
+    }
+
+    pub fn write_inode(&self, inode: &RawInode) -> Result<(), FsError> {
+        self.write(self.inode_offset(inode.number)?, &inode.data)
+    }
+
+    /// Allocates and writes a new inode with the given mode and link count, preferably near
+    /// `near`, the inode of its directory.
+    pub fn new_inode(&self, near: u32, mode: u16, links: u16) -> Result<RawInode, FsError> {
+        let directory = mode & MODE_TYPE == MODE_DIRECTORY;
+        let number = self.allocate_inode(self.group_of_inode(near), directory)?;
+
+        let mut inode = RawInode {
+            number,
+            data: vec![0; self.inode_size as usize],
+        };
+
+        let now = now();
+        set_u16(&mut inode.data, INODE_MODE, mode);
+        set_u32(&mut inode.data, INODE_ACCESSED, now);
+        inode.set_links(links);
+        inode.touch();
+
+        if let Err(e) = self.write_inode(&inode) {
+            self.free_inode(number, directory)?;
+            return Err(e);
+        }
+
+        Ok(inode)
+    }
+
+    /// Frees an inode whose last link is gone along with its blocks.
+    pub fn delete_inode(&self, inode: &mut RawInode) -> Result<(), FsError> {
+        if inode.has_blocks(self.block_size) {
+            self.free_blocks(inode, 0)?;
+        }
+
+        inode.set_links(0);
+        set_u32(&mut inode.data, INODE_DELETED, now());
+        self.write_inode(inode)?;
+
+        self.free_inode(inode.number, inode.is_directory())
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    fn pointer_offset(&self, block: u32, index: u64) -> u64 {
+        self.block_offset(block) + index * 4
+    }
+
+    /// Where the `index`th block of the file is, `None` for holes. With `allocate`, holes are
+    /// filled with new zeroed blocks, along with the indirect blocks leading to them.
+    pub fn map_block(
+        &self,
+        inode: &mut RawInode,
+        index: u64,
+        allocate: bool,
+    ) -> Result<Option<u32>, FsError> {
+        let pointers = self.pointers_per_block();
+
+        // Which of the inode's pointers leads to the block and the indices in the indirect
+        // blocks after it.
+        let mut path = [0; MAX_LEVELS];
+        let (slot, levels) = if index < DIRECT_BLOCKS {
+            (index as usize, 0)
+        } else {
+            let mut index = index - DIRECT_BLOCKS;
+            let mut span = pointers;
+            let mut levels = 1;
+
+            while index >= span {
+                index -= span;
+                span *= pointers;
+                levels += 1;
+
+                if levels > MAX_LEVELS {
+                    return Err(FsError::NoSpace);
+                }
+            }
+
+            for level in (0..levels).rev() {
+                path[level] = index % pointers;
+                index /= pointers;
+            }
+
+            (INDIRECT_BLOCK + levels - 1, levels)
+        };
+
+        let group = self.group_of_inode(inode.number);
+        let sectors = (self.block_size / SECTOR_SIZE) as i32;
+
+        let mut block = inode.block(slot);
+
+        if block == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+
+            block = self.allocate_block(group)?;
+            inode.set_block(slot, block);
+            inode.add_sectors(sectors);
+        }
+
+        for index in &path[..levels] {
+            let offset = self.pointer_offset(block, *index);
+
+            let mut pointer = [0; 4];
+            self.read(offset, &mut pointer)?;
+            let mut next = u32::from_le_bytes(pointer);
+
+            if next == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+
+                next = self.allocate_block(group)?;
+                self.write(offset, &next.to_le_bytes())?;
+                inode.add_sectors(sectors);
+            }
+
+            block = next;
+        }
+
+        Ok(Some(block))
+    }
+
+    /// Frees the file's blocks from the `first`th on, and the indirect blocks no longer needed.
+    pub fn free_blocks(&self, inode: &mut RawInode, first: u64) -> Result<(), FsError> {
+        let pointers = self.pointers_per_block();
+
+        for index in first..DIRECT_BLOCKS {
+            let block = inode.block(index as usize);
+
+            if block != 0 {
+                self.free_block(block)?;
+                inode.set_block(index as usize, 0);
+                inode.add_sectors(-((self.block_size / SECTOR_SIZE) as i32));
+            }
+        }
+
+        let mut start = DIRECT_BLOCKS;
+        let mut span = pointers;
+
+        for level in 1..=MAX_LEVELS {
+            let slot = INDIRECT_BLOCK + level - 1;
+            let block = inode.block(slot);
+
+            if block != 0 && first < start + span {
+                let from = first.saturating_sub(start);
+
+                if self.free_tree(inode, block, level, from, span / pointers)? {
+                    inode.set_block(slot, 0);
+                }
+            }
+
+            start += span;
+            span *= pointers;
+        }
+
+        Ok(())
+    }
+
+    /// Frees the blocks under an indirect block of `level` from the `from`th on, where each of its
+    /// pointers covers `span` blocks. Returns whether the indirect block itself was freed.
+    fn free_tree(
+        &self,
+        inode: &mut RawInode,
+        block: u32,
+        level: usize,
+        from: u64,
+        span: u64,
+    ) -> Result<bool, FsError> {
+        let mut pointers = self.read_block(block)?;
+        let count = self.pointers_per_block();
+        let sectors = (self.block_size / SECTOR_SIZE) as i32;
+
+        for index in from / span..count {
+            let offset = index as usize * 4;
+            let child = u32_at(&pointers, offset);
+
+            if child == 0 {
+                continue;
+            }
+
+            let freed = if level == 1 {
+                self.free_block(child)?;
+                inode.add_sectors(-sectors);
+                true
+            } else {
+                let child_from = from.saturating_sub(index * span);
+                self.free_tree(inode, child, level - 1, child_from, span / count)?
+            };
+
+            if freed {
+                set_u32(&mut pointers, offset, 0);
+            }
+        }
+
+        if from == 0 {
+            self.free_block(block)?;
+            inode.add_sectors(-sectors);
+            return Ok(true);
+        }
+
+        self.write_block(block, &pointers)?;
+
+        Ok(false)
+    }
+
+    /// Reads the file's contents at `offset`, holes read as zeros.
+    pub fn read_data(
+        &self,
+        inode: &mut RawInode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, FsError> {
+        let size = inode.size();
+
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let length = buffer.len().min((size - offset) as usize);
+
+        let mut read = 0;
+        while read < length {
+            let position = offset + read as u64;
+            let within = position % self.block_size;
+            let chunk = (length - read).min((self.block_size - within) as usize);
+
+            match self.map_block(inode, position / self.block_size, false)? {
+                Some(block) => self.read(
+                    self.block_offset(block) + within,
+                    &mut buffer[read..read + chunk],
+                )?,
+                None => buffer[read..read + chunk].fill(0),
+            }
+
+            read += chunk;
+        }
+
+        Ok(length)
+    }
+
+    /// Writes `buffer` at `offset`, allocating blocks and growing the file as needed. The inode
+    /// is changed in memory only, the caller writes it back, even if this fails.
+    pub fn write_data(
+        &self,
+        inode: &mut RawInode,
+        offset: u64,
+        buffer: &[u8],
+    ) -> Result<(), FsError> {
+        let end = offset + buffer.len() as u64;
+
+        // Older drivers take sizes of 2 GiB and more as negative.
+        if end > i32::MAX as u64 && inode.mode() & MODE_TYPE == MODE_FILE {
+            self.enable_read_only_feature(READ_ONLY_LARGE_FILES)?;
+        }
+
+        let mut written = 0;
+        while written < buffer.len() {
+            let position = offset + written as u64;
+            let within = position % self.block_size;
+            let chunk = (buffer.len() - written).min((self.block_size - within) as usize);
+
+            let block = self
+                .map_block(inode, position / self.block_size, true)?
+                .unwrap();
+
+            self.write(
+                self.block_offset(block) + within,
+                &buffer[written..written + chunk],
+            )?;
+            written += chunk;
+
+            if position + chunk as u64 > inode.size() {
+                inode.set_size(position + chunk as u64);
+            }
+        }
+
+        inode.touch();
+
+        Ok(())
+    }
+}
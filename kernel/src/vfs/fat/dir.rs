@@ -0,0 +1,639 @@
+use crate::rtc::{self, DateTime};
+use crate::vfs::fat::{u16_at, Fat, FatKind};
+use crate::vfs::FsError;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_HIDDEN: u8 = 0x02;
+const ATTRIBUTE_SYSTEM: u8 = 0x04;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTES_LONG_NAME: u8 =
+    ATTRIBUTE_READ_ONLY | ATTRIBUTE_HIDDEN | ATTRIBUTE_SYSTEM | ATTRIBUTE_VOLUME_ID;
+const ATTRIBUTES_LONG_NAME_MASK: u8 =
+    ATTRIBUTES_LONG_NAME | ATTRIBUTE_DIRECTORY | ATTRIBUTE_ARCHIVE;
+
+// The first byte of the name marks unused entries.
+pub const FREE: u8 = 0xE5;
+const END: u8 = 0x00;
+// Names really starting with 0xE5 store 0x05 instead.
+const ESCAPED_FREE: u8 = 0x05;
+
+pub const ENTRY_ATTRIBUTES: u64 = 11;
+const ENTRY_CASE_FLAGS: usize = 12;
+const ENTRY_CREATED_TIME: usize = 14;
+const ENTRY_CREATED_DATE: usize = 16;
+const ENTRY_ACCESSED_DATE: usize = 18;
+pub const ENTRY_CLUSTER_HIGH: u64 = 20;
+pub const ENTRY_MODIFIED_TIME: u64 = 22;
+pub const ENTRY_CLUSTER_LOW: u64 = 26;
+pub const ENTRY_FILE_SIZE: u64 = 28;
+
+// Set by Windows for short names that are all lowercase instead of storing a long name.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+const LONG_ORDER: usize = 0;
+const LONG_CHECKSUM: usize = 13;
+const LONG_LAST: u8 = 0x40;
+const LONG_ORDER_MASK: u8 = 0x1F;
+// Where the 13 UCS-2 characters of a long name entry are, as offset and count.
+const LONG_NAME_PARTS: [(usize, usize); 3] = [(1, 5), (14, 6), (28, 2)];
+const LONG_NAME_CHARACTERS: usize = 13;
+
+const MAX_NAME_LENGTH: usize = 255;
+const SHORT_BASE_LENGTH: usize = 8;
+const SHORT_NAME_LENGTH: usize = 11;
+const MAX_NUMERIC_TAIL: u32 = 999_999;
+
+const INVALID_CHARACTERS: &str = "\"*/:<>?\\|";
+const SHORT_NAME_SPECIAL_CHARACTERS: &str = "!#$%&'()-@^_`{}~";
+
+/// Seconds since the Unix epoch from a FAT date and time, which are local time. We treat them
+/// as UTC, like the RTC.
+fn timestamp(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+
+    DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0xF) as u8,
+        day: (date & 0x1F) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+    .unix_timestamp()
+}
+
+/// When a short entry was last modified, in seconds since the Unix epoch.
+pub fn modified(entry: &[u8]) -> u64 {
+    timestamp(
+        u16_at(entry, ENTRY_MODIFIED_TIME as usize + 2),
+        u16_at(entry, ENTRY_MODIFIED_TIME as usize),
+    )
+}
+
+/// The current time as FAT time and date, in that order like in an entry.
+pub fn now() -> [u8; 4] {
+    let now = rtc::now();
+
+    let date = (now.year.saturating_sub(1980) << 9) | (now.month as u16) << 5 | now.day as u16;
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second as u16 / 2);
+
+    let mut bytes = [0; 4];
+    bytes[..2].copy_from_slice(&time.to_le_bytes());
+    bytes[2..].copy_from_slice(&date.to_le_bytes());
+    bytes
+}
+
+fn short_name_checksum(short_name: &[u8]) -> u8 {
+    short_name[..SHORT_NAME_LENGTH]
+        .iter()
+        .fold(0u8, |sum, byte| {
+            (sum >> 1).wrapping_add(sum << 7).wrapping_add(*byte)
+        })
+}
+
+/// Makes a short name readable, `README  TXT` becomes `README.TXT`.
+fn display_short_name(entry: &[u8]) -> String {
+    let mut name = entry[..SHORT_NAME_LENGTH].to_vec();
+    if name[0] == ESCAPED_FREE {
+        name[0] = FREE;
+    }
+
+    // Bytes above ASCII are in some OEM code page, Latin-1 is as good a guess as any.
+    let part = |bytes: &[u8], lower: bool| {
+        bytes
+            .iter()
+            .map(|byte| *byte as char)
+            .map(|c| if lower { c.to_ascii_lowercase() } else { c })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+
+    let base: String = part(
+        &name[..SHORT_BASE_LENGTH],
+        entry[ENTRY_CASE_FLAGS] & CASE_LOWER_BASE != 0,
+    );
+    let extension: String = part(
+        &name[SHORT_BASE_LENGTH..],
+        entry[ENTRY_CASE_FLAGS] & CASE_LOWER_EXTENSION != 0,
+    );
+
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+fn is_short_name_character(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL_CHARACTERS.contains(c)
+}
+
+/// The name as short name if it is a valid one as it is, so it needs no long name.
+fn exact_short_name(name: &str) -> Option<[u8; SHORT_NAME_LENGTH]> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    if base.is_empty()
+        || base.len() > SHORT_BASE_LENGTH
+        || extension.len() > SHORT_NAME_LENGTH - SHORT_BASE_LENGTH
+        || (name.contains('.') && extension.is_empty())
+        || !base
+            .chars()
+            .chain(extension.chars())
+            .all(is_short_name_character)
+    {
+        return None;
+    }
+
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[SHORT_BASE_LENGTH..][..extension.len()].copy_from_slice(extension.as_bytes());
+
+    Some(short_name)
+}
+
+/// Generates a short name like `LONGFI~1.TXT` for a name that needs a long one, unique among
+/// `existing`.
+fn generated_short_name(
+    name: &str,
+    existing: &BTreeSet<[u8; SHORT_NAME_LENGTH]>,
+) -> Result<[u8; SHORT_NAME_LENGTH], FsError> {
+    let convert = |part: &str, length: usize| {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| {
+                if is_short_name_character(c) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(length)
+            .collect::<Vec<_>>()
+    };
+
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+
+    let base = convert(base, SHORT_BASE_LENGTH);
+    let extension = convert(extension, SHORT_NAME_LENGTH - SHORT_BASE_LENGTH);
+
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    short_name[SHORT_BASE_LENGTH..][..extension.len()].copy_from_slice(&extension);
+
+    for number in 1..=MAX_NUMERIC_TAIL {
+        let tail = format!("~{}", number);
+        let base_length = base.len().min(SHORT_BASE_LENGTH - tail.len());
+
+        short_name[..SHORT_BASE_LENGTH].fill(b' ');
+        short_name[..base_length].copy_from_slice(&base[..base_length]);
+        short_name[base_length..base_length + tail.len()].copy_from_slice(tail.as_bytes());
+
+        if !existing.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    Err(FsError::NoSpace)
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_LENGTH
+        || name
+            .chars()
+            .any(|c| c.is_control() || INVALID_CHARACTERS.contains(c))
+    {
+        return Err(FsError::InvalidPath);
+    }
+
+    Ok(())
+}
+
+/// The parts of a long name collected from the entries before a short one. They come last part
+/// first, numbered down to 1.
+#[derive(Default)]
+struct LongName {
+    characters: Vec<u16>,
+    slots: Vec<u64>,
+    checksum: u8,
+    // The number of the part expected next, 0 once complete.
+    expected: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn add(&mut self, slot: &[u8], location: u64) {
+        let order = slot[LONG_ORDER] & LONG_ORDER_MASK;
+
+        if slot[LONG_ORDER] & LONG_LAST != 0 {
+            *self = LongName {
+                characters: vec![0xFFFF; order as usize * LONG_NAME_CHARACTERS],
+                slots: Vec::new(),
+                checksum: slot[LONG_CHECKSUM],
+                expected: order,
+                valid: order != 0,
+            };
+        } else if order == 0 || order != self.expected || slot[LONG_CHECKSUM] != self.checksum {
+            self.valid = false;
+        }
+
+        if !self.valid {
+            return;
+        }
+
+        let characters = &mut self.characters[(order as usize - 1) * LONG_NAME_CHARACTERS..];
+        let mut index = 0;
+        for (offset, count) in LONG_NAME_PARTS {
+            for i in 0..count {
+                characters[index] = u16_at(slot, offset + i * 2);
+                index += 1;
+            }
+        }
+
+        self.slots.push(location);
+        self.expected -= 1;
+    }
+
+    /// The name, if it's complete and belongs to the short entry.
+    fn take(&mut self, short_entry: &[u8]) -> Option<(String, Vec<u64>)> {
+        let long_name = core::mem::take(self);
+
+        if !long_name.valid
+            || long_name.expected != 0
+            || long_name.checksum != short_name_checksum(short_entry)
+        {
+            return None;
+        }
+
+        let length = long_name
+            .characters
+            .iter()
+            .position(|c| *c == 0 || *c == 0xFFFF)
+            .unwrap_or(long_name.characters.len());
+
+        let name = char::decode_utf16(long_name.characters[..length].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        Some((name, long_name.slots))
+    }
+}
+
+/// An entry as read from a directory.
+pub struct DirectoryEntry {
+    pub name: String,
+    /// Like `LONGFI~1.TXT`, the same as `name` without a long name.
+    pub short_name: String,
+    pub attributes: u8,
+    pub cluster: u32,
+    /// Where the short entry is on the device.
+    pub location: u64,
+    /// The locations of all the entry's slots, long name ones included.
+    pub slots: Vec<u64>,
+}
+
+impl DirectoryEntry {
+    fn parse(fat: &Fat, slot: &[u8], location: u64, long_name: &mut LongName) -> Self {
+        let (name, mut slots) = long_name
+            .take(slot)
+            .unwrap_or_else(|| (display_short_name(slot), Vec::new()));
+        slots.push(location);
+
+        Self {
+            name,
+            short_name: display_short_name(slot),
+            attributes: slot[ENTRY_ATTRIBUTES as usize],
+            cluster: cluster(fat, slot),
+            location,
+            slots,
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+}
+
+/// The first cluster of a short entry.
+pub fn cluster(fat: &Fat, entry: &[u8]) -> u32 {
+    let low = u16_at(entry, ENTRY_CLUSTER_LOW as usize) as u32;
+
+    // The high half is only defined on FAT32, other systems used it for their own purposes.
+    if fat.kind == FatKind::Fat32 {
+        low | (u16_at(entry, ENTRY_CLUSTER_HIGH as usize) as u32) << 16
+    } else {
+        low
+    }
+}
+
+/// Builds a short entry.
+fn short_entry(short_name: &[u8; SHORT_NAME_LENGTH], attributes: u8, cluster: u32) -> [u8; 32] {
+    let mut entry = [0; ENTRY_SIZE];
+    let now = now();
+
+    entry[..SHORT_NAME_LENGTH].copy_from_slice(short_name);
+    entry[ENTRY_ATTRIBUTES as usize] = attributes;
+    entry[ENTRY_CREATED_TIME..ENTRY_CREATED_TIME + 2].copy_from_slice(&now[..2]);
+    entry[ENTRY_CREATED_DATE..ENTRY_CREATED_DATE + 2].copy_from_slice(&now[2..]);
+    entry[ENTRY_ACCESSED_DATE..ENTRY_ACCESSED_DATE + 2].copy_from_slice(&now[2..]);
+    entry[ENTRY_CLUSTER_HIGH as usize..][..2]
+        .copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[ENTRY_MODIFIED_TIME as usize..][..4].copy_from_slice(&now);
+    entry[ENTRY_CLUSTER_LOW as usize..][..2].copy_from_slice(&(cluster as u16).to_le_bytes());
+
+    entry
+}
+
+/// The contents of a new directory's first cluster, its `.` and `..` entries. `parent` is 0 for
+/// the root directory.
+pub fn dot_entries(cluster: u32, parent: u32) -> [u8; 2 * ENTRY_SIZE] {
+    let mut entries = [0; 2 * ENTRY_SIZE];
+
+    entries[..ENTRY_SIZE].copy_from_slice(&short_entry(
+        b".          ",
+        ATTRIBUTE_DIRECTORY,
+        cluster,
+    ));
+    entries[ENTRY_SIZE..].copy_from_slice(&short_entry(
+        b"..         ",
+        ATTRIBUTE_DIRECTORY,
+        parent,
+    ));
+
+    entries
+}
+
+/// A directory's entries: the fixed size root directory of FAT12 and FAT16 or a cluster chain
+/// like everything else.
+#[derive(Copy, Clone)]
+pub enum Directory {
+    Fixed { start: u64, length: u64 },
+    Chain(u32),
+}
+
+impl Directory {
+    /// The parts of the device the directory is stored in, in order, as start and length.
+    fn regions(&self, fat: &Fat) -> Result<Vec<(u64, u64)>, FsError> {
+        match *self {
+            Directory::Fixed { start, length } => Ok(vec![(start, length)]),
+            Directory::Chain(first) => Ok(fat
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (fat.cluster_offset(cluster), fat.cluster_size as u64))
+                .collect()),
+        }
+    }
+
+    /// The first cluster, 0 for the fixed root directory.
+    pub fn cluster(&self) -> u32 {
+        match self {
+            Directory::Fixed { .. } => 0,
+            Directory::Chain(first) => *first,
+        }
+    }
+
+    /// Calls `f` with the index, location and contents of each slot from index `start` on,
+    /// until it breaks.
+    fn for_each_slot(
+        &self,
+        fat: &Fat,
+        start: u64,
+        mut f: impl FnMut(u64, u64, &[u8]) -> ControlFlow<()>,
+    ) -> Result<(), FsError> {
+        let mut index = 0;
+
+        for (region_start, length) in self.regions(fat)? {
+            let slots = length / ENTRY_SIZE as u64;
+
+            if index + slots <= start {
+                index += slots;
+                continue;
+            }
+
+            let mut data = vec![0; length as usize];
+            fat.read(region_start, &mut data)?;
+
+            for (i, slot) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                let slot_index = index + i as u64;
+
+                if slot_index < start {
+                    continue;
+                }
+
+                let location = region_start + (i * ENTRY_SIZE) as u64;
+                if f(slot_index, location, slot).is_break() {
+                    return Ok(());
+                }
+            }
+
+            index += slots;
+        }
+
+        Ok(())
+    }
+
+    /// The entry at or after slot `position` and the position after it. Volume labels, `.`
+    /// and `..` are left out.
+    pub fn entry_at(
+        &self,
+        fat: &Fat,
+        position: u64,
+    ) -> Result<Option<(DirectoryEntry, u64)>, FsError> {
+        let mut long_name = LongName::default();
+        let mut entry = None;
+
+        self.for_each_slot(fat, position, |index, location, slot| {
+            match slot[0] {
+                END => return ControlFlow::Break(()),
+                FREE => {
+                    long_name = LongName::default();
+                    return ControlFlow::Continue(());
+                }
+                _ => {}
+            }
+
+            let attributes = slot[ENTRY_ATTRIBUTES as usize];
+
+            if attributes & ATTRIBUTES_LONG_NAME_MASK == ATTRIBUTES_LONG_NAME {
+                long_name.add(slot, location);
+                return ControlFlow::Continue(());
+            }
+
+            if attributes & ATTRIBUTE_VOLUME_ID != 0 || slot[0] == b'.' {
+                long_name = LongName::default();
+                return ControlFlow::Continue(());
+            }
+
+            entry = Some((
+                DirectoryEntry::parse(fat, slot, location, &mut long_name),
+                index + 1,
+            ));
+            ControlFlow::Break(())
+        })?;
+
+        Ok(entry)
+    }
+
+    /// Looks up `name`, ignoring case like everybody else does on FAT.
+    pub fn find(&self, fat: &Fat, name: &str) -> Result<Option<DirectoryEntry>, FsError> {
+        let mut position = 0;
+
+        while let Some((entry, next)) = self.entry_at(fat, position)? {
+            if entry.name.eq_ignore_ascii_case(name) || entry.short_name.eq_ignore_ascii_case(name)
+            {
+                return Ok(Some(entry));
+            }
+
+            position = next;
+        }
+
+        Ok(None)
+    }
+
+    fn short_names(&self, fat: &Fat) -> Result<BTreeSet<[u8; SHORT_NAME_LENGTH]>, FsError> {
+        let mut short_names = BTreeSet::new();
+
+        self.for_each_slot(fat, 0, |_, _, slot| {
+            if slot[0] == END {
+                return ControlFlow::Break(());
+            }
+
+            if slot[0] != FREE
+                && slot[ENTRY_ATTRIBUTES as usize] & ATTRIBUTES_LONG_NAME_MASK
+                    != ATTRIBUTES_LONG_NAME
+            {
+                short_names.insert(slot[..SHORT_NAME_LENGTH].try_into().unwrap());
+            }
+
+            ControlFlow::Continue(())
+        })?;
+
+        Ok(short_names)
+    }
+
+    /// Finds `count` consecutive unused slots, growing the directory if needed.
+    fn free_slots(&self, fat: &Fat, count: usize) -> Result<Vec<u64>, FsError> {
+        let mut slots = Vec::new();
+
+        self.for_each_slot(fat, 0, |_, location, slot| {
+            if slot[0] == FREE || slot[0] == END {
+                slots.push(location);
+            } else {
+                slots.clear();
+            }
+
+            if slots.len() == count {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+
+        // Free slots at the very end still count, the new clusters continue right after them.
+        let Directory::Chain(first) = *self else {
+            return if slots.len() == count {
+                Ok(slots)
+            } else {
+                Err(FsError::NoSpace)
+            };
+        };
+
+        let mut last = *fat.chain(first)?.last().unwrap();
+
+        while slots.len() < count {
+            last = fat.allocate(Some(last), true)?;
+
+            let start = fat.cluster_offset(last);
+            slots.extend(
+                (0..fat.cluster_size / ENTRY_SIZE)
+                    .map(|i| start + (i * ENTRY_SIZE) as u64)
+                    .take(count - slots.len()),
+            );
+        }
+
+        Ok(slots)
+    }
+
+    /// Adds an entry for a new file or directory and returns the location of its short entry.
+    pub fn add(&self, fat: &Fat, name: &str, attributes: u8, cluster: u32) -> Result<u64, FsError> {
+        validate_name(name)?;
+
+        if self.find(fat, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, long_name) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => (
+                generated_short_name(name, &self.short_names(fat)?)?,
+                name.encode_utf16().collect::<Vec<_>>(),
+            ),
+        };
+
+        let long_slots = long_name.len().div_ceil(LONG_NAME_CHARACTERS);
+        let slots = self.free_slots(fat, long_slots + 1)?;
+        let checksum = short_name_checksum(&short_name);
+
+        // The last part of the name comes first.
+        for (i, location) in slots[..long_slots].iter().enumerate() {
+            let order = (long_slots - i) as u8;
+            let part = (order as usize - 1) * LONG_NAME_CHARACTERS;
+
+            let mut slot = [0; ENTRY_SIZE];
+            slot[LONG_ORDER] = if i == 0 { order | LONG_LAST } else { order };
+            slot[ENTRY_ATTRIBUTES as usize] = ATTRIBUTES_LONG_NAME;
+            slot[LONG_CHECKSUM] = checksum;
+
+            // Terminated with a NUL if there's room, padded with 0xFFFF after.
+            let mut index = part;
+            for (offset, count) in LONG_NAME_PARTS {
+                for j in 0..count {
+                    let character = match index.cmp(&long_name.len()) {
+                        core::cmp::Ordering::Less => long_name[index],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xFFFF,
+                    };
+                    slot[offset + j * 2..offset + j * 2 + 2]
+                        .copy_from_slice(&character.to_le_bytes());
+                    index += 1;
+                }
+            }
+
+            fat.write(*location, &slot)?;
+        }
+
+        let location = slots[long_slots];
+        fat.write(location, &short_entry(&short_name, attributes, cluster))?;
+
+        Ok(location)
+    }
+
+    /// Marks the entry's slots unused, its clusters are up to the caller.
+    pub fn remove(&self, fat: &Fat, entry: &DirectoryEntry) -> Result<(), FsError> {
+        for location in &entry.slots {
+            fat.write(*location, &[FREE])?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+use crate::vfs::fat::dir::{
+    self, Directory, DirectoryEntry, ATTRIBUTE_ARCHIVE, ATTRIBUTE_DIRECTORY, ATTRIBUTE_READ_ONLY,
+    ENTRY_ATTRIBUTES, ENTRY_CLUSTER_HIGH, ENTRY_CLUSTER_LOW, ENTRY_FILE_SIZE, ENTRY_MODIFIED_TIME,
+    ENTRY_SIZE,
+};
+use crate::vfs::fat::{u32_at, Fat};
+use crate::vfs::{DirEntry, FsError, Inode, Metadata, NodeKind};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// FAT has no inode numbers, we number nodes by where their entry is. The root has none.
+const ROOT_INODE: u64 = 1;
+
+/// A file or directory of a FAT volume. Everything about it is read from its directory entry
+/// when needed, so several of these for the same file don't disagree.
+pub struct FatInode {
+    fat: Arc<Fat>,
+    // Where the short entry is, `None` for the root directory.
+    location: Option<u64>,
+    // `Some` for directories, whose first cluster never changes.
+    directory: Option<Directory>,
+}
+
+impl FatInode {
+    pub fn root(fat: Arc<Fat>) -> Self {
+        Self {
+            directory: Some(fat.root),
+            fat,
+            location: None,
+        }
+    }
+
+    fn from_entry(fat: Arc<Fat>, entry: &DirectoryEntry) -> Result<Self, FsError> {
+        let directory = if entry.is_directory() {
+            if !fat.is_valid(entry.cluster) {
+                return Err(FsError::Corrupted);
+            }
+
+            Some(Directory::Chain(entry.cluster))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            fat,
+            location: Some(entry.location),
+            directory,
+        })
+    }
+
+    fn directory(&self) -> Result<Directory, FsError> {
+        self.directory.ok_or(FsError::NotADirectory)
+    }
+
+    /// The file's short entry, read fresh from the device.
+    fn entry(&self) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let location = self.location.ok_or(FsError::IsADirectory)?;
+
+        let mut entry = [0; ENTRY_SIZE];
+        self.fat.read(location, &mut entry)?;
+
+        // Removed since it was looked up.
+        if entry[0] == dir::FREE {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(entry)
+    }
+
+    fn file_entry(&self) -> Result<[u8; ENTRY_SIZE], FsError> {
+        if self.directory.is_some() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.entry()
+    }
+
+    fn set_cluster(&self, location: u64, cluster: u32) -> Result<(), FsError> {
+        self.fat.write(
+            location + ENTRY_CLUSTER_HIGH,
+            &((cluster >> 16) as u16).to_le_bytes(),
+        )?;
+        self.fat.write(
+            location + ENTRY_CLUSTER_LOW,
+            &(cluster as u16).to_le_bytes(),
+        )
+    }
+
+    /// Records a new size along with the modification time.
+    fn set_size(&self, location: u64, attributes: u8, size: u32) -> Result<(), FsError> {
+        self.fat
+            .write(location + ENTRY_FILE_SIZE, &size.to_le_bytes())?;
+        self.fat
+            .write(location + ENTRY_MODIFIED_TIME, &dir::now())?;
+        // Tells backup tools the file changed.
+        self.fat.write(
+            location + ENTRY_ATTRIBUTES,
+            &[attributes | ATTRIBUTE_ARCHIVE],
+        )
+    }
+
+    /// Writes `buffer` at `offset`, which is at most the file's size, growing the cluster chain
+    /// as needed. Needs the write lock.
+    fn write_locked(&self, offset: u64, buffer: &[u8]) -> Result<(), FsError> {
+        let entry = self.file_entry()?;
+        let location = self.location.unwrap();
+
+        let end = offset + buffer.len() as u64;
+        let size = u32_at(&entry, ENTRY_FILE_SIZE as usize);
+        let new_size = u32::try_from(end.max(size as u64)).map_err(|_| FsError::NoSpace)?;
+
+        let cluster_size = self.fat.cluster_size as u64;
+        let first = dir::cluster(&self.fat, &entry);
+
+        let mut chain = if first == 0 {
+            Vec::new()
+        } else {
+            self.fat.chain(first)?
+        };
+
+        while (chain.len() as u64) < end.div_ceil(cluster_size) {
+            let cluster = self.fat.allocate(chain.last().copied(), false)?;
+
+            // Right away, so the cluster isn't lost if the next one can't be allocated.
+            if chain.is_empty() {
+                self.set_cluster(location, cluster)?;
+            }
+
+            chain.push(cluster);
+        }
+
+        let mut written = 0;
+        while written < buffer.len() {
+            let position = offset + written as u64;
+            let within = position % cluster_size;
+            let length = (buffer.len() - written).min((cluster_size - within) as usize);
+            let cluster = chain[(position / cluster_size) as usize];
+
+            self.fat.write(
+                self.fat.cluster_offset(cluster) + within,
+                &buffer[written..written + length],
+            )?;
+            written += length;
+        }
+
+        self.set_size(location, entry[ENTRY_ATTRIBUTES as usize], new_size)
+    }
+
+    /// Fills the file with zeros from its end up to `end`. Needs the write lock.
+    fn extend_locked(&self, end: u64) -> Result<(), FsError> {
+        let zeros = vec![0; self.fat.cluster_size];
+
+        loop {
+            let size = u32_at(&self.file_entry()?, ENTRY_FILE_SIZE as usize) as u64;
+
+            if size >= end {
+                return Ok(());
+            }
+
+            let length = (end - size).min(zeros.len() as u64) as usize;
+            self.write_locked(size, &zeros[..length])?;
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let entry = match self.location {
+            Some(_) => self.entry().ok(),
+            None => None,
+        };
+
+        let (attributes, size, modified) = match &entry {
+            Some(entry) => (
+                entry[ENTRY_ATTRIBUTES as usize],
+                u32_at(entry, ENTRY_FILE_SIZE as usize) as u64,
+                dir::modified(entry),
+            ),
+            None => (ATTRIBUTE_DIRECTORY, 0, 0),
+        };
+
+        let (kind, permissions) = if self.directory.is_some() {
+            (NodeKind::Directory, 0o755)
+        } else {
+            (NodeKind::File, 0o644)
+        };
+
+        Metadata {
+            inode: self
+                .location
+                .map_or(ROOT_INODE, |location| location / ENTRY_SIZE as u64),
+            kind,
+            size,
+            // Read only is the only permission FAT knows about.
+            permissions: if attributes & ATTRIBUTE_READ_ONLY != 0 {
+                permissions & 0o555
+            } else {
+                permissions
+            },
+            links: 1,
+            modified,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.file_entry()?;
+        let size = u32_at(&entry, ENTRY_FILE_SIZE as usize) as u64;
+
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let length = buffer.len().min((size - offset) as usize);
+        let cluster_size = self.fat.cluster_size as u64;
+
+        // Following the chain up to where the read starts.
+        let mut cluster = dir::cluster(&self.fat, &entry);
+        for _ in 0..offset / cluster_size {
+            cluster = self.fat.next(cluster)?.ok_or(FsError::Corrupted)?;
+        }
+
+        let mut read = 0;
+        while read < length {
+            let position = offset + read as u64;
+            let within = position % cluster_size;
+            let chunk = (length - read).min((cluster_size - within) as usize);
+
+            if !self.fat.is_valid(cluster) {
+                return Err(FsError::Corrupted);
+            }
+
+            self.fat.read(
+                self.fat.cluster_offset(cluster) + within,
+                &mut buffer[read..read + chunk],
+            )?;
+            read += chunk;
+
+            if read < length {
+                cluster = self.fat.next(cluster)?.ok_or(FsError::Corrupted)?;
+            }
+        }
+
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let _lock = self.fat.write_lock.lock();
+
+        // Anything between the end and `offset` reads as zeros.
+        self.extend_locked(offset)?;
+        self.write_locked(offset, buffer)?;
+
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let _lock = self.fat.write_lock.lock();
+
+        let entry = self.file_entry()?;
+        let location = self.location.unwrap();
+
+        if size > u32_at(&entry, ENTRY_FILE_SIZE as usize) as u64 {
+            return self.extend_locked(size);
+        }
+
+        let first = dir::cluster(&self.fat, &entry);
+        let clusters = size.div_ceil(self.fat.cluster_size as u64) as usize;
+
+        if first != 0 {
+            self.fat.truncate_chain(first, clusters)?;
+
+            if clusters == 0 {
+                self.set_cluster(location, 0)?;
+            }
+        }
+
+        self.set_size(location, entry[ENTRY_ATTRIBUTES as usize], size as u32)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let entry = self
+            .directory()?
+            .find(&self.fat, name)?
+            .ok_or(FsError::NotFound)?;
+
+        Ok(Arc::new(FatInode::from_entry(self.fat.clone(), &entry)?))
+    }
+
+    fn read_dir(&self, position: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        Ok(self
+            .directory()?
+            .entry_at(&self.fat, position)?
+            .map(|(entry, next)| {
+                let kind = if entry.is_directory() {
+                    NodeKind::Directory
+                } else {
+                    NodeKind::File
+                };
+
+                let entry = DirEntry {
+                    name: entry.name,
+                    inode: entry.location / ENTRY_SIZE as u64,
+                    kind,
+                };
+
+                (entry, next)
+            }))
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let directory = self.directory()?;
+        let _lock = self.fat.write_lock.lock();
+
+        let (location, new_directory) = match kind {
+            NodeKind::File => (directory.add(&self.fat, name, ATTRIBUTE_ARCHIVE, 0)?, None),
+            NodeKind::Directory => {
+                let cluster = self.fat.allocate(None, true)?;
+
+                // `..` is 0 for the root, even on FAT32 where it's a cluster chain.
+                let parent = match self.location {
+                    Some(_) => directory.cluster(),
+                    None => 0,
+                };
+
+                let result = self
+                    .fat
+                    .write(
+                        self.fat.cluster_offset(cluster),
+                        &dir::dot_entries(cluster, parent),
+                    )
+                    .and_then(|_| directory.add(&self.fat, name, ATTRIBUTE_DIRECTORY, cluster));
+
+                if result.is_err() {
+                    self.fat.free_chain(cluster)?;
+                }
+
+                (result?, Some(Directory::Chain(cluster)))
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+
+        Ok(Arc::new(FatInode {
+            fat: self.fat.clone(),
+            location: Some(location),
+            directory: new_directory,
+        }))
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.directory()?;
+
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let directory = self.directory()?;
+        let _lock = self.fat.write_lock.lock();
+
+        let entry = directory.find(&self.fat, name)?.ok_or(FsError::NotFound)?;
+
+        directory.remove(&self.fat, &entry)?;
+
+        if entry.cluster != 0 {
+            self.fat.free_chain(entry.cluster)?;
+        }
+
+        Ok(())
+    }
+}
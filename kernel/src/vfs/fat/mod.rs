@@ -0,0 +1,526 @@
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::vfs::fat::dir::Directory;
+use crate::vfs::fat::inode::FatInode;
+use crate::vfs::{FileSystem, FsError, Inode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use log::{debug, info};
+use spinning_top::Spinlock;
+
+mod dir;
+mod inode;
+
+const BOOT_SECTOR_SIZE: usize = 512;
+const BOOT_SIGNATURE: u16 = 0xAA55;
+const JUMP_SHORT: u8 = 0xEB;
+const JUMP_NEAR: u8 = 0xE9;
+
+const BPB_BYTES_PER_SECTOR: usize = 11;
+const BPB_SECTORS_PER_CLUSTER: usize = 13;
+const BPB_RESERVED_SECTORS: usize = 14;
+const BPB_FAT_COUNT: usize = 16;
+const BPB_ROOT_ENTRIES: usize = 17;
+const BPB_TOTAL_SECTORS_16: usize = 19;
+const BPB_FAT_SIZE_16: usize = 22;
+const BPB_TOTAL_SECTORS_32: usize = 32;
+const BPB_FAT_SIZE_32: usize = 36;
+const BPB_ROOT_CLUSTER: usize = 44;
+const BPB_FS_INFO_SECTOR: usize = 48;
+const BPB_LABEL_16: usize = 43;
+const BPB_LABEL_32: usize = 71;
+const LABEL_LENGTH: usize = 11;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_FREE_COUNT: u64 = 488;
+const FS_INFO_NEXT_FREE: u64 = 492;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// The FAT type only depends on the number of clusters, these are the limits from the
+// specification.
+const MAX_FAT12_CLUSTERS: u32 = 4084;
+const MAX_FAT16_CLUSTERS: u32 = 65524;
+
+/// The first data cluster, 0 and 1 are reserved.
+const FIRST_CLUSTER: u32 = 2;
+// FAT32 entries are 28 bit, the top ones are reserved and have to be kept.
+const FAT32_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+    /// Entries from this value on end a chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFF8,
+            FatKind::Fat16 => 0xFFF8,
+            FatKind::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// What we write to end a chain.
+    fn end_of_chain_marker(self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFFF,
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The layout of a FAT volume and access to its allocation table. Offsets are in bytes from the
+/// start of the device.
+struct Fat {
+    device: Arc<dyn BlockDevice>,
+    kind: FatKind,
+    cluster_size: usize,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u32,
+    data_start: u64,
+    cluster_count: u32,
+    root: Directory,
+    fs_info: Option<u64>,
+    label: String,
+    // Held by everything that changes the filesystem, reading doesn't need it.
+    write_lock: Spinlock<()>,
+    // Where to start looking for a free cluster.
+    next_free: AtomicU32,
+    fs_info_outdated: AtomicBool,
+}
+
+impl Fat {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        if device.size() < BOOT_SECTOR_SIZE as u64 {
+            return Err(FsError::Unsupported);
+        }
+
+        let mut boot_sector = [0; BOOT_SECTOR_SIZE];
+        read_bytes(device.as_ref(), 0, &mut boot_sector)?;
+
+        if u16_at(&boot_sector, 510) != BOOT_SIGNATURE
+            || ![JUMP_SHORT, JUMP_NEAR].contains(&boot_sector[0])
+        {
+            return Err(FsError::Unsupported);
+        }
+
+        let bytes_per_sector = u16_at(&boot_sector, BPB_BYTES_PER_SECTOR) as u64;
+        let sectors_per_cluster = boot_sector[BPB_SECTORS_PER_CLUSTER] as u64;
+        let reserved_sectors = u16_at(&boot_sector, BPB_RESERVED_SECTORS) as u64;
+        let fat_count = boot_sector[BPB_FAT_COUNT] as u32;
+        let root_entries = u16_at(&boot_sector, BPB_ROOT_ENTRIES) as u64;
+
+        let total_sectors = match u16_at(&boot_sector, BPB_TOTAL_SECTORS_16) {
+            0 => u32_at(&boot_sector, BPB_TOTAL_SECTORS_32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match u16_at(&boot_sector, BPB_FAT_SIZE_16) {
+            0 => u32_at(&boot_sector, BPB_FAT_SIZE_32) as u64,
+            sectors => sectors as u64,
+        };
+
+        // Other boot sectors have the same signature, the BPB has to make sense as well.
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || total_sectors == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Unsupported);
+        }
+
+        let root_size = root_entries * dir::ENTRY_SIZE as u64;
+        let root_sectors = root_size.div_ceil(bytes_per_sector);
+        let metadata_sectors = reserved_sectors + fat_count as u64 * fat_sectors + root_sectors;
+
+        let cluster_count = (total_sectors
+            .checked_sub(metadata_sectors)
+            .ok_or(FsError::Corrupted)?
+            / sectors_per_cluster) as u32;
+
+        let kind = if cluster_count <= MAX_FAT12_CLUSTERS {
+            FatKind::Fat12
+        } else if cluster_count <= MAX_FAT16_CLUSTERS {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        };
+
+        if total_sectors * bytes_per_sector > device.size() {
+            return Err(FsError::Corrupted);
+        }
+
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let root_start = fat_start + fat_count as u64 * fat_sectors * bytes_per_sector;
+
+        let (root, fs_info, label_offset) = if kind == FatKind::Fat32 {
+            let root_cluster = u32_at(&boot_sector, BPB_ROOT_CLUSTER);
+
+            if root_entries != 0 || root_cluster < FIRST_CLUSTER {
+                return Err(FsError::Corrupted);
+            }
+
+            let fs_info = match u16_at(&boot_sector, BPB_FS_INFO_SECTOR) as u64 {
+                0 | 0xFFFF => None,
+                sector => Some(sector * bytes_per_sector),
+            };
+
+            (Directory::Chain(root_cluster), fs_info, BPB_LABEL_32)
+        } else {
+            let root = Directory::Fixed {
+                start: root_start,
+                length: root_size,
+            };
+
+            (root, None, BPB_LABEL_16)
+        };
+
+        let label = String::from_utf8_lossy(&boot_sector[label_offset..][..LABEL_LENGTH])
+            .trim_end()
+            .into();
+
+        let fat = Self {
+            device,
+            kind,
+            cluster_size: (sectors_per_cluster * bytes_per_sector) as usize,
+            fat_start,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count,
+            data_start: root_start + root_sectors * bytes_per_sector,
+            cluster_count,
+            root,
+            fs_info,
+            label,
+            write_lock: Spinlock::new(()),
+            next_free: AtomicU32::new(FIRST_CLUSTER),
+            fs_info_outdated: AtomicBool::new(false),
+        };
+
+        // The FAT has to have an entry for every cluster, or we'd read past it.
+        if fat.entry_offset(cluster_count + FIRST_CLUSTER) > fat.fat_size {
+            return Err(FsError::Corrupted);
+        }
+
+        if let Some(next_free) = fat.read_fs_info()? {
+            fat.next_free.store(next_free, Ordering::Relaxed);
+        }
+
+        Ok(fat)
+    }
+
+    /// The next free cluster hint from the FSInfo sector, if there is a valid one.
+    fn read_fs_info(&self) -> Result<Option<u32>, FsError> {
+        let Some(fs_info) = self.fs_info else {
+            return Ok(None);
+        };
+
+        let mut signature = [0; 4];
+        self.read(fs_info, &mut signature)?;
+
+        if u32::from_le_bytes(signature) != FS_INFO_LEAD_SIGNATURE {
+            return Ok(None);
+        }
+
+        let mut next_free = [0; 4];
+        self.read(fs_info + FS_INFO_NEXT_FREE, &mut next_free)?;
+
+        Ok(Some(u32::from_le_bytes(next_free)).filter(|cluster| self.is_valid(*cluster)))
+    }
+
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(read_bytes(self.device.as_ref(), offset, buffer)?)
+    }
+
+    fn write(&self, offset: u64, buffer: &[u8]) -> Result<(), FsError> {
+        Ok(write_bytes(self.device.as_ref(), offset, buffer)?)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size as u64
+    }
+
+    /// Where the cluster's entry is within a FAT.
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        match self.kind {
+            // 12 bit entries, two share three bytes.
+            FatKind::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatKind::Fat16 => cluster as u64 * 2,
+            FatKind::Fat32 => cluster as u64 * 4,
+        }
+    }
+
+    fn entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_start + self.entry_offset(cluster);
+
+        Ok(match self.kind {
+            FatKind::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read(offset, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+
+                if cluster % 2 == 0 {
+                    value & 0xFFF
+                } else {
+                    value >> 4
+                }
+            }
+            FatKind::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read(offset, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            }
+            FatKind::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read(offset, &mut bytes)?;
+                u32::from_le_bytes(bytes) & FAT32_ENTRY_MASK
+            }
+        })
+    }
+
+    /// Sets the cluster's entry in every copy of the FAT.
+    fn set_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..self.fat_count {
+            let offset = self.fat_start + copy as u64 * self.fat_size + self.entry_offset(cluster);
+
+            match self.kind {
+                FatKind::Fat12 => {
+                    let mut bytes = [0; 2];
+                    self.read(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+
+                    let new = if cluster % 2 == 0 {
+                        (old & 0xF000) | (value as u16 & 0xFFF)
+                    } else {
+                        (old & 0x000F) | ((value as u16) << 4)
+                    };
+
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+                FatKind::Fat16 => self.write(offset, &(value as u16).to_le_bytes())?,
+                FatKind::Fat32 => {
+                    let mut bytes = [0; 4];
+                    self.read(offset, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+
+                    let new = (old & !FAT32_ENTRY_MASK) | (value & FAT32_ENTRY_MASK);
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The cluster following `cluster` in its chain, `None` at the end.
+    fn next(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        if !self.is_valid(cluster) {
+            return Err(FsError::Corrupted);
+        }
+
+        let next = self.entry(cluster)?;
+
+        if next >= self.kind.end_of_chain() {
+            Ok(None)
+        } else if self.is_valid(next) {
+            Ok(Some(next))
+        } else {
+            // Free or bad clusters don't belong into a chain.
+            Err(FsError::Corrupted)
+        }
+    }
+
+    /// All clusters of the chain starting at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        if !self.is_valid(first) {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut chain = vec![first];
+
+        while let Some(next) = self.next(*chain.last().unwrap())? {
+            // Longer than the volume, it has to loop.
+            if chain.len() > self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+
+            chain.push(next);
+        }
+
+        Ok(chain)
+    }
+
+    /// Finds a free cluster, appends it to the chain ending at `last` if there's one and returns
+    /// it. Directories want it filled with zeros.
+    fn allocate(&self, last: Option<u32>, zero: bool) -> Result<u32, FsError> {
+        let start = self.next_free.load(Ordering::Relaxed);
+        let start = if self.is_valid(start) {
+            start
+        } else {
+            FIRST_CLUSTER
+        };
+
+        let mut cluster = start;
+
+        loop {
+            if self.entry(cluster)? == 0 {
+                break;
+            }
+
+            cluster += 1;
+            if !self.is_valid(cluster) {
+                cluster = FIRST_CLUSTER;
+            }
+
+            if cluster == start {
+                return Err(FsError::NoSpace);
+            }
+        }
+
+        if zero {
+            self.write(self.cluster_offset(cluster), &vec![0; self.cluster_size])?;
+        }
+
+        self.set_entry(cluster, self.kind.end_of_chain_marker())?;
+
+        if let Some(last) = last {
+            self.set_entry(last, cluster)?;
+        }
+
+        self.next_free.store(cluster + 1, Ordering::Relaxed);
+        self.fs_info_outdated.store(true, Ordering::Relaxed);
+
+        Ok(cluster)
+    }
+
+    /// Marks all clusters of the chain starting at `first` as free.
+    fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set_entry(cluster, 0)?;
+        }
+
+        self.fs_info_outdated.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Cuts the chain starting at `first` down to `length` clusters, freeing the rest.
+    fn truncate_chain(&self, first: u32, length: usize) -> Result<(), FsError> {
+        let chain = self.chain(first)?;
+
+        if length == 0 {
+            return self.free_chain(first);
+        }
+
+        if let Some(&rest) = chain.get(length) {
+            self.set_entry(chain[length - 1], self.kind.end_of_chain_marker())?;
+            self.free_chain(rest)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        if let Some(fs_info) = self.fs_info {
+            if self.fs_info_outdated.swap(false, Ordering::Relaxed) {
+                // Counting the free clusters would mean reading the whole FAT, unknown is
+                // allowed.
+                self.write(fs_info + FS_INFO_FREE_COUNT, &FS_INFO_UNKNOWN.to_le_bytes())?;
+                self.write(
+                    fs_info + FS_INFO_NEXT_FREE,
+                    &self.next_free.load(Ordering::Relaxed).to_le_bytes(),
+                )?;
+            }
+        }
+
+        Ok(self.device.flush()?)
+    }
+}
+
+/// A FAT12, FAT16 or FAT32 volume, with long file names.
+pub struct FatFs {
+    fat: Arc<Fat>,
+}
+
+#[allow(unused)]
+impl FatFs {
+    /// Reads the volume's boot sector, [`FsError::Unsupported`] if the device doesn't contain a
+    /// FAT filesystem.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let fat = Fat::new(device)?;
+
+        info!(
+            "{}: {:?} volume \"{}\", {} clusters of {} bytes",
+            fat.device.name(),
+            fat.kind,
+            fat.label,
+            fat.cluster_count,
+            fat.cluster_size
+        );
+        debug!(
+            "{}: FAT at {:#x}, data at {:#x}",
+            fat.device.name(),
+            fat.fat_start,
+            fat.data_start
+        );
+
+        Ok(Self { fat: Arc::new(fat) })
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.fat.kind
+    }
+
+    pub fn label(&self) -> &str {
+        &self.fat.label
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        match self.fat.kind {
+            FatKind::Fat12 => "fat12",
+            FatKind::Fat16 => "fat16",
+            FatKind::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatInode::root(self.fat.clone()))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.fat.is_read_only()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _lock = self.fat.write_lock.lock();
+
+        self.fat.sync()
+    }
+}
@@ -0,0 +1,228 @@
+use crate::vfs::dentry::Dentry;
+use crate::vfs::path::{resolve, resolve_parent};
+use crate::vfs::{DirEntry, FsError, Metadata, NodeKind};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+
+// How much `read_to_end` asks for at once.
+const READ_CHUNK_SIZE: usize = 4096;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// How to open a file, like the one in `std`.
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+#[allow(unused)]
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file, implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn open(&self, path: &str) -> Result<File, FsError> {
+        let write = self.write || self.append;
+
+        let dentry = match resolve(path, true) {
+            Ok(dentry) => dentry,
+            Err(FsError::NotFound) if self.create => {
+                let (parent, name) = resolve_parent(path)?;
+
+                if parent.is_read_only() {
+                    return Err(FsError::ReadOnly);
+                }
+
+                parent.inode().create(&name, NodeKind::File)?;
+                parent.child(&name)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        if write {
+            if dentry.kind() == NodeKind::Directory {
+                return Err(FsError::IsADirectory);
+            }
+
+            if dentry.is_read_only() {
+                return Err(FsError::ReadOnly);
+            }
+
+            if self.truncate {
+                dentry.inode().truncate(0)?;
+            }
+        }
+
+        Ok(File::from_dentry(dentry, self.read, write, self.append))
+    }
+}
+
+/// An open file or directory, with its own offset.
+pub struct File {
+    dentry: Arc<Dentry>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    // For directories the position to continue reading entries from.
+    offset: Spinlock<u64>,
+}
+
+#[allow(unused)]
+impl File {
+    pub fn from_dentry(dentry: Arc<Dentry>, readable: bool, writable: bool, append: bool) -> Self {
+        Self {
+            dentry,
+            readable,
+            writable,
+            append,
+            offset: Spinlock::new(0),
+        }
+    }
+
+    /// Opens a file for reading.
+    pub fn open(path: &str) -> Result<File, FsError> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// Opens a file for writing, creating it if it doesn't exist and truncating it if it does.
+    pub fn create(path: &str) -> Result<File, FsError> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
+
+    pub fn path(&self) -> String {
+        self.dentry.path()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if !self.readable {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let mut offset = self.offset.lock();
+        let read = self.dentry.inode().read_at(*offset, buffer)?;
+        *offset += read as u64;
+
+        Ok(read)
+    }
+
+    /// Reads everything from the offset on and appends it to `buffer`.
+    pub fn read_to_end(&self, buffer: &mut Vec<u8>) -> Result<usize, FsError> {
+        let start = buffer.len();
+
+        loop {
+            let length = buffer.len();
+            buffer.resize(length + READ_CHUNK_SIZE, 0);
+
+            match self.read(&mut buffer[length..]) {
+                Ok(0) => {
+                    buffer.truncate(length);
+                    return Ok(length - start);
+                }
+                Ok(read) => buffer.truncate(length + read),
+                Err(e) => {
+                    buffer.truncate(length);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        if !self.writable {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let mut offset = self.offset.lock();
+        if self.append {
+            *offset = self.metadata().size;
+        }
+
+        let written = self.dentry.inode().write_at(*offset, buffer)?;
+        *offset += written as u64;
+
+        Ok(written)
+    }
+
+    /// Moves the offset and returns the new one, from the start of the file.
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+
+        let new_offset = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.metadata().size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+        };
+
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+
+        Ok(*offset)
+    }
+
+    pub fn set_len(&self, size: u64) -> Result<(), FsError> {
+        if !self.writable {
+            return Err(FsError::PermissionDenied);
+        }
+
+        self.dentry.inode().truncate(size)
+    }
+
+    /// The next entry of a directory, `None` after the last one.
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, FsError> {
+        let mut offset = self.offset.lock();
+
+        match self.dentry.inode().read_dir(*offset)? {
+            Some((entry, next)) => {
+                *offset = next;
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+}
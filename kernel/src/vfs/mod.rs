@@ -0,0 +1,370 @@
+use crate::block::partition::partitions;
+use crate::block::{devices, ramdisk, BlockDevice, BlockError};
+use crate::vfs::archive::ArchiveFs;
+use crate::vfs::dentry::Dentry;
+use crate::vfs::ext2::Ext2Fs;
+use crate::vfs::fat::FatFs;
+use crate::vfs::file::File;
+use crate::vfs::path::{resolve, resolve_parent};
+use crate::vfs::ramfs::RamFs;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use log::{info, warn};
+
+pub mod archive;
+pub mod dentry;
+pub mod ext2;
+pub mod fat;
+pub mod file;
+pub mod mount;
+pub mod path;
+pub mod ramfs;
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotASymlink,
+    AlreadyExists,
+    NotEmpty,
+    /// The path is empty, relative or ends in `.` or `..` where a name is needed.
+    InvalidPath,
+    /// Resolving the path followed too many symlinks, they probably form a loop.
+    TooManyLinks,
+    /// Like seeking before the start of a file.
+    InvalidArgument,
+    ReadOnly,
+    /// The file wasn't opened for reading or writing.
+    PermissionDenied,
+    Unsupported,
+    NoSpace,
+    /// Something is mounted there, or below.
+    Busy,
+    /// The filesystem's on-disk structures don't make sense.
+    Corrupted,
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Block(error)
+    }
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+}
+
+#[allow(unused)]
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    /// The inode number, unique within the filesystem.
+    pub inode: u64,
+    pub kind: NodeKind,
+    pub size: u64,
+    /// Unix style permission bits, like `0o755`.
+    pub permissions: u16,
+    pub links: u32,
+    /// Seconds since the Unix epoch, 0 if the filesystem doesn't know.
+    pub modified: u64,
+}
+
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: NodeKind,
+}
+
+/// A file, directory or other node of a filesystem. Everything but [`Inode::metadata`] is
+/// optional, the defaults fail the way a node of the wrong kind would.
+///
+/// Directories neither return nor look up `.` and `..`, those are handled by path resolution.
+#[allow(unused)]
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads from `offset` on, returns the number of bytes read, 0 at the end of the file.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    /// Writes at `offset`, growing the file if needed, returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Returns the entry at `position` and the position of the one after it, `None` past the
+    /// last entry. Positions are opaque to the caller, iteration starts at 0.
+    fn read_dir(&self, _position: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Removes the entry `name`. Directories are checked to be empty before.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::NotASymlink)
+    }
+}
+
+fn not_a_file(kind: NodeKind) -> FsError {
+    match kind {
+        NodeKind::Directory => FsError::IsADirectory,
+        _ => FsError::Unsupported,
+    }
+}
+
+/// A mountable filesystem, like a FAT partition or the contents of an archive.
+#[allow(unused)]
+pub trait FileSystem: Send + Sync {
+    /// The type of the filesystem, like `fat` or `ramfs`.
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Writes everything that's only in memory to the underlying storage.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Mounts the root filesystem, the initramfs if the bootloader loaded one and an empty
+/// [`RamFs`] otherwise.
+pub fn init() {
+    let root: Arc<dyn FileSystem> = match ramdisk::initrd().map(ArchiveFs::new) {
+        Some(Ok(archive)) => Arc::new(archive),
+        Some(Err(FsError::Unsupported)) => {
+            info!("The ramdisk isn't an archive, starting with an empty root");
+            Arc::new(RamFs::new())
+        }
+        Some(Err(e)) => {
+            warn!(
+                "Couldn't read the initramfs, starting with an empty root: {:?}",
+                e
+            );
+            Arc::new(RamFs::new())
+        }
+        None => Arc::new(RamFs::new()),
+    };
+
+    mount::mount("/", root).expect("mounting the root filesystem failed");
+}
+
+/// Looks for a filesystem on the device.
+fn probe(device: &Arc<dyn BlockDevice>) -> Option<Arc<dyn FileSystem>> {
+    match FatFs::new(device.clone()) {
+        Ok(fat) => return Some(Arc::new(fat)),
+        Err(FsError::Unsupported) => {}
+        Err(e) => warn!("{}: couldn't read FAT filesystem: {:?}", device.name(), e),
+    }
+
+    match Ext2Fs::new(device.clone()) {
+        Ok(ext2) => return Some(Arc::new(ext2)),
+        Err(FsError::Unsupported) => {}
+        Err(e) => warn!("{}: couldn't read ext2 filesystem: {:?}", device.name(), e),
+    }
+
+    None
+}
+
+/// Where filesystems of block devices are mounted.
+const MOUNT_DIRECTORY: &str = "/mnt";
+
+/// Makes sure mount points can be created in [`MOUNT_DIRECTORY`]. A read only root, like the
+/// initramfs, gets a [`RamFs`] there.
+fn prepare_mount_directory() -> Result<(), FsError> {
+    match resolve(MOUNT_DIRECTORY, true) {
+        Ok(directory) if directory.is_read_only() => {
+            mount::mount(MOUNT_DIRECTORY, Arc::new(RamFs::new()))
+        }
+        Ok(_) => Ok(()),
+        Err(FsError::NotFound) => create_dir(MOUNT_DIRECTORY),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mounts the filesystems found on block devices at `/mnt/<device>`. Devices with partitions
+/// are left out, their partitions are tried instead.
+pub fn mount_block_devices() {
+    if let Err(e) = prepare_mount_directory() {
+        warn!("Can't mount block devices at {}: {:?}", MOUNT_DIRECTORY, e);
+        return;
+    }
+
+    let partitioned = partitions()
+        .iter()
+        .map(|partition| partition.parent().name().to_string())
+        .collect::<BTreeSet<_>>();
+
+    for device in devices() {
+        if partitioned.contains(device.name()) {
+            continue;
+        }
+
+        let Some(filesystem) = probe(&device) else {
+            continue;
+        };
+
+        let path = format!("{}/{}", MOUNT_DIRECTORY, device.name());
+
+        if let Err(e) = create_dir_all(&path).and_then(|_| mount::mount(&path, filesystem)) {
+            warn!("Couldn't mount {} at {}: {:?}", device.name(), path, e);
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(resolve(path, true)?.inode().metadata())
+}
+
+/// Like [`metadata`], but describes a symlink itself instead of what it points to.
+#[allow(unused)]
+pub fn symlink_metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(resolve(path, false)?.inode().metadata())
+}
+
+/// Looks up the parent of `path` for creating an entry in it.
+fn writable_parent(path: &str) -> Result<(Arc<Dentry>, String), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+
+    if parent.is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+
+    Ok((parent, name))
+}
+
+#[allow(unused)]
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = writable_parent(path)?;
+
+    parent.inode().create(&name, NodeKind::Directory)?;
+
+    Ok(())
+}
+
+/// Creates the directory along with all missing ones above it.
+#[allow(unused)]
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    let mut prefix = String::new();
+
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        prefix.push('/');
+        prefix.push_str(component);
+
+        match metadata(&prefix) {
+            Ok(metadata) if metadata.kind == NodeKind::Directory => {}
+            Ok(_) => return Err(FsError::NotADirectory),
+            Err(FsError::NotFound) => create_dir(&prefix)?,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a symlink at `path` pointing to `target`, which isn't checked to exist.
+#[allow(unused)]
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = writable_parent(path)?;
+
+    parent.inode().symlink(&name, target)?;
+
+    Ok(())
+}
+
+#[allow(unused)]
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    resolve(path, false)?.inode().read_link()
+}
+
+/// Removes a file, symlink or empty directory.
+#[allow(unused)]
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = writable_parent(path)?;
+    let dentry = parent.child(&name)?;
+
+    if mount::find(&dentry.path()).is_some() {
+        return Err(FsError::Busy);
+    }
+
+    if dentry.kind() == NodeKind::Directory && dentry.inode().read_dir(0)?.is_some() {
+        return Err(FsError::NotEmpty);
+    }
+
+    parent.inode().unlink(&name)?;
+    parent.forget_child(&name);
+
+    Ok(())
+}
+
+/// Iterates over the entries of a directory.
+#[allow(unused)]
+pub fn read_dir(path: &str) -> Result<ReadDir, FsError> {
+    let dentry = resolve(path, true)?;
+
+    if dentry.kind() != NodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    Ok(ReadDir {
+        file: File::from_dentry(dentry, true, false, false),
+    })
+}
+
+pub struct ReadDir {
+    file: File,
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.file.read_dir().transpose()
+    }
+}
+
+/// Syncs all mounted filesystems.
+#[allow(unused)]
+pub fn sync_all() {
+    for mount in mount::mounts() {
+        if let Err(e) = mount.filesystem().sync() {
+            warn!("Couldn't sync {}: {:?}", mount.path(), e);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::vfs::dentry::Dentry;
+use crate::vfs::path::resolve;
+use crate::vfs::{FileSystem, FsError, NodeKind};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::info;
+use spinning_top::Spinlock;
+
+/// A filesystem mounted at a path.
+pub struct Mount {
+    path: String,
+    filesystem: Arc<dyn FileSystem>,
+    root: Arc<Dentry>,
+}
+
+#[allow(unused)]
+impl Mount {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.filesystem
+    }
+
+    pub fn root(&self) -> &Arc<Dentry> {
+        &self.root
+    }
+}
+
+// By path, so the mount covering a directory is found by its path.
+static MOUNTS: Spinlock<BTreeMap<String, Arc<Mount>>> = Spinlock::new(BTreeMap::new());
+
+/// Mounts `filesystem` on the directory at `path`, hiding what's in there until it's unmounted.
+/// The first mount has to be at `/`.
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let root = if path == "/" {
+        if find("/").is_some() {
+            return Err(FsError::Busy);
+        }
+
+        Dentry::new("", filesystem.root(), None, filesystem.clone())
+    } else {
+        let mount_point = resolve(path, true)?;
+
+        if mount_point.kind() != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        Dentry::new(
+            mount_point.name(),
+            filesystem.root(),
+            mount_point.parent().cloned(),
+            filesystem.clone(),
+        )
+    };
+
+    let path = root.path();
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.contains_key(&path) {
+        return Err(FsError::Busy);
+    }
+
+    info!(
+        "Mounted {}{} at {}",
+        filesystem.name(),
+        if filesystem.is_read_only() {
+            " (read only)"
+        } else {
+            ""
+        },
+        path
+    );
+
+    mounts.insert(
+        path.clone(),
+        Arc::new(Mount {
+            path,
+            filesystem,
+            root,
+        }),
+    );
+
+    Ok(())
+}
+
+/// Unmounts the filesystem at `path` after syncing it. Files still open on it keep working.
+#[allow(unused)]
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = resolve(path, true)?.path();
+
+    if path == "/" {
+        return Err(FsError::Busy);
+    }
+
+    let mount = find(&path).ok_or(FsError::InvalidPath)?;
+
+    let prefix = path.clone() + "/";
+    if MOUNTS.lock().keys().any(|other| other.starts_with(&prefix)) {
+        return Err(FsError::Busy);
+    }
+
+    mount.filesystem.sync()?;
+    MOUNTS.lock().remove(&path);
+
+    info!("Unmounted {}", path);
+
+    Ok(())
+}
+
+/// The mount at exactly `path`, which has to be normalized like [`Dentry::path`].
+pub fn find(path: &str) -> Option<Arc<Mount>> {
+    MOUNTS.lock().get(path).cloned()
+}
+
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().values().cloned().collect()
+}
+
+/// The root of the directory tree.
+pub fn root() -> Result<Arc<Dentry>, FsError> {
+    find("/")
+        .map(|mount| mount.root.clone())
+        .ok_or(FsError::NotFound)
+}
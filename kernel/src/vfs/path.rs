@@ -0,0 +1,89 @@
+use crate::vfs::dentry::Dentry;
+use crate::vfs::{mount, FsError, NodeKind};
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+
+// Same as Linux, anything beyond is most likely a loop.
+const MAX_SYMLINKS: usize = 40;
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Resolves an absolute path, following symlinks on the way and into mounted filesystems. A
+/// symlink at the end is only followed with `follow`.
+pub fn resolve(path: &str, follow: bool) -> Result<Arc<Dentry>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let root = mount::root()?;
+    let mut current = root.clone();
+    let mut remaining = components(path)
+        .map(|component| component.to_string())
+        .collect::<VecDeque<_>>();
+    let mut symlinks = 0;
+
+    while let Some(component) = remaining.pop_front() {
+        if current.kind() != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        match component.as_str() {
+            "." => {}
+            // The root is its own parent.
+            ".." => current = current.parent().unwrap_or(&current).clone(),
+            name => {
+                let mut child = current.child(name)?;
+
+                if let Some(mount) = mount::find(&child.path()) {
+                    child = mount.root().clone();
+                }
+
+                if child.kind() != NodeKind::Symlink || (remaining.is_empty() && !follow) {
+                    current = child;
+                    continue;
+                }
+
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(FsError::TooManyLinks);
+                }
+
+                // Relative targets start from the directory the symlink is in, which is still
+                // `current`.
+                let target = child.inode().read_link()?;
+                if target.starts_with('/') {
+                    current = root.clone();
+                }
+
+                for component in components(&target).rev() {
+                    remaining.push_front(component.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(current)
+}
+
+/// Resolves everything but the last component of `path`, which is returned with the directory
+/// it's in. For creating and removing entries.
+pub fn resolve_parent(path: &str) -> Result<(Arc<Dentry>, String), FsError> {
+    let path = path.trim_end_matches('/');
+
+    let (parent, name) = path.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+
+    let parent = resolve(if parent.is_empty() { "/" } else { parent }, true)?;
+
+    if parent.kind() != NodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    Ok((parent, name.to_string()))
+}
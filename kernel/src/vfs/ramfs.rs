@@ -0,0 +1,209 @@
+use crate::rtc;
+use crate::vfs::{DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spinning_top::Spinlock;
+
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+    Symlink(String),
+}
+
+struct RamInode {
+    inode: u64,
+    contents: Spinlock<Contents>,
+    modified: AtomicU64,
+}
+
+impl RamInode {
+    fn new(contents: Contents) -> Arc<Self> {
+        Arc::new(Self {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            contents: Spinlock::new(contents),
+            modified: AtomicU64::new(rtc::now().unix_timestamp()),
+        })
+    }
+
+    fn touch(&self) {
+        self.modified
+            .store(rtc::now().unix_timestamp(), Ordering::Relaxed);
+    }
+
+    fn add_entry(&self, name: &str, contents: Contents) -> Result<Arc<dyn Inode>, FsError> {
+        let Contents::Directory(entries) = &mut *self.contents.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = RamInode::new(contents);
+        entries.insert(name.to_string(), inode.clone());
+        self.touch();
+
+        Ok(inode)
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (kind, size, permissions) = match &*self.contents.lock() {
+            Contents::File(data) => (NodeKind::File, data.len() as u64, 0o644),
+            Contents::Directory(entries) => (NodeKind::Directory, entries.len() as u64, 0o755),
+            Contents::Symlink(target) => (NodeKind::Symlink, target.len() as u64, 0o777),
+        };
+
+        Metadata {
+            inode: self.inode,
+            kind,
+            size,
+            permissions,
+            links: 1,
+            modified: self.modified.load(Ordering::Relaxed),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let Contents::File(data) = &*self.contents.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let offset = (offset as usize).min(data.len());
+        let length = buffer.len().min(data.len() - offset);
+        buffer[..length].copy_from_slice(&data[offset..offset + length]);
+
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let Contents::File(data) = &mut *self.contents.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let offset = offset as usize;
+        let end = offset.checked_add(buffer.len()).ok_or(FsError::NoSpace)?;
+
+        if end > data.len() {
+            data.try_reserve(end - data.len())
+                .map_err(|_| FsError::NoSpace)?;
+            data.resize(end, 0);
+        }
+
+        data[offset..end].copy_from_slice(buffer);
+        self.touch();
+
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let Contents::File(data) = &mut *self.contents.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let size = size as usize;
+        if size > data.len() {
+            data.try_reserve(size - data.len())
+                .map_err(|_| FsError::NoSpace)?;
+        }
+
+        data.resize(size, 0);
+        self.touch();
+
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let Contents::Directory(entries) = &*self.contents.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+
+        entries
+            .get(name)
+            .map(|inode| inode.clone() as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self, position: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let (name, inode) = {
+            let Contents::Directory(entries) = &*self.contents.lock() else {
+                return Err(FsError::NotADirectory);
+            };
+
+            // Positions are indices, the entries are sorted by name.
+            match entries.iter().nth(position as usize) {
+                Some((name, inode)) => (name.clone(), inode.clone()),
+                None => return Ok(None),
+            }
+        };
+
+        let entry = DirEntry {
+            name,
+            inode: inode.inode,
+            kind: inode.metadata().kind,
+        };
+
+        Ok(Some((entry, position + 1)))
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let contents = match kind {
+            NodeKind::File => Contents::File(Vec::new()),
+            NodeKind::Directory => Contents::Directory(BTreeMap::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+
+        self.add_entry(name, contents)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.add_entry(name, Contents::Symlink(target.to_string()))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let Contents::Directory(entries) = &mut *self.contents.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+
+        entries.remove(name).ok_or(FsError::NotFound)?;
+        self.touch();
+
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &*self.contents.lock() {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::NotASymlink),
+        }
+    }
+}
+
+/// A filesystem that only lives in memory, everything is lost on reboot.
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        Self {
+            root: RamInode::new(Contents::Directory(BTreeMap::new())),
+        }
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,620 @@
+use crate::block::{check_request, disk_name, register_device, BlockDevice, BlockError};
+use crate::memory::dma::DmaBuffer;
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::driver::{PciDeviceId, PciDriver, PciProbeError};
+use crate::pci_express::register_driver;
+use crate::virtio::pci::VirtioPciDevice;
+use crate::virtio::queue::{VirtqBuffer, Virtqueue};
+use crate::virtio::{VirtioDeviceType, FEATURE_RING_PACKED, VIRTIO_VENDOR_ID};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use log::{debug, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1001;
+const MODERN_DEVICE_ID: u16 = 0x1042;
+
+const FEATURE_SIZE_MAX: u64 = 1 << 1;
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_BLOCK_SIZE: u64 = 1 << 6;
+const FEATURE_FLUSH: u64 = 1 << 9;
+const FEATURE_DISCARD: u64 = 1 << 13;
+
+const CONFIG_CAPACITY: u64 = 0;
+const CONFIG_SIZE_MAX: u64 = 8;
+const CONFIG_BLOCK_SIZE: u64 = 20;
+const CONFIG_MAX_DISCARD_SECTORS: u64 = 36;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_GET_ID: u32 = 8;
+const REQUEST_DISCARD: u32 = 11;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Requests are always addressed in 512 byte sectors, whatever the block size.
+const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 128;
+const MAX_TRANSFER_SIZE: usize = 128 * 1024;
+const ID_LENGTH: usize = 20;
+
+// Layout of the per request buffer: the header the device reads, an optional discard range
+// and the status byte the device writes.
+const HEADER_SIZE: usize = 16;
+const DISCARD_RANGE_OFFSET: usize = 16;
+const DISCARD_RANGE_SIZE: usize = 16;
+const STATUS_OFFSET: usize = 32;
+
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static VIRTIO_BLOCK_DRIVER: VirtioBlockDriver = VirtioBlockDriver;
+
+static DEVICE_IDS: [PciDeviceId; 2] = [
+    PciDeviceId::device(VIRTIO_VENDOR_ID, TRANSITIONAL_DEVICE_ID),
+    PciDeviceId::device(VIRTIO_VENDOR_ID, MODERN_DEVICE_ID),
+];
+
+pub fn init() {
+    register_driver(&VIRTIO_BLOCK_DRIVER);
+}
+
+struct InFlightRequest {
+    request: DmaBuffer,
+    data: Option<DmaBuffer>,
+    status: Option<u8>,
+    waker: Option<Waker>,
+    // Nobody waits for the result anymore, the request goes away once it completes.
+    abandoned: bool,
+}
+
+struct RequestQueue {
+    queue: Virtqueue,
+    // Keyed by our own ids, the queue reuses a token as soon as the device is done with it,
+    // possibly before the waiter took the result.
+    in_flight: BTreeMap<u64, InFlightRequest>,
+    // The requests the device still has, by token.
+    ids: BTreeMap<u16, u64>,
+    next_id: u64,
+}
+
+impl RequestQueue {
+    fn process_used(&mut self) {
+        while let Some((token, _)) = self.queue.pop_used() {
+            let Some(id) = self.ids.remove(&token) else {
+                warn!("Virtio block device used unknown request {}", token);
+                continue;
+            };
+
+            let Some(request) = self.in_flight.get_mut(&id) else {
+                continue;
+            };
+
+            if request.abandoned {
+                self.in_flight.remove(&id);
+                continue;
+            }
+
+            let status = unsafe {
+                request
+                    .request
+                    .as_ptr::<u8>()
+                    .add(STATUS_OFFSET)
+                    .read_volatile()
+            };
+            request.status = Some(status);
+
+            if let Some(waker) = request.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Removes a completed request and returns its status and data buffer.
+    fn take_completed(&mut self, id: u64) -> Option<(u8, Option<DmaBuffer>)> {
+        self.in_flight.get(&id)?.status?;
+
+        let request = self.in_flight.remove(&id)?;
+
+        Some((request.status?, request.data))
+    }
+}
+
+pub struct VirtioBlock {
+    name: String,
+    device: VirtioPciDevice,
+    queue: Spinlock<RequestQueue>,
+    has_interrupts: bool,
+    capacity: u64,
+    block_size: usize,
+    max_transfer_size: usize,
+    max_discard_sectors: u64,
+}
+
+/// A request the device is working on, see [`VirtioBlock::submit`]. Dropping it abandons the
+/// result.
+pub struct PendingRequest<'a> {
+    device: &'a VirtioBlock,
+    id: Option<u64>,
+}
+
+fn status_to_result(status: u8) -> Result<(), BlockError> {
+    match status {
+        STATUS_OK => Ok(()),
+        STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+        _ => Err(BlockError::Io),
+    }
+}
+
+impl<'a> PendingRequest<'a> {
+    /// Blocks until the device completed the request and returns the data buffer.
+    pub fn wait(mut self) -> Result<Option<DmaBuffer>, BlockError> {
+        let id = self.id.take().expect("request already completed");
+        let (status, data) = self.device.wait_until(|queue| queue.take_completed(id));
+
+        status_to_result(status).map(|()| data)
+    }
+}
+
+impl<'a> Future for PendingRequest<'a> {
+    type Output = Result<Option<DmaBuffer>, BlockError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = self.id.expect("request already completed");
+
+        let completed = self.device.with_queue(|queue| {
+            queue.process_used();
+
+            let completed = queue.take_completed(id);
+
+            if completed.is_none() {
+                if let Some(request) = queue.in_flight.get_mut(&id) {
+                    request.waker = Some(context.waker().clone());
+                }
+            }
+
+            completed
+        });
+
+        match completed {
+            Some((status, data)) => {
+                self.id = None;
+                Poll::Ready(status_to_result(status).map(|()| data))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> Drop for PendingRequest<'a> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.device.with_queue(|queue| {
+                if queue.take_completed(id).is_none() {
+                    if let Some(request) = queue.in_flight.get_mut(&id) {
+                        request.abandoned = true;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[allow(unused)]
+impl VirtioBlock {
+    fn with_queue<R>(&self, f: impl FnOnce(&mut RequestQueue) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.queue.lock()))
+    }
+
+    /// Collects completions until `f` returns something. Halts in between if the completion
+    /// interrupt will wake us up, polls otherwise.
+    fn wait_until<R>(&self, mut f: impl FnMut(&mut RequestQueue) -> Option<R>) -> R {
+        let interrupts_were_enabled = interrupts::are_enabled();
+
+        loop {
+            // Checking and halting with interrupts off, so the completion can't slip in
+            // between.
+            interrupts::disable();
+
+            let result = {
+                let mut queue = self.queue.lock();
+                queue.process_used();
+                f(&mut queue)
+            };
+
+            if let Some(result) = result {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                return result;
+            }
+
+            if interrupts_were_enabled && self.has_interrupts {
+                interrupts::enable_and_hlt();
+            } else {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Hands a request to the device without waiting for it, so several can be in flight at
+    /// once. `data` is read by the device for writes and written for reads.
+    fn submit(
+        &self,
+        kind: u32,
+        sector: u64,
+        data: Option<(DmaBuffer, usize)>,
+        discard_sectors: Option<u32>,
+    ) -> Result<PendingRequest<'_>, BlockError> {
+        let request = DmaBuffer::new(STATUS_OFFSET + 1).ok_or(BlockError::OutOfMemory)?;
+
+        unsafe {
+            let header = request.as_mut_ptr::<u32>();
+            header.write_volatile(kind);
+            header.add(1).write_volatile(0);
+            request.as_mut_ptr::<u64>().add(1).write_volatile(sector);
+
+            if let Some(count) = discard_sectors {
+                let range = request.as_mut_ptr::<u8>().add(DISCARD_RANGE_OFFSET);
+                (range as *mut u64).write_volatile(sector);
+                (range.add(8) as *mut u32).write_volatile(count);
+                (range.add(12) as *mut u32).write_volatile(0);
+            }
+        }
+
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(VirtqBuffer::readable(
+            request.physical_address(),
+            HEADER_SIZE as u32,
+        ));
+
+        if discard_sectors.is_some() {
+            buffers.push(VirtqBuffer::readable(
+                request.physical_address() + DISCARD_RANGE_OFFSET as u64,
+                DISCARD_RANGE_SIZE as u32,
+            ));
+        }
+
+        if let Some((data, length)) = &data {
+            buffers.push(VirtqBuffer {
+                address: data.physical_address(),
+                length: *length as u32,
+                device_writable: kind != REQUEST_OUT,
+            });
+        }
+
+        buffers.push(VirtqBuffer::writable(
+            request.physical_address() + STATUS_OFFSET as u64,
+            1,
+        ));
+
+        let mut in_flight = Some(InFlightRequest {
+            request,
+            data: data.map(|(data, _)| data),
+            status: None,
+            waker: None,
+            abandoned: false,
+        });
+
+        // If the queue is full, wait for the device to finish something. The request has to
+        // be tracked before the lock is dropped, it may complete right away.
+        let id = self.wait_until(|queue| {
+            if (queue.queue.free_descriptors() as usize) < buffers.len() {
+                return None;
+            }
+
+            let token = queue.queue.add(&buffers).ok()?;
+            let id = queue.next_id;
+            queue.next_id += 1;
+
+            queue.ids.insert(token, id);
+            queue.in_flight.insert(id, in_flight.take()?);
+            queue.queue.notify();
+
+            Some(id)
+        });
+
+        Ok(PendingRequest {
+            device: self,
+            id: Some(id),
+        })
+    }
+
+    /// The device's serial number, if it reports one.
+    pub fn serial(&self) -> Option<String> {
+        let data = DmaBuffer::new(ID_LENGTH)?;
+        let data = self
+            .submit(REQUEST_GET_ID, 0, Some((data, ID_LENGTH)), None)
+            .ok()?
+            .wait()
+            .ok()??;
+
+        let id = &data.as_slice()[..ID_LENGTH];
+        let length = id.iter().position(|byte| *byte == 0).unwrap_or(ID_LENGTH);
+
+        Some(String::from_utf8_lossy(&id[..length]).into())
+    }
+
+    fn sector(&self, block: u64) -> u64 {
+        block * (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    fn handle_interrupt(&self) {
+        self.queue.lock().process_used();
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity * SECTOR_SIZE as u64 / self.block_size as u64
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.has_feature(FEATURE_READ_ONLY)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+
+        let blocks_per_transfer = (self.max_transfer_size / self.block_size) as u64;
+
+        // Submit everything first so the device can work on all of it at once.
+        let requests = buffer
+            .chunks(self.max_transfer_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let data = DmaBuffer::new(chunk.len()).ok_or(BlockError::OutOfMemory)?;
+                let block = start + index as u64 * blocks_per_transfer;
+
+                self.submit(
+                    REQUEST_IN,
+                    self.sector(block),
+                    Some((data, chunk.len())),
+                    None,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (request, chunk) in requests
+            .into_iter()
+            .zip(buffer.chunks_mut(self.max_transfer_size))
+        {
+            let data = request.wait()?.expect("read request without data");
+            chunk.copy_from_slice(&data.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        check_request(self, start, buffer.len())?;
+
+        let blocks_per_transfer = (self.max_transfer_size / self.block_size) as u64;
+
+        let requests = buffer
+            .chunks(self.max_transfer_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut data = DmaBuffer::new(chunk.len()).ok_or(BlockError::OutOfMemory)?;
+                data.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+                let block = start + index as u64 * blocks_per_transfer;
+
+                self.submit(
+                    REQUEST_OUT,
+                    self.sector(block),
+                    Some((data, chunk.len())),
+                    None,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for request in requests {
+            request.wait()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Without the flush feature the device writes through.
+        if !self.device.has_feature(FEATURE_FLUSH) {
+            return Ok(());
+        }
+
+        self.submit(REQUEST_FLUSH, 0, None, None)?
+            .wait()
+            .map(|_| ())
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        if !self.device.has_feature(FEATURE_DISCARD) {
+            return Err(BlockError::Unsupported);
+        }
+
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let length = count
+            .checked_mul(self.block_size as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        check_request(self, start, length as usize)?;
+
+        let mut sector = self.sector(start);
+        let end = self.sector(start + count);
+
+        while sector < end {
+            let sectors = (end - sector).min(self.max_discard_sectors);
+
+            self.submit(REQUEST_DISCARD, sector, None, Some(sectors as u32))?
+                .wait()?;
+
+            sector += sectors;
+        }
+
+        Ok(())
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<Arc<VirtioBlock>, PciProbeError> {
+    let mut virtio_device = VirtioPciDevice::new(device)
+        .map_err(|_| PciProbeError::InitializationFailed("not a virtio 1.x device"))?;
+
+    if virtio_device.device_type() != VirtioDeviceType::Block {
+        return Err(PciProbeError::NotSupported);
+    }
+
+    virtio_device
+        .initialize(
+            FEATURE_SIZE_MAX
+                | FEATURE_READ_ONLY
+                | FEATURE_BLOCK_SIZE
+                | FEATURE_FLUSH
+                | FEATURE_DISCARD
+                | FEATURE_RING_PACKED,
+        )
+        .map_err(|_| PciProbeError::InitializationFailed("feature negotiation failed"))?;
+
+    // The interrupt handler needs the device, which needs the queue, which needs the
+    // interrupts to be set up first.
+    let slot: Arc<OnceCell<Arc<VirtioBlock>>> = Arc::new(OnceCell::uninit());
+    let handler_slot = slot.clone();
+
+    let has_interrupts = match virtio_device.enable_interrupts(
+        1,
+        Arc::new(move |_| {
+            if let Some(device) = handler_slot.get() {
+                device.handle_interrupt();
+            }
+        }),
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "No interrupts for virtio block device {}, polling: {:?}",
+                device.address(),
+                e
+            );
+            false
+        }
+    };
+
+    let queue = virtio_device.setup_queue(0, QUEUE_SIZE).map_err(|_| {
+        virtio_device.fail();
+        virtio_device.release_interrupts();
+        PciProbeError::InitializationFailed("couldn't set up the request queue")
+    })?;
+
+    let read_config = |offset| virtio_device.read_device_config::<u32>(offset);
+
+    let capacity = virtio_device
+        .read_device_config::<u64>(CONFIG_CAPACITY)
+        .unwrap_or(0);
+
+    let block_size = if virtio_device.has_feature(FEATURE_BLOCK_SIZE) {
+        read_config(CONFIG_BLOCK_SIZE)
+            .map(|size| size as usize)
+            .filter(|size| size.is_power_of_two() && *size >= SECTOR_SIZE)
+            .unwrap_or(SECTOR_SIZE)
+    } else {
+        SECTOR_SIZE
+    };
+
+    let size_max = if virtio_device.has_feature(FEATURE_SIZE_MAX) {
+        read_config(CONFIG_SIZE_MAX).map_or(MAX_TRANSFER_SIZE, |size| size as usize)
+    } else {
+        MAX_TRANSFER_SIZE
+    };
+
+    let max_transfer_size = (size_max.min(MAX_TRANSFER_SIZE) / block_size).max(1) * block_size;
+
+    let max_discard_sectors = if virtio_device.has_feature(FEATURE_DISCARD) {
+        read_config(CONFIG_MAX_DISCARD_SECTORS)
+            .filter(|sectors| *sectors != 0)
+            .unwrap_or(u32::MAX) as u64
+    } else {
+        0
+    };
+
+    virtio_device.driver_ok();
+
+    let index = DEVICE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let name = disk_name("vd", index);
+
+    let block_device = slot.get_or_init(|| {
+        Arc::new(VirtioBlock {
+            name,
+            device: virtio_device,
+            queue: Spinlock::new(RequestQueue {
+                queue,
+                in_flight: BTreeMap::new(),
+                ids: BTreeMap::new(),
+                next_id: 0,
+            }),
+            has_interrupts,
+            capacity,
+            block_size,
+            max_transfer_size,
+            max_discard_sectors,
+        })
+    });
+
+    debug!(
+        "Virtio block device {} features {:#x}, transfers up to {} bytes",
+        block_device.name,
+        block_device.device.features(),
+        max_transfer_size
+    );
+
+    if let Some(serial) = block_device.serial() {
+        info!("{} has serial number {:?}", block_device.name, serial);
+    }
+
+    Ok(block_device.clone())
+}
+
+struct VirtioBlockDriver;
+
+impl PciDriver for VirtioBlockDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &DEVICE_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<(), PciProbeError> {
+        let block_device = probe(device)?;
+
+        register_device(block_device);
+
+        Ok(())
+    }
+}
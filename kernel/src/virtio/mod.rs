@@ -0,0 +1,91 @@
+use crate::pci_express::interrupts::PciInterruptError;
+
+pub mod block;
+pub mod pci;
+pub mod queue;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// Modern (virtio 1.x only) devices have this plus their device type as PCI device ID,
+// transitional devices one of 0x1000..=0x103F and their device type as subsystem ID.
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+pub const FEATURE_RING_PACKED: u64 = 1 << 34;
+
+/// Device types, from the virtio specification's device ID list.
+#[allow(unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VirtioDeviceType {
+    Network,
+    Block,
+    Console,
+    EntropySource,
+    MemoryBalloon,
+    Scsi,
+    Gpu,
+    Input,
+    Socket,
+    Other(u16),
+}
+
+#[allow(unused)]
+impl VirtioDeviceType {
+    pub fn from_id(id: u16) -> Self {
+        match id {
+            1 => Self::Network,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::EntropySource,
+            5 => Self::MemoryBalloon,
+            8 => Self::Scsi,
+            16 => Self::Gpu,
+            18 => Self::Input,
+            19 => Self::Socket,
+            id => Self::Other(id),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::Network => 1,
+            Self::Block => 2,
+            Self::Console => 3,
+            Self::EntropySource => 4,
+            Self::MemoryBalloon => 5,
+            Self::Scsi => 8,
+            Self::Gpu => 16,
+            Self::Input => 18,
+            Self::Socket => 19,
+            Self::Other(id) => *id,
+        }
+    }
+
+    /// The PCI device ID of the modern variant, for driver ID tables.
+    pub fn modern_pci_device_id(&self) -> u16 {
+        MODERN_DEVICE_ID_BASE + self.id()
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub enum VirtioError {
+    NotVirtio,
+    MissingCapability(&'static str),
+    BarNotMapped(u8),
+    /// The device doesn't offer `VIRTIO_F_VERSION_1`, i.e. is legacy only.
+    LegacyOnly,
+    FeaturesRejected,
+    QueueUnavailable(u16),
+    NoMsixVector,
+    OutOfMemory,
+    QueueFull,
+    Interrupts(PciInterruptError),
+}
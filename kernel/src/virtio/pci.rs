@@ -0,0 +1,411 @@
+use crate::apic;
+use crate::apic::IrqHandler;
+use crate::pci_express::device::PciDevice;
+use crate::pci_express::device_capabilities::{PciDeviceCapability, PciVendorSpecificCapability};
+use crate::pci_express::interrupts::PciInterrupts;
+use crate::virtio::queue::Virtqueue;
+use crate::virtio::{
+    VirtioDeviceType, VirtioError, FEATURE_RING_PACKED, FEATURE_VERSION_1, MODERN_DEVICE_ID_BASE,
+    STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED, STATUS_FEATURES_OK,
+    TRANSITIONAL_DEVICE_IDS, VIRTIO_VENDOR_ID,
+};
+use log::debug;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAPABILITY_COMMON_CONFIG: u8 = 1;
+const CAPABILITY_NOTIFY_CONFIG: u8 = 2;
+const CAPABILITY_ISR_CONFIG: u8 = 3;
+const CAPABILITY_DEVICE_CONFIG: u8 = 4;
+
+const CAPABILITY_TYPE: u16 = 3;
+const CAPABILITY_BAR: u16 = 4;
+const CAPABILITY_OFFSET: u16 = 8;
+const CAPABILITY_NOTIFY_OFFSET_MULTIPLIER: u16 = 16;
+
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_CONFIG_MSIX_VECTOR: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1E;
+const COMMON_QUEUE_DESCRIPTOR: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+const NO_MSIX_VECTOR: u16 = 0xFFFF;
+
+/// A virtio 1.x device behind the PCI transport, found through the vendor specific
+/// capabilities that point at its configuration structures.
+pub struct VirtioPciDevice {
+    device: PciDevice,
+    device_type: VirtioDeviceType,
+    common_config: VirtAddr,
+    notify_base: VirtAddr,
+    notify_offset_multiplier: u32,
+    isr_status: VirtAddr,
+    device_config: Option<VirtAddr>,
+    features: u64,
+    interrupts: Option<PciInterrupts>,
+}
+
+/// Where a configuration structure lives: the BAR it is in, mapped, plus its offset.
+fn map_structure(
+    device: &PciDevice,
+    capability: &PciVendorSpecificCapability,
+) -> Result<VirtAddr, VirtioError> {
+    let bar = capability.read_u8(CAPABILITY_BAR);
+    let offset = capability.read_u32(CAPABILITY_OFFSET);
+
+    let base = device
+        .bar(bar)
+        .and_then(|bar| bar.map())
+        .ok_or(VirtioError::BarNotMapped(bar))?;
+
+    Ok(base + offset as u64)
+}
+
+#[allow(unused)]
+impl VirtioPciDevice {
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        if device.vendor_id() != VIRTIO_VENDOR_ID {
+            return Err(VirtioError::NotVirtio);
+        }
+
+        let device_type = match device.device_id() {
+            id if id >= MODERN_DEVICE_ID_BASE => {
+                VirtioDeviceType::from_id(id - MODERN_DEVICE_ID_BASE)
+            }
+            id if TRANSITIONAL_DEVICE_IDS.contains(&id) => {
+                let (_, subsystem_id) = device.subsystem().ok_or(VirtioError::NotVirtio)?;
+                VirtioDeviceType::from_id(subsystem_id)
+            }
+            _ => return Err(VirtioError::NotVirtio),
+        };
+
+        let mut common_config = None;
+        let mut notify = None;
+        let mut isr_status = None;
+        let mut device_config = None;
+
+        // A device may offer a structure more than once, the first one is the preferred one.
+        for capability in device.capabilities() {
+            let PciDeviceCapability::VendorSpecific(capability) = capability else {
+                continue;
+            };
+
+            match capability.read_u8(CAPABILITY_TYPE) {
+                CAPABILITY_COMMON_CONFIG if common_config.is_none() => {
+                    common_config = Some(map_structure(device, &capability)?);
+                }
+                CAPABILITY_NOTIFY_CONFIG if notify.is_none() => {
+                    notify = Some((
+                        map_structure(device, &capability)?,
+                        capability.read_u32(CAPABILITY_NOTIFY_OFFSET_MULTIPLIER),
+                    ));
+                }
+                CAPABILITY_ISR_CONFIG if isr_status.is_none() => {
+                    isr_status = Some(map_structure(device, &capability)?);
+                }
+                CAPABILITY_DEVICE_CONFIG if device_config.is_none() => {
+                    device_config = Some(map_structure(device, &capability)?);
+                }
+                _ => {}
+            }
+        }
+
+        let (notify_base, notify_offset_multiplier) =
+            notify.ok_or(VirtioError::MissingCapability("notify"))?;
+
+        device.enable_bus_master();
+
+        let virtio_device = Self {
+            device: device.clone(),
+            device_type,
+            common_config: common_config.ok_or(VirtioError::MissingCapability("common"))?,
+            notify_base,
+            notify_offset_multiplier,
+            isr_status: isr_status.ok_or(VirtioError::MissingCapability("ISR"))?,
+            device_config,
+            features: 0,
+            interrupts: None,
+        };
+
+        debug!(
+            "Virtio {:?} device at {}, {} queue(s)",
+            device_type,
+            device.address(),
+            virtio_device.queue_count()
+        );
+
+        Ok(virtio_device)
+    }
+
+    pub fn pci_device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    pub fn device_type(&self) -> VirtioDeviceType {
+        self.device_type
+    }
+
+    fn read_common<T>(&self, offset: u64) -> T {
+        unsafe { (self.common_config + offset).as_ptr::<T>().read_volatile() }
+    }
+
+    fn write_common<T>(&self, offset: u64, value: T) {
+        unsafe {
+            (self.common_config + offset)
+                .as_mut_ptr::<T>()
+                .write_volatile(value)
+        }
+    }
+
+    /// 64 bit fields may be written as two halves, low half first.
+    fn write_common_u64(&self, offset: u64, value: u64) {
+        self.write_common(offset, value as u32);
+        self.write_common(offset + 4, (value >> 32) as u32);
+    }
+
+    pub fn status(&self) -> u8 {
+        self.read_common(COMMON_DEVICE_STATUS)
+    }
+
+    fn add_status(&self, status: u8) {
+        self.write_common(COMMON_DEVICE_STATUS, self.status() | status);
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        self.read_common(COMMON_NUM_QUEUES)
+    }
+
+    /// The features both the device and the driver support, valid after
+    /// [`VirtioPciDevice::initialize`].
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = 0;
+
+        for select in 0..2u32 {
+            self.write_common(COMMON_DEVICE_FEATURE_SELECT, select);
+            features |= (self.read_common::<u32>(COMMON_DEVICE_FEATURE) as u64) << (select * 32);
+        }
+
+        features
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        for select in 0..2u32 {
+            self.write_common(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.write_common(COMMON_DRIVER_FEATURE, (features >> (select * 32)) as u32);
+        }
+    }
+
+    /// Writing 0 resets the device, which is done once it reads back as 0.
+    pub fn reset(&self) {
+        self.write_common(COMMON_DEVICE_STATUS, 0u8);
+
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the device and negotiates the features from `supported_features` that the device
+    /// offers too. Afterwards the driver sets up its queues and calls
+    /// [`VirtioPciDevice::driver_ok`].
+    pub fn initialize(&mut self, supported_features: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let device_features = self.device_features();
+
+        if device_features & FEATURE_VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::LegacyOnly);
+        }
+
+        let features = device_features & (supported_features | FEATURE_VERSION_1);
+        self.set_driver_features(features);
+
+        self.add_status(STATUS_FEATURES_OK);
+
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        debug!(
+            "Negotiated virtio features {:#x} (device offers {:#x}) for {}",
+            features,
+            device_features,
+            self.device.address()
+        );
+
+        self.features = features;
+
+        Ok(features)
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Sets up MSI-X with one vector for configuration changes and one per queue, as far as
+    /// the device and the free vectors allow. Queues set up afterwards are assigned a vector.
+    pub fn enable_interrupts(
+        &mut self,
+        queues: usize,
+        handler: IrqHandler,
+    ) -> Result<(), VirtioError> {
+        let interrupts = self
+            .device
+            .enable_msix(queues + 1, apic::local_apic_id(), handler)
+            .map_err(VirtioError::Interrupts)?;
+
+        self.write_common(COMMON_CONFIG_MSIX_VECTOR, 0u16);
+
+        if self.read_common::<u16>(COMMON_CONFIG_MSIX_VECTOR) == NO_MSIX_VECTOR {
+            interrupts.release();
+            return Err(VirtioError::NoMsixVector);
+        }
+
+        self.interrupts = Some(interrupts);
+
+        Ok(())
+    }
+
+    /// Gives the vectors from [`Self::enable_interrupts`] back, for when the device is given up.
+    pub fn release_interrupts(&mut self) {
+        if let Some(interrupts) = self.interrupts.take() {
+            interrupts.release();
+        }
+    }
+
+    /// The MSI-X table entry a queue interrupts with. Queues share vectors if there are
+    /// fewer than queues, the configuration change vector is only shared if there's just one.
+    fn queue_msix_entry(&self, queue: u16) -> Option<u16> {
+        let count = self.interrupts.as_ref()?.count() as u16;
+
+        Some(if count == 1 {
+            0
+        } else {
+            1 + queue % (count - 1)
+        })
+    }
+
+    /// The CPU interrupt vector a queue's interrupts arrive at.
+    pub fn queue_vector(&self, queue: u16) -> Option<u8> {
+        let entry = self.queue_msix_entry(queue)?;
+
+        self.interrupts.as_ref()?.vector(entry as usize)
+    }
+
+    /// Sets up and enables a queue with at most `max_size` entries, in the packed layout if
+    /// that was negotiated.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, VirtioError> {
+        self.write_common(COMMON_QUEUE_SELECT, index);
+
+        let device_size: u16 = self.read_common(COMMON_QUEUE_SIZE);
+
+        if index >= self.queue_count() || device_size == 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+
+        let packed = self.has_feature(FEATURE_RING_PACKED);
+        let size = device_size.min(max_size).max(1);
+
+        let size = if packed || size.is_power_of_two() {
+            size
+        } else {
+            size.next_power_of_two() >> 1
+        };
+
+        self.write_common(COMMON_QUEUE_SIZE, size);
+
+        let notify_offset: u16 = self.read_common(COMMON_QUEUE_NOTIFY_OFFSET);
+        let notify_address =
+            self.notify_base + notify_offset as u64 * self.notify_offset_multiplier as u64;
+
+        let queue = if packed {
+            Virtqueue::new_packed(index, size, notify_address)?
+        } else {
+            Virtqueue::new_split(index, size, notify_address)?
+        };
+
+        let (descriptor_area, driver_area, device_area): (PhysAddr, PhysAddr, PhysAddr) =
+            queue.ring_addresses();
+
+        self.write_common_u64(COMMON_QUEUE_DESCRIPTOR, descriptor_area.as_u64());
+        self.write_common_u64(COMMON_QUEUE_DRIVER, driver_area.as_u64());
+        self.write_common_u64(COMMON_QUEUE_DEVICE, device_area.as_u64());
+
+        if let Some(entry) = self.queue_msix_entry(index) {
+            self.write_common(COMMON_QUEUE_MSIX_VECTOR, entry);
+
+            if self.read_common::<u16>(COMMON_QUEUE_MSIX_VECTOR) == NO_MSIX_VECTOR {
+                return Err(VirtioError::NoMsixVector);
+            }
+        }
+
+        self.write_common(COMMON_QUEUE_ENABLE, 1u16);
+
+        debug!(
+            "Virtio queue {} of {} set up with {} {} entries",
+            index,
+            self.device.address(),
+            size,
+            if packed { "packed" } else { "split" }
+        );
+
+        Ok(queue)
+    }
+
+    /// Reads and thereby clears the ISR status, only needed with legacy INTx interrupts. Bit 0
+    /// means a queue was used, bit 1 a configuration change.
+    pub fn isr_status(&self) -> u8 {
+        unsafe { self.isr_status.as_ptr::<u8>().read_volatile() }
+    }
+
+    /// Reads from the device specific configuration. The device can change it at any time, so
+    /// the read is repeated until the configuration generation stays the same.
+    pub fn read_device_config<T: Copy>(&self, offset: u64) -> Option<T> {
+        let address = self.device_config? + offset;
+
+        loop {
+            let generation: u8 = self.read_common(COMMON_CONFIG_GENERATION);
+            let value = unsafe { address.as_ptr::<T>().read_volatile() };
+
+            if self.read_common::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return Some(value);
+            }
+        }
+    }
+
+    pub fn write_device_config<T: Copy>(&self, offset: u64, value: T) {
+        if let Some(device_config) = self.device_config {
+            unsafe {
+                (device_config + offset)
+                    .as_mut_ptr::<T>()
+                    .write_volatile(value)
+            };
+        }
+    }
+}
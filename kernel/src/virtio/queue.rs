@@ -0,0 +1,515 @@
+use crate::memory::dma::DmaBuffer;
+use crate::virtio::VirtioError;
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+const PACKED_DESCRIPTOR_AVAILABLE: usize = 7;
+const PACKED_DESCRIPTOR_USED: usize = 15;
+
+const AVAILABLE_NO_INTERRUPT: u16 = 1;
+const USED_NO_NOTIFY: u16 = 1;
+const PACKED_EVENTS_ENABLE: u16 = 0;
+const PACKED_EVENTS_DISABLE: u16 = 1;
+
+// Where the device event suppression structure of a packed queue starts, the driver's is at
+// the start of the same buffer.
+const PACKED_DEVICE_EVENT_OFFSET: usize = 4;
+
+/// One part of a request, `device_writable` for the parts the device fills in.
+#[derive(Copy, Clone, Debug)]
+pub struct VirtqBuffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    pub device_writable: bool,
+}
+
+#[allow(unused)]
+impl VirtqBuffer {
+    pub fn readable(address: PhysAddr, length: u32) -> Self {
+        Self {
+            address,
+            length,
+            device_writable: false,
+        }
+    }
+
+    pub fn writable(address: PhysAddr, length: u32) -> Self {
+        Self {
+            address,
+            length,
+            device_writable: true,
+        }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct PackedDescriptor {
+    address: u64,
+    length: u32,
+    id: u16,
+    flags: u16,
+}
+
+/// The virtio 1.0 layout: a descriptor table plus separate rings for the buffers the driver
+/// made available and the ones the device is done with.
+struct SplitRing {
+    descriptors: DmaBuffer,
+    available: DmaBuffer,
+    used: DmaBuffer,
+    free_head: u16,
+    next_available: u16,
+    last_used: u16,
+}
+
+/// The virtio 1.1 layout: a single ring that both sides write descriptors to, told apart by
+/// wrap counters.
+struct PackedRing {
+    descriptors: DmaBuffer,
+    events: DmaBuffer,
+    next_available: u16,
+    available_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+    free_ids: Vec<u16>,
+    chain_lengths: Vec<u16>,
+}
+
+enum Ring {
+    Split(SplitRing),
+    Packed(PackedRing),
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_address: VirtAddr,
+    free_descriptors: u16,
+    ring: Ring,
+}
+
+impl SplitRing {
+    fn new(size: u16) -> Result<Self, VirtioError> {
+        let size = size as usize;
+
+        let ring = Self {
+            descriptors: DmaBuffer::new(size * DESCRIPTOR_SIZE).ok_or(VirtioError::OutOfMemory)?,
+            available: DmaBuffer::new(6 + 2 * size).ok_or(VirtioError::OutOfMemory)?,
+            used: DmaBuffer::new(6 + USED_ELEMENT_SIZE * size).ok_or(VirtioError::OutOfMemory)?,
+            free_head: 0,
+            next_available: 0,
+            last_used: 0,
+        };
+
+        for index in 0..size {
+            unsafe { (*ring.descriptor(index as u16)).next = (index + 1) as u16 };
+        }
+
+        Ok(ring)
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe {
+            self.descriptors
+                .as_mut_ptr::<Descriptor>()
+                .add(index as usize)
+        }
+    }
+
+    fn available_flags(&self) -> *mut u16 {
+        self.available.as_mut_ptr()
+    }
+
+    fn available_index(&self) -> *mut u16 {
+        unsafe { self.available.as_mut_ptr::<u16>().add(1) }
+    }
+
+    fn available_entry(&self, slot: u16) -> *mut u16 {
+        unsafe { self.available.as_mut_ptr::<u16>().add(2 + slot as usize) }
+    }
+
+    fn used_flags(&self) -> *const u16 {
+        self.used.as_ptr()
+    }
+
+    fn used_index(&self) -> *const u16 {
+        unsafe { self.used.as_ptr::<u16>().add(1) }
+    }
+
+    /// The ID and written length of an entry in the used ring.
+    fn used_entry(&self, slot: u16) -> (u32, u32) {
+        unsafe {
+            let entry = self
+                .used
+                .as_ptr::<u8>()
+                .add(4 + slot as usize * USED_ELEMENT_SIZE) as *const u32;
+
+            (entry.read_volatile(), entry.add(1).read_volatile())
+        }
+    }
+
+    fn add(&mut self, size: u16, buffers: &[VirtqBuffer]) -> u16 {
+        let head = self.free_head;
+        let mut index = head;
+
+        for (position, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            let next = unsafe { (*descriptor).next };
+            let is_last = position == buffers.len() - 1;
+
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            if !is_last {
+                flags |= DESCRIPTOR_NEXT;
+            }
+
+            unsafe {
+                descriptor.write_volatile(Descriptor {
+                    address: buffer.address.as_u64(),
+                    length: buffer.length,
+                    flags,
+                    next,
+                })
+            };
+
+            if is_last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+
+        unsafe {
+            self.available_entry(self.next_available % size)
+                .write_volatile(head);
+        }
+
+        // The device may look at the entry as soon as the index covers it.
+        fence(Ordering::Release);
+
+        self.next_available = self.next_available.wrapping_add(1);
+        unsafe { self.available_index().write_volatile(self.next_available) };
+
+        head
+    }
+
+    fn pop_used(&mut self, size: u16) -> Option<(u16, u32, u16)> {
+        let used_index = unsafe { self.used_index().read_volatile() };
+
+        if used_index == self.last_used {
+            return None;
+        }
+
+        fence(Ordering::Acquire);
+
+        let (head, length) = self.used_entry(self.last_used % size);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Put the chain back on the free list.
+        let head = head as u16;
+        let mut last = head;
+        let mut freed = 1;
+
+        unsafe {
+            while (*self.descriptor(last)).flags & DESCRIPTOR_NEXT != 0 {
+                last = (*self.descriptor(last)).next;
+                freed += 1;
+            }
+
+            (*self.descriptor(last)).next = self.free_head;
+        }
+
+        self.free_head = head;
+
+        Some((head, length, freed))
+    }
+
+    fn device_wants_notification(&self) -> bool {
+        // The available index has to be visible before looking at the device's flags.
+        fence(Ordering::SeqCst);
+
+        unsafe { self.used_flags().read_volatile() & USED_NO_NOTIFY == 0 }
+    }
+
+    fn set_interrupts_enabled(&mut self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAILABLE_NO_INTERRUPT };
+
+        unsafe { self.available_flags().write_volatile(flags) };
+    }
+}
+
+impl PackedRing {
+    fn new(size: u16) -> Result<Self, VirtioError> {
+        Ok(Self {
+            descriptors: DmaBuffer::new(size as usize * DESCRIPTOR_SIZE)
+                .ok_or(VirtioError::OutOfMemory)?,
+            events: DmaBuffer::new(2 * PACKED_DEVICE_EVENT_OFFSET)
+                .ok_or(VirtioError::OutOfMemory)?,
+            next_available: 0,
+            available_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            free_ids: (0..size).rev().collect(),
+            chain_lengths: vec![0; size as usize],
+        })
+    }
+
+    fn descriptor(&self, index: u16) -> *mut PackedDescriptor {
+        unsafe {
+            self.descriptors
+                .as_mut_ptr::<PackedDescriptor>()
+                .add(index as usize)
+        }
+    }
+
+    fn driver_event_flags(&self) -> *mut u16 {
+        unsafe { self.events.as_mut_ptr::<u16>().add(1) }
+    }
+
+    fn device_event_flags(&self) -> *const u16 {
+        unsafe {
+            self.events
+                .as_ptr::<u8>()
+                .add(PACKED_DEVICE_EVENT_OFFSET + 2) as *const u16
+        }
+    }
+
+    fn add(&mut self, size: u16, buffers: &[VirtqBuffer]) -> u16 {
+        let id = self
+            .free_ids
+            .pop()
+            .expect("packed queue has free descriptors but no free buffer ID");
+
+        let head = self.next_available;
+        let mut head_flags = 0;
+
+        for (position, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            if position != buffers.len() - 1 {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            flags.set_bit(PACKED_DESCRIPTOR_AVAILABLE, self.available_wrap);
+            flags.set_bit(PACKED_DESCRIPTOR_USED, !self.available_wrap);
+
+            let descriptor = self.descriptor(self.next_available);
+
+            unsafe {
+                (*descriptor).address = buffer.address.as_u64();
+                (*descriptor).length = buffer.length;
+                (*descriptor).id = id;
+            }
+
+            // The head is made available last, the device stops reading at it until then.
+            if position == 0 {
+                head_flags = flags;
+            } else {
+                unsafe { core::ptr::addr_of_mut!((*descriptor).flags).write_volatile(flags) };
+            }
+
+            self.next_available += 1;
+            if self.next_available == size {
+                self.next_available = 0;
+                self.available_wrap = !self.available_wrap;
+            }
+        }
+
+        self.chain_lengths[id as usize] = buffers.len() as u16;
+
+        fence(Ordering::Release);
+
+        unsafe {
+            core::ptr::addr_of_mut!((*self.descriptor(head)).flags).write_volatile(head_flags)
+        };
+
+        id
+    }
+
+    fn pop_used(&mut self, size: u16) -> Option<(u16, u32, u16)> {
+        let descriptor = self.descriptor(self.next_used);
+        let flags = unsafe { core::ptr::addr_of!((*descriptor).flags).read_volatile() };
+
+        let available = flags.get_bit(PACKED_DESCRIPTOR_AVAILABLE);
+        let used = flags.get_bit(PACKED_DESCRIPTOR_USED);
+
+        if available != used || used != self.used_wrap {
+            return None;
+        }
+
+        fence(Ordering::Acquire);
+
+        let (id, length) = unsafe {
+            (
+                core::ptr::addr_of!((*descriptor).id).read_volatile(),
+                core::ptr::addr_of!((*descriptor).length).read_volatile(),
+            )
+        };
+
+        let freed = self.chain_lengths[id as usize];
+
+        self.next_used += freed;
+        if self.next_used >= size {
+            self.next_used -= size;
+            self.used_wrap = !self.used_wrap;
+        }
+
+        self.free_ids.push(id);
+
+        Some((id, length, freed))
+    }
+
+    fn device_wants_notification(&self) -> bool {
+        fence(Ordering::SeqCst);
+
+        unsafe { self.device_event_flags().read_volatile() != PACKED_EVENTS_DISABLE }
+    }
+
+    fn set_interrupts_enabled(&mut self, enabled: bool) {
+        let flags = if enabled {
+            PACKED_EVENTS_ENABLE
+        } else {
+            PACKED_EVENTS_DISABLE
+        };
+
+        unsafe { self.driver_event_flags().write_volatile(flags) };
+    }
+}
+
+#[allow(unused)]
+impl Virtqueue {
+    pub fn new_split(index: u16, size: u16, notify_address: VirtAddr) -> Result<Self, VirtioError> {
+        assert!(
+            size.is_power_of_two(),
+            "split virtqueue sizes are powers of two"
+        );
+
+        Ok(Self {
+            index,
+            size,
+            notify_address,
+            free_descriptors: size,
+            ring: Ring::Split(SplitRing::new(size)?),
+        })
+    }
+
+    pub fn new_packed(
+        index: u16,
+        size: u16,
+        notify_address: VirtAddr,
+    ) -> Result<Self, VirtioError> {
+        Ok(Self {
+            index,
+            size,
+            notify_address,
+            free_descriptors: size,
+            ring: Ring::Packed(PackedRing::new(size)?),
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn is_packed(&self) -> bool {
+        matches!(self.ring, Ring::Packed(_))
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_descriptors
+    }
+
+    /// The addresses of the descriptor area, the driver area and the device area, for the
+    /// transport to hand to the device.
+    pub fn ring_addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr) {
+        match &self.ring {
+            Ring::Split(ring) => (
+                ring.descriptors.physical_address(),
+                ring.available.physical_address(),
+                ring.used.physical_address(),
+            ),
+            Ring::Packed(ring) => (
+                ring.descriptors.physical_address(),
+                ring.events.physical_address(),
+                ring.events.physical_address() + PACKED_DEVICE_EVENT_OFFSET as u64,
+            ),
+        }
+    }
+
+    /// Makes a chain of buffers available to the device and returns the token that
+    /// [`Virtqueue::pop_used`] reports it with. The device isn't told, see
+    /// [`Virtqueue::notify`].
+    pub fn add(&mut self, buffers: &[VirtqBuffer]) -> Result<u16, VirtioError> {
+        assert!(!buffers.is_empty(), "can't add an empty buffer chain");
+
+        if buffers.len() > self.free_descriptors as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        self.free_descriptors -= buffers.len() as u16;
+
+        Ok(match &mut self.ring {
+            Ring::Split(ring) => ring.add(self.size, buffers),
+            Ring::Packed(ring) => ring.add(self.size, buffers),
+        })
+    }
+
+    /// Tells the device about newly added buffers, unless it asked not to be.
+    pub fn notify(&self) {
+        let wants_notification = match &self.ring {
+            Ring::Split(ring) => ring.device_wants_notification(),
+            Ring::Packed(ring) => ring.device_wants_notification(),
+        };
+
+        if wants_notification {
+            unsafe {
+                self.notify_address
+                    .as_mut_ptr::<u16>()
+                    .write_volatile(self.index)
+            };
+        }
+    }
+
+    /// The token of a chain the device is done with and how many bytes it wrote to it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (token, length, freed) = match &mut self.ring {
+            Ring::Split(ring) => ring.pop_used(self.size)?,
+            Ring::Packed(ring) => ring.pop_used(self.size)?,
+        };
+
+        self.free_descriptors += freed;
+
+        Some((token, length))
+    }
+
+    /// Asks the device not to interrupt when it uses buffers, e.g. while polling. It's only a
+    /// hint, interrupts may still arrive.
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        match &mut self.ring {
+            Ring::Split(ring) => ring.set_interrupts_enabled(enabled),
+            Ring::Packed(ring) => ring.set_interrupts_enabled(enabled),
+        }
+    }
+}